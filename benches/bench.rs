@@ -1,5 +1,7 @@
+use std::time::Duration;
+
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
-use mrecordlog::MultiRecordLog;
+use mrecordlog::{MultiRecordLog, SyncPolicy};
 
 async fn bench_single_size(size: usize, count: usize, loop_count: usize) {
     let tempdir = tempfile::tempdir().unwrap();
@@ -50,5 +52,151 @@ fn insert_throughput(c: &mut Criterion) {
     }
 }
 
-criterion_group!(benches, insert_throughput);
+fn queue_lookup(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let tempdir = tempfile::tempdir().unwrap();
+    let (record_log, queue_id) = runtime.block_on(async {
+        let mut record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        for i in 0..1_000 {
+            record_log
+                .create_queue(&format!("queue-{i}"))
+                .await
+                .unwrap();
+        }
+        let queue_id = record_log.resolve_queue("queue-500").unwrap();
+        record_log
+            .append_record_by_id(queue_id, None, &b"record"[..])
+            .await
+            .unwrap();
+        (record_log, queue_id)
+    });
+
+    let mut group = c.benchmark_group("queue lookup");
+    group.bench_function("last_position_by_name", |b| {
+        b.iter(|| record_log.last_position("queue-500").unwrap())
+    });
+    group.bench_function("last_position_by_id", |b| {
+        b.iter(|| record_log.last_position_by_id(queue_id).unwrap())
+    });
+}
+
+fn append_overhead(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let tempdir = tempfile::tempdir().unwrap();
+    // Syncing rarely, so that the per append overhead is not hidden by io.
+    let mut record_log = runtime.block_on(async {
+        let sync_policy = SyncPolicy::OnDelay(Duration::from_secs(3600));
+        let mut record_log = MultiRecordLog::open_with_prefs(tempdir.path(), sync_policy)
+            .await
+            .unwrap();
+        record_log.create_queue("q1").await.unwrap();
+        record_log
+    });
+    let queue_id = record_log.resolve_queue("q1").unwrap();
+
+    let mut group = c.benchmark_group("append overhead");
+    group.bench_function("append_small_record", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let position = record_log
+                    .append_record_by_id(queue_id, None, &b"record"[..])
+                    .await
+                    .unwrap()
+                    .unwrap();
+                // keep memory usage bounded
+                if position % 1024 == 1023 {
+                    record_log.truncate("q1", position).await.unwrap();
+                }
+            })
+        })
+    });
+}
+
+fn multi_queue_burst(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let tempdir = tempfile::tempdir().unwrap();
+    let queues: Vec<String> = (0..64).map(|i| format!("queue-{i}")).collect();
+    // Flushing on each operation, so that the number of write syscalls shows.
+    let mut record_log = runtime.block_on(async {
+        let mut record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        for queue in &queues {
+            record_log.create_queue(queue).await.unwrap();
+        }
+        record_log
+    });
+
+    let mut group = c.benchmark_group("multi queue burst");
+    group.throughput(criterion::Throughput::Elements(queues.len() as u64));
+    group.bench_function("append_record", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                for queue in &queues {
+                    record_log
+                        .append_record(queue, None, &b"record"[..])
+                        .await
+                        .unwrap();
+                }
+            })
+        })
+    });
+    group.bench_function("append_to_queues", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let records = queues.iter().map(|queue| (queue.as_str(), &b"record"[..]));
+                record_log.append_to_queues(records).await.unwrap();
+            })
+        })
+    });
+}
+
+fn replay(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let tempdir = tempfile::tempdir().unwrap();
+    let num_bytes: usize = 1 << 26;
+    runtime.block_on(async {
+        let sync_policy = SyncPolicy::OnDelay(Duration::from_secs(3600));
+        let mut record_log = MultiRecordLog::open_with_prefs(tempdir.path(), sync_policy)
+            .await
+            .unwrap();
+        record_log.create_queue("q1").await.unwrap();
+        let record = vec![0; 1 << 10];
+        for _ in 0..num_bytes / record.len() {
+            record_log
+                .append_record("q1", None, &record[..])
+                .await
+                .unwrap();
+        }
+    });
+
+    let mut group = c.benchmark_group("replay");
+    group.throughput(criterion::Throughput::Bytes(num_bytes as _));
+    group.bench_function("open", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                MultiRecordLog::open(tempdir.path()).await.unwrap();
+            })
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    insert_throughput,
+    queue_lookup,
+    append_overhead,
+    multi_queue_burst,
+    replay
+);
 criterion_main!(benches);
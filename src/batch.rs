@@ -0,0 +1,94 @@
+use bytes::{Buf, Bytes};
+
+/// Operations applied together by
+/// [`MultiRecordLog::commit_batch`](crate::MultiRecordLog::commit_batch): either all of them
+/// are, or none.
+///
+/// Operations are applied in the order they were added, each one seeing the effects of the
+/// previous ones.
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    pub(crate) operations: Vec<BatchOperation>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum BatchOperation {
+    CreateQueue { queue: String },
+    Append { queue: String, payloads: Vec<Bytes> },
+    Truncate { queue: String, position: u64 },
+    DeleteQueue { queue: String },
+}
+
+impl BatchOperation {
+    pub fn queue(&self) -> &str {
+        match self {
+            BatchOperation::CreateQueue { queue }
+            | BatchOperation::Append { queue, .. }
+            | BatchOperation::Truncate { queue, .. }
+            | BatchOperation::DeleteQueue { queue } => queue,
+        }
+    }
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Batch::default()
+    }
+
+    /// Creates a queue. See [`MultiRecordLog::create_queue`](crate::MultiRecordLog::create_queue).
+    pub fn create_queue(&mut self, queue: &str) -> &mut Self {
+        self.operations.push(BatchOperation::CreateQueue {
+            queue: queue.to_string(),
+        });
+        self
+    }
+
+    /// Appends a record at the next position of the queue.
+    pub fn append_record(&mut self, queue: &str, payload: impl Buf) -> &mut Self {
+        self.append_records(queue, std::iter::once(payload))
+    }
+
+    /// Appends records at the next positions of the queue.
+    pub fn append_records(
+        &mut self,
+        queue: &str,
+        payloads: impl IntoIterator<Item = impl Buf>,
+    ) -> &mut Self {
+        let payloads = payloads
+            .into_iter()
+            .map(|mut payload| payload.copy_to_bytes(payload.remaining()))
+            .collect();
+        self.operations.push(BatchOperation::Append {
+            queue: queue.to_string(),
+            payloads,
+        });
+        self
+    }
+
+    /// Truncates the queue up to `position`, included. See
+    /// [`MultiRecordLog::truncate`](crate::MultiRecordLog::truncate).
+    pub fn truncate(&mut self, queue: &str, position: u64) -> &mut Self {
+        self.operations.push(BatchOperation::Truncate {
+            queue: queue.to_string(),
+            position,
+        });
+        self
+    }
+
+    /// Deletes a queue. See [`MultiRecordLog::delete_queue`](crate::MultiRecordLog::delete_queue).
+    pub fn delete_queue(&mut self, queue: &str) -> &mut Self {
+        self.operations.push(BatchOperation::DeleteQueue {
+            queue: queue.to_string(),
+        });
+        self
+    }
+
+    /// Returns the number of operations.
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+}
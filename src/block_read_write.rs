@@ -2,6 +2,8 @@ use std::io;
 
 use async_trait::async_trait;
 
+use crate::frame::ChecksumAlgo;
+
 pub const BLOCK_NUM_BYTES: usize = 32_768;
 
 #[async_trait]
@@ -23,6 +25,19 @@ pub trait BlockRead {
     /// May panic if the last call to next_block returned `false`
     /// or returned an io::Error.
     fn block(&self) -> &[u8; BLOCK_NUM_BYTES];
+
+    /// Returns true if blocks were skipped between the previous block and the current one.
+    ///
+    /// Frames at the beginning of the current block may then continue a record whose other
+    /// frames were not read.
+    fn follows_skipped_blocks(&self) -> bool {
+        false
+    }
+
+    /// Returns the algorithm of the checksums of the frames of the current block.
+    fn checksum_algo(&self) -> ChecksumAlgo {
+        ChecksumAlgo::Crc32
+    }
 }
 
 #[async_trait]
@@ -33,13 +48,19 @@ pub trait BlockWrite {
     async fn flush(&mut self) -> io::Result<()>;
     /// Number of bytes that can be added in the block.
     fn num_bytes_remaining_in_block(&self) -> usize;
+    /// Returns the algorithm of the checksums of the frames written next.
+    fn checksum_algo(&self) -> ChecksumAlgo {
+        ChecksumAlgo::Crc32
+    }
 }
 
+#[cfg(test)]
 pub struct ArrayReader<'a> {
     block: [u8; BLOCK_NUM_BYTES],
     data: &'a [u8],
 }
 
+#[cfg(test)]
 impl<'a> From<&'a [u8]> for ArrayReader<'a> {
     fn from(data: &'a [u8]) -> Self {
         assert!(data.len() >= BLOCK_NUM_BYTES);
@@ -50,6 +71,7 @@ impl<'a> From<&'a [u8]> for ArrayReader<'a> {
     }
 }
 
+#[cfg(test)]
 #[async_trait]
 impl<'a> BlockRead for ArrayReader<'a> {
     async fn next_block(&mut self) -> io::Result<bool> {
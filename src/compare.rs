@@ -0,0 +1,113 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use crate::error::ReadRecordError;
+use crate::mem::MemQueues;
+use crate::multi_record_log::replay;
+use crate::recordlog::RecordReader;
+use crate::rolling::RollingReader;
+use crate::{TokioFileSystem, UnknownRecordPolicy};
+
+/// Result of [`logs_equivalent`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Equivalence {
+    Equal,
+    /// The logs differ. Only the first difference found is reported, queues being compared in
+    /// the order of their names.
+    Diff {
+        queue: String,
+        diff: QueueDiff,
+    },
+}
+
+/// Difference between the two versions of a queue compared by [`logs_equivalent`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueueDiff {
+    /// The queue only exists in the first log.
+    OnlyInFirst,
+    /// The queue only exists in the second log.
+    OnlyInSecond,
+    /// The record at `position` is only stored in one of the logs, or has a different payload or
+    /// different headers.
+    Record { position: u64 },
+    /// The queues hold the same records, but their next positions differ. This can only happen
+    /// when the queues are empty.
+    NextPosition { first: u64, second: u64 },
+}
+
+/// Compares the logs stored in the directories `first` and `second`.
+///
+/// Logs are equivalent if they have the same queues, holding the same records at the same
+/// positions, regardless of how these records are laid out in files. The logs are only replayed:
+/// none of their files gets written, created or removed.
+pub async fn logs_equivalent(first: &Path, second: &Path) -> Result<Equivalence, ReadRecordError> {
+    let first_queues = load_queues(first).await?;
+    let second_queues = load_queues(second).await?;
+    let queues: BTreeSet<&str> = first_queues
+        .list_queues()
+        .chain(second_queues.list_queues())
+        .collect();
+    for queue in queues {
+        if let Some(diff) = diff_queue(&first_queues, &second_queues, queue) {
+            return Ok(Equivalence::Diff {
+                queue: queue.to_string(),
+                diff,
+            });
+        }
+    }
+    Ok(Equivalence::Equal)
+}
+
+async fn load_queues(dir_path: &Path) -> Result<MemQueues, ReadRecordError> {
+    let mut in_mem_queues = MemQueues::default();
+    let Some(rolling_reader) =
+        RollingReader::open_read_only(TokioFileSystem::new(dir_path)).await?
+    else {
+        return Ok(in_mem_queues);
+    };
+    let mut record_reader = RecordReader::open(rolling_reader);
+    replay(
+        &mut record_reader,
+        &mut in_mem_queues,
+        None,
+        UnknownRecordPolicy::Skip,
+    )
+    .await?;
+    Ok(in_mem_queues)
+}
+
+fn diff_queue(first: &MemQueues, second: &MemQueues, queue: &str) -> Option<QueueDiff> {
+    let (Ok(mut first_records), Ok(mut second_records)) = (
+        first.range_with_headers(queue, ..),
+        second.range_with_headers(queue, ..),
+    ) else {
+        return if first.contains_queue(queue) {
+            Some(QueueDiff::OnlyInFirst)
+        } else {
+            Some(QueueDiff::OnlyInSecond)
+        };
+    };
+    loop {
+        match (first_records.next(), second_records.next()) {
+            (None, None) => break,
+            (Some((position, ..)), None) | (None, Some((position, ..))) => {
+                return Some(QueueDiff::Record { position });
+            }
+            (Some(first_record), Some(second_record)) => {
+                if first_record != second_record {
+                    let position = first_record.0.min(second_record.0);
+                    return Some(QueueDiff::Record { position });
+                }
+            }
+        }
+    }
+    let first_next_position = first.next_position(queue).ok()?;
+    let second_next_position = second.next_position(queue).ok()?;
+    if first_next_position != second_next_position {
+        return Some(QueueDiff::NextPosition {
+            first: first_next_position,
+            second: second_next_position,
+        });
+    }
+    None
+}
@@ -1,4 +1,6 @@
 use std::io;
+use std::ops::Bound;
+use std::time::Duration;
 
 use thiserror::Error;
 
@@ -9,6 +11,25 @@ pub struct AlreadyExists;
 pub enum CreateQueueError {
     #[error("Already exists")]
     AlreadyExists,
+    #[error("Payload too large")]
+    TooLarge,
+    /// The queue was deleted at the last position, no record can be appended to it anymore.
+    #[error("Position overflow")]
+    PositionOverflow,
+    #[error("Invalid queue name: {reason}")]
+    InvalidName { reason: String },
+    /// The queue is not in the queues filter, and files which may hold it were skipped on
+    /// open, see [`Options::write_queue_indexes`](crate::Options::write_queue_indexes).
+    #[error("Queue filtered out")]
+    FilteredOut,
+    /// The payload of the first record was empty, while the log was opened with
+    /// [`Options::reject_empty_payloads`](crate::Options::reject_empty_payloads).
+    #[error("Empty payload")]
+    EmptyPayload,
+    /// Writing the first record of the queue could make disk usage exceed
+    /// [`Options::hard_disk_cap`](crate::Options::hard_disk_cap). Nothing was written.
+    #[error("Disk full")]
+    DiskFull,
     #[error("Io error: {0}")]
     IoError(#[from] io::Error),
 }
@@ -33,9 +54,24 @@ impl From<MissingQueue> for DeleteQueueError {
     }
 }
 
-#[derive(Debug, Error)]
-#[error("MultiRecordCorruption")]
-pub struct MultiRecordCorruption;
+/// Kind of corruption found in a multi record.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CorruptionReason {
+    /// The bytes left are too few to hold the position and length of an item.
+    TooShort,
+    /// The length of the item goes past the end of the buffer.
+    LengthOverflow,
+    /// The item is flagged as having headers, but they could not be parsed.
+    InvalidHeaders,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Error)]
+#[error("MultiRecordCorruption at byte {byte_offset}: {reason:?}")]
+pub struct MultiRecordCorruption {
+    /// Offset, in the multi record buffer, of the start of the corrupted item.
+    pub byte_offset: usize,
+    pub reason: CorruptionReason,
+}
 
 impl From<MultiRecordCorruption> for ReadRecordError {
     fn from(_: MultiRecordCorruption) -> ReadRecordError {
@@ -47,6 +83,9 @@ impl From<MultiRecordCorruption> for ReadRecordError {
 pub enum TruncateError {
     #[error("Missing queue: {0}")]
     MissingQueue(String),
+    /// The position was not reached yet by the queue, whose next position is `next_position`.
+    #[error("Future position: the next position of the queue is {next_position}")]
+    Future { next_position: u64 },
     #[error("Io error: {0}")]
     IoError(#[from] io::Error),
 }
@@ -60,11 +99,92 @@ impl From<MissingQueue> for TruncateError {
 #[derive(Error, Debug)]
 pub enum AppendError {
     #[error("Io error: {0}")]
-    IoError(#[from] io::Error),
+    IoError(io::Error),
     #[error("Missing queue: {0}")]
     MissingQueue(String),
+    /// The queue was deleted recently. Once files get garbage collected, appending to a
+    /// deleted queue returns [`AppendError::MissingQueue`] instead.
+    #[error("Queue deleted: {0}")]
+    QueueDeleted(String),
     #[error("Past")]
     Past,
+    #[error("Future")]
+    Future,
+    /// The position is further ahead of the next position of the queue, `expected`, than
+    /// [`Options::max_position_gap`](crate::Options::max_position_gap) allows.
+    #[error("Position gap too large: expected {expected}, got {got}")]
+    PositionGapTooLarge { expected: u64, got: u64 },
+    #[error("Corruption")]
+    Corruption,
+    #[error("Payload too large")]
+    TooLarge,
+    /// The key of a header is longer than `u16::MAX` bytes. Nothing was written.
+    #[error("Header key too long: {len} bytes")]
+    HeaderKeyTooLong { len: usize },
+    /// The position of a record would be `u64::MAX`, while the position following the last
+    /// record of a queue must fit in a `u64`. Nothing was written.
+    #[error("Position overflow")]
+    PositionOverflow,
+    /// A payload was empty, while the log was opened with
+    /// [`Options::reject_empty_payloads`](crate::Options::reject_empty_payloads).
+    #[error("Empty payload")]
+    EmptyPayload,
+    /// The append was at the position of the last record of the queue, but does not hold the
+    /// same payload, while the log was opened with
+    /// [`Options::strict_idempotence`](crate::Options::strict_idempotence).
+    #[error("Position conflict")]
+    PositionConflict,
+    /// The last position of the queue was not the one expected by
+    /// [`MultiRecordLog::append_if_last_is`](crate::MultiRecordLog::append_if_last_is).
+    #[error("Last position mismatch: actual {actual:?}")]
+    CasMismatch { actual: Option<u64> },
+    /// Appending the records could make disk usage exceed
+    /// [`Options::hard_disk_cap`](crate::Options::hard_disk_cap). Nothing was written.
+    #[error("Disk full")]
+    DiskFull,
+    /// Appending the records would make the queue exceed
+    /// [`Options::per_queue_rate_limit`](crate::Options::per_queue_rate_limit). Nothing was
+    /// written. The append would be accepted after `retry_after`, if no other append is made to
+    /// the queue in between.
+    #[error("Rate limited: retry after {retry_after:?}")]
+    RateLimited { retry_after: Duration },
+    /// The records were appended, but flushing them did not complete before the flush timeout.
+    /// They get flushed along with the next operation.
+    #[error("Flush timeout")]
+    FlushTimeout,
+}
+
+/// Error wrapped in the io error returned when a flush times out.
+#[derive(Error, Debug)]
+#[error("flush timed out")]
+pub(crate) struct FlushTimeout;
+
+impl FlushTimeout {
+    pub(crate) fn into_io_error(self) -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, self)
+    }
+
+    pub(crate) fn is_flush_timeout(io_error: &io::Error) -> bool {
+        io_error
+            .get_ref()
+            .map_or(false, |error| error.is::<FlushTimeout>())
+    }
+}
+
+impl From<io::Error> for AppendError {
+    fn from(io_error: io::Error) -> Self {
+        if FlushTimeout::is_flush_timeout(&io_error) {
+            AppendError::FlushTimeout
+        } else {
+            AppendError::IoError(io_error)
+        }
+    }
+}
+
+impl From<MultiRecordCorruption> for AppendError {
+    fn from(_: MultiRecordCorruption) -> Self {
+        AppendError::Corruption
+    }
 }
 
 impl From<MissingQueue> for AppendError {
@@ -76,10 +196,163 @@ impl From<MissingQueue> for AppendError {
 #[derive(Debug)]
 pub struct MissingQueue(pub String);
 
+/// Error returned by [`MultiRecordLog::try_range`](crate::MultiRecordLog::try_range).
 #[derive(Error, Debug)]
-pub enum ReadRecordError {
+pub enum RangeError {
+    #[error("Missing queue: {0}")]
+    MissingQueue(String),
+    /// The range starts after its end, so that no position could ever be contained in it.
+    #[error("Inverted range: from {start:?} to {end:?}")]
+    Inverted { start: Bound<u64>, end: Bound<u64> },
+}
+
+impl From<MissingQueue> for RangeError {
+    fn from(missing_queue: MissingQueue) -> Self {
+        RangeError::MissingQueue(missing_queue.0)
+    }
+}
+
+/// Error returned by [`MultiRecordLog::commit_batch`](crate::MultiRecordLog::commit_batch).
+///
+/// The operation errors carry the index of the operation they were returned for, in the order
+/// the operations were added to the batch. Nothing was written when they are returned.
+#[derive(Error, Debug)]
+pub enum BatchError {
+    #[error("Operation {index}: {error}")]
+    CreateQueue {
+        index: usize,
+        error: CreateQueueError,
+    },
+    #[error("Operation {index}: {error}")]
+    Append { index: usize, error: AppendError },
+    #[error("Operation {index}: {error}")]
+    Truncate { index: usize, error: TruncateError },
+    #[error("Operation {index}: {error}")]
+    DeleteQueue {
+        index: usize,
+        error: DeleteQueueError,
+    },
+    /// Writing the batch could make disk usage exceed
+    /// [`Options::hard_disk_cap`](crate::Options::hard_disk_cap). Nothing was written.
+    #[error("Disk full")]
+    DiskFull,
+    /// Writing or syncing the batch failed. The batch was applied if it could not be discarded
+    /// from the log anymore, in which case it gets persisted along with the next operation.
     #[error("Io error: {0}")]
     IoError(#[from] io::Error),
+}
+
+/// Error returned by [`MultiRecordLog::extract_queue`](crate::MultiRecordLog::extract_queue).
+#[derive(Error, Debug)]
+pub enum ExtractError {
+    #[error("Missing queue: {0}")]
+    MissingQueue(String),
+    /// The destination log already has a queue with the same name.
+    #[error("Already exists in the destination log")]
+    AlreadyExists,
+    /// The destination log could not be opened.
+    #[error("Failed to open the destination log: {0}")]
+    OpenDestination(ReadRecordError),
+    /// Writing the records to the destination log failed. The source log was left untouched.
+    #[error("Failed to write to the destination log: {0}")]
+    WriteDestination(AppendError),
+    /// Deleting the queue from the source log failed, once the destination log holds it.
+    #[error("Io error: {0}")]
+    IoError(#[from] io::Error),
+}
+
+impl From<MissingQueue> for ExtractError {
+    fn from(missing_queue: MissingQueue) -> Self {
+        ExtractError::MissingQueue(missing_queue.0)
+    }
+}
+
+impl From<DeleteQueueError> for ExtractError {
+    fn from(delete_queue_error: DeleteQueueError) -> Self {
+        match delete_queue_error {
+            DeleteQueueError::MissingQueue(queue) => ExtractError::MissingQueue(queue),
+            DeleteQueueError::IoError(io_error) => ExtractError::IoError(io_error),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ReadRecordError {
+    #[error("Io error: {0}")]
+    IoError(io::Error),
     #[error("Corruption")]
     Corruption,
+    #[error("Unsupported format version: {got}")]
+    UnsupportedVersion { got: u8 },
+    /// A file header refers to a checksum algorithm unknown to this version.
+    #[error("Unknown checksum algorithm: {id}")]
+    UnknownChecksumAlgo { id: u8 },
+    #[error("Files belonging to different logs were found: {ids:?}")]
+    MixedLogs { ids: Vec<String> },
+    /// A file was found missing while files after it are present. This can't be the result of
+    /// garbage collection, which only deletes the first files.
+    #[error("File {file_number} is missing, while files after it are present")]
+    MissingFile { file_number: u64 },
+    /// Another record log, possibly in another process, has the files open for writing.
+    #[error("Already locked by process {pid:?}")]
+    AlreadyLocked { pid: Option<u32> },
+    /// A record of a type unknown to this version was found, while the log was opened with
+    /// [`UnknownRecordPolicy::Fail`](crate::UnknownRecordPolicy::Fail).
+    #[error("Unknown record type: {record_type}")]
+    UnknownRecordType { record_type: u8 },
+    /// The header of a file holds another file number than its name, while the log was opened
+    /// with [`Options::strict_file_attribution`](crate::Options::strict_file_attribution).
+    #[error("File {file_number} has the header of file {header_file_number}")]
+    FileNumberMismatch {
+        file_number: u64,
+        header_file_number: u64,
+    },
+    /// The directory holds a file which does not belong to the log, while the log was opened
+    /// with [`Options::strict_directory`](crate::Options::strict_directory).
+    #[error("Unexpected file in the log directory: {name}")]
+    UnexpectedFile { name: String },
+    /// Reading was to resume in a file which was garbage collected since the cursor was saved.
+    #[error("Cursor in file {file_number}, behind the first file {first_file_number}")]
+    CursorBehindGc {
+        file_number: u64,
+        first_file_number: u64,
+    },
+    /// Reading was to resume at a location which is not the start of a record.
+    #[error("Cursor at offset {offset} of file {file_number} is not at a record boundary")]
+    InvalidCursor { file_number: u64, offset: u64 },
+    /// Compacting the log once opened failed, see
+    /// [`Options::auto_compact_on_open`](crate::Options::auto_compact_on_open).
+    #[error("Compaction on open failed: {0}")]
+    AutoCompact(AppendError),
+}
+
+impl From<io::Error> for ReadRecordError {
+    fn from(io_error: io::Error) -> Self {
+        match AlreadyLocked::from_io_error(&io_error) {
+            Some(AlreadyLocked { pid }) => ReadRecordError::AlreadyLocked { pid },
+            None => ReadRecordError::IoError(io_error),
+        }
+    }
+}
+
+/// Error wrapped in the io error returned by [`FileSystem::lock`](crate::FileSystem::lock) when
+/// the lock is held by someone else.
+#[derive(Error, Debug, Clone, Copy)]
+#[error("already locked by process {pid:?}")]
+pub struct AlreadyLocked {
+    /// Id of the process holding the lock, if known.
+    pub pid: Option<u32>,
+}
+
+impl AlreadyLocked {
+    pub fn into_io_error(self) -> io::Error {
+        io::Error::new(io::ErrorKind::WouldBlock, self)
+    }
+
+    pub(crate) fn from_io_error(io_error: &io::Error) -> Option<AlreadyLocked> {
+        io_error
+            .get_ref()
+            .and_then(|error| error.downcast_ref::<AlreadyLocked>())
+            .copied()
+    }
 }
@@ -0,0 +1,387 @@
+use std::future::Future;
+use std::io;
+#[cfg(feature = "tokio")]
+use std::io::SeekFrom;
+#[cfg(feature = "tokio")]
+use std::path::{Path, PathBuf};
+use std::task::Poll;
+use std::time::Duration;
+
+use async_trait::async_trait;
+#[cfg(feature = "tokio")]
+use tokio::fs::{File, OpenOptions};
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// A file, as used by the record log.
+///
+/// All operations take an explicit offset, so that implementations do not need to track a
+/// cursor.
+#[async_trait]
+#[allow(clippy::len_without_is_empty)]
+pub trait AsyncFile: Send + Sync + Unpin + 'static {
+    /// Reads bytes starting at `offset` into `buf`, and returns the number of bytes read.
+    ///
+    /// Returning fewer bytes than `buf.len()` is allowed. `0` means the end of the file was
+    /// reached.
+    async fn read(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Writes the entirety of `buf` starting at `offset`.
+    async fn write(&mut self, offset: u64, buf: &[u8]) -> io::Result<()>;
+
+    /// Waits for previous writes to be handed to the underlying storage.
+    async fn flush(&mut self) -> io::Result<()>;
+
+    /// Makes sure previous writes are persisted on the underlying storage.
+    async fn sync(&mut self) -> io::Result<()>;
+
+    /// Returns the length of the file.
+    async fn len(&self) -> io::Result<u64>;
+
+    /// Truncates or extends the file to `len` bytes. Added bytes are zeroes.
+    async fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Hints that the content of the file will not be read soon, so that the memory caching it
+    /// can be released.
+    ///
+    /// This is only a hint: the default implementation does nothing.
+    async fn advise_dont_need(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Guard of the lock taken by [`FileSystem::lock`], releasing it when dropped.
+pub type LockGuard = Box<dyn Send + Sync>;
+
+/// A flat set of files, such as a directory.
+///
+/// This is what the record log uses to create, find and delete its files. Implementing it
+/// makes it possible to run the record log on top of something else than tokio files.
+#[async_trait]
+pub trait FileSystem: Send + Sync + Unpin + 'static {
+    type File: AsyncFile;
+
+    /// Lists the names of the files present.
+    async fn list_files(&self) -> io::Result<Vec<String>>;
+
+    /// Creates a new empty file, failing if it already exists.
+    async fn create_file(&self, name: &str) -> io::Result<Self::File>;
+
+    /// Opens an existing file for reading and writing.
+    async fn open_file(&self, name: &str) -> io::Result<Self::File>;
+
+    /// Deletes a file.
+    async fn remove_file(&self, name: &str) -> io::Result<()>;
+
+    /// Makes the files created and deleted so far durable.
+    ///
+    /// The default implementation is a no-op, for file systems which don't need it.
+    async fn sync_directory(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Takes an exclusive lock on the files, held until the returned guard is dropped, so that
+    /// a single record log writes to them at a time.
+    ///
+    /// If the lock is held by someone else, this fails right away with the error returned by
+    /// [`AlreadyLocked::into_io_error`](crate::error::AlreadyLocked::into_io_error). The default
+    /// implementation does not lock anything.
+    async fn lock(&self) -> io::Result<LockGuard> {
+        Ok(Box::new(()))
+    }
+
+    /// Waits for `duration`.
+    ///
+    /// This is the only timer used by the record log, for the backoff between write retries
+    /// and for [`Options::flush_timeout`](crate::Options::flush_timeout). It is part of the file
+    /// system so that the record log does not depend on the runtime of its files.
+    async fn sleep(duration: Duration);
+}
+
+/// Runs `future`, giving up on it once `duration` has elapsed, as measured by
+/// [`FileSystem::sleep`]. Returns `None` if it timed out.
+pub(crate) async fn timeout<FS: FileSystem, T>(
+    duration: Duration,
+    future: impl Future<Output = T>,
+) -> Option<T> {
+    let mut future = std::pin::pin!(future);
+    let mut sleep = FS::sleep(duration);
+    std::future::poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Some(output));
+        }
+        sleep.as_mut().poll(cx).map(|()| None)
+    })
+    .await
+}
+
+/// File system of the record log types when none is specified: [`TokioFileSystem`] with the
+/// `tokio` feature.
+#[cfg(feature = "tokio")]
+pub type DefaultFileSystem = TokioFileSystem;
+
+/// File system of the record log types when none is specified, without the `tokio` feature.
+#[cfg(not(feature = "tokio"))]
+pub type DefaultFileSystem = NoFileSystem;
+
+/// A [`FileSystem`] which can't be instantiated, used as the default file system without the
+/// `tokio` feature: another one has to be provided to open a log.
+#[cfg(not(feature = "tokio"))]
+#[derive(Clone)]
+pub enum NoFileSystem {}
+
+#[cfg(not(feature = "tokio"))]
+#[async_trait]
+impl AsyncFile for NoFileSystem {
+    async fn read(&mut self, _offset: u64, _buf: &mut [u8]) -> io::Result<usize> {
+        match *self {}
+    }
+
+    async fn write(&mut self, _offset: u64, _buf: &[u8]) -> io::Result<()> {
+        match *self {}
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        match *self {}
+    }
+
+    async fn sync(&mut self) -> io::Result<()> {
+        match *self {}
+    }
+
+    async fn len(&self) -> io::Result<u64> {
+        match *self {}
+    }
+
+    async fn set_len(&mut self, _len: u64) -> io::Result<()> {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "tokio"))]
+#[async_trait]
+impl FileSystem for NoFileSystem {
+    type File = NoFileSystem;
+
+    async fn list_files(&self) -> io::Result<Vec<String>> {
+        match *self {}
+    }
+
+    async fn create_file(&self, _name: &str) -> io::Result<NoFileSystem> {
+        match *self {}
+    }
+
+    async fn open_file(&self, _name: &str) -> io::Result<NoFileSystem> {
+        match *self {}
+    }
+
+    async fn remove_file(&self, _name: &str) -> io::Result<()> {
+        match *self {}
+    }
+
+    async fn sleep(_duration: Duration) {
+        unreachable!("no log can be opened without a file system")
+    }
+}
+
+/// The default [`FileSystem`], storing files in a directory using tokio.
+#[cfg(feature = "tokio")]
+#[derive(Clone)]
+pub struct TokioFileSystem {
+    dir: PathBuf,
+}
+
+#[cfg(feature = "tokio")]
+impl TokioFileSystem {
+    /// Creates a file system backed by `dir_path`, which must exist and be a directory.
+    pub fn new(dir_path: &Path) -> Self {
+        TokioFileSystem {
+            dir: dir_path.to_path_buf(),
+        }
+    }
+
+    pub fn dir_path(&self) -> &Path {
+        &self.dir
+    }
+}
+
+#[cfg(feature = "tokio")]
+#[async_trait]
+impl FileSystem for TokioFileSystem {
+    type File = TokioFile;
+
+    async fn list_files(&self) -> io::Result<Vec<String>> {
+        let mut file_names = Vec::new();
+        let mut read_dir = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(dir_entry) = read_dir.next_entry().await? {
+            if !dir_entry.file_type().await?.is_file() {
+                continue;
+            }
+            if let Some(file_name) = dir_entry.file_name().to_str() {
+                file_names.push(file_name.to_string());
+            }
+        }
+        Ok(file_names)
+    }
+
+    async fn create_file(&self, name: &str) -> io::Result<TokioFile> {
+        let file = OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(self.dir.join(name))
+            .await?;
+        Ok(TokioFile::from(file))
+    }
+
+    async fn open_file(&self, name: &str) -> io::Result<TokioFile> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(self.dir.join(name))
+            .await?;
+        Ok(TokioFile::from(file))
+    }
+
+    async fn remove_file(&self, name: &str) -> io::Result<()> {
+        tokio::fs::remove_file(self.dir.join(name)).await
+    }
+
+    /// Fsyncs the directory, so that its entries survive a crash. This is a no-op on platforms
+    /// other than unix ones, where directories can't be opened.
+    #[cfg(unix)]
+    async fn sync_directory(&self) -> io::Result<()> {
+        tokio::fs::File::open(&self.dir).await?.sync_all().await
+    }
+
+    /// Locks the `LOCK` file of the directory with `flock`, and writes the id of the current
+    /// process to it. This is a no-op on platforms other than unix ones.
+    #[cfg(unix)]
+    async fn lock(&self) -> io::Result<LockGuard> {
+        let path = self.dir.join(LOCK_FILE_NAME);
+        tokio::task::spawn_blocking(move || lock_file(&path)).await?
+    }
+
+    async fn sleep(duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+}
+
+/// Name of the file locked by [`TokioFileSystem::lock`].
+pub(crate) const LOCK_FILE_NAME: &str = "LOCK";
+
+#[cfg(all(unix, feature = "tokio"))]
+fn lock_file(path: &Path) -> io::Result<LockGuard> {
+    use std::io::{Read, Write};
+    use std::os::unix::io::AsRawFd;
+
+    use crate::error::AlreadyLocked;
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)?;
+    // Safety: `fd` is a valid file descriptor, owned by `file` for the whole call.
+    let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if ret != 0 {
+        let io_error = io::Error::last_os_error();
+        if io_error.kind() != io::ErrorKind::WouldBlock {
+            return Err(io_error);
+        }
+        let mut content = String::new();
+        // The pid is only informative: the holder may not have written it yet.
+        let pid = file
+            .read_to_string(&mut content)
+            .ok()
+            .and_then(|_| content.trim().parse().ok());
+        return Err(AlreadyLocked { pid }.into_io_error());
+    }
+    file.set_len(0)?;
+    write!(file, "{}", std::process::id())?;
+    // The lock is released when the file gets closed.
+    Ok(Box::new(file))
+}
+
+/// A tokio file, remembering its cursor to avoid seeking when reads and writes are sequential.
+#[cfg(feature = "tokio")]
+pub struct TokioFile {
+    file: File,
+    // None if unknown, e.g. after a failed operation.
+    cursor: Option<u64>,
+}
+
+#[cfg(feature = "tokio")]
+impl From<File> for TokioFile {
+    fn from(file: File) -> Self {
+        TokioFile {
+            file,
+            cursor: Some(0),
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl TokioFile {
+    async fn seek(&mut self, offset: u64) -> io::Result<()> {
+        if self.cursor != Some(offset) {
+            self.cursor = None;
+            self.file.seek(SeekFrom::Start(offset)).await?;
+            self.cursor = Some(offset);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tokio")]
+#[async_trait]
+impl AsyncFile for TokioFile {
+    async fn read(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.seek(offset).await?;
+        self.cursor = None;
+        let num_bytes = self.file.read(buf).await?;
+        self.cursor = Some(offset + num_bytes as u64);
+        Ok(num_bytes)
+    }
+
+    async fn write(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.seek(offset).await?;
+        self.cursor = None;
+        self.file.write_all(buf).await?;
+        self.cursor = Some(offset + buf.len() as u64);
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.file.flush().await
+    }
+
+    async fn sync(&mut self) -> io::Result<()> {
+        self.file.flush().await?;
+        self.file.sync_data().await
+    }
+
+    async fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata().await?.len())
+    }
+
+    async fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len).await
+    }
+
+    /// Advises the kernel to drop the file from the page cache. This is a no-op on platforms
+    /// other than Linux.
+    #[cfg(target_os = "linux")]
+    async fn advise_dont_need(&mut self) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let fd = self.file.as_raw_fd();
+        // Safety: `fd` is a valid file descriptor, owned by `self.file` for the whole call.
+        let errno = unsafe { libc::posix_fadvise(fd, 0, 0, libc::POSIX_FADV_DONTNEED) };
+        if errno != 0 {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+/// Algorithm computing the checksum of each frame, see
+/// [`Options::checksum`](crate::Options::checksum).
+///
+/// The algorithm is recorded in the header of each file, so that files written with different
+/// algorithms can be read together. Logs written with an algorithm other than
+/// [`ChecksumAlgo::Crc32`] cannot be read by versions which don't know how to choose it.
+///
+/// The default is [`ChecksumAlgo::Crc32c`] if the CPU accelerates it, and
+/// [`ChecksumAlgo::Crc32`] otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgo {
+    /// CRC-32 (IEEE), the algorithm used before it could be chosen. It is hardware accelerated
+    /// on most platforms.
+    Crc32,
+    /// CRC-32C (Castagnoli), which detects more errors than CRC-32. It is hardware accelerated
+    /// on x86-64 CPUs supporting SSE 4.2.
+    Crc32c,
+    /// xxHash32, which is fast on any CPU.
+    XxHash32,
+}
+
+impl Default for ChecksumAlgo {
+    fn default() -> Self {
+        if has_crc32c_acceleration() {
+            ChecksumAlgo::Crc32c
+        } else {
+            ChecksumAlgo::Crc32
+        }
+    }
+}
+
+impl ChecksumAlgo {
+    /// Returns the id of the algorithm, as stored in file headers.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            ChecksumAlgo::Crc32 => 0,
+            ChecksumAlgo::Crc32c => 1,
+            ChecksumAlgo::XxHash32 => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<ChecksumAlgo> {
+        match id {
+            0 => Some(ChecksumAlgo::Crc32),
+            1 => Some(ChecksumAlgo::Crc32c),
+            2 => Some(ChecksumAlgo::XxHash32),
+            _ => None,
+        }
+    }
+
+    /// Computes the checksum of a frame.
+    pub(crate) fn frame_checksum(self, frame_type: u8, payload: &[u8]) -> u32 {
+        match self {
+            ChecksumAlgo::Crc32 => {
+                let mut hash = crc32fast::Hasher::default();
+                hash.update(&[frame_type]);
+                hash.update(payload);
+                hash.finalize()
+            }
+            ChecksumAlgo::Crc32c => crc32c::crc32c_append(crc32c::crc32c(&[frame_type]), payload),
+            ChecksumAlgo::XxHash32 => xxhash_rust::xxh32::xxh32(payload, frame_type as u32),
+        }
+    }
+}
+
+fn has_crc32c_acceleration() -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("sse4.2") {
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum_algo_id() {
+        for checksum_algo in [
+            ChecksumAlgo::Crc32,
+            ChecksumAlgo::Crc32c,
+            ChecksumAlgo::XxHash32,
+        ] {
+            assert_eq!(
+                ChecksumAlgo::from_id(checksum_algo.id()),
+                Some(checksum_algo)
+            );
+        }
+        assert_eq!(ChecksumAlgo::from_id(3), None);
+    }
+
+    #[test]
+    fn test_frame_checksum_depends_on_frame_type() {
+        for checksum_algo in [
+            ChecksumAlgo::Crc32,
+            ChecksumAlgo::Crc32c,
+            ChecksumAlgo::XxHash32,
+        ] {
+            assert_ne!(
+                checksum_algo.frame_checksum(1, b"payload"),
+                checksum_algo.frame_checksum(2, b"payload")
+            );
+        }
+        // The checksums stored in existing files must not change.
+        assert_eq!(
+            ChecksumAlgo::Crc32c.frame_checksum(1, b"payload"),
+            0x8ac9_ccb0
+        );
+        assert_eq!(
+            ChecksumAlgo::XxHash32.frame_checksum(1, b"payload"),
+            0x3de6_91c5
+        );
+        let data: Vec<u8> = (0..100u8).collect();
+        assert_eq!(ChecksumAlgo::Crc32c.frame_checksum(3, &data), 0x068d_8c16);
+        assert_eq!(ChecksumAlgo::XxHash32.frame_checksum(3, &data), 0xdd70_6768);
+        // Frames written before the algorithm could be chosen.
+        let mut hash = crc32fast::Hasher::default();
+        hash.update(&[1u8]);
+        hash.update(b"payload");
+        assert_eq!(
+            ChecksumAlgo::Crc32.frame_checksum(1, b"payload"),
+            hash.finalize()
+        );
+    }
+}
@@ -1,11 +1,6 @@
-pub const HEADER_LEN: usize = 4 + 2 + 1;
+use crate::frame::ChecksumAlgo;
 
-fn crc32(data: &[u8], frame_type: u8) -> u32 {
-    let mut hash = crc32fast::Hasher::default();
-    hash.update(&[frame_type]);
-    hash.update(data);
-    hash.finalize()
-}
+pub const HEADER_LEN: usize = 4 + 2 + 1;
 
 #[derive(Debug, Eq, PartialEq)]
 pub(crate) struct Header {
@@ -15,10 +10,14 @@ pub(crate) struct Header {
 }
 
 impl Header {
-    pub fn for_payload(frame_type: FrameType, payload: &[u8]) -> Header {
+    pub fn for_payload(
+        frame_type: FrameType,
+        payload: &[u8],
+        checksum_algo: ChecksumAlgo,
+    ) -> Header {
         assert!(payload.len() < crate::BLOCK_NUM_BYTES);
         Header {
-            checksum: crc32(payload, frame_type as u8),
+            checksum: checksum_algo.frame_checksum(frame_type as u8, payload),
             len: payload.len() as u16,
             frame_type,
         }
@@ -32,8 +31,8 @@ impl Header {
         self.frame_type
     }
 
-    pub fn check(&self, payload: &[u8]) -> bool {
-        crc32(payload, self.frame_type as u8) == self.checksum
+    pub fn check(&self, payload: &[u8], checksum_algo: ChecksumAlgo) -> bool {
+        checksum_algo.frame_checksum(self.frame_type as u8, payload) == self.checksum
     }
 
     /// Serialize the header
@@ -1,10 +1,14 @@
+mod checksum;
 mod header;
 mod reader;
 mod writer;
 
+pub use self::checksum::ChecksumAlgo;
 use self::header::Header;
 pub(crate) use self::header::{FrameType, HEADER_LEN};
+pub(crate) use self::reader::{scan_frame, ScannedFrame};
 pub use self::reader::{FrameReader, ReadFrameError};
+pub(crate) use self::writer::padding_frame;
 pub use self::writer::FrameWriter;
 
 #[cfg(test)]
@@ -1,8 +1,10 @@
 use std::io;
+use std::ops::Range;
 
 use thiserror::Error;
 
-use crate::frame::{FrameType, FrameWriter, Header, HEADER_LEN};
+use crate::file_system::FileSystem;
+use crate::frame::{ChecksumAlgo, FrameType, FrameWriter, Header, HEADER_LEN};
 use crate::rolling::{RollingReader, RollingWriter};
 use crate::{BlockRead, BLOCK_NUM_BYTES};
 
@@ -12,8 +14,14 @@ pub struct FrameReader<R> {
     /// In block cursor
     cursor: usize,
 
+    // In block offset of the last frame read.
+    frame_start: usize,
+
     // The current block is corrupted.
     block_corrupted: bool,
+
+    // Blocks were skipped: frames are dropped until one starts a record.
+    skip_continuation_frames: bool,
 }
 
 #[derive(Error, Debug)]
@@ -31,7 +39,9 @@ impl<R: BlockRead + Unpin> FrameReader<R> {
         FrameReader {
             reader,
             cursor: 0,
+            frame_start: 0,
             block_corrupted: false,
+            skip_continuation_frames: false,
         }
     }
 
@@ -39,6 +49,26 @@ impl<R: BlockRead + Unpin> FrameReader<R> {
         &self.reader
     }
 
+    /// Returns the offset of the next frame in the current block.
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Moves to the offset `cursor` of the current block of the underlying reader.
+    pub fn set_cursor(&mut self, cursor: usize) {
+        assert!(cursor <= BLOCK_NUM_BYTES);
+        self.cursor = cursor;
+        self.block_corrupted = false;
+        self.skip_continuation_frames = false;
+    }
+
+    /// Skips the rest of the current block, starting with the last frame read: reading resumes
+    /// with the next block. Returns the range of the bytes skipped in the current block.
+    pub fn skip_block(&mut self) -> Range<usize> {
+        self.block_corrupted = true;
+        self.frame_start..BLOCK_NUM_BYTES
+    }
+
     // Returns the number of bytes remaining into
     // the current block.
     //
@@ -56,6 +86,9 @@ impl<R: BlockRead + Unpin> FrameReader<R> {
         if !self.reader.next_block().await? {
             return Err(ReadFrameError::NotAvailable);
         }
+        if self.reader.follows_skipped_blocks() {
+            self.skip_continuation_frames = true;
+        }
 
         self.cursor = 0;
         self.block_corrupted = false;
@@ -78,36 +111,106 @@ impl<R: BlockRead + Unpin> FrameReader<R> {
         }
     }
 
+    // Returns true if the next frame is valid and starts a record, or if there is no next frame
+    // yet. The frame is not consumed.
+    pub async fn is_at_record_boundary(&mut self) -> Result<bool, ReadFrameError> {
+        match self.go_to_next_block_if_necessary().await {
+            Ok(()) => {}
+            Err(ReadFrameError::NotAvailable) => return Ok(true),
+            Err(err) => return Err(err),
+        }
+        let header = match self.get_frame_header() {
+            Ok(header) => header,
+            Err(ReadFrameError::NotAvailable) => return Ok(true),
+            Err(ReadFrameError::Corruption) => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        let payload_start = self.cursor + HEADER_LEN;
+        if payload_start + header.len() > BLOCK_NUM_BYTES {
+            return Ok(false);
+        }
+        let frame_payload = &self.reader.block()[payload_start..][..header.len()];
+        let checksum_algo = self.reader.checksum_algo();
+        Ok(header.check(frame_payload, checksum_algo)
+            && header.frame_type().is_first_frame_of_record())
+    }
+
     // Reads the next frame.
+    //
+    // Frames following skipped blocks which continue a record are dropped, as the beginning of
+    // their record was not read.
     pub async fn read_frame(&mut self) -> Result<(FrameType, &[u8]), ReadFrameError> {
-        self.go_to_next_block_if_necessary().await?;
-        let header = self.get_frame_header()?;
-        self.cursor += HEADER_LEN;
-        if self.cursor + header.len() > BLOCK_NUM_BYTES {
-            // The number of bytes for this frame would span over
-            // the next block.
-            // This is a corruption for which we need to drop the entire block.
-            self.block_corrupted = true;
-            return Err(ReadFrameError::Corruption);
-        }
-        let frame_payload = &self.reader.block()[self.cursor..][..header.len()];
-        self.cursor += header.len();
-        if !header.check(frame_payload) {
-            // The CRC check is wrong.
-            // We do not necessarily need to corrupt the block.
-            //
-            // With a little luck, a single frame payload byte was corrupted
-            // but the frame length was correct.
-            return Err(ReadFrameError::Corruption);
+        loop {
+            self.go_to_next_block_if_necessary().await?;
+            self.frame_start = self.cursor;
+            let header = self.get_frame_header()?;
+            self.cursor += HEADER_LEN;
+            if self.cursor + header.len() > BLOCK_NUM_BYTES {
+                // The number of bytes for this frame would span over
+                // the next block.
+                // This is a corruption for which we need to drop the entire block.
+                self.block_corrupted = true;
+                return Err(ReadFrameError::Corruption);
+            }
+            let frame_start = self.cursor;
+            self.cursor += header.len();
+            let frame_payload = &self.reader.block()[frame_start..][..header.len()];
+            if !header.check(frame_payload, self.reader.checksum_algo()) {
+                // The CRC check is wrong.
+                // We do not necessarily need to corrupt the block.
+                //
+                // With a little luck, a single frame payload byte was corrupted
+                // but the frame length was correct.
+                return Err(ReadFrameError::Corruption);
+            }
+            if self.skip_continuation_frames {
+                if !header.frame_type().is_first_frame_of_record() {
+                    continue;
+                }
+                self.skip_continuation_frames = false;
+            }
+            let frame_payload = &self.reader.block()[frame_start..][..header.len()];
+            return Ok((header.frame_type(), frame_payload));
         }
-        Ok((header.frame_type(), frame_payload))
     }
 }
 
-impl FrameReader<RollingReader> {
-    pub async fn into_writer(self) -> io::Result<FrameWriter<RollingWriter>> {
-        let mut rolling_writer: RollingWriter = self.reader.into_writer().await?;
+impl<FS: FileSystem> FrameReader<RollingReader<FS>> {
+    pub async fn into_writer(self) -> io::Result<FrameWriter<RollingWriter<FS>>> {
+        let mut rolling_writer: RollingWriter<FS> = self.reader.into_writer().await?;
         rolling_writer.forward(self.cursor).await?;
         Ok(FrameWriter::create(rolling_writer))
     }
 }
+
+/// Frame found by [`scan_frame`].
+pub(crate) enum ScannedFrame {
+    /// A valid frame, taking `num_bytes` bytes header included.
+    Valid {
+        frame_type: FrameType,
+        num_bytes: usize,
+    },
+    /// No frame was written there yet.
+    NotAvailable,
+    Corrupted,
+}
+
+/// Checks the frame starting at the beginning of `data`, which holds the rest of its block, the
+/// way [`FrameReader::read_frame`] does.
+pub(crate) fn scan_frame(data: &[u8], checksum_algo: ChecksumAlgo) -> ScannedFrame {
+    let header_bytes = &data[..HEADER_LEN];
+    if header_bytes == [0u8; HEADER_LEN] {
+        return ScannedFrame::NotAvailable;
+    }
+    let Some(header) = Header::deserialize(header_bytes) else {
+        return ScannedFrame::Corrupted;
+    };
+    let num_bytes = HEADER_LEN + header.len();
+    if num_bytes > data.len() || !header.check(&data[HEADER_LEN..num_bytes], checksum_algo) {
+        return ScannedFrame::Corrupted;
+    }
+    ScannedFrame::Valid {
+        frame_type: header.frame_type(),
+        num_bytes,
+    }
+}
@@ -1,6 +1,7 @@
 use std::io;
 
-use crate::frame::{FrameType, Header, HEADER_LEN};
+use crate::file_system::FileSystem;
+use crate::frame::{ChecksumAlgo, FrameType, Header, HEADER_LEN};
 use crate::rolling::{Directory, RollingWriter};
 use crate::{BlockWrite, BLOCK_NUM_BYTES};
 
@@ -32,7 +33,7 @@ impl<W: BlockWrite + Unpin> FrameWriter<W> {
         let record_len = HEADER_LEN + payload.len();
         let (buffer_header, buffer_record) = self.buffer[..record_len].split_at_mut(HEADER_LEN);
         buffer_record.copy_from_slice(payload);
-        Header::for_payload(frame_type, payload).serialize(buffer_header);
+        Header::for_payload(frame_type, payload, self.wrt.checksum_algo()).serialize(buffer_header);
         self.wrt.write(&self.buffer[..record_len]).await?;
         Ok(())
     }
@@ -57,18 +58,55 @@ impl<W: BlockWrite + Unpin> FrameWriter<W> {
         }
     }
 
+    /// Returns the number of bytes written to store a record of `record_len` bytes, frame
+    /// headers and block padding included.
+    pub fn num_bytes_for_record(&self, record_len: usize) -> usize {
+        let mut num_bytes_remaining_in_block = self.wrt.num_bytes_remaining_in_block();
+        let mut record_len_remaining = record_len;
+        let mut num_bytes = 0;
+        loop {
+            if num_bytes_remaining_in_block < HEADER_LEN {
+                num_bytes += num_bytes_remaining_in_block;
+                num_bytes_remaining_in_block = BLOCK_NUM_BYTES;
+            }
+            let frame_payload_len =
+                (num_bytes_remaining_in_block - HEADER_LEN).min(record_len_remaining);
+            num_bytes += HEADER_LEN + frame_payload_len;
+            num_bytes_remaining_in_block -= HEADER_LEN + frame_payload_len;
+            record_len_remaining -= frame_payload_len;
+            if record_len_remaining == 0 {
+                return num_bytes;
+            }
+        }
+    }
+
     pub fn get_underlying_wrt(&self) -> &W {
         &self.wrt
     }
 
+    pub fn get_underlying_wrt_mut(&mut self) -> &mut W {
+        &mut self.wrt
+    }
+
     #[cfg(test)]
     pub fn into_writer(self) -> W {
         self.wrt
     }
 }
 
-impl FrameWriter<RollingWriter> {
-    pub fn directory(&mut self) -> &mut Directory {
+impl<FS: FileSystem> FrameWriter<RollingWriter<FS>> {
+    pub fn directory(&mut self) -> &mut Directory<FS> {
         &mut self.wrt.directory
     }
 }
+
+/// Returns `num_bytes` bytes which readers skip when found between records: a middle frame
+/// taking all of them, or zeros if they are too few to hold a frame header.
+pub(crate) fn padding_frame(num_bytes: usize, checksum_algo: ChecksumAlgo) -> Vec<u8> {
+    let mut frame = vec![0u8; num_bytes];
+    if num_bytes >= HEADER_LEN {
+        let (header, payload) = frame.split_at_mut(HEADER_LEN);
+        Header::for_payload(FrameType::Middle, payload, checksum_algo).serialize(header);
+    }
+    frame
+}
@@ -0,0 +1,114 @@
+use std::ops::Range;
+use std::path::Path;
+
+use crate::error::ReadRecordError;
+use crate::record::{MultiPlexedRecord, MultiRecord};
+use crate::recordlog::RecordReader;
+use crate::rolling::RollingReader;
+use crate::TokioFileSystem;
+
+/// Operation on a queue found in the log, as returned by [`queue_history`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueueEvent {
+    /// The next position of the queue was recorded. The first one creates the queue. It is also
+    /// recorded again for empty queues, when the files holding it get garbage collected.
+    PositionRecorded { next_position: u64 },
+    /// Records were appended at `positions`.
+    Append { positions: Range<u64> },
+    /// Records were truncated, up to `position` included.
+    Truncate { position: u64 },
+    /// The records of the queue were written again, to compact it or to move it to another log.
+    /// They are now at `positions`.
+    Replace { positions: Range<u64> },
+    /// The queue was deleted, while its next position was `next_position`.
+    Delete { next_position: u64 },
+}
+
+/// Returns the operations on `queue` stored in the log in the directory `dir_path`, in the order
+/// they were written.
+///
+/// Only the operations stored in files which were not garbage collected yet are returned.
+/// Records don't store when they were written, so events are only ordered. Corrupted records
+/// are skipped. The log is not modified, so it may be inspected while being written.
+pub async fn queue_history(
+    dir_path: &Path,
+    queue: &str,
+) -> Result<Vec<QueueEvent>, ReadRecordError> {
+    let mut events = Vec::new();
+    let Some(rolling_reader) =
+        RollingReader::open_read_only(TokioFileSystem::new(dir_path)).await?
+    else {
+        return Ok(events);
+    };
+    let mut record_reader = RecordReader::open(rolling_reader);
+    loop {
+        match record_reader.go_next().await {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(ReadRecordError::Corruption) => continue,
+            Err(err) => return Err(err),
+        }
+        let Some(record) = record_reader.record::<MultiPlexedRecord>() else {
+            continue;
+        };
+        if let MultiPlexedRecord::Batch { records } = record {
+            events.extend(records.filter_map(|record| queue_event(record, queue)));
+        } else if let Some(event) = queue_event(record, queue) {
+            events.push(event);
+        }
+    }
+    Ok(events)
+}
+
+fn queue_event(record: MultiPlexedRecord<'_>, queue: &str) -> Option<QueueEvent> {
+    let event = match record {
+        MultiPlexedRecord::RecordPosition {
+            queue: record_queue,
+            position,
+            ..
+        } if record_queue == queue => QueueEvent::PositionRecorded {
+            next_position: position,
+        },
+        MultiPlexedRecord::AppendRecords {
+            queue: record_queue,
+            records,
+            ..
+        } if record_queue == queue => QueueEvent::Append {
+            positions: positions(records)?,
+        },
+        MultiPlexedRecord::AppendSidecar {
+            queue: record_queue,
+            position,
+            ..
+        } if record_queue == queue => QueueEvent::Append {
+            positions: position..position + 1,
+        },
+        MultiPlexedRecord::Truncate {
+            queue: record_queue,
+            position,
+        } if record_queue == queue => QueueEvent::Truncate { position },
+        MultiPlexedRecord::ReplaceRecords {
+            queue: record_queue,
+            position,
+            records,
+        } if record_queue == queue => QueueEvent::Replace {
+            positions: positions(records).unwrap_or(position..position),
+        },
+        MultiPlexedRecord::DeleteQueue {
+            queue: record_queue,
+            position,
+        } if record_queue == queue => QueueEvent::Delete {
+            next_position: position,
+        },
+        _ => return None,
+    };
+    Some(event)
+}
+
+/// Returns the range of positions of the records, or `None` if there are none.
+fn positions(records: MultiRecord<'_>) -> Option<Range<u64>> {
+    let mut positions = records.filter_map(Result::ok).map(|(position, _)| position);
+    let first_position = positions.next()?;
+    let last_position = positions.last().unwrap_or(first_position);
+    Some(first_position..last_position + 1)
+}
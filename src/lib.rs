@@ -1,20 +1,60 @@
+// Most tests need the `tokio` feature: without it, the helpers they use are unused.
+#![cfg_attr(all(test, not(feature = "tokio")), allow(dead_code, unused_imports))]
+
 mod block_read_write;
 pub use self::block_read_write::{BlockRead, BlockWrite, BLOCK_NUM_BYTES};
 
+mod batch;
+#[cfg(feature = "tokio")]
+mod compare;
 pub mod error;
+mod file_system;
 mod frame;
+#[cfg(feature = "tokio")]
+mod history;
 mod mem;
 mod multi_record_log;
+mod options;
+mod read_only;
 mod record;
 mod recordlog;
 mod rolling;
+mod shared;
+mod typed;
 
-pub use self::multi_record_log::{MultiRecordLog, SyncPolicy};
+pub use self::batch::Batch;
+#[cfg(feature = "tokio")]
+pub use self::compare::{logs_equivalent, Equivalence, QueueDiff};
+#[cfg(not(feature = "tokio"))]
+pub use self::file_system::NoFileSystem;
+pub use self::file_system::{AsyncFile, DefaultFileSystem, FileSystem, LockGuard};
+#[cfg(feature = "tokio")]
+pub use self::file_system::{TokioFile, TokioFileSystem};
+pub use self::frame::ChecksumAlgo;
+#[cfg(feature = "tokio")]
+pub use self::history::{queue_history, QueueEvent};
+pub use self::mem::{MemoryUsage, QueueId, QueueMemoryUsage, QueueStats};
+pub use self::multi_record_log::{
+    CompactReport, DurableAcks, Health, LogStats, MultiRecordLog, PendingFlush, RangeCursor,
+    RangeSnapshot, SyncPolicy,
+};
+pub use self::options::{
+    AutoCompact, CacheAdvice, OpenProgress, Options, QueueNamePolicy, RateLimit, RetryPolicy,
+    UnknownRecordPolicy,
+};
+pub use self::read_only::ReadOnlyMultiRecordLog;
+pub use self::record::{MultiRecord, MultiRecordBuilder, RecordHeaders};
+pub use self::recordlog::RecordReader;
+#[cfg(feature = "tokio")]
+pub use self::rolling::verify_and_repair_in_place;
+pub use self::rolling::{verify_and_repair_in_place_with_file_system, RepairReport, RollingReader};
+pub use self::shared::SharedMultiRecordLog;
+pub use self::typed::{Record, TypedMultiRecordLog};
 
-#[cfg(test)]
+#[cfg(all(test, feature = "tokio"))]
 mod tests;
 
-#[cfg(test)]
+#[cfg(all(test, feature = "tokio"))]
 mod proptests;
 
 pub trait Serializable<'a>: Sized {
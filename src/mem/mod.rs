@@ -1,8 +1,11 @@
 mod queue;
+mod queue_map;
 mod queues;
+mod rate_limiter;
 
 pub use self::queue::MemQueue;
-pub use self::queues::MemQueues;
+pub use self::queue_map::QueueId;
+pub use self::queues::{MemQueues, MemoryUsage, QueueMemoryUsage, QueueStats};
 
 #[cfg(test)]
 mod tests;
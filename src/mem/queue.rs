@@ -1,10 +1,23 @@
 use std::borrow::Cow;
-use std::collections::VecDeque;
-use std::ops::{Bound, RangeBounds};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::ops::{Bound, Range, RangeBounds};
+
+use tokio::sync::watch;
 
 use crate::error::AppendError;
+use crate::record::{RecordHeaders, KEY_HEADER};
 use crate::rolling::FileNumber;
 
+/// Runs `f` on a blocking thread if called from a tokio runtime, so as not to block its
+/// executor. Other runtimes run it in place.
+async fn run_blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) => handle.spawn_blocking(f).await.unwrap(),
+        Err(_) => f(),
+    }
+}
+
 #[derive(Default)]
 struct RollingBuffer {
     buffer: VecDeque<u8>,
@@ -21,6 +34,10 @@ impl RollingBuffer {
         self.buffer.len()
     }
 
+    fn capacity(&self) -> usize {
+        self.buffer.capacity()
+    }
+
     fn clear(&mut self) {
         self.buffer.clear();
         self.buffer.shrink_to_fit();
@@ -38,12 +55,11 @@ impl RollingBuffer {
 
         if self.buffer.capacity() > target_capacity {
             let mut buffer = std::mem::take(&mut self.buffer);
-            self.buffer = tokio::task::spawn_blocking(move || {
+            self.buffer = run_blocking(move || {
                 buffer.shrink_to(target_capacity);
                 buffer
             })
-            .await
-            .unwrap();
+            .await;
         }
     }
 
@@ -59,16 +75,34 @@ impl RollingBuffer {
             // we need to realocate, which can be slow. Do that on a
             // blocking task
             let mut buffer = std::mem::take(&mut self.buffer);
-            self.buffer = tokio::task::spawn_blocking(move || {
+            self.buffer = run_blocking(move || {
                 buffer.reserve(capacity);
                 buffer
             })
-            .await
-            .unwrap();
+            .await;
         }
     }
 
-    fn get_range(&self, bounds: impl RangeBounds<usize>) -> Cow<[u8]> {
+    /// Returns the bytes in `range`, as two slices to concatenate.
+    fn get_slices(&self, range: Range<usize>) -> (&[u8], &[u8]) {
+        let (left_part_of_queue, right_part_of_queue) = self.buffer.as_slices();
+        let left_len = left_part_of_queue.len();
+        if range.end <= left_len {
+            (&left_part_of_queue[range], &[])
+        } else if range.start >= left_len {
+            (
+                &right_part_of_queue[range.start - left_len..range.end - left_len],
+                &[],
+            )
+        } else {
+            (
+                &left_part_of_queue[range.start..],
+                &right_part_of_queue[..range.end - left_len],
+            )
+        }
+    }
+
+    fn get_range(&self, bounds: impl RangeBounds<usize>) -> Cow<'_, [u8]> {
         let start = match bounds.start_bound() {
             Bound::Included(pos) => *pos,
             Bound::Excluded(pos) => pos + 1,
@@ -106,6 +140,27 @@ impl RollingBuffer {
     }
 }
 
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Hashes a record, for the digest of its queue.
+///
+/// This uses FNV-1a, so that the digest does not depend on the version or the platform of the
+/// process computing it. The payload may be provided in several slices.
+fn record_digest(position: u64, payload_slices: &[&[u8]]) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS;
+    let position_bytes = position.to_le_bytes();
+    let bytes = payload_slices
+        .iter()
+        .flat_map(|slice| slice.iter())
+        .chain(position_bytes.iter());
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
 #[derive(Clone)]
 struct RecordMeta {
     start_offset: usize,
@@ -115,12 +170,31 @@ struct RecordMeta {
     position: u64,
 }
 
-#[derive(Default)]
 pub struct MemQueue {
     // Concatenated records
     concatenated_records: RollingBuffer,
     start_position: u64,
     record_metas: Vec<RecordMeta>,
+    // Headers of the records which have some, indexed by position.
+    headers: BTreeMap<u64, RecordHeaders>,
+    // Position of the last record appended with each key, if keys are indexed.
+    positions_by_key: HashMap<Vec<u8>, u64>,
+    // Publishes the next position each time it moves forward. It gets closed when the queue is
+    // dropped.
+    next_position_tx: watch::Sender<u64>,
+    // File number and next position of the last position record written for the queue while it
+    // was empty.
+    recorded_position: Option<(u64, u64)>,
+    // Format tag of the queue, with the file number of the last record holding it.
+    format_tag: Option<(u32, u64)>,
+    // XOR of the digests of the records of the queue.
+    digest: u64,
+}
+
+impl Default for MemQueue {
+    fn default() -> Self {
+        MemQueue::with_next_position(0)
+    }
 }
 
 impl MemQueue {
@@ -129,9 +203,57 @@ impl MemQueue {
             concatenated_records: RollingBuffer::new(),
             start_position: next_position,
             record_metas: Vec::new(),
+            headers: BTreeMap::new(),
+            positions_by_key: HashMap::new(),
+            next_position_tx: watch::channel(next_position).0,
+            recorded_position: None,
+            format_tag: None,
+            digest: 0,
         }
     }
 
+    /// Returns true if the position of the queue needs to be recorded so that it survives the
+    /// deletion of the files before `first_kept_file_number`.
+    ///
+    /// This is not the case if it was already recorded in a file that will be kept, and the
+    /// next position has not changed since.
+    pub fn needs_position_record(&self, first_kept_file_number: u64) -> bool {
+        let Some((file_number, position)) = self.recorded_position else {
+            return true;
+        };
+        position != self.next_position() || file_number < first_kept_file_number
+    }
+
+    /// Remembers that the next position of the queue was recorded in the file `file_number`.
+    pub fn set_position_recorded(&mut self, file_number: u64) {
+        self.recorded_position = Some((file_number, self.next_position()));
+    }
+
+    /// Returns the format tag the queue was created with, if any.
+    pub fn format_tag(&self) -> Option<u32> {
+        self.format_tag.map(|(format_tag, _)| format_tag)
+    }
+
+    /// Remembers the format tag of the queue, recorded in the file `file_number`.
+    pub fn set_format_tag(&mut self, format_tag: u32, file_number: u64) {
+        self.format_tag = Some((format_tag, file_number));
+    }
+
+    /// Returns true if the queue has a format tag which needs to be recorded again so that it
+    /// survives the deletion of the files before `first_kept_file_number`.
+    pub fn needs_format_tag_record(&self, first_kept_file_number: u64) -> bool {
+        matches!(self.format_tag, Some((_, file_number)) if file_number < first_kept_file_number)
+    }
+
+    /// Returns a receiver notified each time the next position of the queue moves forward.
+    pub fn subscribe_next_position(&self) -> watch::Receiver<u64> {
+        self.next_position_tx.subscribe()
+    }
+
+    fn notify_next_position(&self) {
+        self.next_position_tx.send_replace(self.next_position());
+    }
+
     pub fn is_empty(&self) -> bool {
         self.record_metas.is_empty()
     }
@@ -141,8 +263,16 @@ impl MemQueue {
         self.next_position().checked_sub(1)
     }
 
+    /// Returns a digest of the records of the queue, their positions and payloads.
+    ///
+    /// Queues holding the same records have the same digest. It is maintained as records are
+    /// appended and truncated, so that reading it is cheap.
+    pub fn digest(&self) -> u64 {
+        self.digest
+    }
+
     /// Returns the last record stored in the queue.
-    pub fn last_record(&self) -> Option<(u64, Cow<[u8]>)> {
+    pub fn last_record(&self) -> Option<(u64, Cow<'_, [u8]>)> {
         self.record_metas.last().map(|record| {
             (
                 record.position,
@@ -159,6 +289,16 @@ impl MemQueue {
             .unwrap_or(self.start_position)
     }
 
+    /// Returns the range of positions of the records currently stored in the queue.
+    pub fn live_range(&self) -> Range<u64> {
+        let start_position = self
+            .record_metas
+            .first()
+            .map(|record| record.position)
+            .unwrap_or(self.start_position);
+        start_position..self.next_position()
+    }
+
     /// Appends a new record at a given position.
     /// Returns an error if the record was not added.
     ///
@@ -173,6 +313,9 @@ impl MemQueue {
         if target_position < next_position {
             return Err(AppendError::Past);
         }
+        if target_position == u64::MAX {
+            return Err(AppendError::PositionOverflow);
+        }
         if self.start_position == 0u64 && self.record_metas.is_empty() {
             self.start_position = target_position;
         }
@@ -194,9 +337,53 @@ impl MemQueue {
         };
         self.record_metas.push(record_meta);
         self.concatenated_records.extend(payload).await;
+        self.digest ^= record_digest(target_position, &[payload]);
+        self.notify_next_position();
         Ok(())
     }
 
+    /// Makes all the records of the queue reference `file_number`, once they were all written
+    /// again to this file.
+    pub fn move_to_file(&mut self, file_number: &FileNumber) {
+        for record_meta in &mut self.record_metas {
+            record_meta.file_number = None;
+        }
+        if let Some(last_record_meta) = self.record_metas.last_mut() {
+            last_record_meta.file_number = Some(file_number.clone());
+        }
+    }
+
+    /// Attaches headers to the record at `position`.
+    pub fn set_headers(&mut self, position: u64, headers: RecordHeaders) {
+        self.headers.insert(position, headers);
+    }
+
+    /// Indexes the record at `position` by its key, if its headers hold one.
+    pub fn index_key(&mut self, position: u64) {
+        let key = self
+            .headers(position)
+            .iter()
+            .find(|(name, _)| name == KEY_HEADER)
+            .map(|(_, key)| key.clone());
+        if let Some(key) = key {
+            self.positions_by_key.insert(key, position);
+        }
+    }
+
+    /// Returns the position of the last record appended with `key`, if it is still stored and
+    /// keys are indexed.
+    pub fn position_of_key(&self, key: &[u8]) -> Option<u64> {
+        self.positions_by_key.get(key).copied()
+    }
+
+    /// Returns the headers of the record at `position`, empty if it has none.
+    pub fn headers(&self, position: u64) -> &[(String, Vec<u8>)] {
+        self.headers
+            .get(&position)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
     /// Get the position of the record.
     ///
     /// Returns Ok(_) if the record was found, or Err(idx) with idx being the index just after
@@ -206,7 +393,11 @@ impl MemQueue {
             .binary_search_by_key(&position, |record| record.position)
     }
 
-    pub fn range<R>(&self, range: R) -> impl Iterator<Item = (u64, Cow<[u8]>)> + '_
+    /// Returns the records whose position is contained in `range`, in increasing position order.
+    ///
+    /// Bounds may fall outside of the live range of the queue: they are clamped to it. A range
+    /// whose start comes after its end yields no records.
+    pub fn range<R>(&self, range: R) -> impl Iterator<Item = (u64, Cow<'_, [u8]>)> + '_
     where R: RangeBounds<u64> + 'static {
         let start_idx: usize = match range.start_bound() {
             Bound::Included(&start_from) => {
@@ -245,6 +436,27 @@ impl MemQueue {
             })
     }
 
+    /// Returns a reader over the payload of the record at `position`, if it is stored.
+    ///
+    /// Unlike [`Self::range`], this never copies the payload.
+    pub fn record_reader(&self, position: u64) -> Option<impl io::Read + '_> {
+        let idx = self.position_to_idx(position).ok()?;
+        let (first_part, second_part) =
+            self.concatenated_records.get_slices(self.record_range(idx));
+        Some(io::Read::chain(first_part, second_part))
+    }
+
+    /// Returns the range of the payload of the record at `idx` in the concatenated records.
+    fn record_range(&self, idx: usize) -> Range<usize> {
+        let start_offset = self.record_metas[idx].start_offset;
+        let end_offset = self
+            .record_metas
+            .get(idx + 1)
+            .map(|next_record_meta| next_record_meta.start_offset)
+            .unwrap_or_else(|| self.concatenated_records.len());
+        start_offset..end_offset
+    }
+
     /// Removes all records coming before position, and including the record at "position".
     ///
     /// If truncating to a future position, make the queue go forward to that position.
@@ -253,11 +465,25 @@ impl MemQueue {
         if self.start_position > truncate_up_to_pos {
             return 0;
         }
+        let kept_headers = self.headers.split_off(&(truncate_up_to_pos + 1));
+        let removed_headers = std::mem::replace(&mut self.headers, kept_headers);
+        if !self.positions_by_key.is_empty() {
+            for (position, headers) in removed_headers {
+                for (name, key) in headers {
+                    // The key may have been appended again since.
+                    if name == KEY_HEADER && self.positions_by_key.get(&key) == Some(&position) {
+                        self.positions_by_key.remove(&key);
+                    }
+                }
+            }
+        }
         if truncate_up_to_pos + 1 >= self.next_position() {
             self.start_position = truncate_up_to_pos + 1;
             self.concatenated_records.clear();
             let record_count = self.record_metas.len();
             self.record_metas.clear();
+            self.digest = 0;
+            self.notify_next_position();
             return record_count;
         }
         let first_record_to_keep = self
@@ -265,6 +491,11 @@ impl MemQueue {
             .unwrap_or_else(std::convert::identity);
 
         let start_offset_to_keep: usize = self.record_metas[first_record_to_keep].start_offset;
+        for idx in 0..first_record_to_keep {
+            let position = self.record_metas[idx].position;
+            let (left, right) = self.concatenated_records.get_slices(self.record_range(idx));
+            self.digest ^= record_digest(position, &[left, right]);
+        }
         self.record_metas.drain(..first_record_to_keep);
         for record_meta in &mut self.record_metas {
             record_meta.start_offset -= start_offset_to_keep;
@@ -276,6 +507,95 @@ impl MemQueue {
         first_record_to_keep
     }
 
+    /// Returns a description of each broken invariant of this queue.
+    ///
+    /// Positions must be strictly increasing, and start at or after `start_position`.
+    pub fn consistency_issues(&self) -> Vec<String> {
+        let mut issues = Vec::new();
+        if let Some(first_record) = self.record_metas.first() {
+            if first_record.position < self.start_position {
+                issues.push(format!(
+                    "first record position {} is before start position {}",
+                    first_record.position, self.start_position
+                ));
+            }
+        }
+        for window in self.record_metas.windows(2) {
+            if window[0].position >= window[1].position {
+                issues.push(format!(
+                    "record positions are not increasing ({} then {})",
+                    window[0].position, window[1].position
+                ));
+            }
+        }
+        issues
+    }
+
+    /// Returns the file numbers referenced by the records of this queue.
+    pub fn file_numbers(&self) -> impl Iterator<Item = &FileNumber> + '_ {
+        self.record_metas
+            .iter()
+            .filter_map(|record_meta| record_meta.file_number.as_ref())
+    }
+
+    /// Returns the position of the last record stored in a file up to `file_number`, included.
+    pub fn last_position_up_to_file(&self, file_number: u64) -> Option<u64> {
+        self.record_metas
+            .iter()
+            .take_while(|record_meta| {
+                record_meta
+                    .file_number
+                    .as_ref()
+                    .map(|record_file_number| record_file_number.file_number() <= file_number)
+                    .unwrap_or(true)
+            })
+            .filter(|record_meta| record_meta.file_number.is_some())
+            .last()
+            .map(|record_meta| record_meta.position)
+    }
+
+    /// Returns the range of positions of the records stored in the file `file_number`, if any.
+    ///
+    /// A record spanning several files is considered to be stored in the first one.
+    pub fn position_range_in_file(&self, file_number: u64) -> Option<Range<u64>> {
+        let mut run_start = self.record_metas.first()?.position;
+        let mut position_range: Option<Range<u64>> = None;
+        for record_meta in &self.record_metas {
+            let Some(record_file_number) = &record_meta.file_number else {
+                continue;
+            };
+            if record_file_number.file_number() == file_number {
+                let start = position_range.map_or(run_start, |position_range| position_range.start);
+                position_range = Some(start..record_meta.position + 1);
+            }
+            run_start = record_meta.position + 1;
+        }
+        position_range
+    }
+
+    #[cfg(test)]
+    pub fn set_start_position_for_test(&mut self, start_position: u64) {
+        self.start_position = start_position;
+    }
+
+    /// Returns the number of records in the queue.
+    pub fn num_records(&self) -> usize {
+        self.record_metas.len()
+    }
+
+    /// Returns the number of payload bytes stored in the queue.
+    pub fn payload_bytes(&self) -> usize {
+        self.concatenated_records.len()
+    }
+
+    /// Returns an estimate of the heap memory used by the queue on top of its payloads: record
+    /// metadata, and allocated but unused capacity.
+    pub fn overhead_bytes_estimate(&self) -> usize {
+        self.record_metas.capacity() * std::mem::size_of::<RecordMeta>()
+            + self.concatenated_records.capacity()
+            - self.concatenated_records.len()
+    }
+
     pub fn size(&self) -> usize {
         self.concatenated_records.len()
             + self.record_metas.len() * std::mem::size_of::<RecordMeta>()
@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Bound;
+
+use crate::mem::MemQueue;
+
+/// Identifier of a queue, giving access to it without looking up its name.
+///
+/// It is obtained when creating the queue, or by resolving its name. An id only designates the
+/// queue it was obtained for: it is invalidated when the queue is deleted, even if a queue with
+/// the same name is created afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct QueueId {
+    index: u32,
+    generation: u32,
+}
+
+impl fmt::Display for QueueId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "queue#{}.{}", self.index, self.generation)
+    }
+}
+
+struct QueueSlot {
+    // Incremented each time the slot is freed, so that ids of the previous queue get rejected.
+    generation: u32,
+    entry: Option<(String, MemQueue)>,
+}
+
+/// Map of queues by name, which can also be accessed by [`QueueId`].
+#[derive(Default)]
+pub(crate) struct QueueMap {
+    // Ordered, so that queue names can be listed page by page.
+    indexes: BTreeMap<String, usize>,
+    slots: Vec<QueueSlot>,
+    free_indexes: Vec<usize>,
+}
+
+impl QueueMap {
+    pub fn id(&self, queue: &str) -> Option<QueueId> {
+        let index = *self.indexes.get(queue)?;
+        Some(QueueId {
+            index: index as u32,
+            generation: self.slots[index].generation,
+        })
+    }
+
+    pub fn get(&self, queue: &str) -> Option<&MemQueue> {
+        let index = *self.indexes.get(queue)?;
+        self.slots[index]
+            .entry
+            .as_ref()
+            .map(|(_, mem_queue)| mem_queue)
+    }
+
+    pub fn get_mut(&mut self, queue: &str) -> Option<&mut MemQueue> {
+        let index = *self.indexes.get(queue)?;
+        self.slots[index]
+            .entry
+            .as_mut()
+            .map(|(_, mem_queue)| mem_queue)
+    }
+
+    fn slot(&self, queue_id: QueueId) -> Option<&QueueSlot> {
+        let slot = self.slots.get(queue_id.index as usize)?;
+        if slot.generation != queue_id.generation {
+            return None;
+        }
+        Some(slot)
+    }
+
+    pub fn get_by_id(&self, queue_id: QueueId) -> Option<(&str, &MemQueue)> {
+        let (queue, mem_queue) = self.slot(queue_id)?.entry.as_ref()?;
+        Some((queue.as_str(), mem_queue))
+    }
+
+    pub fn get_by_id_mut(&mut self, queue_id: QueueId) -> Option<(&str, &mut MemQueue)> {
+        self.slot(queue_id)?;
+        let (queue, mem_queue) = self.slots[queue_id.index as usize].entry.as_mut()?;
+        Some((queue.as_str(), mem_queue))
+    }
+
+    pub fn contains_key(&self, queue: &str) -> bool {
+        self.indexes.contains_key(queue)
+    }
+
+    /// Inserts a queue, replacing the queue with the same name if there is one.
+    pub fn insert(&mut self, queue: String, mem_queue: MemQueue) -> QueueId {
+        self.remove(&queue);
+        let index = if let Some(index) = self.free_indexes.pop() {
+            index
+        } else {
+            self.slots.push(QueueSlot {
+                generation: 0,
+                entry: None,
+            });
+            self.slots.len() - 1
+        };
+        self.indexes.insert(queue.clone(), index);
+        let slot = &mut self.slots[index];
+        slot.entry = Some((queue, mem_queue));
+        QueueId {
+            index: index as u32,
+            generation: slot.generation,
+        }
+    }
+
+    pub fn remove(&mut self, queue: &str) -> Option<MemQueue> {
+        let index = self.indexes.remove(queue)?;
+        let slot = &mut self.slots[index];
+        slot.generation = slot.generation.wrapping_add(1);
+        let (_, mem_queue) = slot.entry.take()?;
+        self.free_indexes.push(index);
+        Some(mem_queue)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &MemQueue)> + '_ {
+        self.slots.iter().filter_map(|slot| {
+            slot.entry
+                .as_ref()
+                .map(|(queue, mem_queue)| (queue, mem_queue))
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&String, &mut MemQueue)> + '_ {
+        self.slots.iter_mut().filter_map(|slot| {
+            slot.entry
+                .as_mut()
+                .map(|(queue, mem_queue)| (&*queue, mem_queue))
+        })
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> + '_ {
+        self.iter().map(|(queue, _)| queue)
+    }
+
+    /// Returns the names of the queues following `after`, or of all queues if `after` is `None`,
+    /// in lexicographic order.
+    pub fn keys_after(&self, after: Option<&str>) -> impl Iterator<Item = &str> + '_ {
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        self.indexes
+            .range::<str, _>((start, Bound::Unbounded))
+            .map(|(queue, _)| queue.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.indexes.len()
+    }
+
+    /// Returns an estimate of the memory used by the map itself, excluding the queues and
+    /// their names.
+    pub fn overhead_bytes_estimate(&self) -> usize {
+        self.indexes.len() * std::mem::size_of::<(String, usize)>()
+            + self.slots.capacity() * std::mem::size_of::<QueueSlot>()
+            + self.free_indexes.capacity() * std::mem::size_of::<usize>()
+    }
+}
@@ -1,54 +1,333 @@
 use std::borrow::Cow;
-use std::collections::HashMap;
-use std::ops::RangeBounds;
+use std::collections::{HashMap, HashSet};
+use std::ops::{Range, RangeBounds};
+use std::time::Instant;
 
 use tracing::{info, warn};
 
 use crate::error::{AlreadyExists, AppendError, MissingQueue};
-use crate::mem::MemQueue;
-use crate::rolling::FileNumber;
+use crate::mem::queue_map::QueueMap;
+use crate::mem::rate_limiter::RateLimiter;
+use crate::mem::{MemQueue, QueueId};
+use crate::options::RateLimit;
+use crate::record::RecordHeaders;
+use crate::rolling::{FileNumber, FileTracker};
+
+/// Breakdown of the memory used by the in-memory queues.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Total number of payload bytes held.
+    pub payload_bytes: usize,
+    /// Estimate of the memory used on top of payloads: record metadata, unused buffer capacity
+    /// and queue map entries.
+    pub overhead_bytes_estimate: usize,
+    pub num_queues: usize,
+    pub num_records: usize,
+    /// Memory used by each queue, ordered by queue name. The overhead of the queue map itself
+    /// is only accounted for in the totals.
+    pub queues: Vec<QueueMemoryUsage>,
+}
+
+/// Memory used by a single queue, as reported in [`MemoryUsage::queues`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueMemoryUsage {
+    pub queue: String,
+    /// Number of payload bytes held by the queue.
+    pub payload_bytes: usize,
+    /// Estimate of the memory used by the queue on top of its payloads, its name included.
+    pub overhead_bytes_estimate: usize,
+    pub num_records: usize,
+}
+
+/// Summary of a queue, as reported by [`crate::MultiRecordLog::stats`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueStats {
+    pub queue: String,
+    /// Number of records currently stored in the queue.
+    pub num_records: usize,
+    /// Number of payload bytes of these records.
+    pub payload_bytes: usize,
+    /// Range of positions of these records.
+    pub live_range: Range<u64>,
+}
 
 #[derive(Default)]
 pub struct MemQueues {
-    queues: HashMap<String, MemQueue>,
+    queues: QueueMap,
+    // If set, only the queues it contains are loaded from disk. Other queues are tracked in
+    // `filtered_out_queues` without their payloads: their positions and file references are
+    // still required to record empty queues positions and to garbage collect files correctly.
+    queues_filter: Option<HashSet<String>>,
+    filtered_out_queues: HashMap<String, MemQueue>,
+    // If set, the positions of deleted queues are kept in `deleted_queues`, so that queues
+    // created again with the same name resume from them.
+    keep_deleted_queue_positions: bool,
+    // If set, records are indexed by the key held in their headers.
+    key_index: bool,
+    deleted_queues: HashMap<String, DeletedQueue>,
+    // Queues deleted since the last gc, so that appending to them can be told apart from
+    // appending to a queue which never existed.
+    recently_deleted_queues: HashSet<String>,
+    recently_deleted_queue_ids: HashSet<QueueId>,
+    // If set, appends to each queue are limited to this rate, tracked in `rate_limiters`.
+    rate_limit: Option<RateLimit>,
+    rate_limiters: HashMap<QueueId, RateLimiter>,
+}
+
+/// Position a deleted queue ended at.
+pub struct DeletedQueue {
+    next_position: u64,
+    // File in which the position was last recorded, if any.
+    recorded_in_file: Option<u64>,
+}
+
+impl DeletedQueue {
+    pub fn next_position(&self) -> u64 {
+        self.next_position
+    }
+
+    /// Returns true if the position of the deleted queue needs to be recorded so that it
+    /// survives the deletion of the files before `first_kept_file_number`.
+    pub fn needs_position_record(&self, first_kept_file_number: u64) -> bool {
+        match self.recorded_in_file {
+            Some(file_number) => file_number < first_kept_file_number,
+            None => true,
+        }
+    }
+
+    /// Remembers that the position of the deleted queue was recorded in the file `file_number`.
+    pub fn set_position_recorded(&mut self, file_number: u64) {
+        self.recorded_in_file = Some(file_number);
+    }
 }
 
 impl MemQueues {
+    pub fn with_queues_filter(queues_filter: Option<HashSet<String>>) -> Self {
+        MemQueues {
+            queues_filter,
+            ..Default::default()
+        }
+    }
+
+    /// Makes queues created with the name of a deleted queue resume from the position the
+    /// deleted queue ended at, instead of 0.
+    pub fn set_keep_deleted_queue_positions(&mut self, keep_deleted_queue_positions: bool) {
+        self.keep_deleted_queue_positions = keep_deleted_queue_positions;
+    }
+
+    /// Makes records appended with a key indexed by it, see [`MemQueue::index_key`].
+    pub fn set_key_index(&mut self, key_index: bool) {
+        self.key_index = key_index;
+    }
+
+    /// Limits the rate of the appends to each queue, see [`Self::check_rate_limit`].
+    pub fn set_rate_limit(&mut self, rate_limit: Option<RateLimit>) {
+        self.rate_limit = rate_limit;
+        self.rate_limiters.clear();
+    }
+
+    /// Returns [`AppendError::RateLimited`] if appending `num_records` records holding
+    /// `num_bytes` bytes to the queue would exceed the rate limit.
+    pub fn check_rate_limit(
+        &self,
+        queue_id: QueueId,
+        num_records: usize,
+        num_bytes: usize,
+    ) -> Result<(), AppendError> {
+        let Some(rate_limit) = self.rate_limit else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let retry_after = match self.rate_limiters.get(&queue_id) {
+            Some(rate_limiter) => rate_limiter.wait_time(num_records, num_bytes, now),
+            None => RateLimiter::new(rate_limit, now).wait_time(num_records, num_bytes, now),
+        };
+        if !retry_after.is_zero() {
+            return Err(AppendError::RateLimited { retry_after });
+        }
+        Ok(())
+    }
+
+    /// Accounts for the append of `num_records` records holding `num_bytes` bytes to the queue
+    /// in its rate limit.
+    pub fn acquire_rate_limit(&mut self, queue_id: QueueId, num_records: usize, num_bytes: usize) {
+        let Some(rate_limit) = self.rate_limit else {
+            return;
+        };
+        let now = Instant::now();
+        self.rate_limiters
+            .entry(queue_id)
+            .or_insert_with(|| RateLimiter::new(rate_limit, now))
+            .acquire(num_records, num_bytes, now);
+    }
+
+    /// Returns true if deleted queues keep their position, so that a queue created with the
+    /// same name starts after it.
+    pub fn keeps_deleted_queue_positions(&self) -> bool {
+        self.keep_deleted_queue_positions
+    }
+
+    /// Returns true if the queue is not loaded in memory because of the queues filter.
+    pub fn is_filtered_out(&self, queue: &str) -> bool {
+        if let Some(queues_filter) = &self.queues_filter {
+            !queues_filter.contains(queue)
+        } else {
+            false
+        }
+    }
+
+    /// Returns the queue, whether it is filtered out or not.
+    fn get_any_queue_mut(&mut self, queue: &str) -> Option<&mut MemQueue> {
+        if self.queues.contains_key(queue) {
+            self.queues.get_mut(queue)
+        } else {
+            self.filtered_out_queues.get_mut(queue)
+        }
+    }
+
+    /// Returns true if the queue exists, including if it is filtered out.
+    pub fn tracks_queue(&self, queue: &str) -> bool {
+        self.queues.contains_key(queue) || self.filtered_out_queues.contains_key(queue)
+    }
+
     /// The file number argument is here unused. Its point is just to make sure we
     /// flushed the file before updating the in memory queue.
-    pub fn create_queue(&mut self, queue: &str) -> Result<(), AlreadyExists> {
-        if self.queues.contains_key(queue) {
+    pub fn create_queue(&mut self, queue: &str) -> Result<QueueId, AlreadyExists> {
+        if self.tracks_queue(queue) {
             return Err(AlreadyExists);
         }
-        self.queues.insert(queue.to_string(), MemQueue::default());
-        Ok(())
+        let next_position = self.initial_position(queue);
+        self.deleted_queues.remove(queue);
+        self.recently_deleted_queues.remove(queue);
+        Ok(self.queues.insert(
+            queue.to_string(),
+            MemQueue::with_next_position(next_position),
+        ))
+    }
+
+    /// Returns the position of the first record of the queue, if it was created now.
+    ///
+    /// This is 0, unless the positions of deleted queues are kept and a queue with this name was
+    /// deleted.
+    pub fn initial_position(&self, queue: &str) -> u64 {
+        self.deleted_queues
+            .get(queue)
+            .map(DeletedQueue::next_position)
+            .unwrap_or(0)
+    }
+
+    /// Returns the id of the queue, which stays valid until the queue is deleted.
+    ///
+    /// Filtered out queues have no id.
+    pub fn queue_id(&self, queue: &str) -> Result<QueueId, MissingQueue> {
+        self.queues
+            .id(queue)
+            .ok_or_else(|| MissingQueue(queue.to_string()))
+    }
+
+    /// Same as [`Self::queue_id`], but tells apart queues deleted since the last gc.
+    pub fn queue_id_for_append(&self, queue: &str) -> Result<QueueId, AppendError> {
+        self.queues.id(queue).ok_or_else(|| {
+            if self.recently_deleted_queues.contains(queue) {
+                AppendError::QueueDeleted(queue.to_string())
+            } else {
+                AppendError::MissingQueue(queue.to_string())
+            }
+        })
+    }
+
+    pub fn queue_name(&self, queue_id: QueueId) -> Result<&str, MissingQueue> {
+        let (queue, _) = self
+            .queues
+            .get_by_id(queue_id)
+            .ok_or_else(|| MissingQueue(queue_id.to_string()))?;
+        Ok(queue)
     }
 
     pub fn delete_queue(&mut self, queue: &str) -> Result<(), MissingQueue> {
         info!(queue = queue, "deleting queue");
-        if self.queues.remove(queue).is_none() {
+        let queue_id_opt = self.queues.id(queue);
+        let Some(mem_queue) = self
+            .queues
+            .remove(queue)
+            .or_else(|| self.filtered_out_queues.remove(queue))
+        else {
             warn!(queue = queue, "attempted to remove a non-existing queue");
             return Err(MissingQueue(queue.to_string()));
+        };
+        self.recently_deleted_queues.insert(queue.to_string());
+        self.recently_deleted_queue_ids.extend(queue_id_opt);
+        if let Some(queue_id) = queue_id_opt {
+            self.rate_limiters.remove(&queue_id);
+        }
+        if self.keep_deleted_queue_positions {
+            let deleted_queue = DeletedQueue {
+                next_position: mem_queue.next_position(),
+                recorded_in_file: None,
+            };
+            self.deleted_queues.insert(queue.to_string(), deleted_queue);
         }
         Ok(())
     }
 
+    /// Remembers that the queue was deleted at `next_position`, as recorded in the file
+    /// `file_number`.
+    ///
+    /// This is a no-op if the positions of deleted queues are not kept, or if the queue exists.
+    pub fn ack_deleted_queue(&mut self, queue: &str, next_position: u64, file_number: u64) {
+        if !self.keep_deleted_queue_positions || self.tracks_queue(queue) {
+            return;
+        }
+        let deleted_queue = DeletedQueue {
+            next_position,
+            recorded_in_file: Some(file_number),
+        };
+        self.deleted_queues.insert(queue.to_string(), deleted_queue);
+    }
+
+    /// Forgets about the queues deleted so far, appending to them now returning a missing queue
+    /// error.
+    pub fn clear_recently_deleted_queues(&mut self) {
+        self.recently_deleted_queues.clear();
+        self.recently_deleted_queue_ids.clear();
+    }
+
+    /// Returns the deleted queues whose positions are kept.
+    pub fn deleted_queues(&mut self) -> impl Iterator<Item = (&'_ str, &mut DeletedQueue)> + '_ {
+        self.deleted_queues
+            .iter_mut()
+            .map(|(queue, deleted_queue)| (queue.as_str(), deleted_queue))
+    }
+
     /// Returns all sub-queues which are currently empty.
+    ///
+    /// This includes queues which are filtered out.
     pub fn empty_queues(&mut self) -> impl Iterator<Item = (&'_ str, &mut MemQueue)> + '_ {
-        self.queues.iter_mut().filter_map(|(queue, mem_queue)| {
-            if mem_queue.is_empty() {
-                Some((queue.as_str(), mem_queue))
-            } else {
-                None
-            }
-        })
+        self.queues
+            .iter_mut()
+            .chain(self.filtered_out_queues.iter_mut())
+            .filter_map(|(queue, mem_queue)| {
+                if mem_queue.is_empty() {
+                    Some((queue.as_str(), mem_queue))
+                } else {
+                    None
+                }
+            })
+    }
+
+    pub fn non_empty_queues(&mut self) -> impl Iterator<Item = (&'_ str, &mut MemQueue)> + '_ {
+        self.queues
+            .iter_mut()
+            .chain(self.filtered_out_queues.iter_mut())
+            .filter(|(_, mem_queue)| !mem_queue.is_empty())
+            .map(|(queue, mem_queue)| (queue.as_str(), mem_queue))
     }
 
     pub fn range<R>(
         &self,
         queue: &str,
         range: R,
-    ) -> Result<impl Iterator<Item = (u64, Cow<[u8]>)> + '_, MissingQueue>
+    ) -> Result<impl Iterator<Item = (u64, Cow<'_, [u8]>)> + '_, MissingQueue>
     where
         R: RangeBounds<u64> + 'static,
     {
@@ -59,6 +338,25 @@ impl MemQueues {
         }
     }
 
+    pub(crate) fn get_queue_by_id(&self, queue_id: QueueId) -> Result<&MemQueue, MissingQueue> {
+        let (_, mem_queue) = self
+            .queues
+            .get_by_id(queue_id)
+            .ok_or_else(|| MissingQueue(queue_id.to_string()))?;
+        Ok(mem_queue)
+    }
+
+    pub fn range_by_id<R>(
+        &self,
+        queue_id: QueueId,
+        range: R,
+    ) -> Result<impl Iterator<Item = (u64, Cow<'_, [u8]>)> + '_, MissingQueue>
+    where
+        R: RangeBounds<u64> + 'static,
+    {
+        Ok(self.get_queue_by_id(queue_id)?.range(range))
+    }
+
     pub(crate) fn get_queue(&self, queue: &str) -> Result<&MemQueue, MissingQueue> {
         // We do not rely on `entry` in order to avoid
         // the allocation.
@@ -67,27 +365,117 @@ impl MemQueues {
             .ok_or_else(|| MissingQueue(queue.to_string()))
     }
 
-    fn get_queue_mut(&mut self, queue: &str) -> Result<&mut MemQueue, MissingQueue> {
-        // We do not rely on `entry` in order to avoid
-        // the allocation.
-        self.queues
-            .get_mut(queue)
-            .ok_or_else(|| MissingQueue(queue.to_string()))
+    /// Appends a record to a queue.
+    ///
+    /// If the queue is filtered out, only its position and file reference are recorded.
+    pub async fn append_record(
+        &mut self,
+        queue: &str,
+        file_number: &FileNumber,
+        target_position: u64,
+        payload: &[u8],
+    ) -> Result<(), AppendError> {
+        let payload = if self.is_filtered_out(queue) {
+            &[]
+        } else {
+            payload
+        };
+        self.get_any_queue_mut(queue)
+            .ok_or_else(|| MissingQueue(queue.to_string()))?
+            .append_record(file_number, target_position, payload)
+            .await?;
+        Ok(())
     }
 
-    pub async fn append_record(
+    /// Appends a record to a queue, along with its headers if it has some.
+    ///
+    /// If the queue is filtered out, only its position and file reference are recorded.
+    pub async fn append_record_with_headers(
         &mut self,
         queue: &str,
         file_number: &FileNumber,
         target_position: u64,
         payload: &[u8],
+        headers: Option<RecordHeaders>,
+    ) -> Result<(), AppendError> {
+        self.append_record(queue, file_number, target_position, payload)
+            .await?;
+        if let (Some(headers), Some(mem_queue)) = (headers, self.queues.get_mut(queue)) {
+            mem_queue.set_headers(target_position, headers);
+            if self.key_index {
+                mem_queue.index_key(target_position);
+            }
+        }
+        Ok(())
+    }
+
+    /// Same as [`Self::append_record_with_headers`], for a queue designated by its id.
+    pub async fn append_record_with_headers_by_id(
+        &mut self,
+        queue_id: QueueId,
+        file_number: &FileNumber,
+        target_position: u64,
+        payload: &[u8],
+        headers: Option<RecordHeaders>,
     ) -> Result<(), AppendError> {
-        self.get_queue_mut(queue)?
+        let (_, mem_queue) = self
+            .queues
+            .get_by_id_mut(queue_id)
+            .ok_or_else(|| MissingQueue(queue_id.to_string()))?;
+        mem_queue
             .append_record(file_number, target_position, payload)
             .await?;
+        if let Some(headers) = headers {
+            mem_queue.set_headers(target_position, headers);
+            if self.key_index {
+                mem_queue.index_key(target_position);
+            }
+        }
         Ok(())
     }
 
+    /// Makes all the records of the queue reference `file_number`, see [`MemQueue::move_to_file`].
+    pub fn move_queue_to_file(
+        &mut self,
+        queue_id: QueueId,
+        file_number: &FileNumber,
+    ) -> Result<(), MissingQueue> {
+        let (_, mem_queue) = self
+            .queues
+            .get_by_id_mut(queue_id)
+            .ok_or_else(|| MissingQueue(queue_id.to_string()))?;
+        mem_queue.move_to_file(file_number);
+        Ok(())
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub fn range_with_headers<R>(
+        &self,
+        queue: &str,
+        range: R,
+    ) -> Result<
+        impl Iterator<Item = (u64, &'_ [(String, Vec<u8>)], Cow<'_, [u8]>)> + '_,
+        MissingQueue,
+    >
+    where
+        R: RangeBounds<u64> + 'static,
+    {
+        let mem_queue = self.get_queue(queue)?;
+        Ok(mem_queue
+            .range(range)
+            .map(|(position, payload)| (position, mem_queue.headers(position), payload)))
+    }
+
+    /// Returns a receiver notified each time the next position of the queue moves forward.
+    ///
+    /// The receiver gets closed if the queue is deleted.
+    pub fn subscribe_next_position(
+        &self,
+        queue: &str,
+    ) -> Result<tokio::sync::watch::Receiver<u64>, MissingQueue> {
+        Ok(self.get_queue(queue)?.subscribe_next_position())
+    }
+
     pub fn contains_queue(&self, queue: &str) -> bool {
         self.queues.contains_key(queue)
     }
@@ -96,6 +484,10 @@ impl MemQueues {
         self.queues.keys().map(|queue| queue.as_str())
     }
 
+    pub fn list_queues_page(&self, after: Option<&str>, limit: usize) -> Vec<&str> {
+        self.queues.keys_after(after).take(limit).collect()
+    }
+
     /// Ensure that the queue is empty and start_position = next_position.
     ///
     /// If the queue doesn't exist, create it. If it does, but isn't empty or the position doesn't
@@ -103,7 +495,13 @@ impl MemQueues {
     ///
     /// This operation is meant only to rebuild the in memory queue from its on-disk state.
     pub fn ack_position(&mut self, queue_name: &str, next_position: u64) {
-        if let Some(queue) = self.queues.get(queue_name) {
+        let is_filtered_out = self.is_filtered_out(queue_name);
+        let queue_opt = if is_filtered_out {
+            self.filtered_out_queues.get(queue_name)
+        } else {
+            self.queues.get(queue_name)
+        };
+        let must_reset = if let Some(queue) = queue_opt {
             // It is possible for `ack_position` to be called when a queue already exists.
             //
             // For instance, we may have recorded the position of an empty stale queue
@@ -111,52 +509,292 @@ impl MemQueues {
             //
             // Another possibility is if an IO error occured right after recording position
             // and before deleting files.
-            if !queue.is_empty() || queue.next_position() != next_position {
-                // if we are here, some updates to the queue were lost/corrupted, but it's no
-                // big deal as they were no longer considered part of the active state. We can
-                // delete and recreate the queue to put it in the expected state.
-                self.queues.remove(queue_name);
-                self.queues.insert(
-                    queue_name.to_string(),
-                    MemQueue::with_next_position(next_position),
-                );
-            }
+            //
+            // If the queue isn't empty or its position doesn't match, some updates to the queue
+            // were lost/corrupted, but it's no big deal as they were no longer considered part
+            // of the active state. We can delete and recreate the queue to put it in the
+            // expected state.
+            !queue.is_empty() || queue.next_position() != next_position
         } else {
             // The queue does not exist! Let's create it and set the right `next_position`.
-            self.queues.insert(
-                queue_name.to_string(),
-                MemQueue::with_next_position(next_position),
-            );
+            true
+        };
+        if !must_reset {
+            return;
+        }
+        self.deleted_queues.remove(queue_name);
+        self.recently_deleted_queues.remove(queue_name);
+        let mem_queue = MemQueue::with_next_position(next_position);
+        if is_filtered_out {
+            self.filtered_out_queues
+                .insert(queue_name.to_string(), mem_queue);
+        } else {
+            self.queues.insert(queue_name.to_string(), mem_queue);
         }
     }
 
+    /// Same as [`Self::ack_position`], for a position record also holding the format tag of the
+    /// queue, recorded in the file `file_number`.
+    ///
+    /// Such records are written again for queues holding records, so that their format tag
+    /// survives the deletion of the file it was first recorded in: the records of the queue are
+    /// kept if it is at `next_position`.
+    pub fn ack_format_tag(
+        &mut self,
+        queue_name: &str,
+        next_position: u64,
+        format_tag: u32,
+        file_number: u64,
+    ) {
+        let keeps_records = self
+            .get_any_queue_mut(queue_name)
+            .map_or(false, |queue| queue.next_position() == next_position);
+        if !keeps_records {
+            self.ack_position(queue_name, next_position);
+        }
+        if let Some(queue) = self.get_any_queue_mut(queue_name) {
+            queue.set_format_tag(format_tag, file_number);
+        }
+    }
+
+    /// Sets the format tag of a queue, recorded in the file `file_number`.
+    pub fn set_format_tag_by_id(&mut self, queue_id: QueueId, format_tag: u32, file_number: u64) {
+        if let Some((_, mem_queue)) = self.queues.get_by_id_mut(queue_id) {
+            mem_queue.set_format_tag(format_tag, file_number);
+        }
+    }
+
+    /// Returns the format tag the queue was created with, if any.
+    pub fn format_tag(&self, queue: &str) -> Result<Option<u32>, MissingQueue> {
+        Ok(self.get_queue(queue)?.format_tag())
+    }
+
     /// Returns the position of the last record appended to the queue.
     pub fn last_position(&self, queue: &str) -> Result<Option<u64>, MissingQueue> {
         Ok(self.get_queue(queue)?.last_position())
     }
 
+    /// Returns a reader over the payload of the record at `position`, if it is stored.
+    pub fn record_reader(
+        &self,
+        queue: &str,
+        position: u64,
+    ) -> Result<Option<impl std::io::Read + '_>, MissingQueue> {
+        Ok(self.get_queue(queue)?.record_reader(position))
+    }
+
+    /// Returns the digest of the records of the queue, see [`MemQueue::digest`].
+    pub fn queue_digest(&self, queue: &str) -> Option<u64> {
+        self.get_queue(queue).ok().map(MemQueue::digest)
+    }
+
+    /// Returns the last record appended to the queue with `key`, if it is still stored.
+    #[allow(clippy::type_complexity)]
+    pub fn get_by_key(
+        &self,
+        queue: &str,
+        key: &[u8],
+    ) -> Result<Option<(u64, Cow<'_, [u8]>)>, MissingQueue> {
+        let mem_queue = self.get_queue(queue)?;
+        let Some(position) = mem_queue.position_of_key(key) else {
+            return Ok(None);
+        };
+        Ok(mem_queue.range(position..=position).next())
+    }
+
     /// Returns the last record stored in the queue.
-    pub fn last_record(&self, queue: &str) -> Result<Option<(u64, Cow<[u8]>)>, MissingQueue> {
+    #[allow(clippy::type_complexity)]
+    pub fn last_record(&self, queue: &str) -> Result<Option<(u64, Cow<'_, [u8]>)>, MissingQueue> {
         Ok(self.get_queue(queue)?.last_record())
     }
 
+    pub fn last_position_by_id(&self, queue_id: QueueId) -> Result<Option<u64>, MissingQueue> {
+        Ok(self.get_queue_by_id(queue_id)?.last_position())
+    }
+
+    pub fn next_position_by_id(&self, queue_id: QueueId) -> Result<u64, MissingQueue> {
+        Ok(self.get_queue_by_id(queue_id)?.next_position())
+    }
+
+    /// Same as [`Self::next_position_by_id`], but tells apart queues deleted since the last gc.
+    pub fn next_position_for_append(&self, queue_id: QueueId) -> Result<u64, AppendError> {
+        self.next_position_by_id(queue_id).map_err(|missing_queue| {
+            if self.recently_deleted_queue_ids.contains(&queue_id) {
+                AppendError::QueueDeleted(missing_queue.0)
+            } else {
+                AppendError::MissingQueue(missing_queue.0)
+            }
+        })
+    }
+
     pub fn next_position(&self, queue: &str) -> Result<u64, MissingQueue> {
         Ok(self.get_queue(queue)?.next_position())
     }
 
+    pub fn live_range(&self, queue: &str) -> Result<Range<u64>, MissingQueue> {
+        Ok(self.get_queue(queue)?.live_range())
+    }
+
+    pub fn live_range_by_id(&self, queue_id: QueueId) -> Result<Range<u64>, MissingQueue> {
+        Ok(self.get_queue_by_id(queue_id)?.live_range())
+    }
+
     /// Removes records up to the supplied `position`,
     /// including the position itself.
     ///
     /// If there are no records `<= position`, the method will
     /// not do anything.
     pub async fn truncate(&mut self, queue: &str, position: u64) -> Option<usize> {
-        if let Ok(queue) = self.get_queue_mut(queue) {
+        if let Some(queue) = self.get_any_queue_mut(queue) {
             Some(queue.truncate(position).await)
         } else {
             None
         }
     }
 
+    /// Same as [`Self::truncate`], for a queue designated by its id.
+    pub async fn truncate_by_id(
+        &mut self,
+        queue_id: QueueId,
+        position: u64,
+    ) -> Result<usize, MissingQueue> {
+        let (_, mem_queue) = self
+            .queues
+            .get_by_id_mut(queue_id)
+            .ok_or_else(|| MissingQueue(queue_id.to_string()))?;
+        Ok(mem_queue.truncate(position).await)
+    }
+
+    /// Returns a description of each broken invariant of the in memory queues.
+    ///
+    /// Records must only reference files that are tracked by `files`, and none of them may be
+    /// after `current_file`, the file being written.
+    pub fn consistency_issues(
+        &self,
+        files: &FileTracker,
+        current_file: &FileNumber,
+    ) -> Vec<String> {
+        let mut issues = Vec::new();
+        for (queue_name, queue) in self.queues.iter().chain(&self.filtered_out_queues) {
+            for issue in queue.consistency_issues() {
+                issues.push(format!("queue {queue_name}: {issue}"));
+            }
+            for file_number in queue.file_numbers() {
+                if file_number > current_file {
+                    issues.push(format!(
+                        "queue {queue_name}: references {} which is after the write head {}",
+                        file_number.filename(),
+                        current_file.filename()
+                    ));
+                }
+                if !files.contains(file_number) {
+                    issues.push(format!(
+                        "queue {queue_name}: references unknown file {}",
+                        file_number.filename()
+                    ));
+                }
+            }
+        }
+        issues
+    }
+
+    /// Returns, for each queue having records stored in a file up to `file_number` included,
+    /// the position of the last of these records.
+    ///
+    /// This includes queues which are filtered out.
+    pub fn last_positions_up_to_file(&self, file_number: u64) -> Vec<(String, u64)> {
+        self.queues
+            .iter()
+            .chain(&self.filtered_out_queues)
+            .filter_map(|(queue_name, queue)| {
+                let position = queue.last_position_up_to_file(file_number)?;
+                Some((queue_name.clone(), position))
+            })
+            .collect()
+    }
+
+    /// Returns the queues having records stored in the file `file_number`, along with the range
+    /// of positions of these records.
+    ///
+    /// This includes queues which are filtered out.
+    pub fn queues_referencing_file(&self, file_number: u64) -> Vec<(&str, Range<u64>)> {
+        self.queues
+            .iter()
+            .chain(&self.filtered_out_queues)
+            .filter_map(|(queue_name, queue)| {
+                let position_range = queue.position_range_in_file(file_number)?;
+                Some((queue_name.as_str(), position_range))
+            })
+            .collect()
+    }
+
+    /// Builds in memory queues holding the records described by `state`, without going through
+    /// the log. Each entry lists the name of a queue, the position of its first record, and the
+    /// payloads of its records.
+    ///
+    /// All the records reference the same untracked file.
+    #[cfg(test)]
+    pub(crate) async fn from_state(state: &[(&str, u64, Vec<&[u8]>)]) -> MemQueues {
+        let file_number = FileNumber::for_test(0);
+        let mut mem_queues = MemQueues::default();
+        for (queue, first_position, payloads) in state {
+            mem_queues.ack_position(queue, *first_position);
+            for (payload, position) in payloads.iter().zip(*first_position..) {
+                mem_queues
+                    .append_record(queue, &file_number, position, payload)
+                    .await
+                    .unwrap();
+            }
+        }
+        mem_queues
+    }
+
+    #[cfg(test)]
+    pub(crate) fn get_queue_mut_for_test(&mut self, queue: &str) -> &mut MemQueue {
+        self.queues.get_mut(queue).unwrap()
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let mut queues: Vec<QueueMemoryUsage> = self
+            .queues
+            .iter()
+            .map(|(queue, mem_queue)| QueueMemoryUsage {
+                queue: queue.clone(),
+                payload_bytes: mem_queue.payload_bytes(),
+                overhead_bytes_estimate: queue.capacity() + mem_queue.overhead_bytes_estimate(),
+                num_records: mem_queue.num_records(),
+            })
+            .collect();
+        queues.sort_unstable_by(|left, right| left.queue.cmp(&right.queue));
+        MemoryUsage {
+            payload_bytes: queues.iter().map(|queue| queue.payload_bytes).sum(),
+            overhead_bytes_estimate: self.queues.overhead_bytes_estimate()
+                + queues
+                    .iter()
+                    .map(|queue| queue.overhead_bytes_estimate)
+                    .sum::<usize>(),
+            num_queues: self.queues.len(),
+            num_records: queues.iter().map(|queue| queue.num_records).sum(),
+            queues,
+        }
+    }
+
+    /// Returns a summary of each queue, ordered by queue name.
+    pub fn queue_stats(&self) -> Vec<QueueStats> {
+        self.queues
+            .keys_after(None)
+            .filter_map(|queue| {
+                let mem_queue = self.queues.get(queue)?;
+                Some(QueueStats {
+                    queue: queue.to_string(),
+                    num_records: mem_queue.num_records(),
+                    payload_bytes: mem_queue.payload_bytes(),
+                    live_range: mem_queue.live_range(),
+                })
+            })
+            .collect()
+    }
+
     pub fn size(&self) -> usize {
         self.queues
             .iter()
@@ -0,0 +1,163 @@
+use std::time::{Duration, Instant};
+
+use crate::options::RateLimit;
+
+/// Token bucket refilled at `rate` tokens per second, holding at most one second worth of
+/// tokens.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last_refill: now,
+        }
+    }
+
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate)
+    }
+
+    /// Returns how long to wait before `cost` tokens can be consumed.
+    ///
+    /// A cost larger than the bucket only requires it to be full: the tokens consumed beyond
+    /// are then paid for by the following appends.
+    fn wait_time(&self, cost: f64, now: Instant) -> Duration {
+        let missing_tokens = cost.min(self.rate) - self.tokens_at(now);
+        if missing_tokens <= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(missing_tokens / self.rate)
+    }
+
+    fn consume(&mut self, cost: f64, now: Instant) {
+        self.tokens = self.tokens_at(now) - cost;
+        self.last_refill = now;
+    }
+}
+
+/// Limits the rate of the appends to a queue, see [`RateLimit`].
+pub(crate) struct RateLimiter {
+    bytes: Option<TokenBucket>,
+    records: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(rate_limit: RateLimit, now: Instant) -> RateLimiter {
+        let token_bucket = |rate: Option<u64>| {
+            rate.filter(|&rate| rate > 0)
+                .map(|rate| TokenBucket::new(rate, now))
+        };
+        RateLimiter {
+            bytes: token_bucket(rate_limit.bytes_per_sec),
+            records: token_bucket(rate_limit.records_per_sec),
+        }
+    }
+
+    /// Returns how long to wait before appending `num_records` records holding `num_bytes`
+    /// bytes is within the limit, or zero if it is right away.
+    pub fn wait_time(&self, num_records: usize, num_bytes: usize, now: Instant) -> Duration {
+        let bytes_wait_time = self.bytes.as_ref().map_or(Duration::ZERO, |bucket| {
+            bucket.wait_time(num_bytes as f64, now)
+        });
+        let records_wait_time = self.records.as_ref().map_or(Duration::ZERO, |bucket| {
+            bucket.wait_time(num_records as f64, now)
+        });
+        bytes_wait_time.max(records_wait_time)
+    }
+
+    /// Accounts for the append of `num_records` records holding `num_bytes` bytes.
+    pub fn acquire(&mut self, num_records: usize, num_bytes: usize, now: Instant) {
+        if let Some(bucket) = &mut self.bytes {
+            bucket.consume(num_bytes as f64, now);
+        }
+        if let Some(bucket) = &mut self.records {
+            bucket.consume(num_records as f64, now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl RateLimiter {
+        fn try_acquire(
+            &mut self,
+            num_records: usize,
+            num_bytes: usize,
+            now: Instant,
+        ) -> Result<(), Duration> {
+            let wait_time = self.wait_time(num_records, num_bytes, now);
+            if !wait_time.is_zero() {
+                return Err(wait_time);
+            }
+            self.acquire(num_records, num_bytes, now);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_rate_limiter_records() {
+        let now = Instant::now();
+        let rate_limit = RateLimit {
+            records_per_sec: Some(10),
+            ..Default::default()
+        };
+        let mut rate_limiter = RateLimiter::new(rate_limit, now);
+        // Bursts of up to one second worth of records are accepted.
+        for _ in 0..10 {
+            rate_limiter.try_acquire(1, 1_000_000, now).unwrap();
+        }
+        assert_eq!(
+            rate_limiter.try_acquire(1, 0, now),
+            Err(Duration::from_millis(100))
+        );
+        let later = now + Duration::from_millis(250);
+        rate_limiter.try_acquire(2, 0, later).unwrap();
+        assert_eq!(
+            rate_limiter.try_acquire(1, 0, later),
+            Err(Duration::from_millis(50))
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter_bytes() {
+        let now = Instant::now();
+        let rate_limit = RateLimit {
+            bytes_per_sec: Some(1000),
+            records_per_sec: Some(1000),
+        };
+        let mut rate_limiter = RateLimiter::new(rate_limit, now);
+        rate_limiter.try_acquire(1, 600, now).unwrap();
+        assert_eq!(
+            rate_limiter.try_acquire(1, 600, now),
+            Err(Duration::from_millis(200))
+        );
+        // A rejected append consumes nothing.
+        rate_limiter.try_acquire(1, 400, now).unwrap();
+        // Appends larger than the bucket wait for it to be full.
+        assert_eq!(
+            rate_limiter.try_acquire(1, 5000, now),
+            Err(Duration::from_secs(1))
+        );
+        let later = now + Duration::from_secs(1);
+        rate_limiter.try_acquire(1, 5000, later).unwrap();
+        assert!(rate_limiter.try_acquire(1, 1, later).is_err());
+    }
+
+    #[test]
+    fn test_rate_limiter_unlimited() {
+        let now = Instant::now();
+        let mut rate_limiter = RateLimiter::new(RateLimit::default(), now);
+        rate_limiter
+            .try_acquire(1_000_000, usize::MAX, now)
+            .unwrap();
+    }
+}
@@ -160,16 +160,7 @@ async fn test_mem_queues_skip_advance() {
 
 #[tokio::test]
 async fn test_mem_queues_append_in_the_past_yield_error() {
-    let mut mem_queues = MemQueues::default();
-    mem_queues.create_queue("droopy").unwrap();
-    assert!(mem_queues
-        .append_record("droopy", &1.into(), 0, b"hello")
-        .await
-        .is_ok());
-    assert!(mem_queues
-        .append_record("droopy", &1.into(), 1, b"happy")
-        .await
-        .is_ok());
+    let mut mem_queues = MemQueues::from_state(&[("droopy", 0, vec![b"hello", b"happy"])]).await;
     assert!(matches!(
         mem_queues
             .append_record("droopy", &1.into(), 0, b"happy")
@@ -178,14 +169,23 @@ async fn test_mem_queues_append_in_the_past_yield_error() {
     ));
 }
 
+#[tokio::test]
+async fn test_mem_queues_append_position_overflow() {
+    let mut mem_queues =
+        MemQueues::from_state(&[("droopy", u64::MAX - 2, vec![b"hello", b"happy"])]).await;
+    assert_eq!(mem_queues.next_position("droopy").unwrap(), u64::MAX);
+    assert!(matches!(
+        mem_queues
+            .append_record("droopy", &1.into(), u64::MAX, b"tax")
+            .await,
+        Err(AppendError::PositionOverflow)
+    ));
+    assert_eq!(mem_queues.next_position("droopy").unwrap(), u64::MAX);
+}
+
 #[tokio::test]
 async fn test_mem_queues_append_idempotence() {
-    let mut mem_queues = MemQueues::default();
-    mem_queues.create_queue("droopy").unwrap();
-    assert!(mem_queues
-        .append_record("droopy", &1.into(), 0, b"hello")
-        .await
-        .is_ok());
+    let mut mem_queues = MemQueues::from_state(&[("droopy", 0, vec![b"hello"])]).await;
     assert!(matches!(
         mem_queues
             .append_record("droopy", &1.into(), 0, b"different")
@@ -277,3 +277,130 @@ async fn test_mem_queues_keep_filenum() {
 
     assert!(files[2].can_be_deleted());
 }
+
+#[tokio::test]
+async fn test_mem_queues_range_bounds() {
+    use std::ops::Bound;
+    // Live positions are 3..8.
+    let payloads: Vec<[u8; 8]> = (3..8u64).map(u64::to_le_bytes).collect();
+    let mem_queues = MemQueues::from_state(&[(
+        "droopy",
+        3,
+        payloads.iter().map(|payload| &payload[..]).collect(),
+    )])
+    .await;
+    let positions = |range: (Bound<u64>, Bound<u64>)| -> Vec<u64> {
+        mem_queues
+            .range("droopy", range)
+            .unwrap()
+            .map(|(position, payload)| {
+                assert_eq!(payload.as_ref(), &position.to_le_bytes());
+                position
+            })
+            .collect()
+    };
+    assert_eq!(
+        positions((Bound::Unbounded, Bound::Unbounded)),
+        &[3, 4, 5, 6, 7]
+    );
+    assert_eq!(positions((Bound::Included(4), Bound::Excluded(6))), &[4, 5]);
+    assert_eq!(positions((Bound::Excluded(4), Bound::Included(6))), &[5, 6]);
+    assert_eq!(
+        positions((Bound::Excluded(4), Bound::Excluded(5))),
+        &[] as &[u64]
+    );
+    // Bounds outside of the live range are clamped.
+    assert_eq!(positions((Bound::Included(0), Bound::Excluded(5))), &[3, 4]);
+    assert_eq!(positions((Bound::Excluded(6), Bound::Included(100))), &[7]);
+    assert_eq!(
+        positions((Bound::Excluded(u64::MAX), Bound::Unbounded)),
+        &[] as &[u64]
+    );
+    assert_eq!(
+        positions((Bound::Included(10), Bound::Unbounded)),
+        &[] as &[u64]
+    );
+    assert_eq!(
+        positions((Bound::Unbounded, Bound::Excluded(0))),
+        &[] as &[u64]
+    );
+    // Empty ranges, where the start comes after the end.
+    assert_eq!(
+        positions((Bound::Included(6), Bound::Excluded(4))),
+        &[] as &[u64]
+    );
+    assert_eq!(
+        positions((Bound::Included(6), Bound::Included(5))),
+        &[] as &[u64]
+    );
+    assert_eq!(
+        positions((Bound::Excluded(5), Bound::Excluded(6))),
+        &[] as &[u64]
+    );
+}
+
+#[tokio::test]
+async fn test_mem_queues_range_after_truncations() {
+    let payloads: Vec<&[u8]> = vec![b"hello"; 100];
+    let mut mem_queues = MemQueues::from_state(&[("droopy", 0, payloads)]).await;
+    for truncate_position in (9..100).step_by(10) {
+        mem_queues.truncate("droopy", truncate_position).await;
+        let first_live_position = truncate_position + 1;
+        for _ in 0..3 {
+            let positions: Vec<u64> = mem_queues
+                .range("droopy", ..)
+                .unwrap()
+                .map(|(position, _)| position)
+                .collect();
+            assert_eq!(positions, (first_live_position..100).collect::<Vec<_>>());
+            assert_eq!(
+                mem_queues
+                    .range("droopy", 0..first_live_position + 2)
+                    .unwrap()
+                    .map(|(position, _)| position)
+                    .collect::<Vec<_>>(),
+                (first_live_position..(first_live_position + 2).min(100)).collect::<Vec<_>>()
+            );
+            assert_eq!(
+                mem_queues
+                    .range("droopy", ..=truncate_position)
+                    .unwrap()
+                    .count(),
+                0
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_mem_queues_range_seeks_start() {
+    let mut mem_queues = MemQueues::default();
+    mem_queues.create_queue("droopy").unwrap();
+    // Positions have gaps, so that the start of a range can't be found by an offset.
+    for position in (0..200_000u64).step_by(2) {
+        mem_queues
+            .append_record(
+                "droopy",
+                &FileNumber::for_test(1),
+                position,
+                &position.to_le_bytes(),
+            )
+            .await
+            .unwrap();
+    }
+    for start in [0, 1, 99_999, 100_000, 150_001, 199_998] {
+        let positions: Vec<u64> = mem_queues
+            .range("droopy", start..)
+            .unwrap()
+            .take(3)
+            .map(|(position, payload)| {
+                assert_eq!(payload.as_ref(), &position.to_le_bytes());
+                position
+            })
+            .collect();
+        let first_position = start + start % 2;
+        let expected: Vec<u64> = (first_position..200_000).step_by(2).take(3).collect();
+        assert_eq!(positions, expected);
+    }
+    assert_eq!(mem_queues.range("droopy", 199_999..).unwrap().count(), 0);
+}
@@ -1,39 +1,225 @@
 use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::future::Future;
 use std::io;
-use std::ops::RangeBounds;
+use std::ops::{Bound, Range, RangeBounds, RangeInclusive};
+#[cfg(feature = "tokio")]
 use std::path::Path;
 use std::time::{Duration, Instant};
 
-use bytes::Buf;
+use bytes::{Buf, Bytes, BytesMut};
 use tracing::{debug, event_enabled, warn, Level};
 
+use crate::batch::{Batch, BatchOperation};
 use crate::error::{
-    AppendError, CreateQueueError, DeleteQueueError, MissingQueue, ReadRecordError, TruncateError,
+    AppendError, BatchError, CreateQueueError, DeleteQueueError, ExtractError, FlushTimeout,
+    MissingQueue, RangeError, ReadRecordError, TruncateError,
 };
+#[cfg(feature = "tokio")]
+use crate::file_system::TokioFileSystem;
+use crate::file_system::{DefaultFileSystem, FileSystem, LockGuard};
 use crate::mem;
-use crate::mem::MemQueue;
-use crate::record::{MultiPlexedRecord, MultiRecord};
-use crate::recordlog::RecordWriter;
-use crate::rolling::RollingWriter;
+use crate::mem::{MemQueue, MemoryUsage, QueueId, QueueStats};
+use crate::options::{AutoCompact, OpenProgress, Options, QueueNamePolicy, UnknownRecordPolicy};
+use crate::record::{
+    unknown_record_type, BatchRecords, MultiPlexedRecord, MultiRecord, KEY_HEADER,
+    MAX_PAYLOAD_NUM_BYTES,
+};
+use crate::recordlog::{RecordReader, RecordWriter};
+use crate::rolling::{FileNumber, QueueIndex, RollingReader, RollingWriter, SidecarId, WriteMark};
+
+/// Number of records read between two checks of whether the open progress should be reported,
+/// so that the clock is not read for each record.
+const PROGRESS_CHECK_NUM_READS: u64 = 1_024;
 
-pub struct MultiRecordLog {
-    record_log_writer: crate::recordlog::RecordWriter<RollingWriter>,
+/// A log storing the records of many queues in the same files.
+///
+/// Operations writing to the log take `&mut self`, so that they cannot interleave. To share it
+/// between tasks, it can be wrapped in an `Arc<tokio::sync::Mutex<_>>`, as done by
+/// [`SharedMultiRecordLog`](crate::SharedMultiRecordLog).
+pub struct MultiRecordLog<FS: FileSystem = DefaultFileSystem> {
+    record_log_writer: crate::recordlog::RecordWriter<RollingWriter<FS>>,
     in_mem_queues: mem::MemQueues,
     next_sync: SyncState,
     // A simple buffer we reuse to avoid allocation.
     multi_record_spare_buffer: Vec<u8>,
+    // Number of records appended since the last flush.
+    num_unflushed_records: usize,
+    // Number of records appended since the log was opened, and how many of them were flushed.
+    num_appended_records: u64,
+    flushed_records_tx: tokio::sync::watch::Sender<u64>,
+    // Payloads larger than this are stored in sidecar files.
+    large_record_threshold: Option<usize>,
+    flush_timeout: Option<Duration>,
+    queue_name_policy: QueueNamePolicy,
+    hard_disk_cap: Option<u64>,
+    reject_empty_payloads: bool,
+    strict_idempotence: bool,
+    max_position_gap: Option<u64>,
+    compact_multi_records: bool,
+    // Set if files were skipped on open. Queues outside of the filter are then not entirely
+    // known: holding the first file keeps files from being garbage collected.
+    skipped_files_guard: Option<FileNumber>,
+    // Keeps other record logs from writing to the same files, until dropped.
+    _lock_guard: LockGuard,
 }
 
 /// Policy for synchonizing and flushing data
+#[derive(Default)]
 pub enum SyncPolicy {
     /// Sync and flush at each operation
+    #[default]
     OnAppend,
     /// Sync and flush regularly. Sync is realized on the first operation after the delay since
     /// last sync elapsed. This means if no new operation arrive, some content may not get
-    /// flushed for a while.
+    /// flushed for a while, unless [`MultiRecordLog::sync`] is called at
+    /// [`MultiRecordLog::sync_deadline`].
     OnDelay(Duration),
 }
 
+/// Appends waiting for the next flush, as reported by [`MultiRecordLog::pending_unflushed`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PendingFlush {
+    /// Number of records appended since the last flush.
+    pub records: usize,
+    /// Number of bytes buffered by the writer, which were not written to the file yet.
+    pub bytes: usize,
+}
+
+/// Acknowledgments of records becoming durable, as returned by
+/// [`MultiRecordLog::append_records_acked`].
+///
+/// This does not borrow the log, so that it can be awaited while other operations flush the
+/// records.
+pub struct DurableAcks {
+    positions: Range<u64>,
+    // Number of records appended to the log before the next record to acknowledge.
+    next_record: u64,
+    flushed_records_rx: tokio::sync::watch::Receiver<u64>,
+}
+
+impl DurableAcks {
+    /// Returns the positions of the records which were not acknowledged yet.
+    pub fn pending_positions(&self) -> Range<u64> {
+        self.positions.clone()
+    }
+
+    /// Returns the position of the next record once it was flushed, or `None` once all the
+    /// records were acknowledged.
+    ///
+    /// Also returns `None` if the log is dropped before flushing the record.
+    pub async fn next(&mut self) -> Option<u64> {
+        if self.positions.is_empty() {
+            return None;
+        }
+        let next_record = self.next_record;
+        self.flushed_records_rx
+            .wait_for(|&num_flushed_records| num_flushed_records > next_record)
+            .await
+            .ok()?;
+        self.next_record += 1;
+        self.positions.next()
+    }
+}
+
+/// Snapshot of the state of the log, as returned by [`MultiRecordLog::stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogStats {
+    pub num_queues: usize,
+    /// Number of records stored in all queues.
+    pub num_records: usize,
+    /// Number of payload bytes of these records.
+    pub payload_bytes: usize,
+    /// Disk space used, as reported by [`MultiRecordLog::disk_usage`].
+    pub disk_bytes: usize,
+    pub num_files: usize,
+    /// Numbers of the oldest and newest files on disk.
+    pub file_number_range: Option<RangeInclusive<u64>>,
+    /// Summary of each queue, ordered by queue name.
+    pub queues: Vec<QueueStats>,
+}
+
+/// Outcome of [`MultiRecordLog::compact_queue`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactReport {
+    /// Number of records written again.
+    pub num_records: usize,
+    /// Number of files deleted once the queue was compacted.
+    pub num_files_deleted: usize,
+}
+
+/// Reads the records of a range chunk by chunk, as returned by
+/// [`MultiRecordLog::range_chunked_owned`].
+///
+/// The cursor does not borrow the log: it is passed to each call to [`Self::next_chunk`], so that
+/// the log can be written to between chunks.
+#[derive(Debug, Clone)]
+pub struct RangeCursor {
+    queue: String,
+    start: Bound<u64>,
+    end: Bound<u64>,
+    chunk_size: usize,
+}
+
+impl RangeCursor {
+    /// Returns owned copies of the next records of the range, at most `chunk_size` of them.
+    ///
+    /// Each chunk reflects the state of the queue when it is fetched: records truncated since
+    /// the previous chunk are skipped, and records appended since are returned if they are in
+    /// the range. Chunks never overlap, so positions keep increasing from one chunk to the next.
+    ///
+    /// An empty chunk means there are no more records in the range for now. If the range is
+    /// unbounded, records appended afterwards are returned by the next calls.
+    pub fn next_chunk<FS: FileSystem>(
+        &mut self,
+        multi_record_log: &MultiRecordLog<FS>,
+    ) -> Result<Vec<(u64, Vec<u8>)>, MissingQueue> {
+        let chunk: Vec<(u64, Vec<u8>)> = multi_record_log
+            .range(&self.queue, (self.start, self.end))?
+            .take(self.chunk_size)
+            .map(|(position, payload)| (position, payload.into_owned()))
+            .collect();
+        if let Some((last_position, _)) = chunk.last() {
+            self.start = Bound::Excluded(*last_position);
+        }
+        Ok(chunk)
+    }
+}
+
+/// Copies of the records of a range, as returned by [`MultiRecordLog::range_snapshot`].
+///
+/// The snapshot owns its payloads, independently of the log: it can be kept across appends and
+/// truncations. Cloning it is cheap, as clones share the payloads.
+#[derive(Debug, Clone, Default)]
+pub struct RangeSnapshot {
+    records: Vec<(u64, Bytes)>,
+}
+
+impl RangeSnapshot {
+    /// Returns the records of the snapshot, in increasing position order.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &Bytes)> + '_ {
+        self.records
+            .iter()
+            .map(|(position, payload)| (*position, payload))
+    }
+
+    /// Returns the number of records of the snapshot.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+/// Result of the in-memory consistency audit performed by [`MultiRecordLog::health`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Health {
+    Healthy,
+    Unhealthy { reasons: Vec<String> },
+}
+
 #[derive(Debug)]
 enum SyncState {
     OnAppend,
@@ -74,6 +260,7 @@ impl From<SyncPolicy> for SyncState {
     }
 }
 
+#[cfg(feature = "tokio")]
 impl MultiRecordLog {
     /// Open the multi record log, syncing after each operation.
     pub async fn open(directory_path: &Path) -> Result<Self, ReadRecordError> {
@@ -85,70 +272,168 @@ impl MultiRecordLog {
         directory_path: &Path,
         sync_policy: SyncPolicy,
     ) -> Result<Self, ReadRecordError> {
+        let options = Options {
+            sync_policy,
+            ..Default::default()
+        };
+        Self::open_with_options(directory_path, options).await
+    }
+
+    /// Open the multi record log with the provided options.
+    pub async fn open_with_options(
+        directory_path: &Path,
+        options: Options,
+    ) -> Result<Self, ReadRecordError> {
+        Self::open_with_file_system(TokioFileSystem::new(directory_path), options).await
+    }
+}
+
+impl<FS: FileSystem> MultiRecordLog<FS> {
+    /// Open the multi record log stored in `file_system`, with the provided options.
+    pub async fn open_with_file_system(
+        file_system: FS,
+        options: Options,
+    ) -> Result<Self, ReadRecordError> {
+        let lock_guard = file_system.lock().await?;
         // io errors are non-recoverable
-        let rolling_reader = crate::rolling::RollingReader::open(directory_path).await?;
+        let mut rolling_reader = if options.strict_directory {
+            crate::rolling::RollingReader::open_strict_with_file_system(
+                file_system,
+                options.initial_file_number,
+                options.checksum,
+            )
+            .await?
+        } else {
+            crate::rolling::RollingReader::open_with_file_system(
+                file_system,
+                options.initial_file_number,
+                options.checksum,
+            )
+            .await?
+        };
+        if options.strict_file_attribution {
+            rolling_reader.check_file_numbers()?;
+        }
+        let has_skipped_files = match &options.queues_filter {
+            Some(queues_filter) if options.write_queue_indexes => {
+                rolling_reader
+                    .skip_files_without_queues(queues_filter)
+                    .await?
+            }
+            _ => false,
+        };
         let mut record_reader = crate::recordlog::RecordReader::open(rolling_reader);
-        let mut in_mem_queues = crate::mem::MemQueues::default();
-        debug!("loading wal");
-        loop {
-            let file_number = record_reader.read().current_file().clone();
-            let Ok(record) = record_reader.read_record().await else {
-                warn!("Detected corrupted record: some data may have been lost");
-                continue;
-            };
-            if let Some(record) = record {
-                match record {
-                    MultiPlexedRecord::AppendRecords {
-                        queue,
-                        records,
-                        position,
-                    } => {
-                        if !in_mem_queues.contains_queue(queue) {
-                            in_mem_queues.ack_position(queue, position);
-                        }
-                        for record in records {
-                            // if this fails, it means some corruption wasn't detected at a lower
-                            // level, or we wrote invalid data.
-                            let (position, payload) = record?;
-                            // this can fail if queue doesn't exist (it was created just above, so
-                            // it does), or if the position is in the past. This can happen if the
-                            // queue is deleted and recreated in a block which get skipped for
-                            // corruption. In that case, maybe we should ack_position() and try
-                            // to insert again?
-                            in_mem_queues
-                                .append_record(queue, &file_number, position, payload)
-                                .await
-                                .map_err(|_| ReadRecordError::Corruption)?;
-                        }
-                    }
-                    MultiPlexedRecord::Truncate { position, queue } => {
-                        in_mem_queues.truncate(queue, position).await;
-                    }
-                    MultiPlexedRecord::RecordPosition { queue, position } => {
-                        in_mem_queues.ack_position(queue, position);
-                    }
-                    MultiPlexedRecord::DeleteQueue { queue, position: _ } => {
-                        // can fail if we don't know about the queue getting deleted. It's fine to
-                        // just ignore the error, the queue no longer exists either way.
-                        let _ = in_mem_queues.delete_queue(queue);
-                    }
-                }
-            } else {
-                break;
+        let mut in_mem_queues = crate::mem::MemQueues::with_queues_filter(options.queues_filter);
+        in_mem_queues.set_keep_deleted_queue_positions(options.keep_deleted_queue_positions);
+        in_mem_queues.set_key_index(options.key_index);
+        in_mem_queues.set_rate_limit(options.per_queue_rate_limit);
+        let mut queue_index = replay(
+            &mut record_reader,
+            &mut in_mem_queues,
+            options.progress.as_deref(),
+            options.unknown_record_policy,
+        )
+        .await?;
+        // io errors are non-recoverable
+        let mut record_log_writer: RecordWriter<RollingWriter<FS>> =
+            record_reader.into_writer().await?;
+        let skipped_files_guard =
+            has_skipped_files.then(|| record_log_writer.directory().first_file_number().clone());
+        if options.write_queue_indexes {
+            let rolling_writer = record_log_writer.get_underlying_wrt_mut();
+            let file_number = rolling_writer.current_file().file_number();
+            // Records continuing from a skipped file were dropped, but their queues are listed
+            // in the index of the previous file.
+            if let Some(previous_queue_index) = file_number
+                .checked_sub(1)
+                .filter(|_| has_skipped_files)
+                .and_then(|previous_file_number| {
+                    rolling_writer.directory.queue_index(previous_file_number)
+                })
+            {
+                queue_index.extend(previous_queue_index);
             }
+            rolling_writer.enable_queue_index(queue_index);
         }
-        // io errors are non-recoverable
-        let record_log_writer: RecordWriter<RollingWriter> = record_reader.into_writer().await?;
+        record_log_writer
+            .get_underlying_wrt_mut()
+            .set_retry_policy(options.io_retry);
+        record_log_writer
+            .get_underlying_wrt_mut()
+            .set_cache_advice(options.cache_advice);
         let mut multi_record_log = MultiRecordLog {
             record_log_writer,
             in_mem_queues,
-            next_sync: sync_policy.into(),
+            next_sync: options.sync_policy.into(),
             multi_record_spare_buffer: Vec::new(),
+            num_unflushed_records: 0,
+            num_appended_records: 0,
+            flushed_records_tx: tokio::sync::watch::channel(0).0,
+            large_record_threshold: options.large_record_threshold,
+            flush_timeout: options.flush_timeout,
+            queue_name_policy: options.queue_name_policy,
+            hard_disk_cap: options.hard_disk_cap,
+            reject_empty_payloads: options.reject_empty_payloads,
+            strict_idempotence: options.strict_idempotence,
+            max_position_gap: options.max_position_gap,
+            compact_multi_records: options.compact_multi_records,
+            skipped_files_guard,
+            _lock_guard: lock_guard,
         };
         multi_record_log.run_gc_if_necessary().await?;
+        if let Some(auto_compact) = options.auto_compact_on_open {
+            multi_record_log
+                .compact_if_fragmented(auto_compact)
+                .await
+                .map_err(ReadRecordError::AutoCompact)?;
+        }
         Ok(multi_record_log)
     }
 
+    /// Compacts the queues holding records in files before the current one, if the log is
+    /// fragmented beyond the thresholds of `auto_compact`.
+    async fn compact_if_fragmented(
+        &mut self,
+        auto_compact: AutoCompact,
+    ) -> Result<(), AppendError> {
+        let disk_bytes = self.disk_usage() as u64;
+        if disk_bytes == 0 || disk_bytes < auto_compact.min_total_bytes {
+            return Ok(());
+        }
+        let payload_bytes: usize = self
+            .in_mem_queues
+            .queue_stats()
+            .iter()
+            .map(|queue_stats| queue_stats.payload_bytes)
+            .sum();
+        let dead_ratio = 1.0 - payload_bytes as f64 / disk_bytes as f64;
+        if dead_ratio < auto_compact.min_dead_ratio {
+            return Ok(());
+        }
+        let Some(file_number_range) = self.file_number_range() else {
+            return Ok(());
+        };
+        let mut queues = BTreeSet::new();
+        // Records of the current file, the last one, are compacted already.
+        for file_number in *file_number_range.start()..*file_number_range.end() {
+            for (queue, _) in self.in_mem_queues.queues_referencing_file(file_number) {
+                // Filtered out queues are not loaded, and cannot be written again.
+                if self.in_mem_queues.contains_queue(queue) {
+                    queues.insert(queue.to_string());
+                }
+            }
+        }
+        debug!(
+            dead_ratio = dead_ratio,
+            num_queues = queues.len(),
+            "compact fragmented log on open"
+        );
+        for queue in queues {
+            self.compact_queue(&queue).await?;
+        }
+        Ok(())
+    }
+
     #[cfg(test)]
     pub fn list_file_numbers(&self) -> Vec<u64> {
         let rolling_writer = self.record_log_writer.get_underlying_wrt();
@@ -157,23 +442,324 @@ impl MultiRecordLog {
 
     /// Creates a new queue.
     ///
+    /// Returns an error if the queue already exists. On success, returns the id of the queue,
+    /// see [`Self::resolve_queue`].
+    pub async fn create_queue(&mut self, queue: &str) -> Result<QueueId, CreateQueueError> {
+        self.create_queue_with_format_tag(queue, None).await
+    }
+
+    /// Same as [`Self::create_queue`], but also stores `format_tag` along with the queue, for
+    /// consumers to check with [`Self::queue_format`] that the payloads of the queue have the
+    /// format they expect.
+    ///
+    /// The format tag is not interpreted by the log.
+    pub async fn create_queue_with_format(
+        &mut self,
+        queue: &str,
+        format_tag: u32,
+    ) -> Result<QueueId, CreateQueueError> {
+        self.create_queue_with_format_tag(queue, Some(format_tag))
+            .await
+    }
+
+    async fn create_queue_with_format_tag(
+        &mut self,
+        queue: &str,
+        format_tag: Option<u32>,
+    ) -> Result<QueueId, CreateQueueError> {
+        self.check_new_queue(queue)?;
+        let position = self.in_mem_queues.initial_position(queue);
+        let record = MultiPlexedRecord::RecordPosition {
+            queue,
+            position,
+            format_tag,
+        };
+        let file_number = self.record_log_writer.current_file().file_number();
+        self.record_log_writer
+            .write_multiplexed_record(record)
+            .await?;
+        self.sync().await?;
+        let queue_id = self.in_mem_queues.create_queue(queue)?;
+        if let Some(format_tag) = format_tag {
+            self.in_mem_queues
+                .set_format_tag_by_id(queue_id, format_tag, file_number);
+        }
+        Ok(queue_id)
+    }
+
+    /// Returns the format tag `queue` was created with, or None if it was created without one.
+    /// See [`Self::create_queue_with_format`].
+    pub fn queue_format(&self, queue: &str) -> Result<Option<u32>, MissingQueue> {
+        self.in_mem_queues.format_tag(queue)
+    }
+
+    /// Creates several queues at once, and returns their ids in the same order.
+    ///
+    /// Unlike calling [`Self::create_queue`] for each queue, the log is flushed only once. If
+    /// one of the queues already exists, or is listed twice, no queue is created. On an io
+    /// error, queues are not created in memory, but some of them may have been persisted.
+    pub async fn create_queues(
+        &mut self,
+        queues: &[&str],
+    ) -> Result<Vec<QueueId>, CreateQueueError> {
+        let mut unique_queues = HashSet::with_capacity(queues.len());
+        for &queue in queues {
+            self.check_new_queue(queue)?;
+            if !unique_queues.insert(queue) {
+                return Err(CreateQueueError::AlreadyExists);
+            }
+        }
+        for &queue in queues {
+            let position = self.in_mem_queues.initial_position(queue);
+            let record = MultiPlexedRecord::RecordPosition {
+                queue,
+                position,
+                format_tag: None,
+            };
+            self.record_log_writer
+                .write_multiplexed_record(record)
+                .await?;
+        }
+        self.sync().await?;
+        let mut queue_ids = Vec::with_capacity(queues.len());
+        for &queue in queues {
+            queue_ids.push(self.in_mem_queues.create_queue(queue)?);
+        }
+        Ok(queue_ids)
+    }
+
+    /// Creates a new queue holding a first record, and returns the position of that record.
+    ///
+    /// Unlike calling [`Self::create_queue`] then [`Self::append_record`], this writes a single
+    /// record to the log and flushes once: after a crash, the queue either does not exist or
+    /// holds its first record. Replaying the record is enough to create the queue.
+    ///
     /// Returns an error if the queue already exists.
-    pub async fn create_queue(&mut self, queue: &str) -> Result<(), CreateQueueError> {
-        if self.queue_exists(queue) {
+    pub async fn create_queue_with_record(
+        &mut self,
+        queue: &str,
+        payload: impl Buf,
+    ) -> Result<u64, CreateQueueError> {
+        self.check_new_queue(queue)?;
+        if payload.remaining() > MAX_PAYLOAD_NUM_BYTES {
+            return Err(CreateQueueError::TooLarge);
+        }
+        if self.reject_empty_payloads && !payload.has_remaining() {
+            return Err(CreateQueueError::EmptyPayload);
+        }
+        let position = self.in_mem_queues.initial_position(queue);
+        let mut buffer = Vec::new();
+        MultiRecord::serialize(std::iter::once(payload), position, &mut buffer)
+            .map_err(|_| CreateQueueError::PositionOverflow)?;
+        let records = MultiRecord::new_unchecked(&buffer);
+        let file_number = self.record_log_writer.current_file().clone();
+        let record = MultiPlexedRecord::AppendRecords {
+            queue,
+            position,
+            records,
+        };
+        self.check_hard_disk_cap(&record, 0)
+            .map_err(|_| CreateQueueError::DiskFull)?;
+        self.record_log_writer
+            .write_multiplexed_record(record)
+            .await?;
+        self.sync().await?;
+        let queue_id = self.in_mem_queues.create_queue(queue)?;
+        for record in records {
+            // we just serialized it, we know it's valid
+            let (position, payload) = record.unwrap();
+            self.in_mem_queues
+                .append_record_with_headers_by_id(queue_id, &file_number, position, payload, None)
+                .await
+                .expect("appending to a newly created queue should not fail");
+        }
+        Ok(position)
+    }
+
+    /// Checks that a queue named `queue` can be created.
+    fn check_new_queue(&self, queue: &str) -> Result<(), CreateQueueError> {
+        self.queue_name_policy
+            .check(queue)
+            .map_err(|reason| CreateQueueError::InvalidName { reason })?;
+        // filtered out queues are not visible, but they do exist.
+        if self.in_mem_queues.tracks_queue(queue) {
             return Err(CreateQueueError::AlreadyExists);
         }
-        let record = MultiPlexedRecord::RecordPosition { queue, position: 0 };
-        self.record_log_writer.write_record(record).await?;
+        // the queue may exist in the files skipped on open.
+        if self.skipped_files_guard.is_some() && self.in_mem_queues.is_filtered_out(queue) {
+            return Err(CreateQueueError::FilteredOut);
+        }
+        Ok(())
+    }
+
+    /// Writes all the records of `queue` again, to the current file, so that a few old records
+    /// of a slow queue no longer keep the files they were scattered across from being garbage
+    /// collected. Files are still kept if another queue uses them.
+    ///
+    /// Positions and headers of the records are preserved. Other queues are left untouched.
+    pub async fn compact_queue(&mut self, queue: &str) -> Result<CompactReport, AppendError> {
+        let queue_id = self.in_mem_queues.queue_id(queue)?;
+        let mut multi_record_spare_buffer = std::mem::take(&mut self.multi_record_spare_buffer);
+        multi_record_spare_buffer.clear();
+        let mut first_position = None;
+        let mut num_records = 0;
+        for (position, headers, payload) in self.in_mem_queues.range_with_headers(queue, ..)? {
+            first_position.get_or_insert(position);
+            num_records += 1;
+            let headers = headers
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_slice()));
+            MultiRecord::append_with_headers(
+                position,
+                &payload[..],
+                headers,
+                &mut multi_record_spare_buffer,
+            );
+        }
+        let res = match first_position {
+            Some(position) => {
+                self.replace_records(queue_id, position, &multi_record_spare_buffer)
+                    .await
+            }
+            None => Ok(()),
+        };
+        self.multi_record_spare_buffer = multi_record_spare_buffer;
+        res?;
+        let num_files_before = self.record_log_writer.directory().files.count();
+        self.run_gc_if_necessary().await?;
+        let num_files_after = self.record_log_writer.directory().files.count();
+        Ok(CompactReport {
+            num_records,
+            num_files_deleted: num_files_before - num_files_after,
+        })
+    }
+
+    /// Writes `records`, which hold all the records of the queue, in a single record replacing
+    /// them.
+    async fn replace_records(
+        &mut self,
+        queue_id: QueueId,
+        position: u64,
+        records: &[u8],
+    ) -> Result<(), AppendError> {
+        // The records start in the file which is current before writing them.
+        let file_number = self.record_log_writer.current_file().clone();
+        let queue = self.in_mem_queues.queue_name(queue_id)?;
+        let record = MultiPlexedRecord::ReplaceRecords {
+            queue,
+            position,
+            records: MultiRecord::new_unchecked(records),
+        };
+        self.record_log_writer
+            .write_multiplexed_record(record)
+            .await?;
+        // The previous records of the queue are about to become garbage collectable: this
+        // record must be persisted first.
         self.sync().await?;
-        self.in_mem_queues.create_queue(queue)?;
+        self.in_mem_queues
+            .move_queue_to_file(queue_id, &file_number)?;
+        Ok(())
+    }
+
+    /// Moves `queue` to the log stored in `dest_file_system`, opened with `dest_options`. This
+    /// allows splitting a log grown too large.
+    ///
+    /// The records of the queue are written to the destination log with their positions and
+    /// headers, and it is synced before the queue is deleted from this log. The destination log
+    /// must not be open elsewhere, and must not have a queue with the same name.
+    ///
+    /// If the process crashes in between, the queue may be found in both logs.
+    pub async fn extract_queue<DestFS: FileSystem>(
+        &mut self,
+        queue: &str,
+        dest_file_system: DestFS,
+        dest_options: Options,
+    ) -> Result<(), ExtractError> {
+        let live_range = self.in_mem_queues.live_range(queue)?;
+        let mut dest_log = MultiRecordLog::open_with_file_system(dest_file_system, dest_options)
+            .await
+            .map_err(ExtractError::OpenDestination)?;
+        if dest_log.in_mem_queues.tracks_queue(queue) {
+            return Err(ExtractError::AlreadyExists);
+        }
+        let mut multi_record_spare_buffer = std::mem::take(&mut self.multi_record_spare_buffer);
+        multi_record_spare_buffer.clear();
+        for (position, headers, payload) in self.in_mem_queues.range_with_headers(queue, ..)? {
+            let headers = headers
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_slice()));
+            MultiRecord::append_with_headers(
+                position,
+                &payload[..],
+                headers,
+                &mut multi_record_spare_buffer,
+            );
+        }
+        let res = dest_log
+            .import_queue(queue, live_range.start, &multi_record_spare_buffer)
+            .await;
+        self.multi_record_spare_buffer = multi_record_spare_buffer;
+        res.map_err(ExtractError::WriteDestination)?;
+        dest_log
+            .sync_barrier()
+            .await
+            .map_err(|io_error| ExtractError::WriteDestination(io_error.into()))?;
+        drop(dest_log);
+        self.delete_queue(queue).await?;
+        Ok(())
+    }
+
+    /// Creates `queue` holding `records`, the first of which is at `position`.
+    ///
+    /// A single record is written, replaying it creates the queue at `position` even if it is
+    /// empty.
+    async fn import_queue(
+        &mut self,
+        queue: &str,
+        position: u64,
+        records: &[u8],
+    ) -> Result<(), AppendError> {
+        let file_number = self.record_log_writer.current_file().clone();
+        let records = MultiRecord::new_unchecked(records);
+        let record = MultiPlexedRecord::ReplaceRecords {
+            queue,
+            position,
+            records,
+        };
+        self.record_log_writer
+            .write_multiplexed_record(record)
+            .await?;
+        self.in_mem_queues.ack_position(queue, position);
+        let mut records = records;
+        while let Some(record) = records.next_with_headers() {
+            // we just serialized it, we know it's valid
+            let (position, headers, payload) = record.unwrap();
+            self.in_mem_queues
+                .append_record_with_headers(queue, &file_number, position, payload, headers)
+                .await?;
+        }
         Ok(())
     }
 
+    /// Returns the id of the queue.
+    ///
+    /// The `_by_id` methods accept it in place of the queue name, which saves looking the queue
+    /// up by name on each call. The id stays valid until the queue is deleted: after that,
+    /// methods return an error for it, even if a queue with the same name gets created.
+    pub fn resolve_queue(&self, queue: &str) -> Result<QueueId, MissingQueue> {
+        self.in_mem_queues.queue_id(queue)
+    }
+
     pub async fn delete_queue(&mut self, queue: &str) -> Result<(), DeleteQueueError> {
         let position = self.in_mem_queues.next_position(queue)?;
         let record = MultiPlexedRecord::DeleteQueue { queue, position };
-        self.record_log_writer.write_record(record).await?;
+        let file_number = self.record_log_writer.current_file().file_number();
+        self.record_log_writer
+            .write_multiplexed_record(record)
+            .await?;
         self.in_mem_queues.delete_queue(queue)?;
+        self.in_mem_queues
+            .ack_deleted_queue(queue, position, file_number);
         self.run_gc_if_necessary().await?;
         self.sync().await?;
         Ok(())
@@ -187,9 +773,20 @@ impl MultiRecordLog {
         self.in_mem_queues.list_queues()
     }
 
+    /// Returns the names of at most `limit` queues, in lexicographic order, starting after the
+    /// queue named `after`, or at the first queue if `after` is `None`.
+    ///
+    /// The last name of a page is the `after` argument to get the next page. `after` does not
+    /// need to be the name of an existing queue, so that listing goes on even if it got deleted.
+    pub fn list_queues_page(&self, after: Option<&str>, limit: usize) -> Vec<&str> {
+        self.in_mem_queues.list_queues_page(after, limit)
+    }
+
     /// Appends a record to the log.
     ///
     /// The local_position argument can optionally be passed to enforce idempotence.
+    /// An empty payload is a valid record, unless the log was opened with
+    /// [`Options::reject_empty_payloads`].
     /// TODO if an io Error is encounterred, the in mem queue and the record log will
     /// be in an inconsistent state.
     pub async fn append_record(
@@ -198,80 +795,927 @@ impl MultiRecordLog {
         position_opt: Option<u64>,
         payload: impl Buf,
     ) -> Result<Option<u64>, AppendError> {
-        self.append_records(queue, position_opt, std::iter::once(payload))
+        let queue_id = self.in_mem_queues.queue_id_for_append(queue)?;
+        self.append_record_by_id(queue_id, position_opt, payload)
             .await
     }
 
-    /// Appends multiple records to the log.
+    /// Appends a record at the next position of `queue`, only if [`Self::last_position`] returns
+    /// `expected_last` for it, and returns the position of the record.
     ///
-    /// This operation is atomic: either all records get stored, or none do.
-    /// However this function succeeding does not necessarily means records where stored, be sure
-    /// to call [`Self::sync`] to make sure changes are persisted if you don't use
-    /// [`SyncPolicy::OnAppend`] (which is the default).
-    pub async fn append_records<'a, T: Iterator<Item = impl Buf>>(
+    /// Otherwise nothing is appended, and [`AppendError::CasMismatch`] reports the actual last
+    /// position, so that writers can coordinate optimistically: the one whose view of the queue
+    /// is outdated reads it again and retries.
+    pub async fn append_if_last_is(
         &mut self,
         queue: &str,
+        expected_last: Option<u64>,
+        payload: impl Buf,
+    ) -> Result<u64, AppendError> {
+        let queue_id = self.in_mem_queues.queue_id_for_append(queue)?;
+        let actual = self.in_mem_queues.last_position(queue)?;
+        if actual != expected_last {
+            return Err(AppendError::CasMismatch { actual });
+        }
+        let position = self
+            .append_record_by_id(queue_id, None, payload)
+            .await?
+            .expect("records appended without a position are never ignored");
+        Ok(position)
+    }
+
+    /// Runs the checks of [`Self::append_record`], without appending anything.
+    ///
+    /// Returns what [`Self::append_record`] would return if called right away with the same
+    /// arguments: the position the record would be assigned, or `None` if the record would be
+    /// ignored because it was already appended.
+    pub fn validate_append(
+        &self,
+        queue: &str,
         position_opt: Option<u64>,
-        payloads: T,
+        payload: impl Buf,
     ) -> Result<Option<u64>, AppendError> {
-        let next_position = self.in_mem_queues.next_position(queue)?;
-        if let Some(position) = position_opt {
-            // we accept position in the future, and move forward as required.
-            if position + 1 == next_position {
-                return Ok(None);
-            } else if position < next_position {
-                return Err(AppendError::Past);
-            }
+        let queue_id = self.in_mem_queues.queue_id_for_append(queue)?;
+        let payload_len = payload.remaining();
+        check_payload_len(payload_len)?;
+        if self.reject_empty_payloads && payload_len == 0 {
+            return Err(AppendError::EmptyPayload);
         }
-        let position = position_opt.unwrap_or(next_position);
-        let file_number = self.record_log_writer.current_file().clone();
-
-        let mut multi_record_spare_buffer = std::mem::take(&mut self.multi_record_spare_buffer);
-        MultiRecord::serialize(payloads, position, &mut multi_record_spare_buffer);
-        if multi_record_spare_buffer.is_empty() {
-            self.multi_record_spare_buffer = multi_record_spare_buffer;
-            // empty transaction: don't persist it
+        let Some(position) = self.position_to_append(queue_id, position_opt)? else {
+            self.check_retried_append(queue_id, position_opt, std::iter::once(payload))?;
             return Ok(None);
+        };
+        if self
+            .large_record_threshold
+            .map_or(false, |large_record_threshold| {
+                payload_len > large_record_threshold
+            })
+        {
+            let record = MultiPlexedRecord::AppendSidecar {
+                queue,
+                position,
+                sidecar: SidecarId {
+                    file_number: 0,
+                    id: 0,
+                },
+            };
+            self.check_hard_disk_cap(&record, payload_len)?;
+        } else {
+            // The payload is not serialized: only the length of the record matters.
+            let item_header_num_bytes = if self.compact_multi_records { 4 } else { 12 };
+            let record_num_bytes =
+                MultiPlexedRecord::header_num_bytes(queue) + item_header_num_bytes + payload_len;
+            self.check_hard_disk_cap_for_len(record_num_bytes, 0)?;
         }
+        self.in_mem_queues
+            .check_rate_limit(queue_id, 1, payload_len)?;
+        Ok(Some(position))
+    }
 
-        let records = MultiRecord::new_unchecked(&multi_record_spare_buffer);
-        let record = MultiPlexedRecord::AppendRecords {
-            position,
-            queue,
-            records,
+    /// Returns the position at which records should be appended, or `None` if the record at
+    /// `position_opt` was already appended.
+    fn position_to_append(
+        &self,
+        queue_id: QueueId,
+        position_opt: Option<u64>,
+    ) -> Result<Option<u64>, AppendError> {
+        let next_position = self.in_mem_queues.next_position_for_append(queue_id)?;
+        let position = match position_opt {
+            None => next_position,
+            Some(position) if position < next_position => {
+                if position + 1 == next_position {
+                    return Ok(None);
+                }
+                return Err(AppendError::Past);
+            }
+            // we accept position in the future, and move forward as required.
+            Some(position) => {
+                check_position_gap(next_position, position, self.max_position_gap)?;
+                position
+            }
         };
-        self.record_log_writer.write_record(record).await?;
-        self.sync_on_policy().await?;
-
-        let mut max_position = position;
-        for record in records {
-            // we just serialized it, we know it's valid
-            let (position, payload) = record.unwrap();
-            self.in_mem_queues
-                .append_record(queue, &file_number, position, payload)
-                .await?;
-            max_position = position;
+        // The position following the record must fit in a u64 too.
+        if position == u64::MAX {
+            return Err(AppendError::PositionOverflow);
         }
-
-        self.multi_record_spare_buffer = multi_record_spare_buffer;
-        Ok(Some(max_position))
+        Ok(Some(position))
     }
 
-    async fn record_empty_queues_position(&mut self) -> io::Result<()> {
-        let mut has_empty_queues = false;
-        for (queue_id, queue) in self.in_mem_queues.empty_queues() {
-            let next_position = queue.next_position();
-            let record = MultiPlexedRecord::RecordPosition {
-                queue: queue_id,
-                position: next_position,
-            };
-            self.record_log_writer.write_record(record).await?;
-            has_empty_queues = true
+    /// Checks that an append ignored because its record was already appended at `position_opt`
+    /// is a genuine retry: if the log was opened with [`Options::strict_idempotence`], the
+    /// append must hold a single payload, equal to the one of the record stored there.
+    fn check_retried_append(
+        &self,
+        queue_id: QueueId,
+        position_opt: Option<u64>,
+        mut payloads: impl Iterator<Item = impl Buf>,
+    ) -> Result<(), AppendError> {
+        if !self.strict_idempotence {
+            return Ok(());
         }
-        if has_empty_queues {
-            // We need to sync here! We are remove files from the FS
-            // so we need to make sure our empty queue positions are properly persisted.
-            self.sync().await?;
+        let Some(position) = position_opt else {
+            return Ok(());
+        };
+        let (Some(mut payload), None) = (payloads.next(), payloads.next()) else {
+            return Err(AppendError::PositionConflict);
+        };
+        let payload = payload.copy_to_bytes(payload.remaining());
+        match self
+            .in_mem_queues
+            .range_by_id(queue_id, position..=position)?
+            .next()
+        {
+            Some((_, stored_payload)) if stored_payload[..] == payload[..] => Ok(()),
+            _ => Err(AppendError::PositionConflict),
+        }
+    }
+
+    /// Appends multiple records to the log.
+    ///
+    /// This operation is atomic: either all records get stored, or none do.
+    /// However this function succeeding does not necessarily means records where stored, be sure
+    /// to call [`Self::sync`] to make sure changes are persisted if you don't use
+    /// [`SyncPolicy::OnAppend`] (which is the default).
+    pub async fn append_records<T: Iterator<Item = impl Buf>>(
+        &mut self,
+        queue: &str,
+        position_opt: Option<u64>,
+        payloads: T,
+    ) -> Result<Option<u64>, AppendError> {
+        let queue_id = self.in_mem_queues.queue_id_for_append(queue)?;
+        self.append_records_by_id(queue_id, position_opt, payloads)
+            .await
+    }
+
+    /// Same as [`Self::append_records`], but also returns the acknowledgments of the records
+    /// becoming durable.
+    ///
+    /// The acknowledgments yield the position of each record, in order, once the flush
+    /// containing it completed. With [`SyncPolicy::OnAppend`], they are available right away,
+    /// while with [`SyncPolicy::OnDelay`] they arrive with the next flush, so that a producer can
+    /// acknowledge its records as they get flushed without waiting on each append. A retried
+    /// append which did not write anything yields no acknowledgment.
+    pub async fn append_records_acked<T: Iterator<Item = impl Buf>>(
+        &mut self,
+        queue: &str,
+        position_opt: Option<u64>,
+        payloads: T,
+    ) -> Result<DurableAcks, AppendError> {
+        let first_record = self.num_appended_records;
+        let last_position_opt = self.append_records(queue, position_opt, payloads).await?;
+        let num_records = self.num_appended_records - first_record;
+        let positions = match last_position_opt {
+            Some(last_position) => last_position + 1 - num_records..last_position + 1,
+            None => 0..0,
+        };
+        Ok(DurableAcks {
+            positions,
+            next_record: first_record,
+            flushed_records_rx: self.flushed_records_tx.subscribe(),
+        })
+    }
+
+    /// Same as [`Self::append_record`], for a queue designated by its id.
+    pub async fn append_record_by_id(
+        &mut self,
+        queue_id: QueueId,
+        position_opt: Option<u64>,
+        payload: impl Buf,
+    ) -> Result<Option<u64>, AppendError> {
+        check_payload_len(payload.remaining())?;
+        if let Some(large_record_threshold) = self.large_record_threshold {
+            if payload.remaining() > large_record_threshold {
+                return self
+                    .append_large_record(queue_id, position_opt, payload)
+                    .await;
+            }
+        }
+        self.append_records_by_id(queue_id, position_opt, std::iter::once(payload))
+            .await
+    }
+
+    /// Writes `payload` to a sidecar file, then appends a record referencing it.
+    async fn append_large_record(
+        &mut self,
+        queue_id: QueueId,
+        position_opt: Option<u64>,
+        mut payload: impl Buf,
+    ) -> Result<Option<u64>, AppendError> {
+        let Some(position) = self.position_to_append(queue_id, position_opt)? else {
+            self.check_retried_append(queue_id, position_opt, std::iter::once(payload))?;
+            return Ok(None);
+        };
+        let payload = payload.copy_to_bytes(payload.remaining());
+        let queue = self.in_mem_queues.queue_name(queue_id)?;
+        // The id of the sidecar does not change the length of the record.
+        let record = MultiPlexedRecord::AppendSidecar {
+            queue,
+            position,
+            sidecar: SidecarId {
+                file_number: 0,
+                id: 0,
+            },
+        };
+        self.check_hard_disk_cap(&record, payload.len())?;
+        self.in_mem_queues
+            .check_rate_limit(queue_id, 1, payload.len())?;
+        let file_number = self.record_log_writer.current_file().clone();
+        let sidecar = self
+            .record_log_writer
+            .directory()
+            .write_sidecar(&file_number, &payload)
+            .await?;
+        let record = MultiPlexedRecord::AppendSidecar {
+            queue,
+            position,
+            sidecar,
+        };
+        let mark = self.record_log_writer.get_underlying_wrt().mark();
+        if let Err(io_error) = self
+            .record_log_writer
+            .write_multiplexed_record(record)
+            .await
+        {
+            // Frames of the record which were flushed are not followed by its last frame: they
+            // are ignored on replay.
+            self.discard_since(mark, 0);
+            return Err(io_error.into());
+        }
+        self.in_mem_queues
+            .acquire_rate_limit(queue_id, 1, payload.len());
+        self.num_unflushed_records += 1;
+        self.num_appended_records += 1;
+        let sync_result = self.sync_on_policy_for_records(mark, 1).await?;
+        self.in_mem_queues
+            .append_record_with_headers_by_id(queue_id, &file_number, position, &payload, None)
+            .await?;
+        sync_result?;
+        Ok(Some(position))
+    }
+
+    /// Same as [`Self::append_records`], for a queue designated by its id.
+    pub async fn append_records_by_id<T: Iterator<Item = impl Buf>>(
+        &mut self,
+        queue_id: QueueId,
+        position_opt: Option<u64>,
+        payloads: T,
+    ) -> Result<Option<u64>, AppendError> {
+        let Some(position) = self.position_to_append(queue_id, position_opt)? else {
+            self.check_retried_append(queue_id, position_opt, payloads)?;
+            return Ok(None);
+        };
+
+        let mut multi_record_spare_buffer = std::mem::take(&mut self.multi_record_spare_buffer);
+        let serialize_res = if self.compact_multi_records {
+            MultiRecord::serialize_compact(payloads, position, &mut multi_record_spare_buffer)
+        } else {
+            MultiRecord::serialize(payloads, position, &mut multi_record_spare_buffer)
+        };
+        if let Err(append_error) = serialize_res {
+            self.multi_record_spare_buffer = multi_record_spare_buffer;
+            return Err(append_error);
+        }
+        if multi_record_spare_buffer.is_empty() {
+            self.multi_record_spare_buffer = multi_record_spare_buffer;
+            // empty transaction: don't persist it
+            return Ok(None);
+        }
+
+        let records = if self.compact_multi_records {
+            MultiRecord::new_compact_unchecked(&multi_record_spare_buffer, position)
+        } else {
+            MultiRecord::new_unchecked(&multi_record_spare_buffer)
+        };
+        let res = self.append_multi_record(queue_id, position, records).await;
+        self.multi_record_spare_buffer = multi_record_spare_buffer;
+        res.map(Some)
+    }
+
+    /// Appends a record with user defined headers to the log.
+    ///
+    /// Headers are key-values stored alongside the payload, and can be read back with
+    /// [`Self::range_with_headers`]. Position handling is the same as for
+    /// [`Self::append_record`].
+    pub async fn append_record_with_headers(
+        &mut self,
+        queue: &str,
+        position_opt: Option<u64>,
+        payload: impl Buf,
+        headers: &[(&str, &[u8])],
+    ) -> Result<Option<u64>, AppendError> {
+        check_payload_len(payload.remaining())?;
+        check_headers_len(payload.remaining(), headers)?;
+        let queue_id = self.in_mem_queues.queue_id_for_append(queue)?;
+        let Some(position) = self.position_to_append(queue_id, position_opt)? else {
+            self.check_retried_append(queue_id, position_opt, std::iter::once(payload))?;
+            return Ok(None);
+        };
+
+        let mut multi_record_spare_buffer = std::mem::take(&mut self.multi_record_spare_buffer);
+        MultiRecord::serialize_with_headers(
+            position,
+            payload,
+            headers,
+            &mut multi_record_spare_buffer,
+        );
+        let records = MultiRecord::new_unchecked(&multi_record_spare_buffer);
+        let res = self.append_multi_record(queue_id, position, records).await;
+        self.multi_record_spare_buffer = multi_record_spare_buffer;
+        res.map(Some)
+    }
+
+    /// Appends a record at the next position of the queue, along with a key it can be read
+    /// back by with [`Self::get_by_key`], if the log was opened with [`Options::key_index`].
+    ///
+    /// The key is stored in the `mrecordlog-key` header of the record. Appending another record
+    /// with the same key makes it the one returned for it.
+    pub async fn append_record_keyed(
+        &mut self,
+        queue: &str,
+        key: &[u8],
+        payload: impl Buf,
+    ) -> Result<Option<u64>, AppendError> {
+        self.append_record_with_headers(queue, None, payload, &[(KEY_HEADER, key)])
+            .await
+    }
+
+    /// Appends a record to each of several queues, and flushes once according to the sync
+    /// policy. A queue may be listed several times.
+    ///
+    /// Unlike calling [`Self::append_record`] for each record, the records are handed to the
+    /// file together, saving write syscalls on bursts of appends to many queues. Each record is
+    /// still written as a record of its own, in order: this is not atomic. If appending a record
+    /// fails, the records before it are appended, but not the ones after it. Payloads are never
+    /// stored in sidecar files.
+    ///
+    /// Returns the position of each record.
+    pub async fn append_to_queues<'q>(
+        &mut self,
+        records: impl IntoIterator<Item = (&'q str, impl Buf)>,
+    ) -> Result<Vec<u64>, AppendError> {
+        let mut positions = Vec::new();
+        let mut multi_record_spare_buffer = std::mem::take(&mut self.multi_record_spare_buffer);
+        let mut append_res = Ok(());
+        for (queue, payload) in records {
+            append_res = self
+                .append_without_sync(queue, payload, &mut multi_record_spare_buffer)
+                .await
+                .map(|position| positions.push(position));
+            if append_res.is_err() {
+                break;
+            }
+        }
+        self.multi_record_spare_buffer = multi_record_spare_buffer;
+        if positions.is_empty() {
+            append_res?;
+            return Ok(positions);
+        }
+        let sync_result = self.sync_on_policy_for_append().await?;
+        append_res?;
+        sync_result?;
+        Ok(positions)
+    }
+
+    /// Appends a record at the next position of the queue, without flushing it, and returns
+    /// this position. `buffer` is used to serialize it.
+    async fn append_without_sync(
+        &mut self,
+        queue: &str,
+        payload: impl Buf,
+        buffer: &mut Vec<u8>,
+    ) -> Result<u64, AppendError> {
+        let queue_id = self.in_mem_queues.queue_id_for_append(queue)?;
+        check_payload_len(payload.remaining())?;
+        let position = self
+            .position_to_append(queue_id, None)?
+            .expect("the next position is never already appended");
+        MultiRecord::serialize(std::iter::once(payload), position, buffer)?;
+        let records = MultiRecord::new_unchecked(buffer);
+        let file_number = self.write_multi_record(queue_id, position, records).await?;
+        self.apply_multi_record(queue_id, file_number, records)
+            .await
+    }
+
+    /// Appends records at explicit positions to the log.
+    ///
+    /// This is meant for replication followers, which must store records at the exact
+    /// positions assigned by the primary. Positions must be contiguous and the first one must be
+    /// the next position of the queue: a gap returns [`AppendError::Future`] and a regression
+    /// returns [`AppendError::Past`]. Nothing is written if validation fails.
+    pub async fn append_records_at<'a>(
+        &mut self,
+        queue: &str,
+        records: impl Iterator<Item = (u64, &'a [u8])>,
+    ) -> Result<(), AppendError> {
+        let queue_id = self.in_mem_queues.queue_id_for_append(queue)?;
+        let next_position = self.in_mem_queues.next_position_for_append(queue_id)?;
+
+        let mut multi_record_spare_buffer = std::mem::take(&mut self.multi_record_spare_buffer);
+        MultiRecord::serialize_with_pos(records, &mut multi_record_spare_buffer);
+        if multi_record_spare_buffer.is_empty() {
+            self.multi_record_spare_buffer = multi_record_spare_buffer;
+            return Ok(());
+        }
+
+        let records = MultiRecord::new_unchecked(&multi_record_spare_buffer);
+        let res = match check_contiguous(records, next_position, self.max_position_gap) {
+            Ok(()) => self
+                .append_multi_record(queue_id, next_position, records)
+                .await
+                .map(|_| ()),
+            Err(append_error) => Err(append_error),
+        };
+        self.multi_record_spare_buffer = multi_record_spare_buffer;
+        res
+    }
+
+    /// Appends an already serialized multi record buffer to the log.
+    ///
+    /// This is a fast path for forwarding records received from another node: the buffer is
+    /// validated, but not re-serialized. The positions it contains must be contiguous and start
+    /// at the next position of the queue.
+    ///
+    /// Returns the range of positions appended.
+    pub async fn append_raw_multirecord(
+        &mut self,
+        queue: &str,
+        buffer: &[u8],
+    ) -> Result<Range<u64>, AppendError> {
+        let queue_id = self.in_mem_queues.queue_id_for_append(queue)?;
+        let next_position = self.in_mem_queues.next_position_for_append(queue_id)?;
+        let records = MultiRecord::new(buffer)?;
+        if buffer.is_empty() {
+            return Ok(next_position..next_position);
+        }
+        check_contiguous(records, next_position, self.max_position_gap)?;
+        let last_position = self
+            .append_multi_record(queue_id, next_position, records)
+            .await?;
+        Ok(next_position..last_position + 1)
+    }
+
+    /// Applies the operations of `batch`, in order, as a single transaction: after a crash,
+    /// either all of them are replayed, or none.
+    ///
+    /// All operations are validated first, each one taking the previous ones into account:
+    /// nothing is written if one of them is invalid. They are then written as a single record,
+    /// and the log is synced. Appended records take the next positions of their queue, and are
+    /// never stored in sidecar files.
+    ///
+    /// If writing or syncing fails, the batch is not applied, unless it was already handed to
+    /// the file: it is then applied in memory too, so as to stay consistent with the log.
+    pub async fn commit_batch(&mut self, batch: &Batch) -> Result<(), BatchError> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let positions = self.validate_batch(batch)?;
+        let mut multi_record_spare_buffer = std::mem::take(&mut self.multi_record_spare_buffer);
+        let mut record_buffer = Vec::new();
+        let mut batch_buffer = Vec::new();
+        for (operation, &position) in batch.operations.iter().zip(&positions) {
+            let record = match operation {
+                BatchOperation::CreateQueue { queue } => MultiPlexedRecord::RecordPosition {
+                    queue,
+                    position,
+                    format_tag: None,
+                },
+                BatchOperation::Append { queue, payloads } => {
+                    if payloads.is_empty() {
+                        continue;
+                    }
+                    MultiRecord::serialize(
+                        payloads.iter().map(|payload| &payload[..]),
+                        position,
+                        &mut multi_record_spare_buffer,
+                    )
+                    .expect("positions of the batch were validated");
+                    MultiPlexedRecord::AppendRecords {
+                        queue,
+                        position,
+                        records: MultiRecord::new_unchecked(&multi_record_spare_buffer),
+                    }
+                }
+                BatchOperation::Truncate { queue, .. } => {
+                    MultiPlexedRecord::Truncate { queue, position }
+                }
+                BatchOperation::DeleteQueue { queue } => {
+                    MultiPlexedRecord::DeleteQueue { queue, position }
+                }
+            };
+            BatchRecords::serialize_record(record, &mut record_buffer, &mut batch_buffer);
+        }
+        self.multi_record_spare_buffer = multi_record_spare_buffer;
+
+        let record = MultiPlexedRecord::Batch {
+            records: BatchRecords::new_unchecked(&batch_buffer),
+        };
+        self.check_hard_disk_cap(&record, 0)
+            .map_err(|_| BatchError::DiskFull)?;
+        // The records start in the file which is current before writing them.
+        let file_number = self.record_log_writer.current_file().file_number();
+        let mark = self.record_log_writer.get_underlying_wrt().mark();
+        if let Err(io_error) = self
+            .record_log_writer
+            .write_multiplexed_record(record)
+            .await
+        {
+            // Frames of the batch which were flushed are not followed by its last frame: they
+            // are ignored on replay.
+            self.record_log_writer
+                .get_underlying_wrt_mut()
+                .discard_since(mark);
+            return Err(io_error.into());
+        }
+        if let Err(io_error) = self.sync().await {
+            if !self
+                .record_log_writer
+                .get_underlying_wrt_mut()
+                .discard_since(mark)
+            {
+                self.apply_batch(batch, &positions, file_number).await;
+            }
+            return Err(io_error.into());
+        }
+        self.apply_batch(batch, &positions, file_number).await;
+        self.run_gc_if_necessary().await?;
+        Ok(())
+    }
+
+    /// Checks that the operations of `batch` can be applied in order, and returns the position
+    /// each one applies at.
+    fn validate_batch(&self, batch: &Batch) -> Result<Vec<u64>, BatchError> {
+        // Next position of the queues touched so far, and whether the batch deleted them.
+        let mut queue_states: HashMap<&str, (u64, bool)> = HashMap::new();
+        // Number of records and bytes appended so far to the queues existing before the batch,
+        // which must fit in their rate limit together.
+        let mut rate_limited_appends: HashMap<&str, (usize, usize)> = HashMap::new();
+        // Queues created or deleted by the batch so far.
+        let mut replaced_queues: HashSet<&str> = HashSet::new();
+        let mut positions = Vec::with_capacity(batch.len());
+        for (index, operation) in batch.operations.iter().enumerate() {
+            let queue = operation.queue();
+            let queue_state = queue_states.get(queue).copied();
+            let position = match operation {
+                BatchOperation::CreateQueue { .. } => {
+                    let create_queue_error = |error| BatchError::CreateQueue { index, error };
+                    let position = match queue_state {
+                        None => {
+                            self.check_new_queue(queue).map_err(create_queue_error)?;
+                            self.in_mem_queues.initial_position(queue)
+                        }
+                        Some((next_position, true)) => {
+                            if self.in_mem_queues.keeps_deleted_queue_positions() {
+                                next_position
+                            } else {
+                                0
+                            }
+                        }
+                        Some((_, false)) => {
+                            return Err(create_queue_error(CreateQueueError::AlreadyExists));
+                        }
+                    };
+                    queue_states.insert(queue, (position, false));
+                    replaced_queues.insert(queue);
+                    position
+                }
+                BatchOperation::Append { payloads, .. } => {
+                    let append_error = |error| BatchError::Append { index, error };
+                    let position = match queue_state {
+                        None => self
+                            .in_mem_queues
+                            .queue_id_for_append(queue)
+                            .and_then(|queue_id| {
+                                self.in_mem_queues.next_position_for_append(queue_id)
+                            })
+                            .map_err(append_error)?,
+                        Some((_, true)) => {
+                            return Err(append_error(AppendError::QueueDeleted(queue.to_string())));
+                        }
+                        Some((next_position, false)) => next_position,
+                    };
+                    for payload in payloads {
+                        check_payload_len(payload.len()).map_err(append_error)?;
+                        if self.reject_empty_payloads && payload.is_empty() {
+                            return Err(append_error(AppendError::EmptyPayload));
+                        }
+                    }
+                    // The position following the last record must fit in a u64 too.
+                    let next_position = position
+                        .checked_add(payloads.len() as u64)
+                        .ok_or(append_error(AppendError::PositionOverflow))?;
+                    // Queues created by the batch start with a fresh rate limit, which a single
+                    // batch cannot exceed.
+                    if !replaced_queues.contains(queue) {
+                        let queue_id = self
+                            .in_mem_queues
+                            .queue_id(queue)
+                            .map_err(|missing_queue| append_error(missing_queue.into()))?;
+                        let (num_records, num_bytes) =
+                            rate_limited_appends.entry(queue).or_default();
+                        *num_records += payloads.len();
+                        *num_bytes += payloads.iter().map(Bytes::len).sum::<usize>();
+                        self.in_mem_queues
+                            .check_rate_limit(queue_id, *num_records, *num_bytes)
+                            .map_err(append_error)?;
+                    }
+                    queue_states.insert(queue, (next_position, false));
+                    position
+                }
+                BatchOperation::Truncate { position, .. } => {
+                    let truncate_error = |error| BatchError::Truncate { index, error };
+                    let next_position = match queue_state {
+                        None => self
+                            .in_mem_queues
+                            .next_position(queue)
+                            .map_err(|missing_queue| truncate_error(missing_queue.into()))?,
+                        Some((_, true)) => {
+                            return Err(truncate_error(TruncateError::MissingQueue(
+                                queue.to_string(),
+                            )));
+                        }
+                        Some((next_position, false)) => next_position,
+                    };
+                    if *position >= next_position {
+                        return Err(truncate_error(TruncateError::Future { next_position }));
+                    }
+                    *position
+                }
+                BatchOperation::DeleteQueue { .. } => {
+                    let delete_queue_error = |error| BatchError::DeleteQueue { index, error };
+                    let position = match queue_state {
+                        None => self
+                            .in_mem_queues
+                            .next_position(queue)
+                            .map_err(|missing_queue| delete_queue_error(missing_queue.into()))?,
+                        Some((_, true)) => {
+                            return Err(delete_queue_error(DeleteQueueError::MissingQueue(
+                                queue.to_string(),
+                            )));
+                        }
+                        Some((next_position, false)) => next_position,
+                    };
+                    queue_states.insert(queue, (position, true));
+                    replaced_queues.insert(queue);
+                    position
+                }
+            };
+            positions.push(position);
+        }
+        Ok(positions)
+    }
+
+    /// Applies the operations of a batch written to the log to the in memory queues.
+    ///
+    /// `positions` are the ones returned by [`Self::validate_batch`], and `file_number` is the
+    /// file the batch starts in.
+    async fn apply_batch(&mut self, batch: &Batch, positions: &[u64], file_number: u64) {
+        // Files are only deleted by gc, which did not run since.
+        let file = self
+            .record_log_writer
+            .get_underlying_wrt()
+            .tracked_file(file_number)
+            .expect("the file of the batch should be tracked");
+        for (operation, &position) in batch.operations.iter().zip(positions) {
+            match operation {
+                BatchOperation::CreateQueue { queue } => {
+                    self.in_mem_queues
+                        .create_queue(queue)
+                        .expect("the batch was validated");
+                }
+                BatchOperation::Append { queue, payloads } => {
+                    let queue_id = self
+                        .in_mem_queues
+                        .queue_id(queue)
+                        .expect("the batch was validated");
+                    for (record_position, payload) in (position..).zip(payloads) {
+                        self.in_mem_queues
+                            .append_record_with_headers_by_id(
+                                queue_id,
+                                file,
+                                record_position,
+                                payload,
+                                None,
+                            )
+                            .await
+                            .expect("the batch was validated");
+                    }
+                    let num_bytes = payloads.iter().map(Bytes::len).sum();
+                    self.in_mem_queues
+                        .acquire_rate_limit(queue_id, payloads.len(), num_bytes);
+                }
+                BatchOperation::Truncate { queue, .. } => {
+                    let queue_id = self
+                        .in_mem_queues
+                        .queue_id(queue)
+                        .expect("the batch was validated");
+                    self.in_mem_queues
+                        .truncate_by_id(queue_id, position)
+                        .await
+                        .expect("the batch was validated");
+                }
+                BatchOperation::DeleteQueue { queue } => {
+                    self.in_mem_queues
+                        .delete_queue(queue)
+                        .expect("the batch was validated");
+                    self.in_mem_queues
+                        .ack_deleted_queue(queue, position, file_number);
+                }
+            }
+        }
+    }
+
+    /// Returns an error if writing `record`, along with a sidecar of `sidecar_num_bytes`, could
+    /// make disk usage exceed the hard cap.
+    fn check_hard_disk_cap(
+        &self,
+        record: &MultiPlexedRecord<'_>,
+        sidecar_num_bytes: usize,
+    ) -> Result<(), AppendError> {
+        self.check_hard_disk_cap_for_len(record.num_bytes(), sidecar_num_bytes)
+    }
+
+    /// Same as [`Self::check_hard_disk_cap`], for a record of `record_num_bytes` bytes.
+    fn check_hard_disk_cap_for_len(
+        &self,
+        record_num_bytes: usize,
+        sidecar_num_bytes: usize,
+    ) -> Result<(), AppendError> {
+        let Some(hard_disk_cap) = self.hard_disk_cap else {
+            return Ok(());
+        };
+        let num_bytes = self
+            .record_log_writer
+            .num_bytes_for_record(record_num_bytes);
+        let disk_usage = self
+            .record_log_writer
+            .get_underlying_wrt()
+            .size_after_write(num_bytes)
+            + sidecar_num_bytes;
+        if disk_usage as u64 > hard_disk_cap {
+            return Err(AppendError::DiskFull);
+        }
+        Ok(())
+    }
+
+    /// Writes a serialized multi record to the log, then adds its records to the in memory queue.
+    ///
+    /// Returns the position of the last record.
+    async fn append_multi_record(
+        &mut self,
+        queue_id: QueueId,
+        position: u64,
+        records: MultiRecord<'_>,
+    ) -> Result<u64, AppendError> {
+        let mark = self.record_log_writer.get_underlying_wrt().mark();
+        let file_number = match self.write_multi_record(queue_id, position, records).await {
+            Ok(file_number) => file_number,
+            Err(append_error) => {
+                // Frames of the records which were flushed are not followed by their last frame:
+                // they are ignored on replay.
+                self.discard_since(mark, 0);
+                return Err(append_error);
+            }
+        };
+        let sync_result = self
+            .sync_on_policy_for_records(mark, records.count())
+            .await?;
+        let max_position = self
+            .apply_multi_record(queue_id, file_number, records)
+            .await?;
+        sync_result?;
+        Ok(max_position)
+    }
+
+    /// Writes a serialized multi record to the log, without flushing it.
+    ///
+    /// Returns the number of the file the records start in.
+    async fn write_multi_record(
+        &mut self,
+        queue_id: QueueId,
+        position: u64,
+        records: MultiRecord<'_>,
+    ) -> Result<u64, AppendError> {
+        // The records start in the file which is current before writing them.
+        let file_number = self.record_log_writer.current_file().file_number();
+        let queue = self.in_mem_queues.queue_name(queue_id)?;
+        if self.reject_empty_payloads
+            && records
+                .filter_map(Result::ok)
+                .any(|(_, payload)| payload.is_empty())
+        {
+            return Err(AppendError::EmptyPayload);
+        }
+        let record = MultiPlexedRecord::AppendRecords {
+            position,
+            queue,
+            records,
+        };
+        self.check_hard_disk_cap(&record, 0)?;
+        let num_bytes = records
+            .filter_map(Result::ok)
+            .map(|(_, payload)| payload.len())
+            .sum();
+        self.in_mem_queues
+            .check_rate_limit(queue_id, records.count(), num_bytes)?;
+        self.record_log_writer
+            .write_multiplexed_record(record)
+            .await?;
+        self.in_mem_queues
+            .acquire_rate_limit(queue_id, records.count(), num_bytes);
+        self.num_unflushed_records += records.count();
+        self.num_appended_records += records.count() as u64;
+        Ok(file_number)
+    }
+
+    /// Adds the records of a multi record written to `file_number` to the in memory queue.
+    ///
+    /// Returns the position of the last record.
+    async fn apply_multi_record(
+        &mut self,
+        queue_id: QueueId,
+        file_number: u64,
+        mut records: MultiRecord<'_>,
+    ) -> Result<u64, AppendError> {
+        // Files are only deleted by gc, which did not run since.
+        let file_number = self
+            .record_log_writer
+            .get_underlying_wrt()
+            .tracked_file(file_number)
+            .expect("the file of the records should be tracked");
+        let mut max_position = 0;
+        while let Some(record) = records.next_with_headers() {
+            // we just serialized it, we know it's valid
+            let (position, headers, payload) = record.unwrap();
+            self.in_mem_queues
+                .append_record_with_headers_by_id(queue_id, file_number, position, payload, headers)
+                .await?;
+            max_position = position;
+        }
+        Ok(max_position)
+    }
+
+    async fn record_empty_queues_position(&mut self) -> io::Result<()> {
+        // Positions recorded in files which are kept don't need to be recorded again, unless
+        // they changed.
+        let first_kept_file_number = self
+            .record_log_writer
+            .directory()
+            .first_file_number_after_gc()
+            .file_number();
+        for (queue_id, queue) in self.in_mem_queues.empty_queues() {
+            if !queue.needs_position_record(first_kept_file_number)
+                && !queue.needs_format_tag_record(first_kept_file_number)
+            {
+                continue;
+            }
+            let next_position = queue.next_position();
+            let record = MultiPlexedRecord::RecordPosition {
+                queue: queue_id,
+                position: next_position,
+                format_tag: queue.format_tag(),
+            };
+            let file_number = self.record_log_writer.current_file().file_number();
+            self.record_log_writer
+                .write_multiplexed_record(record)
+                .await?;
+            queue.set_position_recorded(file_number);
+            if let Some(format_tag) = queue.format_tag() {
+                queue.set_format_tag(format_tag, file_number);
+            }
+        }
+        // The format tags of queues holding records are recorded again too.
+        for (queue_name, queue) in self.in_mem_queues.non_empty_queues() {
+            let Some(format_tag) = queue.format_tag() else {
+                continue;
+            };
+            if !queue.needs_format_tag_record(first_kept_file_number) {
+                continue;
+            }
+            let record = MultiPlexedRecord::RecordPosition {
+                queue: queue_name,
+                position: queue.next_position(),
+                format_tag: Some(format_tag),
+            };
+            let file_number = self.record_log_writer.current_file().file_number();
+            self.record_log_writer
+                .write_multiplexed_record(record)
+                .await?;
+            queue.set_format_tag(format_tag, file_number);
+        }
+        // The positions of deleted queues are recorded again in the same way, if they are kept.
+        for (queue, deleted_queue) in self.in_mem_queues.deleted_queues() {
+            if !deleted_queue.needs_position_record(first_kept_file_number) {
+                continue;
+            }
+            let record = MultiPlexedRecord::DeleteQueue {
+                queue,
+                position: deleted_queue.next_position(),
+            };
+            let file_number = self.record_log_writer.current_file().file_number();
+            self.record_log_writer
+                .write_multiplexed_record(record)
+                .await?;
+            deleted_queue.set_position_recorded(file_number);
         }
         Ok(())
     }
@@ -282,17 +1726,35 @@ impl MultiRecordLog {
     ///
     /// This method will always truncate the record log and release the associated memory.
     /// It returns the number of records deleted.
+    ///
+    /// Truncating up to a position the queue did not reach yet fails with
+    /// [`TruncateError::Future`], which reports the next position of the queue.
     pub async fn truncate(&mut self, queue: &str, position: u64) -> Result<usize, TruncateError> {
-        debug!(position = position, queue = queue, "truncate queue");
-        if !self.queue_exists(queue) {
-            return Err(TruncateError::MissingQueue(queue.to_string()));
+        let queue_id = self.in_mem_queues.queue_id(queue)?;
+        self.truncate_by_id(queue_id, position).await
+    }
+
+    /// Same as [`Self::truncate`], for a queue designated by its id.
+    pub async fn truncate_by_id(
+        &mut self,
+        queue_id: QueueId,
+        position: u64,
+    ) -> Result<usize, TruncateError> {
+        let queue = self.in_mem_queues.queue_name(queue_id)?;
+        let live_range = self.in_mem_queues.live_range_by_id(queue_id)?;
+        check_truncate_position(&live_range, position)?;
+        if live_range.start > position {
+            // Already truncated up to this position, e.g. by a retried call: writing the
+            // truncation again would not change anything.
+            return Ok(0);
         }
+        debug!(position = position, queue = queue, "truncate queue");
         self.record_log_writer
-            .write_record(MultiPlexedRecord::Truncate { position, queue })
+            .write_multiplexed_record(MultiPlexedRecord::Truncate { position, queue })
             .await?;
         let removed_count = self
             .in_mem_queues
-            .truncate(queue, position)
+            .truncate_by_id(queue_id, position)
             .await
             .unwrap_or(0);
         self.run_gc_if_necessary().await?;
@@ -300,6 +1762,129 @@ impl MultiRecordLog {
         Ok(removed_count)
     }
 
+    /// Truncates the queue so that only its last `keep` records remain, or all of them if it
+    /// holds fewer. See [`Self::truncate`].
+    ///
+    /// It returns the number of records deleted.
+    pub async fn truncate_keep_last(
+        &mut self,
+        queue: &str,
+        keep: usize,
+    ) -> Result<u64, TruncateError> {
+        let queue_id = self.in_mem_queues.queue_id(queue)?;
+        let live_range = self.in_mem_queues.live_range_by_id(queue_id)?;
+        let num_records_to_remove = (live_range.end - live_range.start).saturating_sub(keep as u64);
+        if num_records_to_remove == 0 {
+            return Ok(0);
+        }
+        let position = live_range.start + num_records_to_remove - 1;
+        let removed_count = self.truncate_by_id(queue_id, position).await?;
+        Ok(removed_count as u64)
+    }
+
+    /// Truncates every queue according to `position_fn`.
+    ///
+    /// `position_fn` is called with the name of each queue and the range of positions of the
+    /// records it currently holds. Records up to the returned position, included, are removed.
+    /// Queues for which it returns `None` are left untouched.
+    ///
+    /// Unlike calling [`Self::truncate`] for each queue, garbage collection and syncing happen
+    /// only once, after all queues have been truncated. If a returned position was not reached
+    /// by its queue, [`TruncateError::Future`] is returned before truncating any queue.
+    ///
+    /// Returns the truncated queues, along with the number of records removed from each of them.
+    /// Queues already truncated up to the returned position are skipped.
+    pub async fn truncate_all(
+        &mut self,
+        position_fn: impl Fn(&str, Range<u64>) -> Option<u64>,
+    ) -> Result<Vec<(String, u64)>, TruncateError> {
+        let mut truncations = Vec::new();
+        for queue in self.in_mem_queues.list_queues() {
+            let live_range = self.in_mem_queues.live_range(queue)?;
+            if let Some(position) = position_fn(queue, live_range.clone()) {
+                // Checked for all queues before truncating any of them.
+                check_truncate_position(&live_range, position)?;
+                if live_range.start > position {
+                    // Already truncated up to this position, see `Self::truncate_by_id`.
+                    continue;
+                }
+                truncations.push((queue.to_string(), position));
+            }
+        }
+        let mut removed_counts = Vec::with_capacity(truncations.len());
+        for (queue, position) in truncations {
+            debug!(position = position, queue = queue, "truncate queue");
+            self.record_log_writer
+                .write_multiplexed_record(MultiPlexedRecord::Truncate {
+                    position,
+                    queue: &queue,
+                })
+                .await?;
+            let removed_count = self
+                .in_mem_queues
+                .truncate(&queue, position)
+                .await
+                .unwrap_or(0);
+            removed_counts.push((queue, removed_count as u64));
+        }
+        self.run_gc_if_necessary().await?;
+        self.sync_on_policy().await?;
+        Ok(removed_counts)
+    }
+
+    /// Drops the oldest records until disk usage is at most `max_bytes`.
+    ///
+    /// Space is reclaimed one file at a time, starting with the oldest one: every queue is
+    /// truncated up to its last record stored in that file, and the file is garbage collected.
+    /// In other words, the records dropped first are the oldest ones globally, whatever queue
+    /// they belong to. The file currently being written is never removed, so disk usage may
+    /// remain above `max_bytes` if it is lower than the size of a single file.
+    ///
+    /// Returns the number of bytes reclaimed.
+    pub async fn enforce_size_limit(&mut self, max_bytes: u64) -> Result<u64, TruncateError> {
+        let initial_disk_usage = self.disk_usage() as u64;
+        while self.disk_usage() as u64 > max_bytes {
+            let oldest_file_number = self
+                .record_log_writer
+                .directory()
+                .first_file_number()
+                .file_number();
+            if oldest_file_number == self.record_log_writer.current_file().file_number() {
+                break;
+            }
+            let truncations = self
+                .in_mem_queues
+                .last_positions_up_to_file(oldest_file_number);
+            for (queue, position) in truncations {
+                // Filtered out queues have no live range, but their positions are the ones of
+                // their records too.
+                if let Ok(live_range) = self.in_mem_queues.live_range(&queue) {
+                    check_truncate_position(&live_range, position)?;
+                }
+                debug!(
+                    position = position,
+                    queue = queue,
+                    "truncate queue to enforce size limit"
+                );
+                self.record_log_writer
+                    .write_multiplexed_record(MultiPlexedRecord::Truncate {
+                        position,
+                        queue: &queue,
+                    })
+                    .await?;
+                self.in_mem_queues.truncate(&queue, position).await;
+            }
+            let num_files = self.record_log_writer.directory().files.count();
+            self.run_gc_if_necessary().await?;
+            if self.record_log_writer.directory().files.count() == num_files {
+                // The oldest file could not be released.
+                break;
+            }
+        }
+        self.sync_on_policy().await?;
+        Ok(initial_disk_usage.saturating_sub(self.disk_usage() as u64))
+    }
+
     async fn run_gc_if_necessary(&mut self) -> io::Result<()> {
         debug!("run_gc_if_necessary");
         if self
@@ -315,7 +1900,14 @@ impl MultiRecordLog {
             // contain the truncate positions it self won't be GC'ed.
             let _file_number = self.record_log_writer.current_file().clone();
             self.record_empty_queues_position().await?;
-            self.record_log_writer.directory().gc().await?;
+            // We need to sync here! We are about to remove files from the FS, so we need to make
+            // sure our empty queue positions are properly persisted, as well as the records which
+            // released the files, such as a queue deletion. Otherwise, a crash could bring back
+            // the part of a deleted queue stored in the files which are kept.
+            self.sync().await?;
+            debug_assert_eq!(self.pending_unflushed(), PendingFlush::default());
+            self.record_log_writer.get_underlying_wrt_mut().gc().await?;
+            self.in_mem_queues.clear_recently_deleted_queues();
         }
         // only execute the following if we are above the debug  level in tokio tracing
         if event_enabled!(Level::DEBUG) {
@@ -329,27 +1921,349 @@ impl MultiRecordLog {
         Ok(())
     }
 
+    /// Returns the records of `queue` whose position is contained in `range`, in increasing
+    /// position order.
+    ///
+    /// `Included` and `Excluded` bounds have their usual meaning on both ends, e.g.
+    /// `(Bound::Excluded(a), Bound::Included(b))` yields positions `p` such that `a < p <= b`.
+    /// Only records still stored are yielded: bounds outside of the live range are clamped
+    /// to it, and an empty range, such as one whose start comes after its end, yields nothing.
+    ///
+    /// The first record of the range is found with a binary search over the positions of the
+    /// queue, so resuming from an arbitrary position does not scan the records before it.
     pub fn range<R>(
         &self,
         queue: &str,
         range: R,
-    ) -> Result<impl Iterator<Item = (u64, Cow<[u8]>)> + '_, MissingQueue>
+    ) -> Result<impl Iterator<Item = (u64, Cow<'_, [u8]>)> + '_, MissingQueue>
     where
         R: RangeBounds<u64> + 'static,
     {
         self.in_mem_queues.range(queue, range)
     }
 
+    /// Same as [`Self::range`], but returns [`RangeError::Inverted`] for a range starting after
+    /// its end, which is most likely a bug of the caller, instead of yielding nothing.
+    ///
+    /// As when slicing, empty ranges which are not inverted, such as `5..5` or `5..=4`, are valid.
+    pub fn try_range<R>(
+        &self,
+        queue: &str,
+        range: R,
+    ) -> Result<impl Iterator<Item = (u64, Cow<'_, [u8]>)> + '_, RangeError>
+    where
+        R: RangeBounds<u64> + 'static,
+    {
+        check_range_not_inverted(&range)?;
+        Ok(self.range(queue, range)?)
+    }
+
+    /// Same as [`Self::range`], for a queue designated by its id.
+    pub fn range_by_id<R>(
+        &self,
+        queue_id: QueueId,
+        range: R,
+    ) -> Result<impl Iterator<Item = (u64, Cow<'_, [u8]>)> + '_, MissingQueue>
+    where
+        R: RangeBounds<u64> + 'static,
+    {
+        self.in_mem_queues.range_by_id(queue_id, range)
+    }
+
+    /// Returns owned copies of the records of `queue` whose position is greater than `after`, or
+    /// None if the queue does not exist.
+    ///
+    /// The payloads are copied into a single buffer, which the returned [`Bytes`] share.
+    pub fn records_since(&self, queue: &str, after: u64) -> Option<Vec<(u64, Bytes)>> {
+        let range = (Bound::Excluded(after), Bound::Unbounded);
+        self.copy_records(queue, range).ok()
+    }
+
+    /// Returns the serialized bytes of the record of `queue` at `position`, or None if there is
+    /// no such record.
+    ///
+    /// The bytes are those of the record as an item of a multi record, headers included. Items
+    /// of consecutive positions can be concatenated, and appended to another log as is with
+    /// [`Self::append_raw_multirecord`], without deserializing them.
+    pub fn record_bytes(&self, queue: &str, position: u64) -> Option<Vec<u8>> {
+        let (position, headers, payload) = self
+            .range_with_headers(queue, position..=position)
+            .ok()?
+            .next()?;
+        let mut buffer = Vec::new();
+        MultiRecord::append_with_headers(
+            position,
+            &payload[..],
+            headers
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_slice())),
+            &mut buffer,
+        );
+        Some(buffer)
+    }
+
+    /// Returns a snapshot of the records of `queue` whose position is contained in `range`, see
+    /// [`Self::range`].
+    ///
+    /// The payloads are copied into a single buffer, so taking a snapshot costs as much memory as
+    /// the records of the range. In exchange, unlike the iterator returned by [`Self::range`],
+    /// the snapshot does not borrow the log: it keeps yielding the records it was taken with
+    /// after they are truncated.
+    pub fn range_snapshot<R>(&self, queue: &str, range: R) -> Result<RangeSnapshot, MissingQueue>
+    where R: RangeBounds<u64> + Clone + 'static {
+        let records = self.copy_records(queue, range)?;
+        Ok(RangeSnapshot { records })
+    }
+
+    /// Copies the payloads of the records of `queue` in `range` into a single buffer, which the
+    /// returned [`Bytes`] share.
+    fn copy_records<R>(&self, queue: &str, range: R) -> Result<Vec<(u64, Bytes)>, MissingQueue>
+    where R: RangeBounds<u64> + Clone + 'static {
+        let num_bytes: usize = self
+            .range(queue, range.clone())?
+            .map(|(_, payload)| payload.len())
+            .sum();
+        let mut buffer = BytesMut::with_capacity(num_bytes);
+        let mut records_len = Vec::new();
+        for (position, payload) in self.range(queue, range)? {
+            buffer.extend_from_slice(&payload);
+            records_len.push((position, payload.len()));
+        }
+        let mut buffer = buffer.freeze();
+        let records = records_len
+            .into_iter()
+            .map(|(position, len)| (position, buffer.split_to(len)))
+            .collect();
+        Ok(records)
+    }
+
+    /// Returns a future resolving once the record at `position` has been appended to `queue`,
+    /// that is once the next position of the queue is greater than `position`.
+    ///
+    /// The future resolves right away if the position was already reached. It does not borrow
+    /// the log, so that appends can happen while it is awaited. It resolves to an error if the
+    /// queue does not exist, or gets deleted while waiting.
+    pub fn wait_for_position(
+        &self,
+        queue: &str,
+        position: u64,
+    ) -> impl Future<Output = Result<(), MissingQueue>> + Send + 'static {
+        let next_position_rx_res = self.in_mem_queues.subscribe_next_position(queue);
+        let queue = queue.to_string();
+        async move {
+            let mut next_position_rx = next_position_rx_res?;
+            next_position_rx
+                .wait_for(|&next_position| next_position > position)
+                .await
+                .map_err(|_| MissingQueue(queue))?;
+            Ok(())
+        }
+    }
+
+    /// Same as [`Self::range`], but yields owned copies of the records, in batches of
+    /// `batch_size` records. Only the last batch may be shorter.
+    ///
+    /// Panics if `batch_size` is 0.
+    pub fn range_batched<R>(
+        &self,
+        queue: &str,
+        range: R,
+        batch_size: usize,
+    ) -> Result<impl Iterator<Item = Vec<(u64, Vec<u8>)>> + '_, MissingQueue>
+    where
+        R: RangeBounds<u64> + 'static,
+    {
+        assert!(batch_size > 0, "batch_size must be strictly positive");
+        let mut records = self.range(queue, range)?;
+        Ok(std::iter::from_fn(move || {
+            let batch: Vec<(u64, Vec<u8>)> = records
+                .by_ref()
+                .take(batch_size)
+                .map(|(position, payload)| (position, payload.into_owned()))
+                .collect();
+            if batch.is_empty() {
+                None
+            } else {
+                Some(batch)
+            }
+        }))
+    }
+
+    /// Returns a cursor reading the records of `queue` whose position is contained in `range`,
+    /// `chunk_size` records at a time.
+    ///
+    /// Unlike [`Self::range_batched`], the log is only borrowed while each chunk is fetched, so
+    /// that records can be appended or truncated while a large range is read. See
+    /// [`RangeCursor::next_chunk`] for what each chunk then reflects.
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub fn range_chunked_owned(
+        &self,
+        queue: &str,
+        range: impl RangeBounds<u64>,
+        chunk_size: usize,
+    ) -> Result<RangeCursor, MissingQueue> {
+        assert!(chunk_size > 0, "chunk_size must be strictly positive");
+        self.in_mem_queues.get_queue(queue)?;
+        Ok(RangeCursor {
+            queue: queue.to_string(),
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            chunk_size,
+        })
+    }
+
+    /// Same as [`Self::range`], but also returns the headers of each record. Records appended
+    /// without headers have an empty list of headers.
+    #[allow(clippy::type_complexity)]
+    pub fn range_with_headers<R>(
+        &self,
+        queue: &str,
+        range: R,
+    ) -> Result<
+        impl Iterator<Item = (u64, &'_ [(String, Vec<u8>)], Cow<'_, [u8]>)> + '_,
+        MissingQueue,
+    >
+    where
+        R: RangeBounds<u64> + 'static,
+    {
+        self.in_mem_queues.range_with_headers(queue, range)
+    }
+
     async fn sync_on_policy(&mut self) -> io::Result<()> {
         if self.next_sync.should_sync() {
             self.sync().await?;
-            self.next_sync.update_synced();
         }
         Ok(())
     }
 
+    /// Same as [`Self::sync_on_policy`], for records which were just written.
+    ///
+    /// If the flush times out, the records are still buffered by the writer, and will be flushed
+    /// by the next operation. They must then be appended to the in memory queues too, for them
+    /// to stay consistent with the log: the timeout is returned in the inner result, to be
+    /// reported once this is done.
+    async fn sync_on_policy_for_append(&mut self) -> Result<Result<(), AppendError>, AppendError> {
+        match self.sync_on_policy().await {
+            Ok(()) => Ok(Ok(())),
+            Err(io_error) if FlushTimeout::is_flush_timeout(&io_error) => {
+                Ok(Err(AppendError::FlushTimeout))
+            }
+            Err(io_error) => Err(io_error.into()),
+        }
+    }
+
+    /// Same as [`Self::sync_on_policy_for_append`], for the `num_records` records written since
+    /// `mark`, which are not in the in memory queues yet.
+    ///
+    /// If the flush fails while they are still buffered, they are discarded, so that they don't
+    /// get written along with the next operation. Otherwise they reached the file and get
+    /// replayed: the error is returned in the inner result, as for a timeout.
+    async fn sync_on_policy_for_records(
+        &mut self,
+        mark: WriteMark,
+        num_records: usize,
+    ) -> Result<Result<(), AppendError>, AppendError> {
+        match self.sync_on_policy_for_append().await {
+            Err(append_error) if !self.discard_since(mark, num_records) => Ok(Err(append_error)),
+            sync_result => sync_result,
+        }
+    }
+
+    /// Discards the `num_records` records written since `mark`, see
+    /// [`RollingWriter::discard_since`]. Returns false if some of them were flushed.
+    fn discard_since(&mut self, mark: WriteMark, num_records: usize) -> bool {
+        if !self
+            .record_log_writer
+            .get_underlying_wrt_mut()
+            .discard_since(mark)
+        {
+            return false;
+        }
+        self.num_unflushed_records -= num_records;
+        self.num_appended_records -= num_records as u64;
+        true
+    }
+
     pub async fn sync(&mut self) -> io::Result<()> {
-        self.record_log_writer.flush().await
+        let flush = self.record_log_writer.flush();
+        if let Some(flush_timeout) = self.flush_timeout {
+            crate::file_system::timeout::<FS, _>(flush_timeout, flush)
+                .await
+                .ok_or_else(|| FlushTimeout.into_io_error())??;
+        } else {
+            flush.await?;
+        }
+        // Files created since the last sync, such as the one we just wrote to, would be lost
+        // on a crash if the directory was not synced.
+        self.record_log_writer.directory().sync_directory().await?;
+        self.num_unflushed_records = 0;
+        self.flushed_records_tx
+            .send_replace(self.num_appended_records);
+        self.next_sync.update_synced();
+        Ok(())
+    }
+
+    /// Flushes the log, like [`Self::sync`], then makes sure everything written so far is
+    /// persisted on the underlying storage, whatever the sync policy.
+    ///
+    /// A flush only hands the data to the operating system, which may lose it on a power
+    /// failure. Once this returns, all the records appended before are durable.
+    pub async fn sync_barrier(&mut self) -> io::Result<()> {
+        self.sync().await?;
+        self.record_log_writer.get_underlying_wrt_mut().sync().await
+    }
+
+    /// Returns when what is waiting for the next flush is due to be flushed, or `None` if
+    /// nothing is waiting.
+    ///
+    /// With [`SyncPolicy::OnDelay`], the flush is only done by the first operation after the
+    /// delay, so that the records appended last may not get flushed for a while if no operation
+    /// follows. Callers wanting to bound this delay can call [`Self::sync`] once the deadline is
+    /// reached, e.g. by selecting on a sleep until it in the task owning the log. The delay then
+    /// starts again from this sync.
+    pub fn sync_deadline(&self) -> Option<Instant> {
+        if self.pending_unflushed() == PendingFlush::default() {
+            return None;
+        }
+        match self.next_sync {
+            SyncState::OnAppend => Some(Instant::now()),
+            SyncState::OnDelay { next_sync, .. } => Some(next_sync),
+        }
+    }
+
+    /// Returns what is waiting for the next flush.
+    ///
+    /// With [`SyncPolicy::OnAppend`], this is always empty after an append returns.
+    pub fn pending_unflushed(&self) -> PendingFlush {
+        PendingFlush {
+            records: self.num_unflushed_records,
+            bytes: self
+                .record_log_writer
+                .get_underlying_wrt()
+                .num_buffered_bytes(),
+        }
+    }
+
+    /// Returns the numbers of the oldest and newest files of the log.
+    ///
+    /// All the files in this range are present, and files outside of it are not. Older files
+    /// get removed as queues are truncated, and the newest file is the one being written to.
+    /// Files written before an incremental backup only change if they are the newest one, so
+    /// that a backup only needs to copy files from the last newest file onwards.
+    pub fn file_number_range(&self) -> Option<RangeInclusive<u64>> {
+        self.record_log_writer
+            .get_underlying_wrt()
+            .file_number_range()
+    }
+
+    /// Returns the queues having records stored in the file `file_number`, along with the range
+    /// of positions of these records, in no particular order.
+    ///
+    /// These are the queues keeping the file from being garbage collected: it gets deleted once
+    /// they are all truncated past these ranges, and so are the queues referencing older files.
+    pub fn queues_referencing_file(&self, file_number: u64) -> Vec<(&str, Range<u64>)> {
+        self.in_mem_queues.queues_referencing_file(file_number)
     }
 
     /// Returns the position of the last record appended to the queue.
@@ -357,16 +2271,129 @@ impl MultiRecordLog {
         self.in_mem_queues.last_position(queue)
     }
 
+    /// Same as [`Self::last_position`], for a queue designated by its id.
+    pub fn last_position_by_id(&self, queue_id: QueueId) -> Result<Option<u64>, MissingQueue> {
+        self.in_mem_queues.last_position_by_id(queue_id)
+    }
+
+    /// Returns a reader streaming the payload of the record at `position`, or `None` if no such
+    /// record is stored.
+    ///
+    /// Unlike [`Self::range`], this never copies the payload, which makes it suitable for
+    /// large payloads.
+    pub fn record_reader(
+        &self,
+        queue: &str,
+        position: u64,
+    ) -> Result<Option<impl io::Read + '_>, MissingQueue> {
+        self.in_mem_queues.record_reader(queue, position)
+    }
+
+    /// Returns a digest of the live records of the queue, or `None` if the queue does not exist.
+    ///
+    /// The digest only depends on the positions and payloads of the records, so that queues
+    /// holding the same records have the same digest, regardless of how they were appended.
+    /// This makes it possible to check that a replica matches cheaply: the digest is maintained
+    /// as records are appended and truncated.
+    pub fn queue_digest(&self, queue: &str) -> Option<u64> {
+        self.in_mem_queues.queue_digest(queue)
+    }
+
+    /// Returns the last record appended to the queue with `key`, along with its position, if
+    /// it was not truncated since. See [`Self::append_record_keyed`].
+    ///
+    /// Always returns `None` if the log was not opened with [`Options::key_index`].
+    #[allow(clippy::type_complexity)]
+    pub fn get_by_key(
+        &self,
+        queue: &str,
+        key: &[u8],
+    ) -> Result<Option<(u64, Cow<'_, [u8]>)>, MissingQueue> {
+        self.in_mem_queues.get_by_key(queue, key)
+    }
+
     /// Returns the last record stored in the queue.
-    pub fn last_record(&self, queue: &str) -> Result<Option<(u64, Cow<[u8]>)>, MissingQueue> {
+    #[allow(clippy::type_complexity)]
+    pub fn last_record(&self, queue: &str) -> Result<Option<(u64, Cow<'_, [u8]>)>, MissingQueue> {
         self.in_mem_queues.last_record(queue)
     }
 
+    /// Checks the consistency of the in-memory state, without touching the disk.
+    ///
+    /// This is cheap enough to be used as a readiness probe. It verifies that queue positions are
+    /// consistent, and that queues only reference known files which are not after the file
+    /// currently being written.
+    pub fn health(&self) -> Health {
+        let rolling_writer = self.record_log_writer.get_underlying_wrt();
+        let reasons = self.in_mem_queues.consistency_issues(
+            &rolling_writer.directory.files,
+            rolling_writer.current_file(),
+        );
+        if reasons.is_empty() {
+            Health::Healthy
+        } else {
+            Health::Unhealthy { reasons }
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn directory_for_test(&mut self) -> &mut crate::rolling::Directory<FS> {
+        self.record_log_writer.directory()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn record_log_writer_for_test(&mut self) -> &mut RecordWriter<RollingWriter<FS>> {
+        &mut self.record_log_writer
+    }
+
+    #[cfg(test)]
+    pub(crate) fn in_mem_queues_mut(&mut self) -> &mut mem::MemQueues {
+        &mut self.in_mem_queues
+    }
+
     /// Returns the quantity of data stored in the in memory queue.
-    pub fn memory_usage(&self) -> usize {
+    #[deprecated(note = "use `memory_usage`, which breaks memory usage down")]
+    pub fn memory_usage_bytes(&self) -> usize {
         self.in_mem_queues.size()
     }
 
+    /// Returns a breakdown of the memory used by the in memory queues, in total and for each
+    /// queue.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.in_mem_queues.memory_usage()
+    }
+
+    /// Returns a snapshot of the state of the log, gathering in a single call what monitoring
+    /// usually scrapes.
+    ///
+    /// Totals are computed from the per-queue summaries, so they are always consistent with
+    /// them.
+    pub fn stats(&self) -> LogStats {
+        let queues = self.in_mem_queues.queue_stats();
+        let rolling_writer = self.record_log_writer.get_underlying_wrt();
+        LogStats {
+            num_queues: queues.len(),
+            num_records: queues.iter().map(|queue| queue.num_records).sum(),
+            payload_bytes: queues.iter().map(|queue| queue.payload_bytes).sum(),
+            disk_bytes: self.disk_usage(),
+            num_files: rolling_writer.num_files(),
+            file_number_range: rolling_writer.file_number_range(),
+            queues,
+        }
+    }
+
+    /// Returns the identifier of this log, formatted like a UUID.
+    ///
+    /// It is generated when the log is created, and stored in the header of each of its files.
+    pub fn log_id(&self) -> String {
+        let log_id = self
+            .record_log_writer
+            .get_underlying_wrt()
+            .directory
+            .log_id();
+        crate::rolling::format_log_id(log_id)
+    }
+
     /// Returns the used disk space.
     ///
     /// This is typically higher than what [`Self::memory_usage`] reports as records are first
@@ -376,3 +2403,330 @@ impl MultiRecordLog {
         self.record_log_writer.size()
     }
 }
+
+/// Replays the records read by `record_reader` into `in_mem_queues`.
+pub(crate) async fn replay<FS: FileSystem>(
+    record_reader: &mut RecordReader<RollingReader<FS>>,
+    in_mem_queues: &mut mem::MemQueues,
+    progress_fn: Option<&(dyn Fn(OpenProgress) + Send + Sync)>,
+    unknown_record_policy: UnknownRecordPolicy,
+) -> Result<QueueIndex, ReadRecordError> {
+    let mut progress = OpenProgress {
+        num_files: record_reader.read().num_files(),
+        ..Default::default()
+    };
+    let report_progress = |progress: OpenProgress| {
+        if let Some(progress_fn) = progress_fn {
+            progress_fn(progress);
+        }
+    };
+    let mut last_report = Instant::now();
+    let mut num_reads: u64 = 0;
+    let mut previous_file_number = record_reader.read().current_file().clone();
+    // Queues having records ending in the current file, and the file it refers to.
+    let mut queue_index = QueueIndex::default();
+    let mut queue_index_file_number = previous_file_number.clone();
+    debug!("loading wal");
+    loop {
+        let file_number = record_reader.read().current_file().clone();
+        num_reads += 1;
+        if file_number != previous_file_number {
+            progress.num_files_processed += 1;
+            report_progress(progress);
+            last_report = Instant::now();
+            previous_file_number = file_number.clone();
+        } else if num_reads % PROGRESS_CHECK_NUM_READS == 0
+            && last_report.elapsed() >= OpenProgress::REPORT_INTERVAL
+        {
+            report_progress(progress);
+            last_report = Instant::now();
+        }
+        let next_result = record_reader.go_next().await;
+        // A record spanning several files is indexed in the file it ends in, which is
+        // the file the writer resumes in.
+        if *record_reader.read().current_file() != queue_index_file_number {
+            queue_index.clear();
+            queue_index_file_number = record_reader.read().current_file().clone();
+        }
+        let Ok(has_record) = next_result else {
+            warn!("Detected corrupted record: some data may have been lost");
+            continue;
+        };
+        if has_record {
+            let Some(record) = record_reader.record::<MultiPlexedRecord>() else {
+                match unknown_record_type(record_reader.record_bytes()) {
+                    Some(record_type) if unknown_record_policy == UnknownRecordPolicy::Fail => {
+                        return Err(ReadRecordError::UnknownRecordType { record_type });
+                    }
+                    Some(record_type) => {
+                        warn!(record_type, "skipping record of unknown type");
+                    }
+                    None => {
+                        warn!("Detected corrupted record: some data may have been lost");
+                    }
+                }
+                continue;
+            };
+            for queue in record.queues() {
+                queue_index.insert(queue);
+            }
+            replay_multiplexed_record(
+                record,
+                record_reader.read(),
+                in_mem_queues,
+                &file_number,
+                &mut progress.num_records_replayed,
+            )
+            .await?;
+        } else {
+            break;
+        }
+    }
+    progress.num_files_processed = progress.num_files;
+    report_progress(progress);
+    Ok(queue_index)
+}
+
+/// Applies a record read from the log, starting in `file_number`, to the in memory queues.
+pub(crate) async fn replay_multiplexed_record<FS: FileSystem>(
+    record: MultiPlexedRecord<'_>,
+    rolling_reader: &RollingReader<FS>,
+    in_mem_queues: &mut mem::MemQueues,
+    file_number: &FileNumber,
+    num_records_replayed: &mut u64,
+) -> Result<(), ReadRecordError> {
+    let MultiPlexedRecord::Batch { records } = record else {
+        return replay_record(
+            record,
+            rolling_reader,
+            in_mem_queues,
+            file_number,
+            num_records_replayed,
+        )
+        .await;
+    };
+    for record in records {
+        replay_record(
+            record,
+            rolling_reader,
+            in_mem_queues,
+            file_number,
+            num_records_replayed,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Applies a record read from the log, other than a batch, to the in memory queues.
+async fn replay_record<FS: FileSystem>(
+    record: MultiPlexedRecord<'_>,
+    rolling_reader: &RollingReader<FS>,
+    in_mem_queues: &mut mem::MemQueues,
+    file_number: &FileNumber,
+    num_records_replayed: &mut u64,
+) -> Result<(), ReadRecordError> {
+    match record {
+        MultiPlexedRecord::AppendRecords {
+            queue,
+            records,
+            position,
+        } => {
+            if !in_mem_queues.tracks_queue(queue) {
+                in_mem_queues.ack_position(queue, position);
+            }
+            let mut records = records;
+            while let Some(record) = records.next_with_headers() {
+                // if this fails, it means some corruption wasn't detected at a lower
+                // level, or we wrote invalid data.
+                let (position, headers, payload) = record?;
+                // this can fail if queue doesn't exist (it was created just above, so
+                // it does), or if the position is in the past. This can happen if the
+                // queue is deleted and recreated in a block which get skipped for
+                // corruption. In that case, maybe we should ack_position() and try
+                // to insert again?
+                in_mem_queues
+                    .append_record_with_headers(queue, file_number, position, payload, headers)
+                    .await
+                    .map_err(|_| ReadRecordError::Corruption)?;
+                *num_records_replayed += 1;
+            }
+        }
+        MultiPlexedRecord::ReplaceRecords {
+            queue,
+            records,
+            position,
+        } => {
+            // Drops the records the queue had, which are written again below.
+            in_mem_queues.ack_position(queue, position);
+            let mut records = records;
+            while let Some(record) = records.next_with_headers() {
+                let (position, headers, payload) = record?;
+                in_mem_queues
+                    .append_record_with_headers(queue, file_number, position, payload, headers)
+                    .await
+                    .map_err(|_| ReadRecordError::Corruption)?;
+                *num_records_replayed += 1;
+            }
+        }
+        MultiPlexedRecord::Truncate { position, queue } => {
+            in_mem_queues.truncate(queue, position).await;
+        }
+        MultiPlexedRecord::RecordPosition {
+            queue,
+            position,
+            format_tag: None,
+        } => {
+            in_mem_queues.ack_position(queue, position);
+        }
+        MultiPlexedRecord::RecordPosition {
+            queue,
+            position,
+            format_tag: Some(format_tag),
+        } => {
+            in_mem_queues.ack_format_tag(queue, position, format_tag, file_number.file_number());
+        }
+        MultiPlexedRecord::DeleteQueue { queue, position } => {
+            // can fail if we don't know about the queue getting deleted. It's fine to
+            // just ignore the error, the queue no longer exists either way.
+            let _ = in_mem_queues.delete_queue(queue);
+            in_mem_queues.ack_deleted_queue(queue, position, file_number.file_number());
+        }
+        MultiPlexedRecord::AppendSidecar {
+            queue,
+            position,
+            sidecar,
+        } => {
+            let Some(payload) = rolling_reader.read_sidecar(sidecar).await? else {
+                // Sidecars are synced before their record is written, and only deleted along
+                // with the file they are attached to: if this file is still there, the payload
+                // of the record was lost.
+                if sidecar.file_number >= rolling_reader.first_file_number() {
+                    return Err(ReadRecordError::Corruption);
+                }
+                // Otherwise the file was garbage collected, which requires the record to have
+                // been truncated.
+                debug!(
+                    queue = queue,
+                    position, "skipping truncated record with garbage collected sidecar"
+                );
+                if !in_mem_queues.tracks_queue(queue) {
+                    in_mem_queues.ack_position(queue, position + 1);
+                }
+                return Ok(());
+            };
+            if !in_mem_queues.tracks_queue(queue) {
+                in_mem_queues.ack_position(queue, position);
+            }
+            in_mem_queues
+                .append_record_with_headers(queue, file_number, position, &payload, None)
+                .await
+                .map_err(|_| ReadRecordError::Corruption)?;
+            *num_records_replayed += 1;
+        }
+        MultiPlexedRecord::Batch { .. } => {
+            // batches containing batches are rejected when deserialized.
+            return Err(ReadRecordError::Corruption);
+        }
+    }
+    Ok(())
+}
+
+fn check_payload_len(payload_len: usize) -> Result<(), AppendError> {
+    if payload_len > MAX_PAYLOAD_NUM_BYTES {
+        return Err(AppendError::TooLarge);
+    }
+    Ok(())
+}
+
+/// Checks that `headers` can be serialized along with a payload of `payload_len` bytes: each key
+/// must fit in a `u16`, and the payload and headers together must not be larger than a payload.
+fn check_headers_len(payload_len: usize, headers: &[(&str, &[u8])]) -> Result<(), AppendError> {
+    // The length of the headers is stored before them.
+    let mut record_len = payload_len + 4;
+    for (key, value) in headers {
+        if key.len() > u16::MAX as usize {
+            return Err(AppendError::HeaderKeyTooLong { len: key.len() });
+        }
+        record_len = record_len
+            .saturating_add(2 + key.len() + 4)
+            .saturating_add(value.len());
+    }
+    check_payload_len(record_len)
+}
+
+/// Checks that the records of a multi record have contiguous positions, starting at
+/// `next_position`.
+fn check_contiguous(
+    records: MultiRecord<'_>,
+    next_position: u64,
+    max_position_gap: Option<u64>,
+) -> Result<(), AppendError> {
+    // The position following the last record must fit in a u64 too.
+    let mut expected_positions = next_position..u64::MAX;
+    for record in records {
+        // the multi record was validated or serialized by us, we know it's valid
+        let (position, _) = record.unwrap();
+        let expected_position = expected_positions
+            .next()
+            .ok_or(AppendError::PositionOverflow)?;
+        if position < expected_position {
+            return Err(AppendError::Past);
+        }
+        if position > expected_position {
+            check_position_gap(expected_position, position, max_position_gap)?;
+            return Err(AppendError::Future);
+        }
+    }
+    Ok(())
+}
+
+/// Returns an error if truncating up to `position` would move forward a queue holding the records
+/// of `live_range`, as it did not reach the position yet.
+fn check_truncate_position(live_range: &Range<u64>, position: u64) -> Result<(), TruncateError> {
+    if position >= live_range.end {
+        return Err(TruncateError::Future {
+            next_position: live_range.end,
+        });
+    }
+    Ok(())
+}
+
+/// Returns an error if `position` is more than `max_position_gap` ahead of `expected_position`.
+fn check_position_gap(
+    expected_position: u64,
+    position: u64,
+    max_position_gap: Option<u64>,
+) -> Result<(), AppendError> {
+    match max_position_gap {
+        Some(max_position_gap) if position - expected_position > max_position_gap => {
+            Err(AppendError::PositionGapTooLarge {
+                expected: expected_position,
+                got: position,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Returns an error if `range` starts after its end.
+fn check_range_not_inverted(range: &impl RangeBounds<u64>) -> Result<(), RangeError> {
+    // Bounds are widened so that `Excluded(u64::MAX)` and `Included(u64::MAX)` have successors.
+    let start: u128 = match range.start_bound() {
+        Bound::Included(&start) => start as u128,
+        Bound::Excluded(&start) => start as u128 + 1,
+        Bound::Unbounded => 0,
+    };
+    let end: u128 = match range.end_bound() {
+        Bound::Included(&end) => end as u128 + 1,
+        Bound::Excluded(&end) => end as u128,
+        Bound::Unbounded => u128::MAX,
+    };
+    if start > end {
+        return Err(RangeError::Inverted {
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+        });
+    }
+    Ok(())
+}
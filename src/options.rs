@@ -0,0 +1,295 @@
+use std::collections::HashSet;
+use std::io;
+use std::time::Duration;
+
+use crate::{ChecksumAlgo, SyncPolicy};
+
+/// Options used to open a [`MultiRecordLog`](crate::MultiRecordLog).
+#[derive(Default)]
+pub struct Options {
+    /// Policy for synchronizing and flushing data.
+    pub sync_policy: SyncPolicy,
+    /// Policy for retrying writes which failed because of a transient io error.
+    pub io_retry: RetryPolicy,
+    /// If set, only the listed queues are loaded in memory. Other queues are not visible, but
+    /// their positions are still tracked so that files they use are not garbage collected.
+    pub queues_filter: Option<HashSet<String>>,
+    /// If set, called while the log is replayed on open, to report its progress.
+    ///
+    /// It is called each time a file is done being replayed, and at most every
+    /// [`OpenProgress::REPORT_INTERVAL`] in between.
+    pub progress: Option<Box<dyn Fn(OpenProgress) + Send + Sync>>,
+    /// Advice given to the operating system about caching the files once they are written.
+    pub cache_advice: CacheAdvice,
+    /// If set, payloads appended with [`append_record`](crate::MultiRecordLog::append_record)
+    /// whose length exceeds this threshold are written to a dedicated sidecar file, the log
+    /// only storing a reference to it.
+    ///
+    /// This keeps large payloads from filling the log files, and delaying their garbage
+    /// collection. A sidecar is deleted along with the log file which was current when it got
+    /// created.
+    pub large_record_threshold: Option<usize>,
+    /// If true, a queue created with the name of a deleted queue resumes from the position the
+    /// deleted queue ended at, instead of starting at 0. This keeps consumers holding positions
+    /// of the deleted queue from mistaking new records for old ones.
+    ///
+    /// The position of each deleted queue is then kept, in memory and in the log, for as long as
+    /// no queue with its name is created.
+    pub keep_deleted_queue_positions: bool,
+    /// If set, flushes taking longer than this are abandoned. Appends then fail with
+    /// [`AppendError::FlushTimeout`](crate::error::AppendError::FlushTimeout), and other
+    /// operations with an io error of kind [`TimedOut`](std::io::ErrorKind::TimedOut).
+    ///
+    /// An abandoned flush is retried by the next one.
+    pub flush_timeout: Option<Duration>,
+    /// Names accepted for the queues created. Queues already in the log are loaded whatever
+    /// their names.
+    pub queue_name_policy: QueueNamePolicy,
+    /// Number of the first file created, if the log is new. This is ignored when opening an
+    /// existing log, whose file numbers go on from its last file.
+    pub initial_file_number: u64,
+    /// If set, appends which could make [`disk_usage`](crate::MultiRecordLog::disk_usage)
+    /// exceed this number of bytes fail with
+    /// [`AppendError::DiskFull`](crate::error::AppendError::DiskFull), without writing anything.
+    ///
+    /// Other operations, such as truncations, are not limited, as they only write small records
+    /// and allow reclaiming space.
+    pub hard_disk_cap: Option<u64>,
+    /// What to do, while replaying the log, with records of a type unknown to this version,
+    /// written by a newer version.
+    pub unknown_record_policy: UnknownRecordPolicy,
+    /// If true, appending a record with an empty payload fails with
+    /// [`AppendError::EmptyPayload`](crate::error::AppendError::EmptyPayload), and nothing is
+    /// appended. Otherwise, empty payloads are stored and read back like any other.
+    pub reject_empty_payloads: bool,
+    /// If true, an append at the position of the last record of the queue is only ignored as a
+    /// retry if it holds a single payload equal to the one of that record. Otherwise it fails
+    /// with [`AppendError::PositionConflict`](crate::error::AppendError::PositionConflict),
+    /// so that a new record passed an off-by-one position is not silently dropped.
+    ///
+    /// A retry is also rejected once the record it retries was truncated, as it can no longer
+    /// be compared.
+    pub strict_idempotence: bool,
+    /// If set, appends at a position more than this many positions ahead of the next
+    /// position of the queue fail with
+    /// [`AppendError::PositionGapTooLarge`](crate::error::AppendError::PositionGapTooLarge),
+    /// instead of moving the queue forward or failing with
+    /// [`AppendError::Future`](crate::error::AppendError::Future).
+    ///
+    /// This lets replication followers tell a missing segment of records apart from an append
+    /// which is merely early.
+    pub max_position_gap: Option<u64>,
+    /// If true, opening the log fails with
+    /// [`ReadRecordError::FileNumberMismatch`](crate::error::ReadRecordError::FileNumberMismatch)
+    /// if a file is not named after the number recorded in its header, instead of attributing
+    /// its records to the file it is named after.
+    ///
+    /// Files written before file numbers were recorded in headers are not checked.
+    pub strict_file_attribution: bool,
+    /// If true, opening the log fails with
+    /// [`ReadRecordError::UnexpectedFile`](crate::error::ReadRecordError::UnexpectedFile) if
+    /// its directory holds files which are not named like the files of a log, before any file
+    /// gets removed or created. Otherwise these files are ignored.
+    ///
+    /// Files named like log files are checked whatever this option: a wal file with a corrupted
+    /// header fails opening the log.
+    pub strict_directory: bool,
+    /// If true, records appended together without headers are written in a compact format,
+    /// which stores the position of the first record only, saving 8 bytes per record.
+    ///
+    /// Both formats are read whatever this option, but logs written with it cannot be read by
+    /// versions which don't know the compact format.
+    pub compact_multi_records: bool,
+    /// If true, a file listing the queues having records in each log file is written once the
+    /// log file is full. Opening the log with [`Self::queues_filter`] then skips the files
+    /// holding none of the filtered queues, instead of reading them all.
+    ///
+    /// When files are skipped, queues outside of the filter are not entirely known: files are
+    /// not garbage collected, and creating such a queue fails with
+    /// [`CreateQueueError::FilteredOut`](crate::error::CreateQueueError::FilteredOut), until
+    /// the log is opened again without skipping files.
+    pub write_queue_indexes: bool,
+    /// If true, the records appended with
+    /// [`append_record_keyed`](crate::MultiRecordLog::append_record_keyed) are indexed in
+    /// memory by their key, so that they can be read with
+    /// [`get_by_key`](crate::MultiRecordLog::get_by_key).
+    ///
+    /// Keys are stored in the log whatever this option: the index is rebuilt when the log is
+    /// opened with it.
+    pub key_index: bool,
+    /// Algorithm of the checksums of the frames written to the files created from now on.
+    ///
+    /// It is recorded in the header of each file, so that logs whose files use different
+    /// algorithms are read whatever this option. Frames appended to a file which already exists
+    /// keep using its algorithm.
+    pub checksum: ChecksumAlgo,
+    /// If set, appends making a queue exceed this rate fail with
+    /// [`AppendError::RateLimited`](crate::error::AppendError::RateLimited), without writing
+    /// anything, so that a single queue cannot monopolize the log.
+    ///
+    /// Each queue is limited on its own. The rate is tracked in memory only, starting afresh
+    /// when the log is opened.
+    pub per_queue_rate_limit: Option<RateLimit>,
+    /// If set, the log is compacted once opened when it is fragmented beyond the thresholds,
+    /// so that fragmentation stays bounded across restarts without a separate admin step.
+    ///
+    /// Compaction writes again the records of the queues holding records in old files, like
+    /// [`compact_queue`](crate::MultiRecordLog::compact_queue), so that these files get garbage
+    /// collected.
+    pub auto_compact_on_open: Option<AutoCompact>,
+}
+
+/// Thresholds above which the log is compacted on open, see
+/// [`Options::auto_compact_on_open`].
+///
+/// Both thresholds must be reached for the log to be compacted.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AutoCompact {
+    /// Minimum ratio of the disk usage which is not taken by the payloads of live records,
+    /// between 0 and 1.
+    pub min_dead_ratio: f64,
+    /// Minimum disk usage, in bytes, so that small logs are not compacted.
+    pub min_total_bytes: u64,
+}
+
+/// Limit on the rate of the appends to a queue, see [`Options::per_queue_rate_limit`].
+///
+/// Bursts of up to one second worth of appends are accepted. A single append larger than that
+/// is accepted once no append was made for a second.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Maximum number of payload bytes appended per second. `None` or 0 means no limit.
+    pub bytes_per_sec: Option<u64>,
+    /// Maximum number of records appended per second. `None` or 0 means no limit.
+    pub records_per_sec: Option<u64>,
+}
+
+/// Policy for the records of an unknown type found while replaying the log.
+///
+/// Records are delimited by their frames, so that an unknown record can be skipped without
+/// understanding its content.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownRecordPolicy {
+    /// Skip the record with a warning, and go on replaying the log.
+    #[default]
+    Skip,
+    /// Fail opening the log with
+    /// [`ReadRecordError::UnknownRecordType`](crate::error::ReadRecordError::UnknownRecordType).
+    Fail,
+}
+
+/// Policy deciding which queue names are valid.
+///
+/// Whatever the policy, names are limited to 65535 bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QueueNamePolicy {
+    /// Reject names containing control characters, such as NUL or newlines, which are
+    /// troublesome in logs or file names.
+    #[default]
+    RejectControlChars,
+    /// Accept any name.
+    AllowAll,
+}
+
+impl QueueNamePolicy {
+    /// Returns the reason why `queue` is not a valid name, if it is not.
+    pub fn check(&self, queue: &str) -> Result<(), String> {
+        if queue.len() > u16::MAX as usize {
+            return Err(format!(
+                "name is {} bytes long, the maximum is {}",
+                queue.len(),
+                u16::MAX
+            ));
+        }
+        if *self == QueueNamePolicy::RejectControlChars {
+            if let Some(control_char) = queue.chars().find(|c| c.is_control()) {
+                return Err(format!("name contains control character {control_char:?}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Advice about caching files which are no longer written to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheAdvice {
+    /// Let the operating system cache files as usual.
+    #[default]
+    Normal,
+    /// Release the page cache of a file once it is fully written and the log moved on to the
+    /// next file, using `posix_fadvise(POSIX_FADV_DONTNEED)`.
+    ///
+    /// This avoids filling the page cache with a write-heavy log, at the cost of reading
+    /// the files from disk if they are read again. This is a no-op on platforms other than
+    /// Linux.
+    DontNeed,
+}
+
+/// Progress of the replay of the log files, reported while opening the log.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpenProgress {
+    /// Number of files entirely replayed.
+    pub num_files_processed: usize,
+    /// Number of files to replay.
+    pub num_files: usize,
+    /// Number of records replayed so far.
+    pub num_records_replayed: u64,
+}
+
+impl OpenProgress {
+    /// Minimum delay between two reports made while replaying a file.
+    pub const REPORT_INTERVAL: Duration = Duration::from_millis(100);
+}
+
+/// Policy for retrying transient io errors in the write path.
+///
+/// Only errors considered transient (see [`RetryPolicy::is_retryable`]) are retried. Other errors
+/// are returned right away.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Maximum number of retries after the initial attempt. `0` disables retries.
+    pub max_retries: usize,
+    /// Delay before the first retry. It doubles after each failed retry.
+    pub initial_backoff: Duration,
+    /// Upper bound on the delay between two retries.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy which never retries.
+    pub fn no_retry() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    /// Returns true if an operation which failed with `io_error` may succeed if attempted again.
+    pub fn is_retryable(io_error: &io::Error) -> bool {
+        matches!(
+            io_error.kind(),
+            io::ErrorKind::Interrupted
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::WriteZero
+        )
+    }
+
+    /// Returns the delay to wait before retry number `retry` (starting at 0).
+    pub(crate) fn backoff(&self, retry: usize) -> Duration {
+        let factor = 1u32.checked_shl(retry as u32).unwrap_or(u32::MAX);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
@@ -8,12 +8,14 @@ use proptest::strategy::{Just, Strategy};
 use tempfile::TempDir;
 use tokio::runtime::Runtime;
 
+use crate::error::TruncateError;
 use crate::record::{MultiPlexedRecord, MultiRecord};
 use crate::{MultiRecordLog, Serializable};
 
 struct PropTestEnv {
     tempdir: TempDir,
-    record_log: MultiRecordLog,
+    // Only None while the log is being reopened.
+    record_log: Option<MultiRecordLog>,
     state: HashMap<&'static str, (Range<u64>, u64)>,
     block_to_write: Vec<u8>,
 }
@@ -29,7 +31,7 @@ impl PropTestEnv {
         state.insert("q2", (0..0, 0));
         PropTestEnv {
             tempdir,
-            record_log,
+            record_log: Some(record_log),
             state,
             block_to_write: vec![b'A'; block_size],
         }
@@ -60,10 +62,17 @@ impl PropTestEnv {
     }
 
     pub async fn reload(&mut self) {
-        self.record_log = MultiRecordLog::open(self.tempdir.path()).await.unwrap();
+        // The log must be dropped, releasing its lock, before it gets opened again.
+        self.record_log = None;
+        self.record_log = Some(MultiRecordLog::open(self.tempdir.path()).await.unwrap());
         for (queue, (_range, count)) in &self.state {
             assert_eq!(
-                self.record_log.range(queue, ..).unwrap().count() as u64,
+                self.record_log
+                    .as_mut()
+                    .unwrap()
+                    .range(queue, ..)
+                    .unwrap()
+                    .count() as u64,
                 *count,
             );
         }
@@ -75,6 +84,8 @@ impl PropTestEnv {
         let new_pos = state.0.end + skip_one_pos as u64;
         let res = self
             .record_log
+            .as_mut()
+            .unwrap()
             .append_records(queue, Some(new_pos), std::iter::once(&b"BB"[..]))
             .await
             .unwrap()
@@ -82,6 +93,8 @@ impl PropTestEnv {
 
         assert!(self
             .record_log
+            .as_mut()
+            .unwrap()
             .append_records(queue, Some(new_pos), std::iter::once(&b"BB"[..]))
             .await
             .unwrap()
@@ -98,6 +111,8 @@ impl PropTestEnv {
         let new_pos = state.0.end + skip_one_pos as u64;
         let res = self
             .record_log
+            .as_mut()
+            .unwrap()
             .append_records(
                 queue,
                 Some(new_pos),
@@ -118,15 +133,40 @@ impl PropTestEnv {
         let state = self.state.get_mut(queue).unwrap();
         if state.0.contains(&pos) {
             state.0.start = pos + 1;
-            state.1 -= self.record_log.truncate(queue, pos).await.unwrap() as u64;
+            state.1 -= self
+                .record_log
+                .as_mut()
+                .unwrap()
+                .truncate(queue, pos)
+                .await
+                .unwrap() as u64;
         } else if pos >= state.0.end {
-            // advance the queue to the position.
+            // the queue did not reach the position yet.
+            let record_log = self.record_log.as_mut().unwrap();
+            let truncate_res = record_log.truncate(queue, pos).await;
+            assert!(matches!(
+                truncate_res,
+                Err(TruncateError::Future { next_position }) if next_position == state.0.end
+            ));
+            // advance the queue to the position, then truncate it.
+            record_log
+                .append_record(queue, Some(pos), &b"advance"[..])
+                .await
+                .unwrap();
+            assert_eq!(
+                record_log.truncate(queue, pos).await.unwrap(),
+                state.1 as usize + 1
+            );
             state.0 = (pos + 1)..(pos + 1);
             state.1 = 0;
-            self.record_log.truncate(queue, pos).await.unwrap();
         } else {
             // should be a no-op
-            self.record_log.truncate(queue, pos).await.unwrap();
+            self.record_log
+                .as_mut()
+                .unwrap()
+                .truncate(queue, pos)
+                .await
+                .unwrap();
         }
     }
 }
@@ -293,7 +333,7 @@ proptest::proptest! {
     fn test_proptest_multiplexed_record_roundtrip((kind, queue, position, payload) in
         (0u8..4u8, queue_name_strategy(), proptest::num::u64::ANY, random_multi_record_strategy(64, 65536))) {
         let mut buffer = Vec::new();
-        MultiRecord::serialize(payload.iter().map(|p| p.as_ref()), position, &mut buffer);
+        MultiRecord::serialize(payload.iter().map(|p| p.as_ref()), position, &mut buffer).unwrap();
         let record = match kind {
             0 => MultiPlexedRecord::AppendRecords {
                 queue: &queue,
@@ -303,7 +343,7 @@ proptest::proptest! {
             1 => MultiPlexedRecord::Truncate {
                 queue: &queue,
                 position},
-            2 => MultiPlexedRecord::RecordPosition {queue: &queue, position},
+            2 => MultiPlexedRecord::RecordPosition {queue: &queue, position, format_tag: None},
             3 => MultiPlexedRecord::DeleteQueue {queue: &queue, position},
             4.. => unreachable!(),
         };
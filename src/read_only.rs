@@ -0,0 +1,180 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::ops::RangeBounds;
+#[cfg(feature = "tokio")]
+use std::path::Path;
+
+use tracing::warn;
+
+use crate::error::{MissingQueue, ReadRecordError};
+#[cfg(feature = "tokio")]
+use crate::file_system::TokioFileSystem;
+use crate::file_system::{DefaultFileSystem, FileSystem};
+use crate::mem::MemQueues;
+use crate::multi_record_log::replay_multiplexed_record;
+use crate::options::{Options, UnknownRecordPolicy};
+use crate::record::{unknown_record_type, MultiPlexedRecord};
+use crate::recordlog::RecordReader;
+use crate::rolling::RollingReader;
+
+/// A multi record log opened for reading only, while a [`MultiRecordLog`](crate::MultiRecordLog)
+/// may be writing to the same files.
+///
+/// It neither locks nor modifies the files. The records written since it was opened are read with
+/// [`Self::refresh`].
+pub struct ReadOnlyMultiRecordLog<FS: FileSystem + Clone = DefaultFileSystem> {
+    file_system: FS,
+    in_mem_queues: MemQueues,
+    // Kept to load the queues from scratch again.
+    queues_filter: Option<HashSet<String>>,
+    keep_deleted_queue_positions: bool,
+    key_index: bool,
+    unknown_record_policy: UnknownRecordPolicy,
+    // Location following the last record read, see `RecordReader::cursor`.
+    cursor: (u64, u64),
+}
+
+#[cfg(feature = "tokio")]
+impl ReadOnlyMultiRecordLog {
+    /// Opens the multi record log stored in `directory_path` for reading.
+    pub async fn open(directory_path: &Path) -> Result<Self, ReadRecordError> {
+        Self::open_with_file_system(TokioFileSystem::new(directory_path), Options::default()).await
+    }
+}
+
+impl<FS: FileSystem + Clone> ReadOnlyMultiRecordLog<FS> {
+    /// Opens the multi record log stored in `file_system` for reading.
+    ///
+    /// Only the options deciding what is loaded in memory are used: the queues filter,
+    /// [`Options::keep_deleted_queue_positions`], [`Options::key_index`] and the unknown record
+    /// policy.
+    pub async fn open_with_file_system(
+        file_system: FS,
+        options: Options,
+    ) -> Result<Self, ReadRecordError> {
+        let mut read_only_log = ReadOnlyMultiRecordLog {
+            file_system,
+            in_mem_queues: MemQueues::default(),
+            queues_filter: options.queues_filter,
+            keep_deleted_queue_positions: options.keep_deleted_queue_positions,
+            key_index: options.key_index,
+            unknown_record_policy: options.unknown_record_policy,
+            cursor: (0, 0),
+        };
+        read_only_log.load().await?;
+        Ok(read_only_log)
+    }
+
+    /// Reads the records written since the last refresh, or since the log was opened, and
+    /// applies them to the queues.
+    ///
+    /// Reading stops at the first record which cannot be read entirely, as it is most likely
+    /// being written: it is read again by the next refresh. If the writer garbage collected
+    /// files which were not read yet, the queues are loaded from scratch.
+    pub async fn refresh(&mut self) -> Result<(), ReadRecordError> {
+        let Some(rolling_reader) = RollingReader::open_read_only(self.file_system.clone()).await?
+        else {
+            return Ok(());
+        };
+        let (file_number, offset) = self.cursor;
+        match RecordReader::resume_from(rolling_reader, file_number, offset).await {
+            Ok(mut record_reader) => self.read_records(&mut record_reader).await,
+            Err(ReadRecordError::CursorBehindGc { .. } | ReadRecordError::InvalidCursor { .. }) => {
+                warn!("records were garbage collected before being read, reloading the log");
+                self.load().await
+            }
+            Err(read_record_error) => Err(read_record_error),
+        }
+    }
+
+    /// Loads the queues from the first file.
+    async fn load(&mut self) -> Result<(), ReadRecordError> {
+        let mut in_mem_queues = MemQueues::with_queues_filter(self.queues_filter.clone());
+        in_mem_queues.set_keep_deleted_queue_positions(self.keep_deleted_queue_positions);
+        in_mem_queues.set_key_index(self.key_index);
+        self.in_mem_queues = in_mem_queues;
+        self.cursor = (0, 0);
+        let Some(rolling_reader) = RollingReader::open_read_only(self.file_system.clone()).await?
+        else {
+            // The writer did not create the first file yet.
+            return Ok(());
+        };
+        let mut record_reader = RecordReader::open(rolling_reader);
+        self.cursor = record_reader.cursor();
+        self.read_records(&mut record_reader).await
+    }
+
+    /// Applies the records read by `record_reader` to the queues, up to the first one which
+    /// cannot be read.
+    async fn read_records(
+        &mut self,
+        record_reader: &mut RecordReader<RollingReader<FS>>,
+    ) -> Result<(), ReadRecordError> {
+        let mut num_records_replayed = 0;
+        loop {
+            let file_number = record_reader.read().current_file().clone();
+            match record_reader.go_next().await {
+                Ok(true) => {}
+                Ok(false) | Err(ReadRecordError::Corruption) => return Ok(()),
+                Err(read_record_error) => return Err(read_record_error),
+            }
+            if let Some(record) = record_reader.record::<MultiPlexedRecord>() {
+                replay_multiplexed_record(
+                    record,
+                    record_reader.read(),
+                    &mut self.in_mem_queues,
+                    &file_number,
+                    &mut num_records_replayed,
+                )
+                .await?;
+            } else {
+                match unknown_record_type(record_reader.record_bytes()) {
+                    Some(record_type)
+                        if self.unknown_record_policy == UnknownRecordPolicy::Fail =>
+                    {
+                        return Err(ReadRecordError::UnknownRecordType { record_type });
+                    }
+                    Some(record_type) => {
+                        warn!(record_type, "skipping record of unknown type");
+                    }
+                    None => {
+                        warn!("Detected corrupted record: some data may have been lost");
+                    }
+                }
+            }
+            self.cursor = record_reader.cursor();
+        }
+    }
+
+    /// Returns the records of `queue` whose position is contained in `range`. See
+    /// [`MultiRecordLog::range`](crate::MultiRecordLog::range).
+    pub fn range<R>(
+        &self,
+        queue: &str,
+        range: R,
+    ) -> Result<impl Iterator<Item = (u64, Cow<'_, [u8]>)> + '_, MissingQueue>
+    where
+        R: RangeBounds<u64> + 'static,
+    {
+        self.in_mem_queues.range(queue, range)
+    }
+
+    pub fn queue_exists(&self, queue: &str) -> bool {
+        self.in_mem_queues.contains_queue(queue)
+    }
+
+    pub fn list_queues(&self) -> impl Iterator<Item = &str> {
+        self.in_mem_queues.list_queues()
+    }
+
+    /// Returns the format tag the queue was created with, if any. See
+    /// [`MultiRecordLog::queue_format`](crate::MultiRecordLog::queue_format).
+    pub fn queue_format(&self, queue: &str) -> Result<Option<u32>, MissingQueue> {
+        self.in_mem_queues.format_tag(queue)
+    }
+
+    /// Returns the position of the last record appended to the queue.
+    pub fn last_position(&self, queue: &str) -> Result<Option<u64>, MissingQueue> {
+        self.in_mem_queues.last_position(queue)
+    }
+}
@@ -1,8 +1,10 @@
 use std::convert::{TryFrom, TryInto};
+use std::ops::Range;
 
 use bytes::Buf;
 
-use crate::error::MultiRecordCorruption;
+use crate::error::{AppendError, CorruptionReason, MultiRecordCorruption};
+use crate::rolling::SidecarId;
 use crate::Serializable;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -19,11 +21,33 @@ pub(crate) enum MultiPlexedRecord<'a> {
     /// If the queue does not exists, creates it.
     ///
     /// `position` is the position of the NEXT message to be appended.
-    RecordPosition { queue: &'a str, position: u64 },
+    ///
+    /// The format tag of the queue, if it has one, is stored in the payload.
+    RecordPosition {
+        queue: &'a str,
+        position: u64,
+        format_tag: Option<u32>,
+    },
     DeleteQueue {
         queue: &'a str,
         position: u64, //< not useful tbh
     },
+    /// Replaces all the records of a specific queue, to compact it.
+    ///
+    /// `position` is the position of the first record.
+    ReplaceRecords {
+        queue: &'a str,
+        position: u64,
+        records: MultiRecord<'a>,
+    },
+    /// Adds a record to a specific queue, its payload being stored in a sidecar file.
+    AppendSidecar {
+        queue: &'a str,
+        position: u64,
+        sidecar: SidecarId,
+    },
+    /// Records of other types, applied together: a batch is replayed entirely or not at all.
+    Batch { records: BatchRecords<'a> },
 }
 
 #[repr(u8)]
@@ -33,6 +57,10 @@ enum RecordType {
     Touch = 2,
     DeleteQueue = 3,
     AppendRecords = 4,
+    AppendSidecar = 5,
+    ReplaceRecords = 6,
+    AppendRecordsCompact = 7,
+    Batch = 8,
 }
 
 impl TryFrom<u8> for RecordType {
@@ -44,11 +72,24 @@ impl TryFrom<u8> for RecordType {
             2 => Ok(RecordType::Touch),
             3 => Ok(RecordType::DeleteQueue),
             4 => Ok(RecordType::AppendRecords),
+            5 => Ok(RecordType::AppendSidecar),
+            6 => Ok(RecordType::ReplaceRecords),
+            7 => Ok(RecordType::AppendRecordsCompact),
+            8 => Ok(RecordType::Batch),
             _ => Err(()),
         }
     }
 }
 
+/// Returns the type of the multiplexed record serialized in `buffer`, if it is not one of the
+/// types known to this version.
+pub(crate) fn unknown_record_type(buffer: &[u8]) -> Option<u8> {
+    let record_type = *buffer.first()?;
+    RecordType::try_from(record_type)
+        .is_err()
+        .then_some(record_type)
+}
+
 fn serialize(
     record_type: RecordType,
     position: u64,
@@ -64,6 +105,58 @@ fn serialize(
     buffer.extend(payload);
 }
 
+impl<'a> MultiPlexedRecord<'a> {
+    /// Returns the queue the record applies to, or `None` if it is a batch.
+    fn queue(&self) -> Option<&'a str> {
+        match *self {
+            MultiPlexedRecord::AppendRecords { queue, .. }
+            | MultiPlexedRecord::ReplaceRecords { queue, .. }
+            | MultiPlexedRecord::Truncate { queue, .. }
+            | MultiPlexedRecord::RecordPosition { queue, .. }
+            | MultiPlexedRecord::DeleteQueue { queue, .. }
+            | MultiPlexedRecord::AppendSidecar { queue, .. } => Some(queue),
+            MultiPlexedRecord::Batch { .. } => None,
+        }
+    }
+
+    /// Returns the queues the record applies to: a single one, unless it is a batch.
+    pub fn queues(&self) -> impl Iterator<Item = &'a str> {
+        let batch_records = match *self {
+            MultiPlexedRecord::Batch { records } => Some(records),
+            _ => None,
+        };
+        // Batches don't contain other batches.
+        let batch_queues = batch_records
+            .into_iter()
+            .flatten()
+            .filter_map(|record| record.queue());
+        self.queue().into_iter().chain(batch_queues)
+    }
+
+    /// Returns the length of the record once serialized.
+    pub fn num_bytes(&self) -> usize {
+        let (queue, payload_len) = match *self {
+            MultiPlexedRecord::AppendRecords { queue, records, .. }
+            | MultiPlexedRecord::ReplaceRecords { queue, records, .. } => {
+                (queue, records.buffer.len())
+            }
+            MultiPlexedRecord::RecordPosition {
+                queue, format_tag, ..
+            } => (queue, if format_tag.is_some() { 4 } else { 0 }),
+            MultiPlexedRecord::Truncate { queue, .. }
+            | MultiPlexedRecord::DeleteQueue { queue, .. } => (queue, 0),
+            MultiPlexedRecord::AppendSidecar { queue, .. } => (queue, 16),
+            MultiPlexedRecord::Batch { records } => ("", records.buffer.len()),
+        };
+        Self::header_num_bytes(queue) + payload_len
+    }
+
+    /// Returns the length of the serialized record, without its payload.
+    pub fn header_num_bytes(queue: &str) -> usize {
+        1 + 8 + 2 + queue.len()
+    }
+}
+
 impl<'a> Serializable<'a> for MultiPlexedRecord<'a> {
     fn serialize(&self, buffer: &mut Vec<u8>) {
         buffer.clear();
@@ -73,24 +166,64 @@ impl<'a> Serializable<'a> for MultiPlexedRecord<'a> {
                 queue,
                 records,
             } => {
-                serialize(
-                    RecordType::AppendRecords,
-                    position,
-                    queue,
-                    records.buffer,
-                    buffer,
-                );
+                let record_type = if let Some(start_position) = records.compact_start_position {
+                    debug_assert_eq!(start_position, position);
+                    RecordType::AppendRecordsCompact
+                } else {
+                    RecordType::AppendRecords
+                };
+                serialize(record_type, position, queue, records.buffer, buffer);
             }
 
             MultiPlexedRecord::Truncate { queue, position } => {
                 serialize(RecordType::Truncate, position, queue, &[], buffer);
             }
-            MultiPlexedRecord::RecordPosition { queue, position } => {
-                serialize(RecordType::Touch, position, queue, &[], buffer);
+            MultiPlexedRecord::RecordPosition {
+                queue,
+                position,
+                format_tag,
+            } => {
+                let format_tag_bytes = format_tag.map(u32::to_le_bytes);
+                let payload = format_tag_bytes
+                    .as_ref()
+                    .map_or(&[][..], |bytes| &bytes[..]);
+                serialize(RecordType::Touch, position, queue, payload, buffer);
             }
             MultiPlexedRecord::DeleteQueue { position, queue } => {
                 serialize(RecordType::DeleteQueue, position, queue, &[], buffer);
             }
+            MultiPlexedRecord::ReplaceRecords {
+                queue,
+                position,
+                records,
+            } => {
+                serialize(
+                    RecordType::ReplaceRecords,
+                    position,
+                    queue,
+                    records.buffer,
+                    buffer,
+                );
+            }
+            MultiPlexedRecord::AppendSidecar {
+                queue,
+                position,
+                sidecar,
+            } => {
+                let mut sidecar_bytes = [0u8; 16];
+                sidecar_bytes[..8].copy_from_slice(&sidecar.file_number.to_le_bytes());
+                sidecar_bytes[8..].copy_from_slice(&sidecar.id.to_le_bytes());
+                serialize(
+                    RecordType::AppendSidecar,
+                    position,
+                    queue,
+                    &sidecar_bytes,
+                    buffer,
+                );
+            }
+            MultiPlexedRecord::Batch { records } => {
+                serialize(RecordType::Batch, 0, "", records.buffer, buffer);
+            }
         }
     }
 
@@ -108,117 +241,492 @@ impl<'a> Serializable<'a> for MultiPlexedRecord<'a> {
         let queue = std::str::from_utf8(&remaining[..queue_len]).ok()?;
         let payload = &remaining[queue_len..];
         match enum_tag {
-            RecordType::AppendRecords => Some(MultiPlexedRecord::AppendRecords {
-                queue,
-                position,
-                records: MultiRecord::new(payload).ok()?,
-            }),
+            RecordType::AppendRecords | RecordType::AppendRecordsCompact => {
+                let unchecked_records = if let RecordType::AppendRecordsCompact = enum_tag {
+                    MultiRecord::new_compact_unchecked(payload, position)
+                } else {
+                    MultiRecord::new_unchecked(payload)
+                };
+                // A batch of records is applied entirely or not at all.
+                let records = unchecked_records.validate().ok()?;
+                Some(MultiPlexedRecord::AppendRecords {
+                    queue,
+                    position,
+                    records,
+                })
+            }
             RecordType::Truncate => Some(MultiPlexedRecord::Truncate { queue, position }),
-            RecordType::Touch => Some(MultiPlexedRecord::RecordPosition { queue, position }),
+            RecordType::Touch => {
+                let format_tag = match payload.len() {
+                    0 => None,
+                    4 => Some(u32::from_le_bytes(payload.try_into().unwrap())),
+                    _ => return None,
+                };
+                Some(MultiPlexedRecord::RecordPosition {
+                    queue,
+                    position,
+                    format_tag,
+                })
+            }
             RecordType::DeleteQueue => Some(MultiPlexedRecord::DeleteQueue { queue, position }),
+            RecordType::ReplaceRecords => {
+                // Salvaging the first items would drop the others, which are still stored in
+                // earlier records: the whole record is considered corrupted instead.
+                let records = MultiRecord::new(payload).ok()?;
+                Some(MultiPlexedRecord::ReplaceRecords {
+                    queue,
+                    position,
+                    records,
+                })
+            }
+            RecordType::AppendSidecar => {
+                if payload.len() != 16 {
+                    return None;
+                }
+                let sidecar = SidecarId {
+                    file_number: u64::from_le_bytes(payload[..8].try_into().unwrap()),
+                    id: u64::from_le_bytes(payload[8..].try_into().unwrap()),
+                };
+                Some(MultiPlexedRecord::AppendSidecar {
+                    queue,
+                    position,
+                    sidecar,
+                })
+            }
+            RecordType::Batch => {
+                let records = BatchRecords::new(payload)?;
+                Some(MultiPlexedRecord::Batch { records })
+            }
         }
     }
 }
 
+/// Records of a batch, serialized one after the other, each one following the pattern
+/// <u32 len><serialized record>.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
-pub(crate) struct MultiRecord<'a> {
+pub(crate) struct BatchRecords<'a> {
+    buffer: &'a [u8],
+}
+
+impl<'a> BatchRecords<'a> {
+    /// Checks that the buffer only holds valid records, none of them being a batch.
+    pub fn new(buffer: &'a [u8]) -> Option<BatchRecords<'a>> {
+        let mut remaining = buffer;
+        while !remaining.is_empty() {
+            let (record_bytes, next_remaining) = split_batch_record(remaining)?;
+            match MultiPlexedRecord::deserialize(record_bytes)? {
+                MultiPlexedRecord::Batch { .. } => return None,
+                _ => remaining = next_remaining,
+            }
+        }
+        Some(BatchRecords { buffer })
+    }
+
+    /// Wraps a buffer filled with [`Self::serialize_record`].
+    pub fn new_unchecked(buffer: &'a [u8]) -> BatchRecords<'a> {
+        BatchRecords { buffer }
+    }
+
+    /// Serializes `record` at the end of `buffer`, which holds the records of a batch.
+    pub fn serialize_record(
+        record: MultiPlexedRecord<'_>,
+        record_buffer: &mut Vec<u8>,
+        buffer: &mut Vec<u8>,
+    ) {
+        debug_assert!(!matches!(record, MultiPlexedRecord::Batch { .. }));
+        record.serialize(record_buffer);
+        buffer.extend_from_slice(&(record_buffer.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(record_buffer);
+    }
+}
+
+/// Splits the first record of the records of a batch from the following ones.
+fn split_batch_record(buffer: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = u32::from_le_bytes(buffer.get(..4)?.try_into().unwrap()) as usize;
+    let record_bytes = buffer.get(4..4 + len)?;
+    Some((record_bytes, &buffer[4 + len..]))
+}
+
+impl<'a> Iterator for BatchRecords<'a> {
+    type Item = MultiPlexedRecord<'a>;
+
+    fn next(&mut self) -> Option<MultiPlexedRecord<'a>> {
+        // the buffer was validated on creation.
+        let (record_bytes, remaining) = split_batch_record(self.buffer)?;
+        self.buffer = remaining;
+        MultiPlexedRecord::deserialize(record_bytes)
+    }
+}
+
+/// User defined key-values attached to a record.
+pub type RecordHeaders = Vec<(String, Vec<u8>)>;
+
+/// Name of the header holding the key of a record appended with
+/// [`MultiRecordLog::append_record_keyed`](crate::MultiRecordLog::append_record_keyed).
+pub(crate) const KEY_HEADER: &str = "mrecordlog-key";
+
+/// Bit of the length of an item, set if the item starts with headers.
+///
+/// Payloads are therefore limited to `HEADERS_FLAG - 1` bytes.
+const HEADERS_FLAG: u32 = 1 << 31;
+
+/// Maximum size of a record payload.
+pub(crate) const MAX_PAYLOAD_NUM_BYTES: usize = HEADERS_FLAG as usize - 1;
+
+/// Records at increasing positions serialized in a buffer, as built by [`MultiRecordBuilder`].
+///
+/// Iterating yields the position and payload of each record.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MultiRecord<'a> {
     /// The buffer contains concatenated items following this pattern:
     /// <u64 position><u32 len><len bytes>
     /// The two integers are encoded as little endian.
+    ///
+    /// If the highest bit of `len` is set, it is not part of the length, and the item
+    /// bytes are <u32 headers len><headers><payload>. Headers are concatenated key-values:
+    /// <u16 key len><key><u32 value len><value>.
+    ///
+    /// In the compact format, items don't store their position: they follow the pattern
+    /// <u32 len><len bytes>, and the position of an item is `compact_start_position` plus its
+    /// index.
     buffer: &'a [u8],
+    /// Position of the first item, if the buffer is in the compact format.
+    compact_start_position: Option<u64>,
     /// Offset into the buffer above used while iterating over the serialized items.
     byte_offset: usize,
+    /// Index of the next item, used to compute positions in the compact format.
+    item_index: u64,
 }
 
 impl<'a> MultiRecord<'a> {
-    pub fn new(buffer: &[u8]) -> Result<MultiRecord, MultiRecordCorruption> {
-        let mut mrecord = MultiRecord::new_unchecked(buffer);
+    pub fn new(buffer: &[u8]) -> Result<MultiRecord<'_>, MultiRecordCorruption> {
+        MultiRecord::new_unchecked(buffer).validate()
+    }
+
+    /// Same as [`Self::new`], for a buffer in the compact format whose first item is at
+    /// `start_position`.
+    #[cfg(test)]
+    pub fn new_compact(
+        buffer: &[u8],
+        start_position: u64,
+    ) -> Result<MultiRecord<'_>, MultiRecordCorruption> {
+        MultiRecord::new_compact_unchecked(buffer, start_position).validate()
+    }
 
-        // verify the content is not corrupted
-        for record in mrecord {
+    /// Verifies that the content is not corrupted.
+    fn validate(mut self) -> Result<MultiRecord<'a>, MultiRecordCorruption> {
+        while let Some(record) = self.next_with_headers() {
             record?;
         }
+        self.reset_position();
+        Ok(self)
+    }
 
-        mrecord.reset_position();
+    /// Returns the records of `buffer` preceding its first corruption, if any, along with the
+    /// corruption.
+    ///
+    /// This salvages the valid records of a torn buffer, which [`Self::new`] rejects entirely.
+    #[allow(clippy::type_complexity)]
+    pub fn new_partial(buffer: &[u8]) -> (Vec<(u64, &[u8])>, Option<MultiRecordCorruption>) {
+        MultiRecord::new_unchecked(buffer).items_before_corruption()
+    }
 
-        Ok(mrecord)
+    #[allow(clippy::type_complexity)]
+    fn items_before_corruption(self) -> (Vec<(u64, &'a [u8])>, Option<MultiRecordCorruption>) {
+        let mut items = Vec::new();
+        for item in self {
+            match item {
+                Ok(item) => items.push(item),
+                Err(corruption) => return (items, Some(corruption)),
+            }
+        }
+        (items, None)
     }
 
-    pub fn new_unchecked(buffer: &[u8]) -> MultiRecord {
+    pub fn new_unchecked(buffer: &[u8]) -> MultiRecord<'_> {
         MultiRecord {
             buffer,
+            compact_start_position: None,
             byte_offset: 0,
+            item_index: 0,
         }
     }
 
+    pub fn new_compact_unchecked(buffer: &[u8], start_position: u64) -> MultiRecord<'_> {
+        MultiRecord {
+            buffer,
+            compact_start_position: Some(start_position),
+            byte_offset: 0,
+            item_index: 0,
+        }
+    }
+
+    /// Serializes records at contiguous positions, starting at `position`.
+    ///
+    /// Returns [`AppendError::PositionOverflow`] if a record would get the position `u64::MAX`.
     pub fn serialize<T: Iterator<Item = impl Buf>>(
-        record_payloads: T,
+        mut record_payloads: T,
         position: u64,
         output: &mut Vec<u8>,
-    ) {
-        Self::serialize_with_pos((position..).zip(record_payloads), output);
+    ) -> Result<(), AppendError> {
+        // The position following the last record must fit in a u64 too.
+        Self::serialize_with_pos((position..u64::MAX).zip(record_payloads.by_ref()), output);
+        if record_payloads.next().is_some() {
+            return Err(AppendError::PositionOverflow);
+        }
+        Ok(())
     }
 
-    fn serialize_with_pos(
+    pub fn serialize_with_pos(
         record_payloads: impl Iterator<Item = (u64, impl Buf)>,
         output: &mut Vec<u8>,
     ) {
         output.clear();
-        for (position, mut record_payload) in record_payloads {
-            assert!(record_payload.remaining() <= u32::MAX as usize);
+        for (position, record_payload) in record_payloads {
             // TODO add assert for position monotonicity?
-            let record_payload = &mut record_payload;
-            output.extend_from_slice(&position.to_le_bytes());
+            serialize_item(position, record_payload, output);
+        }
+    }
+
+    /// Serializes records in the compact format, starting at `position`. Their positions are
+    /// not stored: the first one is given when reading the buffer, with
+    /// [`Self::new_compact_unchecked`].
+    ///
+    /// Returns [`AppendError::PositionOverflow`] if a record would get the position `u64::MAX`.
+    pub fn serialize_compact<T: Iterator<Item = impl Buf>>(
+        mut record_payloads: T,
+        position: u64,
+        output: &mut Vec<u8>,
+    ) -> Result<(), AppendError> {
+        output.clear();
+        let num_positions = usize::try_from(u64::MAX - position).unwrap_or(usize::MAX);
+        for mut record_payload in record_payloads.by_ref().take(num_positions) {
+            assert!(record_payload.remaining() < HEADERS_FLAG as usize);
             output.extend_from_slice(&(record_payload.remaining() as u32).to_le_bytes());
-            while record_payload.has_remaining() {
-                let chunk = record_payload.chunk();
-                output.extend_from_slice(record_payload.chunk());
-                record_payload.advance(chunk.len());
-            }
+            extend_from_buf(&mut record_payload, output);
+        }
+        if record_payloads.next().is_some() {
+            return Err(AppendError::PositionOverflow);
+        }
+        Ok(())
+    }
+
+    /// Serializes a single record with headers.
+    ///
+    /// Records without headers are serialized exactly like with [`Self::serialize`].
+    pub fn serialize_with_headers(
+        position: u64,
+        record_payload: impl Buf,
+        headers: &[(&str, &[u8])],
+        output: &mut Vec<u8>,
+    ) {
+        output.clear();
+        Self::append_with_headers(position, record_payload, headers.iter().copied(), output);
+    }
+
+    /// Appends a single record with headers to `output`, without clearing it first.
+    ///
+    /// Records without headers are serialized exactly like with [`Self::serialize`].
+    pub fn append_with_headers<'h>(
+        position: u64,
+        mut record_payload: impl Buf,
+        headers: impl IntoIterator<Item = (&'h str, &'h [u8])>,
+        output: &mut Vec<u8>,
+    ) {
+        let mut headers = headers.into_iter().peekable();
+        if headers.peek().is_none() {
+            serialize_item(position, record_payload, output);
+            return;
+        }
+        output.extend_from_slice(&position.to_le_bytes());
+        let len_offset = output.len();
+        output.extend_from_slice(&0u32.to_le_bytes());
+        let headers_len_offset = output.len();
+        output.extend_from_slice(&0u32.to_le_bytes());
+        for (key, value) in headers {
+            assert!(key.len() <= u16::MAX as usize);
+            assert!(value.len() <= u32::MAX as usize);
+            output.extend_from_slice(&(key.len() as u16).to_le_bytes());
+            output.extend_from_slice(key.as_bytes());
+            output.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            output.extend_from_slice(value);
         }
+        let headers_len = output.len() - headers_len_offset - 4;
+        output[headers_len_offset..headers_len_offset + 4]
+            .copy_from_slice(&(headers_len as u32).to_le_bytes());
+        extend_from_buf(&mut record_payload, output);
+        let len = output.len() - headers_len_offset;
+        assert!(len < HEADERS_FLAG as usize);
+        output[len_offset..len_offset + 4]
+            .copy_from_slice(&(len as u32 | HEADERS_FLAG).to_le_bytes());
     }
 
     pub fn reset_position(&mut self) {
         self.byte_offset = 0;
+        self.item_index = 0;
     }
-}
 
-impl<'a> Iterator for MultiRecord<'a> {
-    type Item = Result<(u64, &'a [u8]), MultiRecordCorruption>;
-
-    fn next(&mut self) -> Option<Self::Item> {
+    /// Returns the next item, along with its headers if it has some.
+    #[allow(clippy::type_complexity)]
+    pub fn next_with_headers(
+        &mut self,
+    ) -> Option<Result<(u64, Option<RecordHeaders>, &'a [u8]), MultiRecordCorruption>> {
         if self.byte_offset == self.buffer.len() {
             // no more record
             return None;
         }
 
-        let buffer = &self.buffer[self.byte_offset..];
-        if buffer.len() < 12 {
+        let item_offset = self.byte_offset;
+        let corruption = |reason| MultiRecordCorruption {
+            byte_offset: item_offset,
+            reason,
+        };
+        let buffer = &self.buffer[item_offset..];
+        let item_header_len = if self.compact_start_position.is_some() {
+            4
+        } else {
+            12
+        };
+        if buffer.len() < item_header_len {
             // too short: corrupted
-            self.byte_offset = buffer.len();
-            return Some(Err(MultiRecordCorruption));
+            self.byte_offset = self.buffer.len();
+            return Some(Err(corruption(CorruptionReason::TooShort)));
         }
 
-        let position = u64::from_le_bytes(buffer[0..8].try_into().unwrap());
-        let len = u32::from_le_bytes(buffer[8..12].try_into().unwrap()) as usize;
+        let position = match self.compact_start_position {
+            Some(start_position) => start_position + self.item_index,
+            None => u64::from_le_bytes(buffer[0..8].try_into().unwrap()),
+        };
+        let len_bytes = &buffer[item_header_len - 4..item_header_len];
+        let len_with_flag = u32::from_le_bytes(len_bytes.try_into().unwrap());
+        let len = (len_with_flag & !HEADERS_FLAG) as usize;
 
-        let buffer = &buffer[12..];
+        let buffer = &buffer[item_header_len..];
 
         if buffer.len() < len {
-            self.byte_offset = buffer.len();
-            return Some(Err(MultiRecordCorruption));
+            self.byte_offset = self.buffer.len();
+            return Some(Err(corruption(CorruptionReason::LengthOverflow)));
         }
 
-        self.byte_offset += 12 + len;
+        self.byte_offset += item_header_len + len;
+        self.item_index += 1;
 
-        Some(Ok((position, &buffer[..len])))
+        let item = &buffer[..len];
+        if len_with_flag & HEADERS_FLAG == 0 {
+            return Some(Ok((position, None, item)));
+        }
+        Some(
+            split_headers(item)
+                .map(|(headers, payload)| (position, Some(headers), payload))
+                .ok_or_else(|| corruption(CorruptionReason::InvalidHeaders)),
+        )
+    }
+}
+
+/// Builds a serialized multi record incrementally, assigning contiguous positions to the records
+/// pushed.
+///
+/// The buffer it produces can be appended with
+/// [`MultiRecordLog::append_raw_multirecord`](crate::MultiRecordLog::append_raw_multirecord).
+#[derive(Debug, Clone)]
+pub struct MultiRecordBuilder {
+    buffer: Vec<u8>,
+    start_position: u64,
+    next_position: u64,
+}
+
+impl MultiRecordBuilder {
+    /// Creates a builder whose first record will get `start_position`.
+    pub fn new(start_position: u64) -> Self {
+        MultiRecordBuilder {
+            buffer: Vec::new(),
+            start_position,
+            next_position: start_position,
+        }
+    }
+
+    /// Adds a record, and returns the position assigned to it.
+    ///
+    /// Panics if the payload is larger than the maximum record size, or if the position would be
+    /// `u64::MAX`.
+    pub fn push(&mut self, payload: impl Buf) -> u64 {
+        let position = self.next_position;
+        self.next_position = position.checked_add(1).expect("position overflow");
+        serialize_item(position, payload, &mut self.buffer);
+        position
+    }
+
+    /// Returns the number of records pushed.
+    pub fn len(&self) -> usize {
+        (self.next_position - self.start_position) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.next_position == self.start_position
+    }
+
+    /// Returns the size of the serialized records.
+    pub fn num_bytes(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Returns the serialized records, along with the range of positions assigned to them.
+    pub fn finish(self) -> (Vec<u8>, Range<u64>) {
+        (self.buffer, self.start_position..self.next_position)
+    }
+}
+
+/// Appends a record without headers to `output`.
+fn serialize_item(position: u64, mut record_payload: impl Buf, output: &mut Vec<u8>) {
+    assert!(record_payload.remaining() < HEADERS_FLAG as usize);
+    output.extend_from_slice(&position.to_le_bytes());
+    output.extend_from_slice(&(record_payload.remaining() as u32).to_le_bytes());
+    extend_from_buf(&mut record_payload, output);
+}
+
+fn extend_from_buf(buf: &mut impl Buf, output: &mut Vec<u8>) {
+    while buf.has_remaining() {
+        let chunk = buf.chunk();
+        output.extend_from_slice(chunk);
+        buf.advance(chunk.len());
+    }
+}
+
+/// Splits the bytes of an item with headers into its parsed headers and its payload.
+fn split_headers(item: &[u8]) -> Option<(RecordHeaders, &[u8])> {
+    let headers_len = u32::from_le_bytes(item.get(..4)?.try_into().unwrap()) as usize;
+    let mut headers_bytes = item.get(4..4 + headers_len)?;
+    let payload = &item[4 + headers_len..];
+    let mut headers = RecordHeaders::new();
+    while !headers_bytes.is_empty() {
+        let key_len = u16::from_le_bytes(headers_bytes.get(..2)?.try_into().unwrap()) as usize;
+        let key = std::str::from_utf8(headers_bytes.get(2..2 + key_len)?).ok()?;
+        headers_bytes = &headers_bytes[2 + key_len..];
+        let value_len = u32::from_le_bytes(headers_bytes.get(..4)?.try_into().unwrap()) as usize;
+        let value = headers_bytes.get(4..4 + value_len)?;
+        headers_bytes = &headers_bytes[4 + value_len..];
+        headers.push((key.to_string(), value.to_vec()));
+    }
+    Some((headers, payload))
+}
+
+/// Iterates over the items, without their headers.
+impl<'a> Iterator for MultiRecord<'a> {
+    type Item = Result<(u64, &'a [u8]), MultiRecordCorruption>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.next_with_headers()?;
+        Some(record.map(|(position, _headers, payload)| (position, payload)))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{MultiRecord, MultiPlexedRecord, RecordType};
     use std::convert::TryFrom;
+
+    use super::{MultiPlexedRecord, MultiRecord, MultiRecordBuilder, RecordType, HEADERS_FLAG};
+    use crate::error::{CorruptionReason, MultiRecordCorruption};
+    use crate::rolling::SidecarId;
     use crate::Serializable;
 
     #[test]
@@ -230,7 +738,7 @@ mod tests {
                 num_record_types += 1;
             }
         }
-        assert_eq!(num_record_types, 4);
+        assert_eq!(num_record_types, 8);
     }
 
     #[test]
@@ -240,7 +748,8 @@ mod tests {
             [b"123".as_slice(), b"4567".as_slice()].into_iter(),
             5,
             &mut buffer,
-        );
+        )
+        .unwrap();
         match MultiRecord::new(&buffer) {
             Err(_) => panic!("Parsing serialized buffers should work"),
             Ok(record) => {
@@ -263,7 +772,8 @@ mod tests {
             [b"123".as_slice(), b"4567".as_slice()].into_iter(),
             5,
             &mut buffer,
-        );
+        )
+        .unwrap();
         for num_truncated_bytes in 1..buffer.len() {
             // This should not panic. Typically, this will be an error, but
             // deserializing can also succeed (but will have wrong data).
@@ -272,13 +782,169 @@ mod tests {
     }
 
     #[test]
-    fn test_multiplexedrecord_deserialization_ok() {
-        let mut buffer_multirecord: Vec<u8> = vec![];
+    fn test_multirecord_corruption_location() {
+        let mut buffer: Vec<u8> = vec![];
         MultiRecord::serialize(
-            [b"123".as_slice()].into_iter(),
-            2,
-            &mut buffer_multirecord,
+            [b"123".as_slice(), b"4567".as_slice()].into_iter(),
+            5,
+            &mut buffer,
+        )
+        .unwrap();
+        // The second item starts after the 12 bytes of metadata and 3 bytes of payload of the
+        // first one.
+        let second_item_offset = 15;
+        assert_eq!(
+            MultiRecord::new(&buffer[..second_item_offset + 5]).unwrap_err(),
+            MultiRecordCorruption {
+                byte_offset: second_item_offset,
+                reason: CorruptionReason::TooShort,
+            }
+        );
+        assert_eq!(
+            MultiRecord::new(&buffer[..buffer.len() - 1]).unwrap_err(),
+            MultiRecordCorruption {
+                byte_offset: second_item_offset,
+                reason: CorruptionReason::LengthOverflow,
+            }
+        );
+        let mut overflowing_buffer = buffer.clone();
+        overflowing_buffer[8..12].copy_from_slice(&1_000u32.to_le_bytes());
+        assert_eq!(
+            MultiRecord::new(&overflowing_buffer).unwrap_err(),
+            MultiRecordCorruption {
+                byte_offset: 0,
+                reason: CorruptionReason::LengthOverflow,
+            }
+        );
+
+        let mut headers_buffer: Vec<u8> = vec![];
+        MultiRecord::serialize_with_headers(
+            5,
+            b"123".as_slice(),
+            &[("key", b"value")],
+            &mut headers_buffer,
+        );
+        // Make the headers length go past the end of the item.
+        headers_buffer[12..16].copy_from_slice(&1_000u32.to_le_bytes());
+        assert_eq!(
+            MultiRecord::new(&headers_buffer).unwrap_err(),
+            MultiRecordCorruption {
+                byte_offset: 0,
+                reason: CorruptionReason::InvalidHeaders,
+            }
+        );
+
+        // Iteration stops after a corruption.
+        let mut record = MultiRecord::new_unchecked(&buffer[..buffer.len() - 1]);
+        assert_eq!(record.next(), Some(Ok((5, b"123".as_slice()))));
+        assert!(record.next().unwrap().is_err());
+        assert_eq!(record.next(), None);
+    }
+
+    #[test]
+    fn test_multirecord_corrupted_length_in_the_middle() {
+        let mut buffer: Vec<u8> = vec![];
+        MultiRecord::serialize(
+            [b"123".as_slice(), b"4567".as_slice(), b"89".as_slice()].into_iter(),
+            5,
+            &mut buffer,
+        )
+        .unwrap();
+        let second_item_offset = 15;
+        // The length of the second item goes past the end of the buffer, while the third item
+        // is still there.
+        buffer[second_item_offset + 8..second_item_offset + 12]
+            .copy_from_slice(&1_000u32.to_le_bytes());
+        let items: Vec<_> = MultiRecord::new_unchecked(&buffer).collect();
+        assert_eq!(
+            items,
+            [
+                Ok((5, b"123".as_slice())),
+                Err(MultiRecordCorruption {
+                    byte_offset: second_item_offset,
+                    reason: CorruptionReason::LengthOverflow,
+                }),
+            ]
+        );
+        let mut record = MultiRecord::new_unchecked(&buffer);
+        record.nth(1);
+        assert_eq!(record.next(), None);
+        assert_eq!(record.next(), None);
+    }
+
+    #[test]
+    fn test_multirecord_new_partial() {
+        let mut buffer: Vec<u8> = vec![];
+        MultiRecord::serialize(
+            [b"123".as_slice(), b"4567".as_slice(), b"89".as_slice()].into_iter(),
+            5,
+            &mut buffer,
+        )
+        .unwrap();
+        assert_eq!(
+            MultiRecord::new_partial(&buffer),
+            (
+                vec![
+                    (5u64, b"123".as_slice()),
+                    (6u64, b"4567".as_slice()),
+                    (7u64, b"89".as_slice())
+                ],
+                None
+            )
+        );
+        // Cut the buffer in the middle of the 3rd item.
+        let third_item_offset = 12 + 3 + 12 + 4;
+        let (items, corruption) = MultiRecord::new_partial(&buffer[..third_item_offset + 13]);
+        assert_eq!(
+            items,
+            vec![(5u64, b"123".as_slice()), (6u64, b"4567".as_slice())]
         );
+        assert_eq!(
+            corruption,
+            Some(MultiRecordCorruption {
+                byte_offset: third_item_offset,
+                reason: CorruptionReason::LengthOverflow,
+            })
+        );
+        assert_eq!(
+            MultiRecord::new_partial(&buffer[..5]),
+            (
+                Vec::new(),
+                Some(MultiRecordCorruption {
+                    byte_offset: 0,
+                    reason: CorruptionReason::TooShort,
+                })
+            )
+        );
+    }
+
+    #[test]
+    fn test_multirecord_builder() {
+        let payloads: [&[u8]; 3] = [b"123", b"", b"4567"];
+        let mut builder = MultiRecordBuilder::new(5);
+        assert!(builder.is_empty());
+        for (expected_position, payload) in (5..).zip(payloads) {
+            assert_eq!(builder.push(payload), expected_position);
+        }
+        assert_eq!(builder.len(), 3);
+        assert_eq!(builder.num_bytes(), 3 * 12 + 7);
+        let (buffer, positions) = builder.finish();
+        assert_eq!(positions, 5..8);
+
+        let mut expected_buffer = Vec::new();
+        MultiRecord::serialize(payloads.into_iter(), 5, &mut expected_buffer).unwrap();
+        assert_eq!(buffer, expected_buffer);
+
+        let (buffer, positions) = MultiRecordBuilder::new(5).finish();
+        assert!(buffer.is_empty());
+        assert!(positions.is_empty());
+    }
+
+    #[test]
+    fn test_multiplexedrecord_deserialization_ok() {
+        let mut buffer_multirecord: Vec<u8> = vec![];
+        MultiRecord::serialize([b"123".as_slice()].into_iter(), 2, &mut buffer_multirecord)
+            .unwrap();
         let record = MultiPlexedRecord::AppendRecords {
             queue: "queue_name",
             position: 10,
@@ -293,15 +959,134 @@ mod tests {
     }
 
     #[test]
-    fn test_multiplexedrecord_deserialization_corruption() {
+    fn test_multirecord_compact_round_trip() {
+        let payloads: Vec<Vec<u8>> = (0..10_000u32)
+            .map(|i| i.to_le_bytes()[..(i % 5) as usize].to_vec())
+            .collect();
+        let start_position = u64::from(u32::MAX) + 7;
+        let mut buffer_multirecord = Vec::new();
+        MultiRecord::serialize_compact(
+            payloads.iter().map(|payload| &payload[..]),
+            start_position,
+            &mut buffer_multirecord,
+        )
+        .unwrap();
+        let payloads_num_bytes: usize = payloads.iter().map(Vec::len).sum();
+        assert_eq!(
+            buffer_multirecord.len(),
+            payloads.len() * 4 + payloads_num_bytes
+        );
+        let records = MultiRecord::new_compact(&buffer_multirecord, start_position).unwrap();
+        let record = MultiPlexedRecord::AppendRecords {
+            queue: "queue_name",
+            position: start_position,
+            records,
+        };
+        let mut buffer_multiplexed = Vec::new();
+        record.serialize(&mut buffer_multiplexed);
+        assert_eq!(
+            buffer_multiplexed[0],
+            RecordType::AppendRecordsCompact as u8
+        );
+        let Some(MultiPlexedRecord::AppendRecords { records, .. }) =
+            MultiPlexedRecord::deserialize(&buffer_multiplexed)
+        else {
+            panic!("Deserialization should work");
+        };
+        let items: Vec<(u64, &[u8])> = records.map(Result::unwrap).collect();
+        assert_eq!(items.len(), payloads.len());
+        for ((position, payload), (expected_position, expected_payload)) in
+            items.into_iter().zip((start_position..).zip(&payloads))
+        {
+            assert_eq!(position, expected_position);
+            assert_eq!(payload, &expected_payload[..]);
+        }
+
+        // A truncated compact record is rejected entirely.
+        let truncated = &buffer_multiplexed[..buffer_multiplexed.len() - 1];
+        assert_eq!(MultiPlexedRecord::deserialize(truncated), None);
+    }
+
+    #[test]
+    fn test_multiplexedrecord_append_sidecar_deserialization() {
+        let record = MultiPlexedRecord::AppendSidecar {
+            queue: "queue_name",
+            position: 10,
+            sidecar: SidecarId {
+                file_number: 3,
+                id: 7,
+            },
+        };
+        let mut buffer: Vec<u8> = vec![];
+        record.serialize(&mut buffer);
+        assert_eq!(MultiPlexedRecord::deserialize(&buffer), Some(record));
+        assert_eq!(
+            MultiPlexedRecord::deserialize(&buffer[..buffer.len() - 1]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_multiplexedrecord_record_position_deserialization() {
+        let record = MultiPlexedRecord::RecordPosition {
+            queue: "queue_name",
+            position: 10,
+            format_tag: Some(0xdead_beef),
+        };
+        let mut buffer: Vec<u8> = vec![];
+        record.serialize(&mut buffer);
+        assert_eq!(buffer.len(), record.num_bytes());
+        assert_eq!(MultiPlexedRecord::deserialize(&buffer), Some(record));
+        assert_eq!(
+            MultiPlexedRecord::deserialize(&buffer[..buffer.len() - 1]),
+            None
+        );
+        // Records written by versions without format tags have an empty payload.
+        assert_eq!(
+            MultiPlexedRecord::deserialize(&buffer[..buffer.len() - 4]),
+            Some(MultiPlexedRecord::RecordPosition {
+                queue: "queue_name",
+                position: 10,
+                format_tag: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_multiplexedrecord_replace_records_deserialization() {
         let mut buffer_multirecord: Vec<u8> = vec![];
-        MultiRecord::serialize(
-            [b"123".as_slice()].into_iter(),
-            2,
+        MultiRecord::append_with_headers(4, &b"1"[..], [], &mut buffer_multirecord);
+        MultiRecord::append_with_headers(
+            7,
+            &b"2"[..],
+            [("key", &b"value"[..])],
             &mut buffer_multirecord,
         );
+        let record = MultiPlexedRecord::ReplaceRecords {
+            queue: "queue_name",
+            position: 4,
+            records: MultiRecord::new_unchecked(&buffer_multirecord),
+        };
+        let mut buffer: Vec<u8> = vec![];
+        record.serialize(&mut buffer);
+        assert_eq!(MultiPlexedRecord::deserialize(&buffer), Some(record));
+        // Replacing records must never salvage part of them.
+        let first_record_len = 12 + 1;
+        for num_truncated_bytes in 1..buffer_multirecord.len() - first_record_len {
+            assert_eq!(
+                MultiPlexedRecord::deserialize(&buffer[..buffer.len() - num_truncated_bytes]),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn test_multiplexedrecord_deserialization_corruption() {
+        let mut buffer_multirecord: Vec<u8> = vec![];
+        MultiRecord::serialize([b"123".as_slice()].into_iter(), 2, &mut buffer_multirecord)
+            .unwrap();
         let record = MultiPlexedRecord::AppendRecords {
-          queue: "queue_name",
+            queue: "queue_name",
             position: 10,
             records: MultiRecord::new_unchecked(&buffer_multirecord),
         };
@@ -311,7 +1096,60 @@ mod tests {
         for num_truncated_bytes in 1..buffer_multiplexed.len() {
             // This should not panic. Typically, this will be an error, but
             // deserializing can also succeed (but will have wrong data).
-            let _ = MultiPlexedRecord::deserialize(&buffer_multiplexed[..buffer_multiplexed.len() - num_truncated_bytes]);
+            let _ = MultiPlexedRecord::deserialize(
+                &buffer_multiplexed[..buffer_multiplexed.len() - num_truncated_bytes],
+            );
         }
     }
+
+    #[test]
+    fn test_multirecord_headers_serialization() {
+        let mut buffer: Vec<u8> = vec![];
+        MultiRecord::serialize_with_headers(
+            5,
+            b"123".as_slice(),
+            &[("content-type", b"json"), ("node", b"")],
+            &mut buffer,
+        );
+        assert_eq!(&buffer[..8], &5u64.to_le_bytes());
+        let len = u32::from_le_bytes(buffer[8..12].try_into().unwrap());
+        assert_ne!(len & HEADERS_FLAG, 0);
+        assert_eq!((len & !HEADERS_FLAG) as usize, buffer.len() - 12);
+        let mut record = MultiRecord::new(&buffer).unwrap();
+        let (position, headers, payload) = record.next_with_headers().unwrap().unwrap();
+        assert_eq!(position, 5);
+        assert_eq!(
+            headers.unwrap(),
+            vec![
+                ("content-type".to_string(), b"json".to_vec()),
+                ("node".to_string(), Vec::new())
+            ]
+        );
+        assert_eq!(payload, b"123");
+        assert!(record.next_with_headers().is_none());
+        // Without headers, the serialization is the same as before headers were introduced.
+        let mut buffer_with_empty_headers: Vec<u8> = vec![];
+        MultiRecord::serialize_with_headers(
+            5,
+            b"123".as_slice(),
+            &[],
+            &mut buffer_with_empty_headers,
+        );
+        MultiRecord::serialize([b"123".as_slice()].into_iter(), 5, &mut buffer).unwrap();
+        assert_eq!(buffer_with_empty_headers, buffer);
+    }
+
+    #[test]
+    fn test_multirecord_headers_corruption() {
+        let mut buffer: Vec<u8> = vec![];
+        MultiRecord::serialize_with_headers(
+            5,
+            b"123".as_slice(),
+            &[("key", b"value")],
+            &mut buffer,
+        );
+        // Corrupt the headers length.
+        buffer[12] = 100;
+        assert!(MultiRecord::new(&buffer).is_err());
+    }
 }
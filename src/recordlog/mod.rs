@@ -3,5 +3,5 @@ mod writer;
 pub use self::reader::RecordReader;
 pub use self::writer::RecordWriter;
 
-#[cfg(test)]
+#[cfg(all(test, feature = "tokio"))]
 mod tests;
@@ -1,11 +1,17 @@
 use std::io;
+use std::ops::Range;
 
 use crate::error::ReadRecordError;
+use crate::file_system::FileSystem;
 use crate::frame::{FrameReader, FrameWriter, ReadFrameError};
 use crate::recordlog::RecordWriter;
 use crate::rolling::{RollingReader, RollingWriter};
-use crate::{BlockRead, Serializable};
+use crate::{BlockRead, Serializable, BLOCK_NUM_BYTES};
 
+/// Reads the records of a log, in the order they were written.
+///
+/// Reading a log directory while a [`MultiRecordLog`](crate::MultiRecordLog) writes to it makes
+/// it possible to tail it: [`Self::cursor`] is the location to resume from later.
 pub struct RecordReader<R> {
     frame_reader: FrameReader<R>,
     record_buffer: Vec<u8>,
@@ -34,7 +40,13 @@ impl<R: BlockRead + Unpin> RecordReader<R> {
         S::deserialize(&self.record_buffer)
     }
 
+    /// Returns the bytes of the current record.
+    pub fn record_bytes(&self) -> &[u8] {
+        &self.record_buffer
+    }
+
     /// Advance cursor and deserialize the next record.
+    #[cfg(test)]
     pub async fn read_record<'a, S: Serializable<'a>>(
         &'a mut self,
     ) -> Result<Option<S>, ReadRecordError> {
@@ -80,11 +92,80 @@ impl<R: BlockRead + Unpin> RecordReader<R> {
             }
         }
     }
+
+    /// Resynchronizes the reader after [`Self::go_next`] returned
+    /// [`ReadRecordError::Corruption`], by skipping the rest of the current block. Returns the
+    /// range of the bytes skipped in that block, starting with the corrupted frame.
+    ///
+    /// Frames are only delimited by their headers: once a header is corrupted, the next frame
+    /// can only be found at the start of a block. Reading then resumes with the first record
+    /// starting in the next block, the record which was being read being dropped.
+    ///
+    /// This is never called implicitly, replaying a log included: a corrupted header already
+    /// drops its block, while the frames following a frame failing its checksum are kept, as
+    /// they are still delimited correctly. Callers iterating with [`Self::go_next`] call it
+    /// explicitly to skip the whole block instead, and learn the range of the bytes lost.
+    pub fn resync(&mut self) -> Range<usize> {
+        self.within_record = false;
+        self.frame_reader.skip_block()
+    }
 }
 
-impl RecordReader<RollingReader> {
-    pub async fn into_writer(self) -> io::Result<RecordWriter<RollingWriter>> {
-        let frame_writer: FrameWriter<RollingWriter> = self.frame_reader.into_writer().await?;
+impl<FS: FileSystem> RecordReader<RollingReader<FS>> {
+    /// Returns the location following the last record read, as the number of its file and the
+    /// offset in this file. Reading can be resumed from there with [`Self::resume_from`].
+    pub fn cursor(&self) -> (u64, u64) {
+        let rolling_reader = self.read();
+        let offset = rolling_reader.block_offset() + self.frame_reader.cursor();
+        (rolling_reader.current_file().file_number(), offset as u64)
+    }
+
+    /// Opens a reader reading the records following the location `(file_number, offset)`, as
+    /// returned by [`Self::cursor`].
+    ///
+    /// Returns [`ReadRecordError::CursorBehindGc`] if the file was garbage collected since, and
+    /// [`ReadRecordError::InvalidCursor`] if the location is not the start of a record.
+    pub async fn resume_from(
+        mut rolling_reader: RollingReader<FS>,
+        file_number: u64,
+        offset: u64,
+    ) -> Result<Self, ReadRecordError> {
+        let first_file_number = rolling_reader.first_file_number();
+        if file_number < first_file_number {
+            return Err(ReadRecordError::CursorBehindGc {
+                file_number,
+                first_file_number,
+            });
+        }
+        let invalid_cursor = ReadRecordError::InvalidCursor {
+            file_number,
+            offset,
+        };
+        let Ok(offset) = usize::try_from(offset) else {
+            return Err(invalid_cursor);
+        };
+        // The end of a block is kept as the cursor position in it: the next block may not have
+        // been read when the cursor was saved.
+        let (block_id, cursor) = if offset > 0 && offset % BLOCK_NUM_BYTES == 0 {
+            (offset / BLOCK_NUM_BYTES - 1, BLOCK_NUM_BYTES)
+        } else {
+            (offset / BLOCK_NUM_BYTES, offset % BLOCK_NUM_BYTES)
+        };
+        if !rolling_reader.seek(file_number, block_id).await? {
+            return Err(invalid_cursor);
+        }
+        let mut record_reader = RecordReader::open(rolling_reader);
+        record_reader.frame_reader.set_cursor(cursor);
+        match record_reader.frame_reader.is_at_record_boundary().await {
+            Ok(true) => Ok(record_reader),
+            Ok(false) => Err(invalid_cursor),
+            Err(ReadFrameError::IoError(io_err)) => Err(ReadRecordError::IoError(io_err)),
+            Err(_) => Err(ReadRecordError::Corruption),
+        }
+    }
+
+    pub async fn into_writer(self) -> io::Result<RecordWriter<RollingWriter<FS>>> {
+        let frame_writer: FrameWriter<RollingWriter<FS>> = self.frame_reader.into_writer().await?;
         Ok(RecordWriter::from(frame_writer))
     }
 }
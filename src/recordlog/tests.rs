@@ -136,3 +136,38 @@ async fn test_behavior_upon_corruption() {
         ));
     }
 }
+
+#[tokio::test]
+async fn test_resync_upon_corruption() {
+    let records: Vec<String> = (0..1_000).map(|i| format!("{i:0>100}")).collect();
+    let mut writer = RecordWriter::in_memory();
+    for record in &records {
+        writer.write_record(record.as_str()).await.unwrap();
+    }
+    writer.flush().await.unwrap();
+    let mut buffer: Vec<u8> = writer.into_writer().into();
+    // Corrupting the payload of the 11th record.
+    let corrupted_frame_start = 10 * (HEADER_LEN + 100);
+    buffer[corrupted_frame_start + HEADER_LEN + 3] ^= 1;
+    let mut reader = RecordReader::open(ArrayReader::from(&buffer[..]));
+    for record in &records[..10] {
+        assert_eq!(
+            reader.read_record::<&str>().await.unwrap(),
+            Some(record.as_str())
+        );
+    }
+    assert!(matches!(
+        reader.read_record::<&str>().await,
+        Err(ReadRecordError::Corruption)
+    ));
+    assert_eq!(reader.resync(), corrupted_frame_start..BLOCK_NUM_BYTES);
+    // The record spanning over the first two blocks is dropped along with the first block.
+    let first_record_in_next_block = BLOCK_NUM_BYTES / (HEADER_LEN + 100) + 1;
+    for record in &records[first_record_in_next_block..] {
+        assert_eq!(
+            reader.read_record::<&str>().await.unwrap(),
+            Some(record.as_str())
+        );
+    }
+    assert_eq!(reader.read_record::<&str>().await.unwrap(), None);
+}
@@ -1,7 +1,9 @@
 use tokio::io;
 
 use crate::block_read_write::VecBlockWriter;
+use crate::file_system::FileSystem;
 use crate::frame::{FrameType, FrameWriter};
+use crate::record::MultiPlexedRecord;
 use crate::rolling::{Directory, FileNumber, RollingWriter};
 use crate::{BlockWrite, Serializable};
 
@@ -79,13 +81,35 @@ impl<W: BlockWrite + Unpin> RecordWriter<W> {
     pub fn get_underlying_wrt(&self) -> &W {
         self.frame_writer.get_underlying_wrt()
     }
+
+    /// Returns the number of bytes written to store a record of `record_len` bytes once
+    /// serialized.
+    pub fn num_bytes_for_record(&self, record_len: usize) -> usize {
+        self.frame_writer.num_bytes_for_record(record_len)
+    }
+
+    pub fn get_underlying_wrt_mut(&mut self) -> &mut W {
+        self.frame_writer.get_underlying_wrt_mut()
+    }
 }
 
-impl RecordWriter<RollingWriter> {
-    pub fn directory(&mut self) -> &mut Directory {
+impl<FS: FileSystem> RecordWriter<RollingWriter<FS>> {
+    pub fn directory(&mut self) -> &mut Directory<FS> {
         self.frame_writer.directory()
     }
 
+    /// Writes a record of a multi record log, see [`Self::write_record`].
+    ///
+    /// Its queues are added to the queue index of the files it gets written to.
+    pub(crate) async fn write_multiplexed_record(
+        &mut self,
+        record: MultiPlexedRecord<'_>,
+    ) -> io::Result<()> {
+        self.get_underlying_wrt_mut()
+            .set_record_queues(record.queues());
+        self.write_record(record).await
+    }
+
     pub fn current_file(&mut self) -> &FileNumber {
         self.get_underlying_wrt().current_file()
     }
@@ -1,18 +1,47 @@
-use std::io::{self, SeekFrom};
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::ops::RangeInclusive;
+#[cfg(test)]
+use std::path::Path;
+use std::time::Duration;
 
 use async_trait::async_trait;
-use tokio::fs::{File, OpenOptions};
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter};
-use tracing::debug;
+use tracing::{debug, warn};
 
-use super::{FileNumber, FileTracker};
+use super::file_header::{
+    format_log_id, generate_log_id, FileHeader, FILE_HEADER_NUM_BYTES, UNKNOWN_LOG_ID,
+};
+use super::{FileNumber, FileTracker, QueueIndex};
+use crate::error::ReadRecordError;
+#[cfg(all(test, feature = "tokio"))]
+use crate::file_system::TokioFileSystem;
+use crate::file_system::{AsyncFile, DefaultFileSystem, FileSystem, LOCK_FILE_NAME};
+use crate::frame::ChecksumAlgo;
+use crate::options::{CacheAdvice, RetryPolicy};
 use crate::rolling::{FILE_NUM_BYTES, FRAME_NUM_BYTES};
 use crate::{BlockRead, BlockWrite, BLOCK_NUM_BYTES};
 
-pub struct Directory {
-    dir: PathBuf,
+pub struct Directory<FS: FileSystem = DefaultFileSystem> {
+    file_system: FS,
     pub(crate) files: FileTracker,
+    // Headers of the files tracked, indexed by file number.
+    file_headers: HashMap<u64, FileHeader>,
+    // Identifier stamped in the header of the files we create.
+    log_id: u128,
+    // Algorithm of the frame checksums of the files we create.
+    checksum_algo: ChecksumAlgo,
+    // Sidecar files holding payloads stored out of line, with their length.
+    sidecars: BTreeMap<SidecarId, u64>,
+    next_sidecar_id: u64,
+    // Queue indexes of the full files which have one, indexed by file number.
+    queue_indexes: HashMap<u64, QueueIndex>,
+    // Files found when opening the directory which do not belong to the log, sorted by name.
+    unexpected_files: Vec<String>,
+    // True if files were created or deleted since the directory was last synced.
+    needs_directory_sync: bool,
+    // Number of upcoming file writes which should fail, to test error handling.
+    #[cfg(test)]
+    injected_write_failures: usize,
 }
 
 fn filename_to_position(file_name: &str) -> Option<u64> {
@@ -29,52 +58,401 @@ fn filename_to_position(file_name: &str) -> Option<u64> {
     file_name[4..].parse::<u64>().ok()
 }
 
-pub(crate) fn filepath(dir: &Path, file_number: &FileNumber) -> PathBuf {
+/// Identifies a sidecar file, holding the payload of a record stored out of line.
+///
+/// A sidecar is attached to the wal file which was current when it got created, and gets deleted
+/// along with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SidecarId {
+    pub file_number: u64,
+    pub id: u64,
+}
+
+impl SidecarId {
+    pub fn filename(&self) -> String {
+        format!("sidecar-{:020}-{:020}", self.file_number, self.id)
+    }
+}
+
+fn filename_to_sidecar_id(file_name: &str) -> Option<SidecarId> {
+    let ids = file_name.strip_prefix("sidecar-")?;
+    if ids.len() != 41 || !ids.is_char_boundary(20) || &ids[20..21] != "-" {
+        return None;
+    }
+    let (file_number_str, id_str) = (&ids[..20], &ids[21..]);
+    let is_number = |s: &str| s.as_bytes().iter().all(u8::is_ascii_digit);
+    if !is_number(file_number_str) || !is_number(id_str) {
+        return None;
+    }
+    Some(SidecarId {
+        file_number: file_number_str.parse().ok()?,
+        id: id_str.parse().ok()?,
+    })
+}
+
+/// Returns true if `file_name` is the name of a file the log may hold.
+fn is_log_file_name(file_name: &str) -> bool {
+    filename_to_position(file_name).is_some()
+        || filename_to_sidecar_id(file_name).is_some()
+        || QueueIndex::filename_to_file_number(file_name).is_some()
+        || file_name == LOCK_FILE_NAME
+}
+
+#[cfg(test)]
+pub(crate) fn filepath(dir: &Path, file_number: &FileNumber) -> std::path::PathBuf {
     dir.join(file_number.filename())
 }
 
-async fn create_file(dir_path: &Path, file_number: &FileNumber) -> io::Result<File> {
-    let new_filepath = filepath(dir_path, file_number);
-    let mut file = OpenOptions::new()
-        .create_new(true)
-        .write(true)
-        .open(&new_filepath)
-        .await?;
-    file.set_len(FILE_NUM_BYTES as u64).await?;
-    file.seek(SeekFrom::Start(0)).await?;
-    Ok(file)
+/// Reads `buf.len()` bytes at `offset`.
+///
+/// Returns false if the end of the file is reached first.
+async fn read_exact_at<F: AsyncFile>(
+    file: &mut F,
+    offset: u64,
+    buf: &mut [u8],
+) -> io::Result<bool> {
+    let mut num_bytes_read = 0;
+    while num_bytes_read < buf.len() {
+        let num_bytes = file
+            .read(offset + num_bytes_read as u64, &mut buf[num_bytes_read..])
+            .await?;
+        if num_bytes == 0 {
+            return Ok(false);
+        }
+        num_bytes_read += num_bytes;
+    }
+    Ok(true)
+}
+
+/// Reads bytes starting at `offset` until `buf` is full or the end of the file is reached, and
+/// returns the number of bytes read.
+pub(super) async fn read_at_most<F: AsyncFile>(
+    file: &mut F,
+    offset: u64,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    let mut num_bytes_read = 0;
+    while num_bytes_read < buf.len() {
+        let num_bytes = file
+            .read(offset + num_bytes_read as u64, &mut buf[num_bytes_read..])
+            .await?;
+        if num_bytes == 0 {
+            break;
+        }
+        num_bytes_read += num_bytes;
+    }
+    Ok(num_bytes_read)
+}
+
+async fn read_file_header<F: AsyncFile>(file: &mut F) -> Result<FileHeader, ReadRecordError> {
+    let mut header_bytes = vec![0u8; FILE_HEADER_NUM_BYTES];
+    let mut num_bytes_read = 0;
+    while num_bytes_read < FILE_HEADER_NUM_BYTES {
+        let num_bytes = file
+            .read(num_bytes_read as u64, &mut header_bytes[num_bytes_read..])
+            .await?;
+        if num_bytes == 0 {
+            break;
+        }
+        num_bytes_read += num_bytes;
+    }
+    FileHeader::deserialize(&header_bytes[..num_bytes_read])
+}
+
+/// Returns the log id shared by files, or None if no file has a known log id.
+///
+/// Files without a known log id are assumed to belong to the same log as the others.
+fn log_id_from_headers<'a>(
+    file_headers: impl Iterator<Item = &'a FileHeader>,
+) -> Result<Option<u128>, ReadRecordError> {
+    let mut log_ids: Vec<u128> = file_headers
+        .map(|file_header| file_header.log_id)
+        .filter(|&log_id| log_id != UNKNOWN_LOG_ID)
+        .collect();
+    log_ids.sort_unstable();
+    log_ids.dedup();
+    match log_ids[..] {
+        [] => Ok(None),
+        [log_id] => Ok(Some(log_id)),
+        _ => Err(ReadRecordError::MixedLogs {
+            ids: log_ids.into_iter().map(format_log_id).collect(),
+        }),
+    }
+}
+
+/// Checks that the file numbers are contiguous.
+///
+/// Files are only ever garbage collected from the first one, so the first files missing is
+/// expected. A file missing after the first one was lost: records of the files following it may
+/// continue the ones it held, and replaying them would yield queues with holes.
+fn check_no_missing_file(file_numbers: &[u64]) -> Result<(), ReadRecordError> {
+    let mut file_numbers = file_numbers.to_vec();
+    file_numbers.sort_unstable();
+    for window in file_numbers.windows(2) {
+        if window[1] != window[0] + 1 {
+            return Err(ReadRecordError::MissingFile {
+                file_number: window[0] + 1,
+            });
+        }
+    }
+    Ok(())
 }
 
+#[cfg(all(test, feature = "tokio"))]
 impl Directory {
     /// Open a `Directory`, or create a new, empty, one. `dir_path` must exist and be a directory.
-    pub async fn open(dir_path: &Path) -> io::Result<Directory> {
-        let mut file_numbers: Vec<u64> = Default::default();
-        let mut read_dir = tokio::fs::read_dir(dir_path).await?;
-        while let Some(dir_entry) = read_dir.next_entry().await? {
-            if !dir_entry.file_type().await?.is_file() {
+    pub async fn open(dir_path: &Path) -> Result<Directory, ReadRecordError> {
+        Directory::open_with_file_system(TokioFileSystem::new(dir_path), 0, ChecksumAlgo::default())
+            .await
+    }
+}
+
+/// How a [`Directory`] gets opened.
+#[derive(Clone, Copy)]
+enum OpenMode {
+    /// Stale files are removed, and the first file is created if the directory holds none.
+    ///
+    /// If `strict_directory` is true, opening fails before any of that if the directory holds
+    /// files which do not belong to the log.
+    ReadWrite {
+        initial_file_number: u64,
+        strict_directory: bool,
+    },
+    /// No file is removed nor created.
+    ReadOnly,
+}
+
+impl<FS: FileSystem> Directory<FS> {
+    /// Open a `Directory` stored in `file_system`, or create a new, empty, one whose first file
+    /// is `initial_file_number`.
+    ///
+    /// The files created from then on use `checksum_algo`, while the existing ones keep the
+    /// algorithm recorded in their header.
+    pub async fn open_with_file_system(
+        file_system: FS,
+        initial_file_number: u64,
+        checksum_algo: ChecksumAlgo,
+    ) -> Result<Self, ReadRecordError> {
+        Self::open_read_write(file_system, initial_file_number, checksum_algo, false).await
+    }
+
+    /// Same as [`Self::open_with_file_system`], but fails with
+    /// [`ReadRecordError::UnexpectedFile`] if the directory holds files which do not belong to
+    /// the log, before any file gets removed or created.
+    pub async fn open_strict_with_file_system(
+        file_system: FS,
+        initial_file_number: u64,
+        checksum_algo: ChecksumAlgo,
+    ) -> Result<Self, ReadRecordError> {
+        Self::open_read_write(file_system, initial_file_number, checksum_algo, true).await
+    }
+
+    async fn open_read_write(
+        file_system: FS,
+        initial_file_number: u64,
+        checksum_algo: ChecksumAlgo,
+        strict_directory: bool,
+    ) -> Result<Self, ReadRecordError> {
+        let open_mode = OpenMode::ReadWrite {
+            initial_file_number,
+            strict_directory,
+        };
+        let directory = Self::open_with_mode(file_system, checksum_algo, open_mode).await?;
+        Ok(directory.expect("a directory opened for writing has a file"))
+    }
+
+    /// Open the `Directory` stored in `file_system` without modifying it, while a writer may be
+    /// using it.
+    ///
+    /// Unlike [`Self::open_with_file_system`], orphan sidecars and stale queue indexes are
+    /// ignored rather than removed. Returns `None` if the directory holds no file yet.
+    pub async fn open_read_only(file_system: FS) -> Result<Option<Self>, ReadRecordError> {
+        Self::open_with_mode(file_system, ChecksumAlgo::default(), OpenMode::ReadOnly).await
+    }
+
+    async fn open_with_mode(
+        file_system: FS,
+        checksum_algo: ChecksumAlgo,
+        open_mode: OpenMode,
+    ) -> Result<Option<Self>, ReadRecordError> {
+        let is_read_only = matches!(open_mode, OpenMode::ReadOnly);
+        let file_names = file_system.list_files().await?;
+        let file_numbers: Vec<u64> = file_names
+            .iter()
+            .filter_map(|file_name| filename_to_position(file_name))
+            .collect();
+        check_no_missing_file(&file_numbers)?;
+        let mut unexpected_files: Vec<String> = file_names
+            .iter()
+            .filter(|file_name| !is_log_file_name(file_name))
+            .cloned()
+            .collect();
+        unexpected_files.sort_unstable();
+        if let OpenMode::ReadWrite {
+            strict_directory: true,
+            ..
+        } = open_mode
+        {
+            if let Some(name) = unexpected_files.first() {
+                return Err(ReadRecordError::UnexpectedFile { name: name.clone() });
+            }
+        }
+        let mut sidecars = BTreeMap::new();
+        let mut has_removed_orphans = false;
+        for sidecar_id in file_names
+            .iter()
+            .filter_map(|file_name| filename_to_sidecar_id(file_name))
+        {
+            let filename = sidecar_id.filename();
+            if !file_numbers.contains(&sidecar_id.file_number) {
+                if is_read_only {
+                    continue;
+                }
+                // The wal file was deleted, but we did not get to delete its sidecars.
+                debug!(file = filename, "remove orphan sidecar");
+                file_system.remove_file(&filename).await?;
+                has_removed_orphans = true;
                 continue;
             }
-            let file_name = if let Some(file_name) = dir_entry.file_name().to_str() {
-                file_name.to_string()
-            } else {
+            let len = file_system.open_file(&filename).await?.len().await?;
+            sidecars.insert(sidecar_id, len);
+        }
+        let next_sidecar_id = sidecars
+            .keys()
+            .map(|sidecar_id| sidecar_id.id + 1)
+            .max()
+            .unwrap_or(0);
+        let last_file_number = file_numbers.iter().copied().max();
+        let mut queue_indexes = HashMap::new();
+        for file_number in file_names
+            .iter()
+            .filter_map(|file_name| QueueIndex::filename_to_file_number(file_name))
+        {
+            let filename = QueueIndex::filename(file_number);
+            // The last file is written to again: its index, if any, would not list the queues
+            // appended from now on.
+            if file_numbers.contains(&file_number) && Some(file_number) != last_file_number {
+                let mut file = file_system.open_file(&filename).await?;
+                let mut data = vec![0u8; file.len().await? as usize];
+                if read_exact_at(&mut file, 0, &mut data).await? {
+                    if let Some(queue_index) = QueueIndex::deserialize(&data) {
+                        queue_indexes.insert(file_number, queue_index);
+                        continue;
+                    }
+                }
+            }
+            if is_read_only {
                 continue;
-            };
-            if let Some(seq_number) = filename_to_position(&file_name) {
-                file_numbers.push(seq_number);
             }
+            // The index was not entirely written, or its wal file got deleted first.
+            debug!(file = filename, "remove stale queue index");
+            file_system.remove_file(&filename).await?;
+            has_removed_orphans = true;
+        }
+        let mut file_headers = HashMap::with_capacity(file_numbers.len());
+        for &file_number in &file_numbers {
+            let filename = FileNumber::for_number(file_number).filename();
+            let mut file = file_system.open_file(&filename).await?;
+            file_headers.insert(file_number, read_file_header(&mut file).await?);
         }
-        let files = if let Some(files) = FileTracker::from_file_numbers(file_numbers) {
-            files
-        } else {
-            let files = FileTracker::new();
-            let file_number = files.first();
-            create_file(dir_path, file_number).await?;
-            files
+        let log_id = log_id_from_headers(file_headers.values())?.unwrap_or_else(generate_log_id);
+        let (files, is_empty) = match (FileTracker::from_file_numbers(file_numbers), open_mode) {
+            (Some(files), _) => (files, false),
+            (
+                None,
+                OpenMode::ReadWrite {
+                    initial_file_number,
+                    ..
+                },
+            ) => (FileTracker::starting_at(initial_file_number), true),
+            (None, OpenMode::ReadOnly) => return Ok(None),
         };
-        Ok(Directory {
-            dir: dir_path.to_path_buf(),
+        let mut directory = Directory {
+            file_system,
             files,
-        })
+            file_headers,
+            log_id,
+            checksum_algo,
+            sidecars,
+            next_sidecar_id,
+            queue_indexes,
+            unexpected_files,
+            needs_directory_sync: has_removed_orphans,
+            #[cfg(test)]
+            injected_write_failures: 0,
+        };
+        if is_empty {
+            let file_number = directory.files.first().clone();
+            directory.create_file(&file_number).await?;
+        }
+        Ok(Some(directory))
+    }
+
+    /// Creates a new file, with its header and its blocks zeroed.
+    async fn create_file(&mut self, file_number: &FileNumber) -> io::Result<FS::File> {
+        let mut file = self
+            .file_system
+            .create_file(&file_number.filename())
+            .await?;
+        self.needs_directory_sync = true;
+        let file_header = FileHeader {
+            log_id: self.log_id,
+            file_number: Some(file_number.file_number()),
+            checksum_algo: self.checksum_algo,
+            ..FileHeader::current()
+        };
+        file.set_len((file_header.num_bytes() + FILE_NUM_BYTES) as u64)
+            .await?;
+        file.write(0, &file_header.serialize()).await?;
+        self.file_headers
+            .insert(file_number.file_number(), file_header);
+        Ok(file)
+    }
+
+    /// Returns the header of a tracked file.
+    pub fn file_header(&self, file_number: &FileNumber) -> FileHeader {
+        self.file_headers
+            .get(&file_number.file_number())
+            .copied()
+            .unwrap_or_else(FileHeader::current)
+    }
+
+    /// Checks that the files recording their number in their header are named after it.
+    ///
+    /// A mismatch means that files were renamed or copied over each other, and that records
+    /// would be attributed to the wrong file.
+    pub fn check_file_numbers(&self) -> Result<(), ReadRecordError> {
+        let mismatch = self
+            .file_headers
+            .iter()
+            .filter_map(|(&file_number, file_header)| {
+                let header_file_number = file_header.file_number?;
+                (header_file_number != file_number).then_some((file_number, header_file_number))
+            })
+            .min();
+        if let Some((file_number, header_file_number)) = mismatch {
+            return Err(ReadRecordError::FileNumberMismatch {
+                file_number,
+                header_file_number,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks that the directory holds no file other than the ones of the log.
+    ///
+    /// Other files are ignored, but most likely got there by mistake, such as files of another
+    /// application sharing the directory.
+    pub fn check_no_unexpected_file(&self) -> Result<(), ReadRecordError> {
+        if let Some(name) = self.unexpected_files.first() {
+            return Err(ReadRecordError::UnexpectedFile { name: name.clone() });
+        }
+        Ok(())
+    }
+
+    /// Returns the identifier of the log stored in this directory.
+    pub fn log_id(&self) -> u128 {
+        self.log_id
     }
 
     /// Get the first still used FileNumber.
@@ -82,6 +460,11 @@ impl Directory {
         self.files.first()
     }
 
+    /// Get the first FileNumber which would be kept by a GC.
+    pub fn first_file_number_after_gc(&self) -> &FileNumber {
+        self.files.first_used()
+    }
+
     /// Returns true if some file could be GCed.
     pub fn has_files_that_can_be_deleted(&self) -> bool {
         self.files.count() >= 2 && self.files.first().can_be_deleted()
@@ -89,51 +472,249 @@ impl Directory {
 
     /// Delete FileNumbers and the associated wal files no longer used.
     ///
-    /// We never delete the last file.
+    /// We never delete the last file, nor files which are still referenced, such as the file
+    /// being written or the files holding records of the in memory queues.
     pub(crate) async fn gc(&mut self) -> io::Result<()> {
         while let Some(file) = self.files.take_first_unused() {
-            let filepath = filepath(&self.dir, &file);
-            debug!(file=%filepath.display(), "gc remove file");
-            tokio::fs::remove_file(&filepath).await?;
+            let filename = file.filename();
+            debug!(file = filename, "gc remove file");
+            self.file_system.remove_file(&filename).await?;
+            self.needs_directory_sync = true;
+            self.file_headers.remove(&file.file_number());
+            let sidecar_ids: Vec<SidecarId> = self
+                .sidecars
+                .keys()
+                .filter(|sidecar_id| sidecar_id.file_number == file.file_number())
+                .copied()
+                .collect();
+            for sidecar_id in sidecar_ids {
+                let filename = sidecar_id.filename();
+                debug!(file = filename, "gc remove sidecar");
+                self.file_system.remove_file(&filename).await?;
+                self.sidecars.remove(&sidecar_id);
+            }
+            self.remove_queue_index(file.file_number()).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the queue index of the file `file_number`, if it has one.
+    pub fn queue_index(&self, file_number: u64) -> Option<&QueueIndex> {
+        self.queue_indexes.get(&file_number)
+    }
+
+    /// Writes the queue index of the file `file_number`, which must not be written to anymore.
+    pub(crate) async fn write_queue_index(
+        &mut self,
+        file_number: u64,
+        queue_index: &QueueIndex,
+    ) -> io::Result<()> {
+        self.remove_queue_index(file_number).await?;
+        let filename = QueueIndex::filename(file_number);
+        let mut file = self.file_system.create_file(&filename).await?;
+        self.needs_directory_sync = true;
+        file.write(0, &queue_index.serialize()).await?;
+        file.flush().await?;
+        self.queue_indexes.insert(file_number, queue_index.clone());
+        Ok(())
+    }
+
+    /// Removes the queue index of the file `file_number`, if it has one.
+    pub(crate) async fn remove_queue_index(&mut self, file_number: u64) -> io::Result<()> {
+        if self.queue_indexes.remove(&file_number).is_none() {
+            return Ok(());
+        }
+        let filename = QueueIndex::filename(file_number);
+        debug!(file = filename, "remove queue index");
+        self.file_system.remove_file(&filename).await?;
+        self.needs_directory_sync = true;
+        Ok(())
+    }
+
+    /// Syncs the directory if files were created or deleted since the last sync, so that a crash
+    /// can't lose them.
+    pub(crate) async fn sync_directory(&mut self) -> io::Result<()> {
+        if !self.needs_directory_sync {
+            return Ok(());
         }
+        self.file_system.sync_directory().await?;
+        self.needs_directory_sync = false;
         Ok(())
     }
 
+    /// Writes `payload` to a new sidecar file, attached to the wal file `file_number`.
+    ///
+    /// The sidecar and the directory are synced before returning, so that it is on disk before
+    /// any record references it: a record is never replayed without its payload.
+    pub(crate) async fn write_sidecar(
+        &mut self,
+        file_number: &FileNumber,
+        payload: &[u8],
+    ) -> io::Result<SidecarId> {
+        let sidecar_id = SidecarId {
+            file_number: file_number.file_number(),
+            id: self.next_sidecar_id,
+        };
+        self.next_sidecar_id += 1;
+        let mut file = self.file_system.create_file(&sidecar_id.filename()).await?;
+        file.write(0, payload).await?;
+        file.flush().await?;
+        file.sync().await?;
+        self.file_system.sync_directory().await?;
+        self.sidecars.insert(sidecar_id, payload.len() as u64);
+        Ok(sidecar_id)
+    }
+
+    /// Reads the payload stored in a sidecar file.
+    ///
+    /// Returns `None` if the sidecar does not exist, which happens if it was garbage collected.
+    pub(crate) async fn read_sidecar(&self, sidecar_id: SidecarId) -> io::Result<Option<Vec<u8>>> {
+        let Some(&len) = self.sidecars.get(&sidecar_id) else {
+            return Ok(None);
+        };
+        let mut file = self.file_system.open_file(&sidecar_id.filename()).await?;
+        let mut payload = vec![0u8; len as usize];
+        if !read_exact_at(&mut file, 0, &mut payload).await? {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        Ok(Some(payload))
+    }
+
+    /// Returns the number of bytes used by sidecar files.
+    pub fn sidecars_num_bytes(&self) -> u64 {
+        self.sidecars.values().sum()
+    }
+
+    /// Makes the next `num_failures` writes or syncs of files of this directory fail with a
+    /// transient error.
+    #[cfg(test)]
+    pub(crate) fn inject_write_failures(&mut self, num_failures: usize) {
+        self.injected_write_failures = num_failures;
+    }
+
+    #[cfg(test)]
+    fn take_injected_write_failure(&mut self) -> io::Result<()> {
+        if self.injected_write_failures == 0 {
+            return Ok(());
+        }
+        self.injected_write_failures -= 1;
+        Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "injected write failure",
+        ))
+    }
+
     /// Open the wal file with the provided FileNumber.
-    pub async fn open_file(&self, file_number: &FileNumber) -> io::Result<File> {
-        let filepath = filepath(&self.dir, file_number);
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&filepath)
-            .await?;
-        file.seek(SeekFrom::Start(0u64)).await?;
-        Ok(file)
+    pub async fn open_file(&self, file_number: &FileNumber) -> io::Result<FS::File> {
+        self.file_system.open_file(&file_number.filename()).await
     }
 }
 
-pub struct RollingReader {
-    file: File,
-    directory: Directory,
+/// Number of blocks read at once by [`RollingReader`], so that replaying the log does not take
+/// a read per block.
+#[cfg(not(test))]
+const READ_AHEAD_NUM_BLOCKS: usize = 32;
+
+// Less than the number of blocks per file, so that tests read files in several parts.
+#[cfg(test)]
+const READ_AHEAD_NUM_BLOCKS: usize = 3;
+
+/// Reads the blocks of the files of a log directory, one file after the other.
+pub struct RollingReader<FS: FileSystem = DefaultFileSystem> {
+    file: FS::File,
+    directory: Directory<FS>,
     file_number: FileNumber,
     block_id: usize,
-    block: Box<[u8; BLOCK_NUM_BYTES]>,
+    // Consecutive blocks of the current file, read at once. The first one is `blocks_start_id`,
+    // and the current one is among them.
+    blocks: Vec<u8>,
+    blocks_start_id: usize,
+    // Buffer the next blocks get read into, reused to avoid allocations.
+    spare_blocks: Vec<u8>,
+    // True if the end of the current file was reached while reading `blocks`.
+    is_end_of_file: bool,
+    // Files which are not read at all, see `skip_files_without_queues`.
+    files_to_skip: HashSet<u64>,
+    // True if files were skipped right before the current one.
+    follows_skipped_file: bool,
 }
 
+#[cfg(all(test, feature = "tokio"))]
 impl RollingReader {
     /// Open a directory for reading.
-    pub async fn open(dir_path: &Path) -> io::Result<Self> {
-        let directory = Directory::open(dir_path).await?;
+    pub async fn open(dir_path: &Path) -> Result<Self, ReadRecordError> {
+        RollingReader::open_with_file_system(
+            TokioFileSystem::new(dir_path),
+            0,
+            ChecksumAlgo::default(),
+        )
+        .await
+    }
+}
+
+impl<FS: FileSystem> RollingReader<FS> {
+    /// Open a directory stored in `file_system` for reading.
+    ///
+    /// If the directory holds no file yet, the first one created is `initial_file_number`. The
+    /// files created once the reader is turned into a writer use `checksum_algo`.
+    pub async fn open_with_file_system(
+        file_system: FS,
+        initial_file_number: u64,
+        checksum_algo: ChecksumAlgo,
+    ) -> Result<Self, ReadRecordError> {
+        let directory =
+            Directory::open_with_file_system(file_system, initial_file_number, checksum_algo)
+                .await?;
+        Self::from_directory(directory).await
+    }
+
+    /// Same as [`Self::open_with_file_system`], but fails before modifying the directory if it
+    /// holds files which do not belong to the log, see [`Directory::open_strict_with_file_system`].
+    pub async fn open_strict_with_file_system(
+        file_system: FS,
+        initial_file_number: u64,
+        checksum_algo: ChecksumAlgo,
+    ) -> Result<Self, ReadRecordError> {
+        let directory = Directory::open_strict_with_file_system(
+            file_system,
+            initial_file_number,
+            checksum_algo,
+        )
+        .await?;
+        Self::from_directory(directory).await
+    }
+
+    /// Open a directory stored in `file_system` for reading, without modifying it, see
+    /// [`Directory::open_read_only`].
+    ///
+    /// Returns `None` if the directory holds no file yet.
+    pub async fn open_read_only(file_system: FS) -> Result<Option<Self>, ReadRecordError> {
+        let Some(directory) = Directory::open_read_only(file_system).await? else {
+            return Ok(None);
+        };
+        Ok(Some(Self::from_directory(directory).await?))
+    }
+
+    async fn from_directory(directory: Directory<FS>) -> Result<Self, ReadRecordError> {
         let first_file = directory.first_file_number().clone();
         let mut file = directory.open_file(&first_file).await?;
-        let mut block = Box::new([0u8; BLOCK_NUM_BYTES]);
-        file.read_exact(&mut *block).await?;
+        let mut blocks = Vec::new();
+        let Some(is_end_of_file) =
+            read_blocks(&directory, &mut file, &first_file, 0, &mut blocks).await?
+        else {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        };
         Ok(RollingReader {
             file,
             directory,
             file_number: first_file.clone(),
             block_id: 0,
-            block,
+            blocks,
+            blocks_start_id: 0,
+            spare_blocks: Vec::new(),
+            is_end_of_file,
+            files_to_skip: HashSet::new(),
+            follows_skipped_file: false,
         })
     }
 
@@ -141,84 +722,268 @@ impl RollingReader {
         &self.file_number
     }
 
+    /// Returns the number of files of the directory.
+    pub fn num_files(&self) -> usize {
+        self.directory.files.count()
+    }
+
+    pub fn first_file_number(&self) -> u64 {
+        self.directory.first_file_number().file_number()
+    }
+
+    /// Returns the offset of the current block in the current file.
+    pub fn block_offset(&self) -> usize {
+        self.block_id * BLOCK_NUM_BYTES
+    }
+
+    /// Positions the reader on the block `block_id` of the file `file_number`.
+    ///
+    /// Returns false, leaving the reader unchanged, if there is no such file or block.
+    pub async fn seek(&mut self, file_number: u64, block_id: usize) -> io::Result<bool> {
+        if block_id >= FILE_NUM_BYTES / BLOCK_NUM_BYTES {
+            return Ok(false);
+        }
+        let Some(file_number) = self.directory.files.get(file_number).cloned() else {
+            return Ok(false);
+        };
+        let mut file = self.directory.open_file(&file_number).await?;
+        let Some(is_end_of_file) = read_blocks(
+            &self.directory,
+            &mut file,
+            &file_number,
+            block_id,
+            &mut self.spare_blocks,
+        )
+        .await?
+        else {
+            return Ok(false);
+        };
+        std::mem::swap(&mut self.blocks, &mut self.spare_blocks);
+        self.file = file;
+        self.file_number = file_number;
+        self.block_id = block_id;
+        self.blocks_start_id = block_id;
+        self.is_end_of_file = is_end_of_file;
+        self.follows_skipped_file = false;
+        Ok(true)
+    }
+
+    /// Makes the reader skip the full files whose queue index shows they hold no record of
+    /// `queues`. It must be called before reading anything.
+    ///
+    /// The records of other queues spanning skipped files are then read partially: their
+    /// frames following a skipped file are dropped, see [`BlockRead::follows_skipped_blocks`].
+    ///
+    /// Returns true if some files are skipped.
+    pub async fn skip_files_without_queues(
+        &mut self,
+        queues: &HashSet<String>,
+    ) -> io::Result<bool> {
+        let directory = &self.directory;
+        self.files_to_skip = directory
+            .queue_indexes
+            .iter()
+            .filter(|(_, queue_index)| !queue_index.contains_any(queues.iter()))
+            .map(|(&file_number, _)| file_number)
+            .filter(|&file_number| file_number != directory.files.last().file_number())
+            .collect();
+        if self.files_to_skip.is_empty() {
+            return Ok(false);
+        }
+        if self.files_to_skip.contains(&self.file_number.file_number()) {
+            // Files with an index are followed by other files, one of them being read.
+            let file_number = self.file_number.clone();
+            self.open_next_file(&file_number).await?;
+        }
+        Ok(true)
+    }
+
+    /// Moves to the first block of the first file following `file_number` which is not skipped
+    /// and has blocks.
+    ///
+    /// Returns false, leaving the reader unchanged, if there is no such file.
+    async fn open_next_file(&mut self, file_number: &FileNumber) -> io::Result<bool> {
+        let mut next_file_number = file_number.clone();
+        let mut follows_skipped_file = false;
+        loop {
+            next_file_number =
+                if let Some(next_file_number) = self.directory.files.next(&next_file_number) {
+                    next_file_number
+                } else {
+                    return Ok(false);
+                };
+            if self.files_to_skip.contains(&next_file_number.file_number()) {
+                debug!(file = next_file_number.filename(), "skip file");
+                follows_skipped_file = true;
+                continue;
+            }
+            let mut next_file: FS::File = self.directory.open_file(&next_file_number).await?;
+            if let Some(is_end_of_file) = read_blocks(
+                &self.directory,
+                &mut next_file,
+                &next_file_number,
+                0,
+                &mut self.spare_blocks,
+            )
+            .await?
+            {
+                self.block_id = 0;
+                std::mem::swap(&mut self.blocks, &mut self.spare_blocks);
+                self.blocks_start_id = 0;
+                self.is_end_of_file = is_end_of_file;
+                self.file = next_file;
+                self.file_number = next_file_number;
+                self.follows_skipped_file = follows_skipped_file;
+                return Ok(true);
+            }
+        }
+    }
+
+    /// See [`Directory::check_file_numbers`].
+    pub fn check_file_numbers(&self) -> Result<(), ReadRecordError> {
+        self.directory.check_file_numbers()
+    }
+
+    /// See [`Directory::check_no_unexpected_file`].
+    pub fn check_no_unexpected_file(&self) -> Result<(), ReadRecordError> {
+        self.directory.check_no_unexpected_file()
+    }
+
+    /// Reads the payload stored in a sidecar file, see [`Directory::read_sidecar`].
+    pub(crate) async fn read_sidecar(&self, sidecar_id: SidecarId) -> io::Result<Option<Vec<u8>>> {
+        self.directory.read_sidecar(sidecar_id).await
+    }
+
     /// Creates a write positioned at the beginning of the last read block.
     ///
     /// If no block was read, positions itself at the beginning.
-    pub async fn into_writer(mut self) -> io::Result<RollingWriter> {
+    pub async fn into_writer(mut self) -> io::Result<RollingWriter<FS>> {
+        // The file gets written to again: its index would be outdated.
+        self.directory
+            .remove_queue_index(self.file_number.file_number())
+            .await?;
         let offset = self.block_id * crate::BLOCK_NUM_BYTES;
-        self.file.seek(SeekFrom::Start(offset as u64)).await?;
+        let data_offset = self.directory.file_header(&self.file_number).num_bytes();
         Ok(RollingWriter {
-            file: BufWriter::with_capacity(FRAME_NUM_BYTES, self.file),
+            file: self.file,
+            data_offset,
+            buffer: Vec::with_capacity(FRAME_NUM_BYTES),
+            flushed_offset: offset,
             offset,
             file_number: self.file_number.clone(),
             directory: self.directory,
+            retry_policy: RetryPolicy::default(),
+            cache_advice: CacheAdvice::default(),
+            queue_index: None,
+            record_queues: Vec::new(),
+            num_record_queues: 0,
         })
     }
 }
 
-async fn read_block(file: &mut File, block: &mut [u8; BLOCK_NUM_BYTES]) -> io::Result<bool> {
-    match file.read_exact(block).await {
-        Ok(len) => {
-            assert_eq!(len, BLOCK_NUM_BYTES);
-            Ok(true)
-        }
-        Err(io_err) if io_err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
-        Err(io_err) => Err(io_err),
+/// Reads up to [`READ_AHEAD_NUM_BLOCKS`] blocks of the file `file_number` into `blocks`,
+/// starting with the block `first_block_id`. Returns whether the end of the file was reached, or
+/// None if this block does not exist.
+///
+/// A block only partially present at the end of the file is not returned, as if it did not exist.
+async fn read_blocks<FS: FileSystem>(
+    directory: &Directory<FS>,
+    file: &mut FS::File,
+    file_number: &FileNumber,
+    first_block_id: usize,
+    blocks: &mut Vec<u8>,
+) -> io::Result<Option<bool>> {
+    let data_offset = directory.file_header(file_number).num_bytes();
+    let offset = data_offset + first_block_id * BLOCK_NUM_BYTES;
+    blocks.resize(READ_AHEAD_NUM_BLOCKS * BLOCK_NUM_BYTES, 0u8);
+    let num_bytes = read_at_most(file, offset as u64, blocks).await?;
+    let is_end_of_file = num_bytes < blocks.len();
+    blocks.truncate(num_bytes - num_bytes % BLOCK_NUM_BYTES);
+    if blocks.is_empty() {
+        return Ok(None);
     }
+    Ok(Some(is_end_of_file))
 }
 
 #[async_trait]
-impl BlockRead for RollingReader {
+impl<FS: FileSystem> BlockRead for RollingReader<FS> {
     async fn next_block(&mut self) -> io::Result<bool> {
-        let success = read_block(&mut self.file, &mut self.block).await?;
-        if success {
-            self.block_id += 1;
+        let next_block_id = self.block_id + 1;
+        if next_block_id < self.blocks_start_id + self.blocks.len() / BLOCK_NUM_BYTES {
+            self.block_id = next_block_id;
             return Ok(true);
         }
-
-        let mut next_file_number =
-            if let Some(next_file_number) = self.directory.files.next(&self.file_number) {
-                next_file_number
-            } else {
-                return Ok(false);
-            };
-
-        loop {
-            let mut next_file: File = self.directory.open_file(&next_file_number).await?;
-            let success = read_block(&mut next_file, &mut self.block).await?;
-            if success {
-                self.block_id = 0;
-                self.file = next_file;
-                self.file_number = next_file_number;
+        if !self.is_end_of_file {
+            if let Some(is_end_of_file) = read_blocks(
+                &self.directory,
+                &mut self.file,
+                &self.file_number,
+                next_block_id,
+                &mut self.spare_blocks,
+            )
+            .await?
+            {
+                self.block_id = next_block_id;
+                std::mem::swap(&mut self.blocks, &mut self.spare_blocks);
+                self.blocks_start_id = next_block_id;
+                self.is_end_of_file = is_end_of_file;
                 return Ok(true);
             }
-
-            next_file_number =
-                if let Some(next_file_number) = self.directory.files.next(&next_file_number) {
-                    next_file_number
-                } else {
-                    return Ok(false);
-                };
         }
+
+        let file_number = self.file_number.clone();
+        self.open_next_file(&file_number).await
+    }
+
+    fn follows_skipped_blocks(&self) -> bool {
+        self.follows_skipped_file && self.block_id == 0
+    }
+
+    fn checksum_algo(&self) -> ChecksumAlgo {
+        self.directory.file_header(&self.file_number).checksum_algo
     }
 
     fn block(&self) -> &[u8; BLOCK_NUM_BYTES] {
-        &self.block
+        let start = (self.block_id - self.blocks_start_id) * BLOCK_NUM_BYTES;
+        self.blocks[start..start + BLOCK_NUM_BYTES]
+            .try_into()
+            .unwrap()
     }
 }
 
-pub struct RollingWriter {
-    file: BufWriter<File>,
+/// Position in the log, see [`RollingWriter::mark`].
+#[derive(Debug, Copy, Clone)]
+pub struct WriteMark {
+    file_number: u64,
+    offset: usize,
+}
+
+pub struct RollingWriter<FS: FileSystem = DefaultFileSystem> {
+    file: FS::File,
+    // Offset of the first block in the current file. Offsets below are relative to it.
+    data_offset: usize,
+    // Bytes written to the current file but not flushed yet. They start at `flushed_offset`.
+    buffer: Vec<u8>,
+    flushed_offset: usize,
     offset: usize,
     file_number: FileNumber,
-    pub(crate) directory: Directory,
+    pub(crate) directory: Directory<FS>,
+    retry_policy: RetryPolicy,
+    cache_advice: CacheAdvice,
+    // Queues having records in the current file, if queue indexes are written.
+    queue_index: Option<QueueIndex>,
+    // Queues of the record being written, which may continue in the next file. Only the first
+    // `num_record_queues` are used, the others are kept to reuse their allocations.
+    record_queues: Vec<String>,
+    num_record_queues: usize,
 }
 
-impl RollingWriter {
+impl<FS: FileSystem> RollingWriter<FS> {
     /// Move forward of `num_bytes` without actually writing anything.
     pub async fn forward(&mut self, num_bytes: usize) -> io::Result<()> {
-        self.file.seek(SeekFrom::Current(num_bytes as i64)).await?;
+        assert!(self.buffer.is_empty());
         self.offset += num_bytes;
+        self.flushed_offset = self.offset;
         Ok(())
     }
 
@@ -226,8 +991,76 @@ impl RollingWriter {
         &self.file_number
     }
 
+    /// Returns the tracked FileNumber with the number `file_number`.
+    ///
+    /// This is cheaper than cloning the current file ahead of time when it is most likely
+    /// still the current file, as no reference count gets updated.
+    pub fn tracked_file(&self, file_number: u64) -> Option<&FileNumber> {
+        if self.file_number.file_number() == file_number {
+            return Some(&self.file_number);
+        }
+        self.directory.files.get(file_number)
+    }
+
+    /// Returns the numbers of the oldest and newest files on disk.
+    pub fn file_number_range(&self) -> Option<RangeInclusive<u64>> {
+        let files = &self.directory.files;
+        Some(files.first().file_number()..=files.last().file_number())
+    }
+
+    /// Returns the number of files on disk.
+    pub fn num_files(&self) -> usize {
+        self.directory.files.count()
+    }
+
     pub fn size(&self) -> usize {
-        self.directory.files.count() * FILE_NUM_BYTES
+        self.directory.files.count() * (FILE_HEADER_NUM_BYTES + FILE_NUM_BYTES)
+            + self.directory.sidecars_num_bytes() as usize
+    }
+
+    /// Returns what [`Self::size`] would be after writing `num_bytes` more bytes, taking into
+    /// account the files which would be created.
+    pub fn size_after_write(&self, num_bytes: usize) -> usize {
+        let end_offset = self.offset + num_bytes;
+        let num_new_files = end_offset.saturating_sub(1) / FILE_NUM_BYTES;
+        self.size() + num_new_files * (FILE_HEADER_NUM_BYTES + FILE_NUM_BYTES)
+    }
+
+    /// Returns the number of bytes written, but not flushed to the file yet.
+    pub fn num_buffered_bytes(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    pub fn set_cache_advice(&mut self, cache_advice: CacheAdvice) {
+        self.cache_advice = cache_advice;
+    }
+
+    /// Makes the writer write the queue index of each file it fills. `queue_index` lists the
+    /// queues having records in the current file so far.
+    pub fn enable_queue_index(&mut self, queue_index: QueueIndex) {
+        self.queue_index = Some(queue_index);
+    }
+
+    /// Sets the queues of the record about to be written, for the queue index.
+    pub fn set_record_queues<'q>(&mut self, queues: impl Iterator<Item = &'q str>) {
+        let Some(queue_index) = &mut self.queue_index else {
+            return;
+        };
+        self.num_record_queues = 0;
+        for queue in queues {
+            queue_index.insert(queue);
+            if let Some(record_queue) = self.record_queues.get_mut(self.num_record_queues) {
+                record_queue.clear();
+                record_queue.push_str(queue);
+            } else {
+                self.record_queues.push(queue.to_string());
+            }
+            self.num_record_queues += 1;
+        }
     }
 
     #[cfg(test)]
@@ -236,49 +1069,194 @@ impl RollingWriter {
             .first_file_number()
             .unroll(&self.directory.files)
     }
+
+    /// Returns the current end of the written bytes, so that the bytes written after it can be
+    /// discarded with [`Self::discard_since`].
+    pub fn mark(&self) -> WriteMark {
+        WriteMark {
+            file_number: self.file_number.file_number(),
+            offset: self.offset,
+        }
+    }
+
+    /// Discards the bytes written since `mark`, as if they had never been written.
+    ///
+    /// This is only possible while they are buffered: returns false, leaving the writer
+    /// untouched, if some of them were flushed to a file.
+    pub fn discard_since(&mut self, mark: WriteMark) -> bool {
+        if mark.file_number != self.file_number.file_number() || self.flushed_offset > mark.offset {
+            return false;
+        }
+        self.buffer.truncate(mark.offset - self.flushed_offset);
+        self.offset = mark.offset;
+        true
+    }
+
+    /// Writes the buffered bytes to the current file, retrying on transient errors as defined by
+    /// the retry policy.
+    ///
+    /// This is cancellation safe: the buffer and offsets are only updated once the write
+    /// succeeded, so that a flush cancelled midway is entirely done again by the next one.
+    async fn flush_buffer(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let mut retry = 0;
+        while let Err(io_error) = self.write_buffer().await {
+            let Some(backoff) = self.retry_backoff(retry, &io_error) else {
+                return Err(io_error);
+            };
+            warn!(error=?io_error, retry, backoff=?backoff, "retrying failed write");
+            FS::sleep(backoff).await;
+            retry += 1;
+        }
+        self.flushed_offset += self.buffer.len();
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Flushes the buffered bytes, then persists the current file on the underlying storage.
+    ///
+    /// Previous files are persisted as the writer moves on from them.
+    pub async fn sync(&mut self) -> io::Result<()> {
+        self.flush_buffer().await?;
+        self.sync_file().await
+    }
+
+    /// Persists the current file, retrying on transient errors as defined by the retry policy.
+    async fn sync_file(&mut self) -> io::Result<()> {
+        let mut retry = 0;
+        while let Err(io_error) = self.sync_file_once().await {
+            let Some(backoff) = self.retry_backoff(retry, &io_error) else {
+                return Err(io_error);
+            };
+            warn!(error=?io_error, retry, backoff=?backoff, "retrying failed sync");
+            FS::sleep(backoff).await;
+            retry += 1;
+        }
+        Ok(())
+    }
+
+    async fn sync_file_once(&mut self) -> io::Result<()> {
+        #[cfg(test)]
+        self.directory.take_injected_write_failure()?;
+        self.file.sync().await
+    }
+
+    /// Returns the delay to wait before attempting again an operation which failed with
+    /// `io_error` after `retry` retries, or `None` if it should not be retried.
+    fn retry_backoff(&self, retry: usize, io_error: &io::Error) -> Option<Duration> {
+        if retry >= self.retry_policy.max_retries || !RetryPolicy::is_retryable(io_error) {
+            return None;
+        }
+        Some(self.retry_policy.backoff(retry))
+    }
+
+    /// Deletes the files which are no longer used, see [`Directory::gc`].
+    ///
+    /// The buffered bytes are flushed first: records which were not flushed yet may already be
+    /// referenced by the in memory queues, and deleting files while they are only buffered could
+    /// make a crash lose both the deleted records and these ones. The current file is never
+    /// deleted, as the writer keeps a reference to it.
+    pub(crate) async fn gc(&mut self) -> io::Result<()> {
+        self.flush_buffer().await?;
+        self.directory.gc().await?;
+        debug_assert!(self.directory.files.contains(&self.file_number));
+        Ok(())
+    }
+
+    /// Attempts to write the whole buffer at `flushed_offset`.
+    ///
+    /// A failed attempt may have written part of the buffer. Writes are positional, so that a
+    /// retry overwrites these bytes instead of writing them a second time.
+    async fn write_buffer(&mut self) -> io::Result<()> {
+        #[cfg(test)]
+        self.directory.take_injected_write_failure()?;
+        let offset = (self.data_offset + self.flushed_offset) as u64;
+        self.file.write(offset, &self.buffer).await?;
+        self.file.flush().await
+    }
 }
 
 #[async_trait]
-impl BlockWrite for RollingWriter {
+impl<FS: FileSystem> BlockWrite for RollingWriter<FS> {
     async fn write(&mut self, buf: &[u8]) -> io::Result<()> {
         if buf.is_empty() {
             return Ok(());
         }
         assert!(buf.len() <= self.num_bytes_remaining_in_block());
         if self.offset + buf.len() > FILE_NUM_BYTES {
-            self.file.flush().await?;
+            self.flush_buffer().await?;
+            // The file won't be written to anymore: persisting it now lets `sync` only care
+            // about the current file.
+            self.sync_file().await?;
+            if let Some(queue_index) = &mut self.queue_index {
+                self.directory
+                    .write_queue_index(self.file_number.file_number(), queue_index)
+                    .await?;
+                queue_index.clear();
+                for queue in &self.record_queues[..self.num_record_queues] {
+                    queue_index.insert(queue);
+                }
+            }
 
             let (file_number, file) =
                 if let Some(next_file_number) = self.directory.files.next(&self.file_number) {
+                    // The file gets overwritten: its index would be outdated.
+                    self.directory
+                        .remove_queue_index(next_file_number.file_number())
+                        .await?;
                     let file = self.directory.open_file(&next_file_number).await?;
                     (next_file_number, file)
                 } else {
                     let next_file_number = self.directory.files.inc(&self.file_number);
-                    let file = create_file(&self.directory.dir, &next_file_number).await?;
+                    let file = self.directory.create_file(&next_file_number).await?;
                     (next_file_number, file)
                 };
 
-            self.file = BufWriter::with_capacity(FRAME_NUM_BYTES, file);
+            self.data_offset = self.directory.file_header(&file_number).num_bytes();
+            let mut previous_file = std::mem::replace(&mut self.file, file);
+            if self.cache_advice == CacheAdvice::DontNeed {
+                // This is only an optimization: failing to apply it is not an error.
+                if let Err(io_error) = previous_file.advise_dont_need().await {
+                    warn!(error=?io_error, "failed to advise dropping file from cache");
+                }
+            }
             self.file_number = file_number;
             self.offset = 0;
+            self.flushed_offset = 0;
         }
         self.offset += buf.len();
-        self.file.write_all(buf).await?;
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= FRAME_NUM_BYTES {
+            self.flush_buffer().await?;
+        }
         Ok(())
     }
 
     async fn flush(&mut self) -> io::Result<()> {
-        self.file.flush().await
+        self.flush_buffer().await
     }
 
     fn num_bytes_remaining_in_block(&self) -> usize {
         BLOCK_NUM_BYTES - (self.offset % BLOCK_NUM_BYTES)
     }
+
+    fn checksum_algo(&self) -> ChecksumAlgo {
+        if self.offset < FILE_NUM_BYTES {
+            return self.directory.file_header(&self.file_number).checksum_algo;
+        }
+        // The next write moves on to the next file.
+        match self.directory.files.next(&self.file_number) {
+            Some(next_file_number) => self.directory.file_header(&next_file_number).checksum_algo,
+            None => self.directory.checksum_algo,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::filename_to_position;
+    use super::{filename_to_position, filename_to_sidecar_id, SidecarId};
 
     #[test]
     fn test_filename_to_seq_number_invalid_prefix_rejected() {
@@ -322,4 +1300,25 @@ mod tests {
             Some(u64::MAX)
         );
     }
+
+    #[test]
+    fn test_filename_to_sidecar_id() {
+        let sidecar_id = SidecarId {
+            file_number: 3,
+            id: u64::MAX,
+        };
+        assert_eq!(
+            filename_to_sidecar_id(&sidecar_id.filename()),
+            Some(sidecar_id)
+        );
+        assert_eq!(filename_to_sidecar_id("wal-00000000000000000001"), None);
+        assert_eq!(
+            filename_to_sidecar_id("sidecar-00000000000000000001_00000000000000000002"),
+            None
+        );
+        assert_eq!(
+            filename_to_sidecar_id("sidecar-0000000000000000001-000000000000000000002"),
+            None
+        );
+    }
 }
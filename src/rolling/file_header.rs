@@ -0,0 +1,280 @@
+use std::convert::TryInto;
+
+use crate::error::ReadRecordError;
+use crate::frame::ChecksumAlgo;
+
+/// Number of bytes reserved for the header at the beginning of each file.
+///
+/// Blocks start right after the header. It is much larger than what we need, so that blocks
+/// stay aligned on memory pages, and so that fields can be added without changing the layout.
+pub const FILE_HEADER_NUM_BYTES: usize = 4096;
+
+/// Files written before the introduction of the file header have no header at all. Their blocks
+/// start at the very beginning of the file.
+pub const LEGACY_FORMAT_VERSION: u8 = 0;
+
+/// Version of the format written by this version of the crate.
+pub const FORMAT_VERSION: u8 = 1;
+
+const MAGIC: [u8; 8] = *b"mrecdlog";
+
+// Layout: <magic: 8 bytes><crc32 of the bytes after it: 4 bytes><format version: 1 byte>
+// <log id: 16 bytes><file number + 1: 8 bytes><checksum algorithm id: 1 byte>
+// All integers are encoded as little endian. Remaining bytes are zeroed.
+const CHECKSUM_OFFSET: usize = MAGIC.len();
+const FORMAT_VERSION_OFFSET: usize = CHECKSUM_OFFSET + 4;
+const LOG_ID_OFFSET: usize = FORMAT_VERSION_OFFSET + 1;
+const LOG_ID_END: usize = LOG_ID_OFFSET + 16;
+const FILE_NUMBER_OFFSET: usize = LOG_ID_END;
+const FILE_NUMBER_END: usize = FILE_NUMBER_OFFSET + 8;
+const CHECKSUM_ALGO_OFFSET: usize = FILE_NUMBER_END;
+
+/// Value of the log id of files which do not have one: legacy files, and files written before
+/// log ids were introduced.
+pub const UNKNOWN_LOG_ID: u128 = 0;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FileHeader {
+    pub format_version: u8,
+    /// Identifier of the log the file belongs to, generated when the log is created.
+    pub log_id: u128,
+    /// Number of the file, as given by its name when it was created. `None` for files written
+    /// before file numbers were recorded.
+    ///
+    /// It is stored plus one, so that zeroed bytes mean it is unknown.
+    pub file_number: Option<u64>,
+    /// Algorithm of the checksums of the frames of the file. Files written before it could be
+    /// chosen, whose id is zeroed, use [`ChecksumAlgo::Crc32`].
+    pub checksum_algo: ChecksumAlgo,
+}
+
+impl FileHeader {
+    /// Header of a file written by this version of the crate, with an unknown log id.
+    pub fn current() -> FileHeader {
+        FileHeader {
+            format_version: FORMAT_VERSION,
+            log_id: UNKNOWN_LOG_ID,
+            file_number: None,
+            checksum_algo: ChecksumAlgo::Crc32,
+        }
+    }
+
+    /// Number of bytes preceding the first block of the file.
+    pub fn num_bytes(&self) -> usize {
+        if self.format_version == LEGACY_FORMAT_VERSION {
+            0
+        } else {
+            FILE_HEADER_NUM_BYTES
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buffer = vec![0u8; FILE_HEADER_NUM_BYTES];
+        buffer[..CHECKSUM_OFFSET].copy_from_slice(&MAGIC);
+        buffer[FORMAT_VERSION_OFFSET] = self.format_version;
+        buffer[LOG_ID_OFFSET..LOG_ID_END].copy_from_slice(&self.log_id.to_le_bytes());
+        let stored_file_number = self.file_number.map_or(0, |file_number| file_number + 1);
+        buffer[FILE_NUMBER_OFFSET..FILE_NUMBER_END]
+            .copy_from_slice(&stored_file_number.to_le_bytes());
+        buffer[CHECKSUM_ALGO_OFFSET] = self.checksum_algo.id();
+        let checksum = crc32fast::hash(&buffer[FORMAT_VERSION_OFFSET..]);
+        buffer[CHECKSUM_OFFSET..FORMAT_VERSION_OFFSET].copy_from_slice(&checksum.to_le_bytes());
+        buffer
+    }
+
+    /// Parses the beginning of a file.
+    ///
+    /// `data` may be shorter than [`FILE_HEADER_NUM_BYTES`] if the file is shorter than that.
+    ///
+    /// A file starting with zeros is a file whose header was never written or got lost, and
+    /// which therefore contains no data. It is considered as using the current format.
+    /// A file starting with anything other than the magic number is a legacy file.
+    pub fn deserialize(data: &[u8]) -> Result<FileHeader, ReadRecordError> {
+        if data.len() < MAGIC.len() || data[..MAGIC.len()] == [0u8; MAGIC.len()] {
+            return Ok(FileHeader::current());
+        }
+        if data[..MAGIC.len()] != MAGIC {
+            return Ok(FileHeader {
+                format_version: LEGACY_FORMAT_VERSION,
+                log_id: UNKNOWN_LOG_ID,
+                file_number: None,
+                checksum_algo: ChecksumAlgo::Crc32,
+            });
+        }
+        if data.len() < FILE_HEADER_NUM_BYTES {
+            return Err(ReadRecordError::Corruption);
+        }
+        let data = &data[..FILE_HEADER_NUM_BYTES];
+        let checksum = u32::from_le_bytes(
+            data[CHECKSUM_OFFSET..FORMAT_VERSION_OFFSET]
+                .try_into()
+                .unwrap(),
+        );
+        if crc32fast::hash(&data[FORMAT_VERSION_OFFSET..]) != checksum {
+            return Err(ReadRecordError::Corruption);
+        }
+        let format_version = data[FORMAT_VERSION_OFFSET];
+        if format_version == LEGACY_FORMAT_VERSION {
+            return Err(ReadRecordError::Corruption);
+        }
+        if format_version > FORMAT_VERSION {
+            return Err(ReadRecordError::UnsupportedVersion {
+                got: format_version,
+            });
+        }
+        let log_id = u128::from_le_bytes(data[LOG_ID_OFFSET..LOG_ID_END].try_into().unwrap());
+        let stored_file_number = u64::from_le_bytes(
+            data[FILE_NUMBER_OFFSET..FILE_NUMBER_END]
+                .try_into()
+                .unwrap(),
+        );
+        let checksum_algo_id = data[CHECKSUM_ALGO_OFFSET];
+        let checksum_algo = ChecksumAlgo::from_id(checksum_algo_id).ok_or(
+            ReadRecordError::UnknownChecksumAlgo {
+                id: checksum_algo_id,
+            },
+        )?;
+        Ok(FileHeader {
+            format_version,
+            log_id,
+            file_number: stored_file_number.checked_sub(1),
+            checksum_algo,
+        })
+    }
+}
+
+/// Generates a new random log id.
+pub fn generate_log_id() -> u128 {
+    loop {
+        let log_id: u128 = rand::random();
+        if log_id != UNKNOWN_LOG_ID {
+            return log_id;
+        }
+    }
+}
+
+/// Formats a log id like a UUID.
+pub fn format_log_id(log_id: u128) -> String {
+    let hex = format!("{log_id:032x}");
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_header_serialize_deserialize() {
+        let header = FileHeader {
+            log_id: generate_log_id(),
+            file_number: Some(0),
+            ..FileHeader::current()
+        };
+        let buffer = header.serialize();
+        assert_eq!(buffer.len(), FILE_HEADER_NUM_BYTES);
+        assert_eq!(FileHeader::deserialize(&buffer).unwrap(), header);
+        assert_eq!(header.num_bytes(), FILE_HEADER_NUM_BYTES);
+    }
+
+    #[test]
+    fn test_file_header_byte_order() {
+        // The format is little endian regardless of the platform.
+        let buffer = FileHeader::current().serialize();
+        assert_eq!(&buffer[..8], b"mrecdlog");
+        let checksum = crc32fast::hash(&buffer[12..]);
+        assert_eq!(&buffer[8..12], &checksum.to_le_bytes());
+        assert_eq!(buffer[12], FORMAT_VERSION);
+        assert!(buffer[13..].iter().all(|&b| b == 0));
+        let buffer = FileHeader {
+            log_id: 1u128 << 8 | 2,
+            ..FileHeader::current()
+        }
+        .serialize();
+        assert_eq!(&buffer[13..15], &[2, 1]);
+        assert!(buffer[15..].iter().all(|&b| b == 0));
+        let buffer = FileHeader {
+            file_number: Some(1 << 8 | 2),
+            ..FileHeader::current()
+        }
+        .serialize();
+        assert_eq!(&buffer[29..31], &[3, 1]);
+        assert!(buffer[31..].iter().all(|&b| b == 0));
+        let buffer = FileHeader {
+            checksum_algo: ChecksumAlgo::XxHash32,
+            ..FileHeader::current()
+        }
+        .serialize();
+        assert_eq!(buffer[37], ChecksumAlgo::XxHash32.id());
+        assert!(buffer[38..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_format_log_id() {
+        assert_eq!(
+            format_log_id(0x0123456789abcdef_0011223344556677),
+            "01234567-89ab-cdef-0011-223344556677"
+        );
+    }
+
+    #[test]
+    fn test_file_header_legacy() {
+        let header = FileHeader::deserialize(b"\x01\x02\x03\x04\x05\x06\x07\x08").unwrap();
+        assert_eq!(header.format_version, LEGACY_FORMAT_VERSION);
+        assert_eq!(header.num_bytes(), 0);
+    }
+
+    #[test]
+    fn test_file_header_blank() {
+        assert_eq!(FileHeader::deserialize(&[]).unwrap(), FileHeader::current());
+        assert_eq!(
+            FileHeader::deserialize(&[0u8; FILE_HEADER_NUM_BYTES]).unwrap(),
+            FileHeader::current()
+        );
+    }
+
+    #[test]
+    fn test_file_header_unsupported_version() {
+        let buffer = FileHeader {
+            format_version: FORMAT_VERSION + 1,
+            ..FileHeader::current()
+        }
+        .serialize();
+        assert!(matches!(
+            FileHeader::deserialize(&buffer),
+            Err(ReadRecordError::UnsupportedVersion { got }) if got == FORMAT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn test_file_header_unknown_checksum_algo() {
+        let mut buffer = FileHeader::current().serialize();
+        buffer[CHECKSUM_ALGO_OFFSET] = 255;
+        let checksum = crc32fast::hash(&buffer[FORMAT_VERSION_OFFSET..]);
+        buffer[CHECKSUM_OFFSET..FORMAT_VERSION_OFFSET].copy_from_slice(&checksum.to_le_bytes());
+        assert!(matches!(
+            FileHeader::deserialize(&buffer),
+            Err(ReadRecordError::UnknownChecksumAlgo { id: 255 })
+        ));
+    }
+
+    #[test]
+    fn test_file_header_corrupted() {
+        let mut buffer = FileHeader::current().serialize();
+        buffer[100] = 1;
+        assert!(matches!(
+            FileHeader::deserialize(&buffer),
+            Err(ReadRecordError::Corruption)
+        ));
+        assert!(matches!(
+            FileHeader::deserialize(&buffer[..20]),
+            Err(ReadRecordError::Corruption)
+        ));
+    }
+}
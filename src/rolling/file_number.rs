@@ -7,9 +7,9 @@ pub struct FileTracker {
 }
 
 impl FileTracker {
-    /// Create a new FileTracker tracking a single file.
-    pub fn new() -> FileTracker {
-        FileTracker::from_file_numbers(vec![0]).unwrap()
+    /// Create a new FileTracker tracking the single file `file_number`.
+    pub fn starting_at(file_number: u64) -> FileTracker {
+        FileTracker::from_file_numbers(vec![file_number]).unwrap()
     }
 
     /// Get the first FileNumber still tracked
@@ -17,6 +17,19 @@ impl FileTracker {
         self.files.first().unwrap()
     }
 
+    /// Get the last FileNumber tracked
+    pub fn last(&self) -> &FileNumber {
+        self.files.last().unwrap()
+    }
+
+    /// Get the first FileNumber which would not be removed by `take_first_unused`
+    pub fn first_used(&self) -> &FileNumber {
+        self.files
+            .iter()
+            .find(|file| !file.can_be_deleted())
+            .unwrap_or_else(|| self.last())
+    }
+
     /// Remove the oldest tracked file if it is no longer used
     ///
     /// By design the last file is always considered used.
@@ -73,6 +86,16 @@ impl FileTracker {
         Some(FileTracker { files })
     }
 
+    /// Get the tracked FileNumber with the number `file_number`.
+    pub fn get(&self, file_number: u64) -> Option<&FileNumber> {
+        self.files.get(&file_number)
+    }
+
+    /// Returns true if `file_number` is tracked.
+    pub fn contains(&self, file_number: &FileNumber) -> bool {
+        self.files.contains(file_number)
+    }
+
     /// Return the number of file tracked.
     pub fn count(&self) -> usize {
         self.files.len()
@@ -114,11 +137,15 @@ impl FileNumber {
         }
     }
 
+    /// Creates a FileNumber which is not tracked by any FileTracker.
+    pub(crate) fn for_number(file_number: u64) -> Self {
+        FileNumber::new(file_number)
+    }
+
     pub fn filename(&self) -> String {
         format!("wal-{:020}", self.file_number)
     }
 
-    #[cfg(test)]
     pub fn file_number(&self) -> u64 {
         *self.file_number
     }
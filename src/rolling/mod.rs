@@ -1,8 +1,18 @@
 mod directory;
+mod file_header;
 mod file_number;
+mod queue_index;
+mod repair;
 
-pub use self::directory::{Directory, RollingReader, RollingWriter};
+pub use self::directory::{Directory, RollingReader, RollingWriter, SidecarId, WriteMark};
+pub use self::file_header::format_log_id;
+#[cfg(test)]
+pub use self::file_header::{FileHeader, FORMAT_VERSION};
 pub use self::file_number::{FileNumber, FileTracker};
+pub use self::queue_index::QueueIndex;
+#[cfg(feature = "tokio")]
+pub use self::repair::verify_and_repair_in_place;
+pub use self::repair::{verify_and_repair_in_place_with_file_system, RepairReport};
 
 const FRAME_NUM_BYTES: usize = 1 << 15;
 
@@ -13,5 +23,5 @@ const NUM_BLOCKS_PER_FILE: usize = 1 << 12;
 const NUM_BLOCKS_PER_FILE: usize = 4;
 
 const FILE_NUM_BYTES: usize = FRAME_NUM_BYTES * NUM_BLOCKS_PER_FILE;
-#[cfg(test)]
+#[cfg(all(test, feature = "tokio"))]
 mod tests;
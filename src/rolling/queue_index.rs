@@ -0,0 +1,137 @@
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+
+/// Lists the queues having records in a wal file, so that replaying only some queues can skip
+/// the files holding none of them.
+///
+/// It is written to its own file once the wal file is full, and deleted along with it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct QueueIndex {
+    queues: BTreeSet<String>,
+}
+
+// Layout: <crc32 of the bytes after it: 4 bytes><number of queues: 4 bytes>
+// then, for each queue, in order: <name length: 2 bytes><name>
+// All integers are encoded as little endian.
+const HEADER_NUM_BYTES: usize = 8;
+
+impl QueueIndex {
+    pub fn filename(file_number: u64) -> String {
+        format!("queues-{file_number:020}")
+    }
+
+    /// Parses the name of an index file, returning the number of the wal file it indexes.
+    pub fn filename_to_file_number(file_name: &str) -> Option<u64> {
+        let file_number_str = file_name.strip_prefix("queues-")?;
+        if file_number_str.len() != 20 || !file_number_str.as_bytes().iter().all(u8::is_ascii_digit)
+        {
+            return None;
+        }
+        file_number_str.parse().ok()
+    }
+
+    pub fn insert(&mut self, queue: &str) {
+        if !self.queues.contains(queue) {
+            self.queues.insert(queue.to_string());
+        }
+    }
+
+    /// Adds the queues listed in `other`.
+    pub fn extend(&mut self, other: &QueueIndex) {
+        self.queues.extend(other.queues.iter().cloned());
+    }
+
+    pub fn clear(&mut self) {
+        self.queues.clear();
+    }
+
+    /// Returns true if the file holds records of one of `queues`.
+    pub fn contains_any<'a>(&self, mut queues: impl Iterator<Item = &'a String>) -> bool {
+        queues.any(|queue| self.queues.contains(queue))
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let num_bytes: usize = self.queues.iter().map(|queue| 2 + queue.len()).sum();
+        let mut buffer = Vec::with_capacity(HEADER_NUM_BYTES + num_bytes);
+        buffer.extend_from_slice(&[0u8; 4]);
+        buffer.extend_from_slice(&(self.queues.len() as u32).to_le_bytes());
+        for queue in &self.queues {
+            buffer.extend_from_slice(&(queue.len() as u16).to_le_bytes());
+            buffer.extend_from_slice(queue.as_bytes());
+        }
+        let checksum = crc32fast::hash(&buffer[4..]);
+        buffer[..4].copy_from_slice(&checksum.to_le_bytes());
+        buffer
+    }
+
+    /// Parses an index file. Returns None if it is incomplete or corrupted.
+    pub fn deserialize(data: &[u8]) -> Option<QueueIndex> {
+        if data.len() < HEADER_NUM_BYTES {
+            return None;
+        }
+        let checksum = u32::from_le_bytes(data[..4].try_into().unwrap());
+        if crc32fast::hash(&data[4..]) != checksum {
+            return None;
+        }
+        let num_queues = u32::from_le_bytes(data[4..8].try_into().unwrap());
+        let mut queues = BTreeSet::new();
+        let mut data = &data[HEADER_NUM_BYTES..];
+        for _ in 0..num_queues {
+            let len = u16::from_le_bytes(data.get(..2)?.try_into().unwrap()) as usize;
+            let queue = std::str::from_utf8(data.get(2..2 + len)?).ok()?;
+            queues.insert(queue.to_string());
+            data = &data[2 + len..];
+        }
+        if !data.is_empty() {
+            return None;
+        }
+        Some(QueueIndex { queues })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue_index_serialize_deserialize() {
+        let mut queue_index = QueueIndex::default();
+        assert_eq!(
+            QueueIndex::deserialize(&queue_index.serialize()),
+            Some(QueueIndex::default())
+        );
+        queue_index.insert("queue2");
+        queue_index.insert("queue1");
+        queue_index.insert("queue2");
+        let buffer = queue_index.serialize();
+        assert_eq!(&buffer[4..8], &[2, 0, 0, 0]);
+        assert_eq!(&buffer[8..16], b"\x06\x00queue1");
+        assert_eq!(QueueIndex::deserialize(&buffer), Some(queue_index.clone()));
+        assert!(queue_index.contains_any(["queue3".to_string(), "queue1".to_string()].iter()));
+        assert!(!queue_index.contains_any(["queue3".to_string()].iter()));
+    }
+
+    #[test]
+    fn test_queue_index_corrupted() {
+        let mut queue_index = QueueIndex::default();
+        queue_index.insert("queue");
+        let mut buffer = queue_index.serialize();
+        assert_eq!(QueueIndex::deserialize(&buffer[..buffer.len() - 1]), None);
+        buffer[10] = b'Q';
+        assert_eq!(QueueIndex::deserialize(&buffer), None);
+    }
+
+    #[test]
+    fn test_queue_index_filename() {
+        assert_eq!(QueueIndex::filename(3), "queues-00000000000000000003");
+        assert_eq!(
+            QueueIndex::filename_to_file_number(&QueueIndex::filename(u64::MAX)),
+            Some(u64::MAX)
+        );
+        assert_eq!(
+            QueueIndex::filename_to_file_number("wal-00000000000000000003"),
+            None
+        );
+        assert_eq!(QueueIndex::filename_to_file_number("queues-3"), None);
+    }
+}
@@ -0,0 +1,221 @@
+#[cfg(feature = "tokio")]
+use std::path::Path;
+
+use tracing::warn;
+
+use super::directory::read_at_most;
+use super::{Directory, FileNumber, FILE_NUM_BYTES};
+use crate::error::ReadRecordError;
+#[cfg(feature = "tokio")]
+use crate::file_system::TokioFileSystem;
+use crate::file_system::{AsyncFile, FileSystem};
+use crate::frame::{padding_frame, scan_frame, ChecksumAlgo, FrameType, ScannedFrame, HEADER_LEN};
+use crate::BLOCK_NUM_BYTES;
+
+/// Result of [`verify_and_repair_in_place`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepairReport {
+    pub num_files_checked: usize,
+    /// Numbers of the files which were rewritten.
+    pub repaired_files: Vec<u64>,
+    /// Numbers of the files holding corrupted frames followed by valid ones, which were left as
+    /// is.
+    pub corrupted_files: Vec<u64>,
+}
+
+/// Checks every file of the log stored in `directory_path`, and repairs the files holding torn
+/// records. See [`verify_and_repair_in_place_with_file_system`].
+#[cfg(feature = "tokio")]
+pub async fn verify_and_repair_in_place(
+    directory_path: &Path,
+) -> Result<RepairReport, ReadRecordError> {
+    verify_and_repair_in_place_with_file_system(TokioFileSystem::new(directory_path)).await
+}
+
+/// Checks every file of the log stored in `file_system`, and repairs the files holding torn
+/// records.
+///
+/// A file is torn when a crash left a corrupted frame after its last valid one, or when it stops
+/// before its end while files follow it, which a crash while rolling to the next file can cause.
+/// Reading would
+/// stop there, losing the records of the following files. The record being written when the
+/// file got torn is dropped, along with the rest of the file: the last file is zeroed from that
+/// point, the others are filled with frames which readers skip, so that reading goes on with the
+/// next file.
+///
+/// A corrupted frame followed by valid ones in the same file was not left by a crash: the file
+/// is not repaired, as that would drop valid records, but reported in
+/// [`RepairReport::corrupted_files`]. Readers skip the corrupted blocks.
+///
+/// The log is locked while it is repaired. Files missing in the middle of the log can't be
+/// repaired: [`ReadRecordError::MissingFile`] is returned.
+pub async fn verify_and_repair_in_place_with_file_system<FS: FileSystem>(
+    file_system: FS,
+) -> Result<RepairReport, ReadRecordError> {
+    let _lock_guard = file_system.lock().await?;
+    let directory =
+        Directory::open_with_file_system(file_system, 0, ChecksumAlgo::default()).await?;
+    let mut file_numbers = vec![directory.files.first().clone()];
+    while let Some(next_file_number) = directory.files.next(file_numbers.last().unwrap()) {
+        file_numbers.push(next_file_number);
+    }
+    // Offset at which each file has to be repaired, relative to its first block.
+    let mut repair_offsets: Vec<Option<usize>> = vec![None; file_numbers.len()];
+    // File and offset of the first frame of the record being read, if any.
+    let mut record_start: Option<(usize, usize)> = None;
+    let mut block = vec![0u8; BLOCK_NUM_BYTES];
+    let mut corrupted_files = Vec::new();
+    for (file_id, file_number) in file_numbers.iter().enumerate() {
+        let is_last_file = file_id + 1 == file_numbers.len();
+        let (file_end, has_corrupted_frames) = scan_file(
+            &directory,
+            file_number,
+            file_id,
+            &mut block,
+            &mut record_start,
+        )
+        .await?;
+        if has_corrupted_frames {
+            corrupted_files.push(file_number.file_number());
+        }
+        let torn_offset = match file_end {
+            // A record spanning over the next file.
+            FileEnd::Full if !is_last_file || record_start.is_none() => continue,
+            FileEnd::Full => FILE_NUM_BYTES,
+            // The end of the log.
+            FileEnd::NotWritten { .. } if is_last_file && record_start.is_none() => continue,
+            FileEnd::NotWritten { offset } | FileEnd::Corrupted { offset } => offset,
+        };
+        // The record being written when the file got torn can't be complete.
+        let (start_file_id, start_offset) = record_start.take().unwrap_or((file_id, torn_offset));
+        for (repaired_file_id, repair_offset) in repair_offsets
+            .iter_mut()
+            .enumerate()
+            .take(file_id + 1)
+            .skip(start_file_id)
+        {
+            let offset = if repaired_file_id == start_file_id {
+                start_offset
+            } else {
+                0
+            };
+            *repair_offset = Some(repair_offset.map_or(offset, |previous| previous.min(offset)));
+        }
+    }
+    let mut repaired_files = Vec::new();
+    for (file_id, (file_number, repair_offset)) in
+        file_numbers.iter().zip(&repair_offsets).enumerate()
+    {
+        let Some(repair_offset) = *repair_offset else {
+            continue;
+        };
+        warn!(
+            file_number = file_number.file_number(),
+            offset = repair_offset,
+            "repairing torn file"
+        );
+        let is_last_file = file_id + 1 == file_numbers.len();
+        let file_header = directory.file_header(file_number);
+        let data_offset = file_header.num_bytes();
+        let mut file = directory.open_file(file_number).await?;
+        let mut offset = repair_offset;
+        while offset < FILE_NUM_BYTES {
+            let num_bytes = BLOCK_NUM_BYTES - offset % BLOCK_NUM_BYTES;
+            let bytes = if is_last_file {
+                vec![0u8; num_bytes]
+            } else {
+                padding_frame(num_bytes, file_header.checksum_algo)
+            };
+            file.write((data_offset + offset) as u64, &bytes).await?;
+            offset += num_bytes;
+        }
+        file.flush().await?;
+        file.sync().await?;
+        repaired_files.push(file_number.file_number());
+    }
+    Ok(RepairReport {
+        num_files_checked: file_numbers.len(),
+        repaired_files,
+        corrupted_files,
+    })
+}
+
+/// How the frames of a file end.
+enum FileEnd {
+    /// The file is filled with valid frames.
+    Full,
+    /// No frame was written from `offset` on.
+    NotWritten { offset: usize },
+    /// The frame at `offset` is corrupted, and no valid frame follows it.
+    Corrupted { offset: usize },
+}
+
+/// Reads the frames of a file, tracking in `record_start` where the record being read starts.
+///
+/// Also returns true if the file holds corrupted frames followed by valid ones.
+async fn scan_file<FS: FileSystem>(
+    directory: &Directory<FS>,
+    file_number: &FileNumber,
+    file_id: usize,
+    block: &mut [u8],
+    record_start: &mut Option<(usize, usize)>,
+) -> Result<(FileEnd, bool), ReadRecordError> {
+    let file_header = directory.file_header(file_number);
+    let data_offset = file_header.num_bytes();
+    let mut file = directory.open_file(file_number).await?;
+    let mut has_corrupted_frames = false;
+    // Offset of the first corrupted frame not followed by a valid frame yet, with the record
+    // being read when it was found. Frames are read again from the next block.
+    let mut corruption: Option<(usize, Option<(usize, usize)>)> = None;
+    for block_offset in (0..FILE_NUM_BYTES).step_by(BLOCK_NUM_BYTES) {
+        let num_bytes_read =
+            read_at_most(&mut file, (data_offset + block_offset) as u64, block).await?;
+        block[num_bytes_read..].fill(0u8);
+        let mut cursor = 0;
+        while BLOCK_NUM_BYTES - cursor >= HEADER_LEN {
+            let offset = block_offset + cursor;
+            match scan_frame(&block[cursor..], file_header.checksum_algo) {
+                ScannedFrame::Valid {
+                    frame_type,
+                    num_bytes,
+                } => {
+                    if let Some((corrupted_offset, _)) = corruption.take() {
+                        warn!(
+                            file_number = file_number.file_number(),
+                            offset = corrupted_offset,
+                            "corrupted frame followed by valid ones"
+                        );
+                        has_corrupted_frames = true;
+                    }
+                    match frame_type {
+                        FrameType::Full | FrameType::Last => *record_start = None,
+                        FrameType::First => *record_start = Some((file_id, offset)),
+                        FrameType::Middle => {}
+                    }
+                    cursor += num_bytes;
+                }
+                ScannedFrame::NotAvailable => {
+                    let file_end = match corruption {
+                        Some((offset, corrupted_record_start)) => {
+                            *record_start = corrupted_record_start;
+                            FileEnd::Corrupted { offset }
+                        }
+                        None => FileEnd::NotWritten { offset },
+                    };
+                    return Ok((file_end, has_corrupted_frames));
+                }
+                ScannedFrame::Corrupted => {
+                    if corruption.is_none() {
+                        corruption = Some((offset, record_start.take()));
+                    }
+                    break;
+                }
+            }
+        }
+    }
+    if let Some((offset, corrupted_record_start)) = corruption {
+        *record_start = corrupted_record_start;
+        return Ok((FileEnd::Corrupted { offset }, has_corrupted_frames));
+    }
+    Ok((FileEnd::Full, has_corrupted_frames))
+}
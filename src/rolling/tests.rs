@@ -192,3 +192,21 @@ async fn test_directory_truncate() {
         assert_eq!(&writer.list_file_numbers(), &[3]);
     }
 }
+
+#[tokio::test]
+async fn test_writer_gc_flushes_first() {
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let reader = RollingReader::open(tmp_dir.path()).await.unwrap();
+    let mut writer: RollingWriter = reader.into_writer().await.unwrap();
+    let buf = vec![1u8; FRAME_NUM_BYTES];
+    for _ in 0..NUM_BLOCKS_PER_FILE {
+        writer.write(&buf).await.unwrap();
+    }
+    writer.write(&buf[..100]).await.unwrap();
+    assert_eq!(&writer.list_file_numbers(), &[0, 1]);
+    assert_eq!(writer.num_buffered_bytes(), 100);
+    writer.gc().await.unwrap();
+    // Nothing references the first file anymore, but the current one is kept.
+    assert_eq!(&writer.list_file_numbers(), &[1]);
+    assert_eq!(writer.num_buffered_bytes(), 0);
+}
@@ -0,0 +1,96 @@
+use std::io;
+use std::sync::Arc;
+
+use bytes::Buf;
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::error::{AppendError, CreateQueueError, TruncateError};
+use crate::file_system::{DefaultFileSystem, FileSystem};
+use crate::mem::QueueId;
+use crate::MultiRecordLog;
+
+/// A [`MultiRecordLog`] shared between tasks.
+///
+/// Operations on a [`MultiRecordLog`] take `&mut self`, so that appends to a queue cannot
+/// interleave. This wraps the log in an async mutex, and can be cloned cheaply into handles on
+/// the same log, which tasks can append through concurrently, to the same queue or not.
+///
+/// Operations are serialized: each one holds the lock until it returns, flush included, and
+/// waiting tasks get the lock in the order they asked for it. Appending to the same queue from
+/// several tasks then behaves as if the appends were made one after the other:
+/// - appends without a position each get their own, in the order they acquired the lock, so that
+///   records are neither lost nor given the same position;
+/// - appends at an explicit position are checked against the records appended by the other tasks,
+///   exactly like sequential appends: if two tasks append at the same position, the second one is
+///   either a retry returning `Ok(None)`, or fails with [`AppendError::Past`].
+///
+/// Other operations are done by holding the lock, see [`Self::lock`].
+pub struct SharedMultiRecordLog<FS: FileSystem = DefaultFileSystem> {
+    multi_record_log: Arc<Mutex<MultiRecordLog<FS>>>,
+}
+
+impl<FS: FileSystem> Clone for SharedMultiRecordLog<FS> {
+    fn clone(&self) -> Self {
+        SharedMultiRecordLog {
+            multi_record_log: self.multi_record_log.clone(),
+        }
+    }
+}
+
+impl<FS: FileSystem> SharedMultiRecordLog<FS> {
+    pub fn new(multi_record_log: MultiRecordLog<FS>) -> Self {
+        SharedMultiRecordLog {
+            multi_record_log: Arc::new(Mutex::new(multi_record_log)),
+        }
+    }
+
+    /// Waits for the other operations to be done, and returns the log.
+    ///
+    /// Other handles wait for the guard to be dropped: it should not be held longer than
+    /// needed, e.g. while reading a range of records.
+    pub async fn lock(&self) -> MutexGuard<'_, MultiRecordLog<FS>> {
+        self.multi_record_log.lock().await
+    }
+
+    /// Creates a new queue. See [`MultiRecordLog::create_queue`].
+    pub async fn create_queue(&self, queue: &str) -> Result<QueueId, CreateQueueError> {
+        self.lock().await.create_queue(queue).await
+    }
+
+    /// Appends a record to the log. See [`MultiRecordLog::append_record`].
+    pub async fn append_record(
+        &self,
+        queue: &str,
+        position_opt: Option<u64>,
+        payload: impl Buf,
+    ) -> Result<Option<u64>, AppendError> {
+        self.lock()
+            .await
+            .append_record(queue, position_opt, payload)
+            .await
+    }
+
+    /// Appends multiple records to the log, atomically. See
+    /// [`MultiRecordLog::append_records`].
+    pub async fn append_records<T: Iterator<Item = impl Buf>>(
+        &self,
+        queue: &str,
+        position_opt: Option<u64>,
+        payloads: T,
+    ) -> Result<Option<u64>, AppendError> {
+        self.lock()
+            .await
+            .append_records(queue, position_opt, payloads)
+            .await
+    }
+
+    /// Truncates the queue up to `position`, included. See [`MultiRecordLog::truncate`].
+    pub async fn truncate(&self, queue: &str, position: u64) -> Result<usize, TruncateError> {
+        self.lock().await.truncate(queue, position).await
+    }
+
+    /// Flushes the log. See [`MultiRecordLog::sync`].
+    pub async fn sync(&self) -> io::Result<()> {
+        self.lock().await.sync().await
+    }
+}
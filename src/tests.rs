@@ -1,16 +1,39 @@
 use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use bytes::Buf;
+use async_trait::async_trait;
+use bytes::{Buf, Bytes};
 
-use crate::MultiRecordLog;
+use crate::error::{
+    AppendError, BatchError, CreateQueueError, ExtractError, RangeError, ReadRecordError,
+    TruncateError,
+};
+use crate::file_system::LOCK_FILE_NAME;
+use crate::record::{MultiPlexedRecord, MultiRecord, RecordHeaders, MAX_PAYLOAD_NUM_BYTES};
+use crate::recordlog::RecordReader;
+use crate::rolling::{
+    FileHeader, FileNumber, QueueIndex, RollingReader, SidecarId, FORMAT_VERSION,
+};
+use crate::{
+    logs_equivalent, queue_history, verify_and_repair_in_place_with_file_system, AsyncFile,
+    AutoCompact, Batch, CacheAdvice, ChecksumAlgo, CompactReport, Equivalence, FileSystem, Health,
+    MemoryUsage, MultiRecordLog, OpenProgress, Options, PendingFlush, QueueDiff, QueueEvent,
+    QueueNamePolicy, RateLimit, ReadOnlyMultiRecordLog, Record, RepairReport, RetryPolicy,
+    SharedMultiRecordLog, SyncPolicy, TypedMultiRecordLog, UnknownRecordPolicy, BLOCK_NUM_BYTES,
+};
 
-fn read_all_records<'a>(multi_record_log: &'a MultiRecordLog, queue: &str) -> Vec<Cow<'a, [u8]>> {
+fn read_all_records<'a, FS: FileSystem>(
+    multi_record_log: &'a MultiRecordLog<FS>,
+    queue: &str,
+) -> Vec<Cow<'a, [u8]>> {
     let mut records = Vec::new();
-    let mut next_pos = u64::default();
-    for (pos, payload) in multi_record_log.range(queue, next_pos..).unwrap() {
+    for (next_pos, (pos, payload)) in (0u64..).zip(multi_record_log.range(queue, ..).unwrap()) {
         assert_eq!(pos, next_pos);
         records.push(payload);
-        next_pos += 1;
     }
     records
 }
@@ -338,25 +361,26 @@ async fn test_truncate_range_correct_pos() {
 }
 
 #[tokio::test]
+#[allow(deprecated)]
 async fn test_multi_record_size() {
     let tempdir = tempfile::tempdir().unwrap();
     {
         let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
-        assert_eq!(multi_record_log.memory_usage(), 0);
+        assert_eq!(multi_record_log.memory_usage_bytes(), 0);
 
         multi_record_log.create_queue("queue").await.unwrap();
-        let size_mem_create = multi_record_log.memory_usage();
+        let size_mem_create = multi_record_log.memory_usage_bytes();
         assert!(size_mem_create > 0);
 
         multi_record_log
             .append_record("queue", None, &b"hello"[..])
             .await
             .unwrap();
-        let size_mem_append = multi_record_log.memory_usage();
+        let size_mem_append = multi_record_log.memory_usage_bytes();
         assert!(size_mem_append > size_mem_create);
 
         multi_record_log.truncate("queue", 0).await.unwrap();
-        let size_mem_truncate = multi_record_log.memory_usage();
+        let size_mem_truncate = multi_record_log.memory_usage_bytes();
         assert!(size_mem_truncate < size_mem_append);
     }
 }
@@ -470,8 +494,5622 @@ async fn test_last_record() {
     assert_eq!(last_position, 0);
     assert_eq!(last_record, &b"hello"[..]);
 
-    multi_record_log.truncate("queue1", 0).await.unwrap();
+    multi_record_log
+        .append_records(
+            "queue1",
+            None,
+            [&b"state1"[..], &b"state2"[..], &b"state3"[..]].into_iter(),
+        )
+        .await
+        .unwrap();
+    let (last_position, last_record) = multi_record_log.last_record("queue1").unwrap().unwrap();
+    assert_eq!(last_position, 3);
+    assert_eq!(last_record, &b"state3"[..]);
+
+    multi_record_log.truncate("queue1", 3).await.unwrap();
 
     let last_record = multi_record_log.last_record("queue1").unwrap();
     assert!(last_record.is_none());
 }
+
+#[tokio::test]
+async fn test_append_records_at() {
+    let tempdir_source = tempfile::tempdir().unwrap();
+    let tempdir_follower = tempfile::tempdir().unwrap();
+    let mut source = MultiRecordLog::open(tempdir_source.path()).await.unwrap();
+    let mut follower = MultiRecordLog::open(tempdir_follower.path()).await.unwrap();
+    source.create_queue("queue").await.unwrap();
+    follower.create_queue("queue").await.unwrap();
+    for i in 0..10u64 {
+        source
+            .append_record("queue", None, format!("record{i}").as_bytes())
+            .await
+            .unwrap();
+    }
+    // The follower replicated the first records before the queue got truncated.
+    let replicated_records: Vec<(u64, Cow<[u8]>)> = source.range("queue", ..3).unwrap().collect();
+    follower
+        .append_records_at(
+            "queue",
+            replicated_records
+                .iter()
+                .map(|(position, payload)| (*position, payload.as_ref())),
+        )
+        .await
+        .unwrap();
+    source.truncate("queue", 2).await.unwrap();
+    follower.truncate("queue", 2).await.unwrap();
+
+    let source_records: Vec<(u64, Cow<[u8]>)> = source.range("queue", ..).unwrap().collect();
+    follower
+        .append_records_at(
+            "queue",
+            source_records
+                .iter()
+                .map(|(position, payload)| (*position, payload.as_ref())),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        follower.range("queue", ..).unwrap().collect::<Vec<_>>(),
+        source_records
+    );
+    drop(follower);
+
+    let follower = MultiRecordLog::open(tempdir_follower.path()).await.unwrap();
+    assert_eq!(
+        follower.range("queue", ..).unwrap().collect::<Vec<_>>(),
+        source_records
+    );
+}
+
+#[tokio::test]
+async fn test_append_records_at_rejects_gaps_and_regressions() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    multi_record_log
+        .append_records_at("queue", [(0, &b"a"[..]), (1, &b"b"[..])].into_iter())
+        .await
+        .unwrap();
+    assert!(matches!(
+        multi_record_log
+            .append_records_at("queue", [(3, &b"c"[..])].into_iter())
+            .await,
+        Err(AppendError::Future)
+    ));
+    assert!(matches!(
+        multi_record_log
+            .append_records_at("queue", [(1, &b"c"[..])].into_iter())
+            .await,
+        Err(AppendError::Past)
+    ));
+    assert!(matches!(
+        multi_record_log
+            .append_records_at("queue", [(2, &b"c"[..]), (4, &b"d"[..])].into_iter())
+            .await,
+        Err(AppendError::Future)
+    ));
+    assert!(matches!(
+        multi_record_log
+            .append_records_at("queue", [(2, &b"c"[..]), (2, &b"d"[..])].into_iter())
+            .await,
+        Err(AppendError::Past)
+    ));
+    assert_eq!(
+        &read_all_records(&multi_record_log, "queue"),
+        &[b"a".as_slice(), b"b".as_slice()]
+    );
+    multi_record_log
+        .append_records_at("queue", [(2, &b"c"[..])].into_iter())
+        .await
+        .unwrap();
+    assert_eq!(multi_record_log.last_position("queue").unwrap(), Some(2));
+}
+
+#[tokio::test]
+async fn test_health() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    multi_record_log.create_queue("queue1").await.unwrap();
+    multi_record_log.create_queue("queue2").await.unwrap();
+    multi_record_log
+        .append_records(
+            "queue1",
+            None,
+            [b"a", b"b", b"c"].into_iter().map(|r| &r[..]),
+        )
+        .await
+        .unwrap();
+    assert_eq!(multi_record_log.health(), Health::Healthy);
+
+    multi_record_log
+        .in_mem_queues_mut()
+        .get_queue_mut_for_test("queue1")
+        .set_start_position_for_test(2);
+    let Health::Unhealthy { reasons } = multi_record_log.health() else {
+        panic!("expected an unhealthy log");
+    };
+    assert_eq!(reasons.len(), 1);
+    assert!(reasons[0].contains("queue1"));
+
+    multi_record_log
+        .in_mem_queues_mut()
+        .get_queue_mut_for_test("queue1")
+        .set_start_position_for_test(0);
+    multi_record_log
+        .in_mem_queues_mut()
+        .append_record("queue2", &FileNumber::for_test(17), 0, b"d")
+        .await
+        .unwrap();
+    let Health::Unhealthy { reasons } = multi_record_log.health() else {
+        panic!("expected an unhealthy log");
+    };
+    // file 17 is both after the write head and unknown.
+    assert_eq!(reasons.len(), 2);
+    assert!(reasons.iter().all(|reason| reason.contains("queue2")));
+}
+
+#[tokio::test]
+async fn test_append_raw_multirecord() {
+    let tempdir_source = tempfile::tempdir().unwrap();
+    let tempdir_dest = tempfile::tempdir().unwrap();
+    let mut source = MultiRecordLog::open(tempdir_source.path()).await.unwrap();
+    let mut dest = MultiRecordLog::open(tempdir_dest.path()).await.unwrap();
+    source.create_queue("queue").await.unwrap();
+    dest.create_queue("queue").await.unwrap();
+    source
+        .append_records(
+            "queue",
+            None,
+            [b"a", b"b", b"c"].into_iter().map(|r| &r[..]),
+        )
+        .await
+        .unwrap();
+
+    let source_records: Vec<(u64, Cow<[u8]>)> = source.range("queue", ..).unwrap().collect();
+    let mut buffer = Vec::new();
+    MultiRecord::serialize(
+        source_records.iter().map(|(_, payload)| payload.as_ref()),
+        0,
+        &mut buffer,
+    )
+    .unwrap();
+    assert_eq!(
+        dest.append_raw_multirecord("queue", &buffer).await.unwrap(),
+        0..3
+    );
+    assert_eq!(
+        dest.range("queue", ..).unwrap().collect::<Vec<_>>(),
+        source.range("queue", ..).unwrap().collect::<Vec<_>>()
+    );
+
+    // the same buffer can't be appended twice.
+    assert!(matches!(
+        dest.append_raw_multirecord("queue", &buffer).await,
+        Err(AppendError::Past)
+    ));
+    assert!(matches!(
+        dest.append_raw_multirecord("queue", &buffer[..buffer.len() - 1])
+            .await,
+        Err(AppendError::Corruption)
+    ));
+    drop(dest);
+
+    let dest = MultiRecordLog::open(tempdir_dest.path()).await.unwrap();
+    assert_eq!(
+        dest.range("queue", ..).unwrap().collect::<Vec<_>>(),
+        source.range("queue", ..).unwrap().collect::<Vec<_>>()
+    );
+}
+
+#[tokio::test]
+async fn test_record_bytes() {
+    let mut source =
+        MultiRecordLog::open_with_file_system(InMemoryFileSystem::default(), Options::default())
+            .await
+            .unwrap();
+    let mut dest =
+        MultiRecordLog::open_with_file_system(InMemoryFileSystem::default(), Options::default())
+            .await
+            .unwrap();
+    assert_eq!(source.record_bytes("queue", 0), None);
+    source.create_queue("queue").await.unwrap();
+    dest.create_queue("queue").await.unwrap();
+    source
+        .append_records("queue", None, [&b"a"[..], b"bb"].into_iter())
+        .await
+        .unwrap();
+    source
+        .append_record_with_headers("queue", None, &b"ccc"[..], &[("key", b"value")])
+        .await
+        .unwrap();
+    assert_eq!(source.record_bytes("queue", 3), None);
+
+    let mut buffer = Vec::new();
+    for position in 0..3 {
+        buffer.extend(source.record_bytes("queue", position).unwrap());
+    }
+    assert_eq!(
+        dest.append_raw_multirecord("queue", &buffer).await.unwrap(),
+        0..3
+    );
+    let records = |log: &MultiRecordLog<InMemoryFileSystem>| {
+        log.range_with_headers("queue", ..)
+            .unwrap()
+            .map(|(position, headers, payload)| (position, headers.to_vec(), payload.to_vec()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(records(&dest), records(&source));
+    assert_eq!(
+        records(&dest)[2].1,
+        &[("key".to_string(), b"value".to_vec())]
+    );
+}
+
+#[tokio::test]
+async fn test_append_retries_transient_write_failures() {
+    let tempdir = tempfile::tempdir().unwrap();
+    {
+        let options = Options {
+            io_retry: RetryPolicy {
+                max_retries: 3,
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(4),
+            },
+            ..Default::default()
+        };
+        let mut multi_record_log = MultiRecordLog::open_with_options(tempdir.path(), options)
+            .await
+            .unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        multi_record_log
+            .directory_for_test()
+            .inject_write_failures(3);
+        multi_record_log
+            .append_record("queue", None, &b"hello"[..])
+            .await
+            .unwrap();
+        multi_record_log
+            .append_record("queue", None, &b"happy"[..])
+            .await
+            .unwrap();
+
+        multi_record_log
+            .directory_for_test()
+            .inject_write_failures(4);
+        assert!(matches!(
+            multi_record_log
+                .append_record("queue", None, &b"failed"[..])
+                .await,
+            Err(AppendError::IoError(_))
+        ));
+    }
+    {
+        // records are not written twice, and the failed record was never written.
+        let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        assert_eq!(
+            &read_all_records(&multi_record_log, "queue"),
+            &[b"hello".as_slice(), b"happy".as_slice()]
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_append_no_retry() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let options = Options {
+        io_retry: RetryPolicy::no_retry(),
+        ..Default::default()
+    };
+    let mut multi_record_log = MultiRecordLog::open_with_options(tempdir.path(), options)
+        .await
+        .unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    multi_record_log
+        .directory_for_test()
+        .inject_write_failures(1);
+    assert!(multi_record_log
+        .append_record("queue", None, &b"hello"[..])
+        .await
+        .is_err());
+    // The failed record is not written along with the next one.
+    multi_record_log
+        .append_record("queue", Some(1), &b"happy"[..])
+        .await
+        .unwrap();
+    drop(multi_record_log);
+
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    assert_eq!(
+        multi_record_log
+            .range("queue", ..)
+            .unwrap()
+            .map(|(position, payload)| (position, payload.into_owned()))
+            .collect::<Vec<_>>(),
+        &[(1, b"happy".to_vec())]
+    );
+}
+
+#[tokio::test]
+async fn test_sync_retry() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let options = |io_retry| Options {
+        io_retry,
+        ..Default::default()
+    };
+    let retry_policy = RetryPolicy {
+        max_retries: 3,
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(4),
+    };
+    let mut multi_record_log =
+        MultiRecordLog::open_with_options(tempdir.path(), options(retry_policy))
+            .await
+            .unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    multi_record_log
+        .directory_for_test()
+        .inject_write_failures(3);
+    multi_record_log.sync_barrier().await.unwrap();
+    drop(multi_record_log);
+
+    let mut multi_record_log =
+        MultiRecordLog::open_with_options(tempdir.path(), options(RetryPolicy::no_retry()))
+            .await
+            .unwrap();
+    multi_record_log
+        .directory_for_test()
+        .inject_write_failures(1);
+    assert!(multi_record_log.sync_barrier().await.is_err());
+    multi_record_log.sync_barrier().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_memory_usage() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    assert_eq!(multi_record_log.memory_usage(), MemoryUsage::default());
+    multi_record_log.create_queue("queue1").await.unwrap();
+    multi_record_log.create_queue("queue2").await.unwrap();
+    multi_record_log
+        .append_records(
+            "queue1",
+            None,
+            [&[1u8; 100][..], &[2u8; 20][..]].into_iter(),
+        )
+        .await
+        .unwrap();
+    multi_record_log
+        .append_record("queue2", None, &[3u8; 7][..])
+        .await
+        .unwrap();
+    let memory_usage = multi_record_log.memory_usage();
+    assert_eq!(memory_usage.payload_bytes, 127);
+    assert_eq!(memory_usage.num_queues, 2);
+    assert_eq!(memory_usage.num_records, 3);
+    assert!(memory_usage.overhead_bytes_estimate > 0);
+    let queue_usages: Vec<(&str, usize, usize)> = memory_usage
+        .queues
+        .iter()
+        .map(|queue_usage| {
+            (
+                queue_usage.queue.as_str(),
+                queue_usage.payload_bytes,
+                queue_usage.num_records,
+            )
+        })
+        .collect();
+    assert_eq!(queue_usages, &[("queue1", 120, 2), ("queue2", 7, 1)]);
+    assert!(
+        memory_usage
+            .queues
+            .iter()
+            .map(|queue_usage| queue_usage.overhead_bytes_estimate)
+            .sum::<usize>()
+            <= memory_usage.overhead_bytes_estimate
+    );
+
+    multi_record_log.truncate("queue1", 0).await.unwrap();
+    let memory_usage = multi_record_log.memory_usage();
+    assert_eq!(memory_usage.payload_bytes, 27);
+    assert_eq!(memory_usage.num_records, 2);
+    assert_eq!(memory_usage.queues[0].payload_bytes, 20);
+}
+
+#[tokio::test]
+async fn test_open_with_queues_filter() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let payload = vec![b'x'; 10_000];
+    {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        for queue in ["queue1", "queue2", "queue3"] {
+            multi_record_log.create_queue(queue).await.unwrap();
+        }
+        multi_record_log
+            .append_record("queue3", Some(4), &b"queue3"[..])
+            .await
+            .unwrap();
+        multi_record_log.truncate("queue3", 4).await.unwrap();
+        // enough data to span over several files.
+        for _ in 0..50 {
+            multi_record_log
+                .append_record("queue1", None, &payload[..])
+                .await
+                .unwrap();
+            multi_record_log
+                .append_record("queue2", None, &payload[..])
+                .await
+                .unwrap();
+        }
+        assert!(multi_record_log.list_file_numbers().len() > 2);
+    }
+    {
+        let options = Options {
+            queues_filter: Some(["queue1".to_string()].into_iter().collect()),
+            ..Default::default()
+        };
+        let mut multi_record_log = MultiRecordLog::open_with_options(tempdir.path(), options)
+            .await
+            .unwrap();
+        assert_eq!(
+            multi_record_log.list_queues().collect::<Vec<_>>(),
+            &["queue1"]
+        );
+        assert_eq!(multi_record_log.range("queue1", ..).unwrap().count(), 50);
+        assert!(multi_record_log.range("queue2", ..).is_err());
+        assert_eq!(
+            multi_record_log.memory_usage().payload_bytes,
+            50 * payload.len()
+        );
+        multi_record_log.create_queue("queue2").await.unwrap_err();
+
+        // queue2 still references the files: they must not be deleted.
+        let num_files = multi_record_log.list_file_numbers().len();
+        multi_record_log.truncate("queue1", 49).await.unwrap();
+        assert_eq!(multi_record_log.list_file_numbers().len(), num_files);
+    }
+    {
+        let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        let mut queues = multi_record_log.list_queues().collect::<Vec<_>>();
+        queues.sort();
+        assert_eq!(queues, &["queue1", "queue2", "queue3"]);
+        assert_eq!(multi_record_log.range("queue1", ..).unwrap().count(), 0);
+        assert_eq!(multi_record_log.range("queue2", ..).unwrap().count(), 50);
+        assert_eq!(
+            &read_all_records(&multi_record_log, "queue2")[0][..],
+            &payload[..]
+        );
+        assert_eq!(multi_record_log.last_position("queue3").unwrap(), Some(4));
+    }
+}
+
+#[tokio::test]
+async fn test_open_rejects_unsupported_format_version() {
+    let tempdir = tempfile::tempdir().unwrap();
+    {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+    }
+    let filepath = tempdir.path().join(FileNumber::for_number(0).filename());
+    let mut content = std::fs::read(&filepath).unwrap();
+    let header = FileHeader {
+        format_version: FORMAT_VERSION + 1,
+        ..FileHeader::current()
+    }
+    .serialize();
+    content[..header.len()].copy_from_slice(&header);
+    std::fs::write(&filepath, content).unwrap();
+    let open_res = MultiRecordLog::open(tempdir.path()).await;
+    assert!(matches!(
+        open_res,
+        Err(ReadRecordError::UnsupportedVersion { got }) if got == FORMAT_VERSION + 1
+    ));
+}
+
+#[tokio::test]
+async fn test_open_strict_directory() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let strict_options = || Options {
+        strict_directory: true,
+        ..Default::default()
+    };
+    {
+        let mut multi_record_log =
+            MultiRecordLog::open_with_options(tempdir.path(), strict_options())
+                .await
+                .unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        multi_record_log
+            .append_record("queue", None, &b"hello"[..])
+            .await
+            .unwrap();
+    }
+    std::fs::write(tempdir.path().join("README"), b"not a log file").unwrap();
+    {
+        let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        assert_eq!(
+            &read_all_records(&multi_record_log, "queue"),
+            &[b"hello".as_slice()]
+        );
+    }
+    assert!(matches!(
+        MultiRecordLog::open_with_options(tempdir.path(), strict_options()).await,
+        Err(ReadRecordError::UnexpectedFile { name }) if name == "README"
+    ));
+    // Opening fails before the directory is modified, the lock file aside.
+    let empty_dir = tempfile::tempdir().unwrap();
+    let orphan_sidecar = SidecarId {
+        file_number: 7,
+        id: 0,
+    }
+    .filename();
+    std::fs::write(empty_dir.path().join("README"), b"not a log file").unwrap();
+    std::fs::write(empty_dir.path().join(&orphan_sidecar), b"sidecar").unwrap();
+    assert!(matches!(
+        MultiRecordLog::open_with_options(empty_dir.path(), strict_options()).await,
+        Err(ReadRecordError::UnexpectedFile { name }) if name == "README"
+    ));
+    let mut file_names: Vec<String> = std::fs::read_dir(empty_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|file_name| file_name != LOCK_FILE_NAME)
+        .collect();
+    file_names.sort();
+    assert_eq!(file_names, ["README".to_string(), orphan_sidecar]);
+
+    // A file named like a log file is not ignored, and its header is checked.
+    std::fs::remove_file(tempdir.path().join("README")).unwrap();
+    let mut garbage = b"mrecdlog".to_vec();
+    garbage.extend([0xab; 100]);
+    std::fs::write(
+        tempdir.path().join(FileNumber::for_number(1).filename()),
+        garbage,
+    )
+    .unwrap();
+    assert!(matches!(
+        MultiRecordLog::open(tempdir.path()).await,
+        Err(ReadRecordError::Corruption)
+    ));
+    assert!(matches!(
+        MultiRecordLog::open_with_options(tempdir.path(), strict_options()).await,
+        Err(ReadRecordError::Corruption)
+    ));
+}
+
+#[tokio::test]
+async fn test_open_legacy_file_without_header() {
+    let tempdir = tempfile::tempdir().unwrap();
+    {
+        // Files written before the file header was introduced used CRC-32.
+        let options = Options {
+            checksum: ChecksumAlgo::Crc32,
+            ..Default::default()
+        };
+        let mut multi_record_log = MultiRecordLog::open_with_options(tempdir.path(), options)
+            .await
+            .unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        multi_record_log
+            .append_records("queue", None, [&b"hello"[..], &b"happy"[..]].into_iter())
+            .await
+            .unwrap();
+    }
+    // They also start directly with their first block.
+    let filepath = tempdir.path().join(FileNumber::for_number(0).filename());
+    let content = std::fs::read(&filepath).unwrap();
+    let header_len = FileHeader::current().num_bytes();
+    std::fs::write(&filepath, &content[header_len..]).unwrap();
+    {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        assert_eq!(
+            &read_all_records(&multi_record_log, "queue"),
+            &[b"hello".as_slice(), b"happy".as_slice()]
+        );
+        multi_record_log
+            .append_record("queue", None, &b"world"[..])
+            .await
+            .unwrap();
+    }
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    assert_eq!(
+        &read_all_records(&multi_record_log, "queue"),
+        &[
+            b"hello".as_slice(),
+            b"happy".as_slice(),
+            b"world".as_slice()
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_truncate_all() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    multi_record_log.create_queue("queue1").await.unwrap();
+    multi_record_log.create_queue("queue2").await.unwrap();
+    multi_record_log.create_queue("queue3").await.unwrap();
+    for i in 0..5u64 {
+        multi_record_log
+            .append_record("queue1", None, &i.to_le_bytes()[..])
+            .await
+            .unwrap();
+    }
+    for i in 0..2u64 {
+        multi_record_log
+            .append_record("queue2", None, &i.to_le_bytes()[..])
+            .await
+            .unwrap();
+    }
+    // Keep the last 2 records of each queue.
+    let mut truncated = multi_record_log
+        .truncate_all(|_queue, live_range| {
+            if live_range.end - live_range.start > 2 {
+                Some(live_range.end - 3)
+            } else {
+                None
+            }
+        })
+        .await
+        .unwrap();
+    truncated.sort();
+    assert_eq!(truncated, vec![("queue1".to_string(), 3)]);
+    assert_eq!(
+        multi_record_log
+            .range("queue1", ..)
+            .unwrap()
+            .map(|(position, _)| position)
+            .collect::<Vec<u64>>(),
+        vec![3, 4]
+    );
+    assert_eq!(read_all_records(&multi_record_log, "queue2").len(), 2);
+    assert!(read_all_records(&multi_record_log, "queue3").is_empty());
+    drop(multi_record_log);
+
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    assert_eq!(
+        multi_record_log
+            .range("queue1", ..)
+            .unwrap()
+            .map(|(position, _)| position)
+            .collect::<Vec<u64>>(),
+        vec![3, 4]
+    );
+}
+
+/// Counts the position records of queues starting with `queue_prefix` stored in the log.
+async fn count_position_records(dir_path: &std::path::Path, queue_prefix: &str) -> usize {
+    let rolling_reader = RollingReader::open(dir_path).await.unwrap();
+    let mut record_reader = RecordReader::open(rolling_reader);
+    let mut num_position_records = 0;
+    while let Some(record) = record_reader
+        .read_record::<MultiPlexedRecord>()
+        .await
+        .unwrap()
+    {
+        if let MultiPlexedRecord::RecordPosition { queue, .. } = record {
+            if queue.starts_with(queue_prefix) {
+                num_position_records += 1;
+            }
+        }
+    }
+    num_position_records
+}
+
+/// Counts the truncate records of `queue` stored in the log.
+async fn count_truncate_records(dir_path: &std::path::Path, queue: &str) -> usize {
+    let rolling_reader = RollingReader::open(dir_path).await.unwrap();
+    let mut record_reader = RecordReader::open(rolling_reader);
+    let mut num_truncate_records = 0;
+    while let Some(record) = record_reader
+        .read_record::<MultiPlexedRecord>()
+        .await
+        .unwrap()
+    {
+        if let MultiPlexedRecord::Truncate {
+            queue: truncated_queue,
+            ..
+        } = record
+        {
+            if truncated_queue == queue {
+                num_truncate_records += 1;
+            }
+        }
+    }
+    num_truncate_records
+}
+
+#[tokio::test]
+async fn test_truncate_twice_writes_one_record() {
+    let tempdir = tempfile::tempdir().unwrap();
+    {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        multi_record_log
+            .append_records("queue", None, std::iter::repeat(&b"hello"[..]).take(5))
+            .await
+            .unwrap();
+        assert_eq!(multi_record_log.truncate("queue", 2).await.unwrap(), 3);
+        assert_eq!(multi_record_log.truncate("queue", 2).await.unwrap(), 0);
+        assert_eq!(multi_record_log.truncate("queue", 1).await.unwrap(), 0);
+    }
+    assert_eq!(count_truncate_records(tempdir.path(), "queue").await, 1);
+    {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        assert_eq!(multi_record_log.range("queue", ..).unwrap().count(), 2);
+        assert_eq!(multi_record_log.truncate("queue", 3).await.unwrap(), 1);
+        assert!(multi_record_log
+            .truncate_all(|_queue, _live_range| Some(3))
+            .await
+            .unwrap()
+            .is_empty());
+    }
+    assert_eq!(count_truncate_records(tempdir.path(), "queue").await, 2);
+}
+
+#[tokio::test]
+async fn test_truncate_errors() {
+    let mut multi_record_log =
+        MultiRecordLog::open_with_file_system(InMemoryFileSystem::default(), Options::default())
+            .await
+            .unwrap();
+    assert!(matches!(
+        multi_record_log.truncate("missing", 0).await,
+        Err(TruncateError::MissingQueue(queue)) if queue == "missing"
+    ));
+    multi_record_log.create_queue("queue").await.unwrap();
+    assert!(matches!(
+        multi_record_log.truncate("queue", 0).await,
+        Err(TruncateError::Future { next_position: 0 })
+    ));
+    multi_record_log
+        .append_records("queue", None, std::iter::repeat(&b"hello"[..]).take(5))
+        .await
+        .unwrap();
+    assert!(matches!(
+        multi_record_log.truncate("queue", 5).await,
+        Err(TruncateError::Future { next_position: 5 })
+    ));
+    assert!(matches!(
+        multi_record_log.truncate("queue", 100).await,
+        Err(TruncateError::Future { next_position: 5 })
+    ));
+    // Nothing was truncated by the failed calls.
+    assert_eq!(multi_record_log.range("queue", ..).unwrap().count(), 5);
+    assert_eq!(multi_record_log.truncate("queue", 4).await.unwrap(), 5);
+    assert!(matches!(
+        multi_record_log.truncate("queue", 5).await,
+        Err(TruncateError::Future { next_position: 5 })
+    ));
+
+    multi_record_log.create_queue("other").await.unwrap();
+    assert!(matches!(
+        multi_record_log
+            .truncate_all(|queue, _| (queue == "other").then_some(0))
+            .await,
+        Err(TruncateError::Future { next_position: 0 })
+    ));
+
+    let mut batch = Batch::default();
+    batch
+        .append_record("queue", &b"world"[..])
+        .truncate("queue", 6);
+    assert!(matches!(
+        multi_record_log.commit_batch(&batch).await,
+        Err(BatchError::Truncate {
+            index: 1,
+            error: TruncateError::Future { next_position: 6 },
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_empty_queues_position_recorded_once() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let num_empty_queues = 100;
+    {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        for i in 0..num_empty_queues {
+            multi_record_log
+                .create_queue(&format!("empty-{i}"))
+                .await
+                .unwrap();
+        }
+        multi_record_log.create_queue("active").await.unwrap();
+        let payload = vec![0u8; 1_000];
+        for _ in 0..300 {
+            multi_record_log
+                .append_record("active", None, &payload[..])
+                .await
+                .unwrap();
+        }
+        // Deletes the first file, which contains the creation of the empty queues: their
+        // positions get recorded again.
+        multi_record_log.truncate("active", 150).await.unwrap();
+        assert_eq!(multi_record_log.list_file_numbers(), &[1, 2]);
+        assert_eq!(
+            count_position_records(tempdir.path(), "empty-").await,
+            num_empty_queues
+        );
+        // Deletes the second file. The positions recorded in the last file are kept, so there
+        // is no need to record them again.
+        multi_record_log.truncate("active", 280).await.unwrap();
+        assert_eq!(multi_record_log.list_file_numbers(), &[2]);
+        assert_eq!(
+            count_position_records(tempdir.path(), "empty-").await,
+            num_empty_queues
+        );
+        // Deletes the file holding the positions, which must then be recorded again.
+        for _ in 0..300 {
+            multi_record_log
+                .append_record("active", None, &payload[..])
+                .await
+                .unwrap();
+        }
+        multi_record_log.truncate("active", 599).await.unwrap();
+        assert!(!multi_record_log.list_file_numbers().contains(&2));
+        assert_eq!(
+            count_position_records(tempdir.path(), "empty-").await,
+            num_empty_queues
+        );
+    }
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    for i in 0..num_empty_queues {
+        let queue = format!("empty-{i}");
+        assert!(multi_record_log.queue_exists(&queue));
+        assert_eq!(multi_record_log.last_position(&queue).unwrap(), None);
+    }
+    assert_eq!(multi_record_log.last_position("active").unwrap(), Some(599));
+}
+
+#[tokio::test]
+async fn test_open_progress() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let num_files = {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        multi_record_log.create_queue("queue1").await.unwrap();
+        multi_record_log.create_queue("queue2").await.unwrap();
+        let payload = vec![0u8; 1_000];
+        for _ in 0..150 {
+            multi_record_log
+                .append_record("queue1", None, &payload[..])
+                .await
+                .unwrap();
+            multi_record_log
+                .append_records("queue2", None, std::iter::repeat(&payload[..]).take(2))
+                .await
+                .unwrap();
+        }
+        multi_record_log.list_file_numbers().len()
+    };
+    assert!(num_files >= 3);
+    let reports: Arc<Mutex<Vec<OpenProgress>>> = Arc::default();
+    let reports_clone = reports.clone();
+    let options = Options {
+        progress: Some(Box::new(move |progress| {
+            reports_clone.lock().unwrap().push(progress)
+        })),
+        ..Default::default()
+    };
+    MultiRecordLog::open_with_options(tempdir.path(), options)
+        .await
+        .unwrap();
+    let reports = reports.lock().unwrap();
+    // At least one report per file.
+    assert!(reports.len() >= num_files);
+    assert!(reports.windows(2).all(|reports| {
+        reports[0].num_files_processed <= reports[1].num_files_processed
+            && reports[0].num_records_replayed <= reports[1].num_records_replayed
+    }));
+    assert!(reports.iter().all(|report| report.num_files == num_files));
+    assert_eq!(
+        reports.last().unwrap(),
+        &OpenProgress {
+            num_files_processed: num_files,
+            num_files,
+            num_records_replayed: 450,
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_validate_append() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    assert_eq!(
+        multi_record_log
+            .validate_append("queue", None, &b"1"[..])
+            .unwrap(),
+        Some(0)
+    );
+    // Nothing was appended.
+    assert_eq!(multi_record_log.last_position("queue").unwrap(), None);
+    assert_eq!(
+        multi_record_log.pending_unflushed(),
+        PendingFlush::default()
+    );
+
+    let position = multi_record_log
+        .validate_append("queue", Some(3), &b"1"[..])
+        .unwrap();
+    assert_eq!(position, Some(3));
+    assert_eq!(
+        multi_record_log
+            .append_record("queue", position, &b"1"[..])
+            .await
+            .unwrap(),
+        Some(3)
+    );
+    // Appending the same record again is a no-op.
+    assert_eq!(
+        multi_record_log
+            .validate_append("queue", Some(3), &b"1"[..])
+            .unwrap(),
+        None
+    );
+    assert!(matches!(
+        multi_record_log.validate_append("queue", Some(1), &b"1"[..]),
+        Err(AppendError::Past)
+    ));
+    assert!(matches!(
+        multi_record_log.validate_append("missing-queue", None, &b"1"[..]),
+        Err(AppendError::MissingQueue(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_record_reader() {
+    use std::io::Read;
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    let large_payload = |seed: u8, len: usize| -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+            .collect()
+    };
+    for seed in 0..4 {
+        multi_record_log
+            .append_record("queue", None, &large_payload(seed, 100_000)[..])
+            .await
+            .unwrap();
+    }
+    // Freeing the start of the buffer makes the next payload wrap around.
+    multi_record_log.truncate("queue", 1).await.unwrap();
+    multi_record_log
+        .append_record("queue", None, &large_payload(4, 150_000)[..])
+        .await
+        .unwrap();
+    let mut has_wrapping_record = false;
+    for (position, payload) in multi_record_log.range("queue", ..).unwrap() {
+        has_wrapping_record |= matches!(payload, Cow::Owned(_));
+        let mut record_reader = multi_record_log
+            .record_reader("queue", position)
+            .unwrap()
+            .unwrap();
+        let mut read_payload = Vec::new();
+        record_reader.read_to_end(&mut read_payload).unwrap();
+        assert_eq!(&read_payload[..], &payload[..]);
+    }
+    assert!(has_wrapping_record);
+    assert!(multi_record_log
+        .record_reader("queue", 0)
+        .unwrap()
+        .is_none());
+    assert!(multi_record_log
+        .record_reader("queue", 5)
+        .unwrap()
+        .is_none());
+    assert!(multi_record_log.record_reader("missing-queue", 0).is_err());
+}
+
+#[tokio::test]
+async fn test_file_number_range() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    assert_eq!(multi_record_log.file_number_range(), Some(0..=0));
+    multi_record_log.create_queue("queue").await.unwrap();
+    let payload = vec![0u8; 1_000];
+    for _ in 0..300 {
+        multi_record_log
+            .append_record("queue", None, &payload[..])
+            .await
+            .unwrap();
+    }
+    let file_number_range = multi_record_log.file_number_range().unwrap();
+    assert_eq!(*file_number_range.start(), 0);
+    assert!(*file_number_range.end() >= 2);
+    assert_eq!(
+        file_number_range.clone().collect::<Vec<u64>>(),
+        multi_record_log.list_file_numbers()
+    );
+
+    multi_record_log.truncate("queue", 200).await.unwrap();
+    let truncated_file_number_range = multi_record_log.file_number_range().unwrap();
+    assert!(*truncated_file_number_range.start() > 0);
+    assert_eq!(truncated_file_number_range.end(), file_number_range.end());
+    assert_eq!(
+        truncated_file_number_range.collect::<Vec<u64>>(),
+        multi_record_log.list_file_numbers()
+    );
+}
+
+#[tokio::test]
+async fn test_enforce_size_limit() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    multi_record_log.create_queue("queue1").await.unwrap();
+    multi_record_log.create_queue("queue2").await.unwrap();
+    let payload = vec![0u8; 1_000];
+    for _ in 0..300 {
+        multi_record_log
+            .append_record("queue1", None, &payload[..])
+            .await
+            .unwrap();
+        multi_record_log
+            .append_record("queue2", None, &payload[..])
+            .await
+            .unwrap();
+    }
+    let initial_disk_usage = multi_record_log.disk_usage();
+    let max_bytes = initial_disk_usage / 2;
+    let reclaimed = multi_record_log
+        .enforce_size_limit(max_bytes as u64)
+        .await
+        .unwrap();
+    let disk_usage = multi_record_log.disk_usage();
+    assert!(disk_usage <= max_bytes);
+    assert_eq!(reclaimed, (initial_disk_usage - disk_usage) as u64);
+    for queue in ["queue1", "queue2"] {
+        let positions: Vec<u64> = multi_record_log
+            .range(queue, ..)
+            .unwrap()
+            .map(|(position, _)| position)
+            .collect();
+        // The newest records are kept.
+        assert!(!positions.is_empty());
+        assert!(positions.len() < 300);
+        assert_eq!(positions.last(), Some(&299));
+    }
+    assert_eq!(multi_record_log.health(), Health::Healthy);
+    // Already under the limit, nothing to reclaim.
+    assert_eq!(
+        multi_record_log
+            .enforce_size_limit(max_bytes as u64)
+            .await
+            .unwrap(),
+        0
+    );
+    let first_positions: Vec<Option<u64>> = ["queue1", "queue2"]
+        .iter()
+        .map(|queue| {
+            multi_record_log
+                .range(queue, ..)
+                .unwrap()
+                .next()
+                .map(|(position, _)| position)
+        })
+        .collect();
+    drop(multi_record_log);
+
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    for (queue, first_position) in ["queue1", "queue2"].iter().zip(first_positions) {
+        assert_eq!(
+            multi_record_log
+                .range(queue, ..)
+                .unwrap()
+                .next()
+                .map(|(position, _)| position),
+            first_position
+        );
+    }
+}
+
+type InMemoryFileData = Arc<Mutex<Vec<u8>>>;
+
+/// A file system keeping its files in memory, shared between its clones.
+#[derive(Clone, Default)]
+struct InMemoryFileSystem {
+    files: Arc<Mutex<HashMap<String, InMemoryFileData>>>,
+    // Names of the files for which `advise_dont_need` was called, in order.
+    dont_need_advices: Arc<Mutex<Vec<String>>>,
+    num_flushes: Arc<AtomicUsize>,
+    num_writes: Arc<AtomicUsize>,
+    num_reads: Arc<AtomicUsize>,
+    // Number of reads of each file.
+    num_reads_by_file: Arc<Mutex<HashMap<String, usize>>>,
+    // Names of the files present when the directory was last synced.
+    synced_file_names: Arc<Mutex<HashSet<String>>>,
+    num_directory_syncs: Arc<AtomicUsize>,
+    // Time each file flush takes, to emulate a slow disk.
+    flush_delay: Arc<Mutex<Duration>>,
+    // Content of each file when it was last synced.
+    synced_data: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    // The file system as it would be found after a crash right before the first file removal.
+    crashed_before_removal: Arc<Mutex<Option<InMemoryFileSystem>>>,
+}
+
+impl InMemoryFileSystem {
+    /// Returns the file system as it would be found after a crash: files created since the last
+    /// directory sync are lost.
+    fn crash(&self) -> InMemoryFileSystem {
+        let synced_file_names = self.synced_file_names.lock().unwrap();
+        let files = self.files.lock().unwrap();
+        let crashed_files = files
+            .iter()
+            .filter(|(name, _)| synced_file_names.contains(*name))
+            .map(|(name, data)| {
+                let data_copy = data.lock().unwrap().clone();
+                (name.clone(), Arc::new(Mutex::new(data_copy)))
+            })
+            .collect();
+        InMemoryFileSystem {
+            files: Arc::new(Mutex::new(crashed_files)),
+            // The files which survived the crash are on disk.
+            synced_file_names: Arc::new(Mutex::new(synced_file_names.clone())),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the file system as it would be found after a power loss: on top of what is lost
+    /// by a crash, files lose what was written to them since they were last synced.
+    fn power_loss(&self) -> InMemoryFileSystem {
+        let crashed_file_system = self.crash();
+        let synced_data = self.synced_data.lock().unwrap();
+        for (name, data) in crashed_file_system.files.lock().unwrap().iter() {
+            *data.lock().unwrap() = synced_data.get(name).cloned().unwrap_or_default();
+        }
+        crashed_file_system
+    }
+
+    fn file(&self, name: &str, data: InMemoryFileData) -> InMemoryFile {
+        InMemoryFile {
+            name: name.to_string(),
+            data,
+            synced_data: self.synced_data.clone(),
+            dont_need_advices: self.dont_need_advices.clone(),
+            num_flushes: self.num_flushes.clone(),
+            num_writes: self.num_writes.clone(),
+            num_reads: self.num_reads.clone(),
+            num_reads_by_file: self.num_reads_by_file.clone(),
+            flush_delay: self.flush_delay.clone(),
+        }
+    }
+}
+
+struct InMemoryFile {
+    name: String,
+    data: InMemoryFileData,
+    synced_data: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    dont_need_advices: Arc<Mutex<Vec<String>>>,
+    num_flushes: Arc<AtomicUsize>,
+    num_writes: Arc<AtomicUsize>,
+    num_reads: Arc<AtomicUsize>,
+    num_reads_by_file: Arc<Mutex<HashMap<String, usize>>>,
+    flush_delay: Arc<Mutex<Duration>>,
+}
+
+#[async_trait]
+impl AsyncFile for InMemoryFile {
+    async fn read(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.num_reads.fetch_add(1, Ordering::Relaxed);
+        *self
+            .num_reads_by_file
+            .lock()
+            .unwrap()
+            .entry(self.name.clone())
+            .or_default() += 1;
+        let data = self.data.lock().unwrap();
+        let start = (offset as usize).min(data.len());
+        let num_bytes = buf.len().min(data.len() - start);
+        buf[..num_bytes].copy_from_slice(&data[start..start + num_bytes]);
+        Ok(num_bytes)
+    }
+
+    async fn write(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.num_writes.fetch_add(1, Ordering::Relaxed);
+        let mut data = self.data.lock().unwrap();
+        let end = offset as usize + buf.len();
+        if data.len() < end {
+            data.resize(end, 0u8);
+        }
+        data[offset as usize..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        let flush_delay = *self.flush_delay.lock().unwrap();
+        if !flush_delay.is_zero() {
+            tokio::time::sleep(flush_delay).await;
+        }
+        self.num_flushes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn sync(&mut self) -> io::Result<()> {
+        let data = self.data.lock().unwrap().clone();
+        self.synced_data
+            .lock()
+            .unwrap()
+            .insert(self.name.clone(), data);
+        Ok(())
+    }
+
+    async fn len(&self) -> io::Result<u64> {
+        Ok(self.data.lock().unwrap().len() as u64)
+    }
+
+    async fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.data.lock().unwrap().resize(len as usize, 0u8);
+        Ok(())
+    }
+
+    async fn advise_dont_need(&mut self) -> io::Result<()> {
+        self.dont_need_advices
+            .lock()
+            .unwrap()
+            .push(self.name.clone());
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl FileSystem for InMemoryFileSystem {
+    type File = InMemoryFile;
+
+    async fn list_files(&self) -> io::Result<Vec<String>> {
+        Ok(self.files.lock().unwrap().keys().cloned().collect())
+    }
+
+    async fn create_file(&self, name: &str) -> io::Result<InMemoryFile> {
+        let mut files = self.files.lock().unwrap();
+        if files.contains_key(name) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        let data = Arc::new(Mutex::new(Vec::new()));
+        files.insert(name.to_string(), data.clone());
+        Ok(self.file(name, data))
+    }
+
+    async fn open_file(&self, name: &str) -> io::Result<InMemoryFile> {
+        let data = self
+            .files
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or(io::ErrorKind::NotFound)?;
+        Ok(self.file(name, data))
+    }
+
+    async fn remove_file(&self, name: &str) -> io::Result<()> {
+        self.crashed_before_removal
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| self.crash());
+        self.files
+            .lock()
+            .unwrap()
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    async fn sync_directory(&self) -> io::Result<()> {
+        self.num_directory_syncs.fetch_add(1, Ordering::Relaxed);
+        *self.synced_file_names.lock().unwrap() =
+            self.files.lock().unwrap().keys().cloned().collect();
+        Ok(())
+    }
+
+    /// Sleeps on a thread of its own, so that the log can run outside of a tokio runtime.
+    async fn sleep(duration: Duration) {
+        let (woken_tx, woken_rx) = futures::channel::oneshot::channel();
+        std::thread::spawn(move || {
+            std::thread::sleep(duration);
+            let _ = woken_tx.send(());
+        });
+        let _ = woken_rx.await;
+    }
+}
+
+#[tokio::test]
+async fn test_multi_record_log_in_memory_file_system() {
+    let file_system = InMemoryFileSystem::default();
+    let payload = vec![7u8; 1_000];
+    {
+        let mut multi_record_log =
+            MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+                .await
+                .unwrap();
+        multi_record_log.create_queue("queue1").await.unwrap();
+        multi_record_log.create_queue("queue2").await.unwrap();
+        for _ in 0..300 {
+            multi_record_log
+                .append_record("queue1", None, &payload[..])
+                .await
+                .unwrap();
+        }
+        multi_record_log
+            .append_record("queue2", None, &b"hello"[..])
+            .await
+            .unwrap();
+        assert!(file_system.list_files().await.unwrap().len() > 1);
+    }
+    {
+        let mut multi_record_log =
+            MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+                .await
+                .unwrap();
+        assert_eq!(read_all_records(&multi_record_log, "queue1").len(), 300);
+        assert_eq!(
+            &read_all_records(&multi_record_log, "queue2"),
+            &[b"hello".as_slice()]
+        );
+        multi_record_log.truncate("queue1", 299).await.unwrap();
+        multi_record_log.delete_queue("queue2").await.unwrap();
+        // Files only holding truncated records get garbage collected.
+        assert_eq!(file_system.list_files().await.unwrap().len(), 1);
+    }
+    let multi_record_log =
+        MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+            .await
+            .unwrap();
+    assert!(read_all_records(&multi_record_log, "queue1").is_empty());
+    assert_eq!(multi_record_log.last_position("queue1").unwrap(), Some(299));
+    assert!(!multi_record_log.queue_exists("queue2"));
+}
+
+#[test]
+fn test_multi_record_log_without_tokio_runtime() {
+    let file_system = InMemoryFileSystem::default();
+    let options = || Options {
+        flush_timeout: Some(Duration::from_secs(60)),
+        ..Default::default()
+    };
+    futures::executor::block_on(async {
+        let mut multi_record_log =
+            MultiRecordLog::open_with_file_system(file_system.clone(), options())
+                .await
+                .unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        for i in 0..300u64 {
+            multi_record_log
+                .append_record("queue", None, &[i as u8; 1_000][..])
+                .await
+                .unwrap();
+        }
+        multi_record_log.truncate("queue", 149).await.unwrap();
+        multi_record_log.sync().await.unwrap();
+    });
+    let multi_record_log = futures::executor::block_on(MultiRecordLog::open_with_file_system(
+        file_system,
+        options(),
+    ))
+    .unwrap();
+    let mut records = multi_record_log.range("queue", ..).unwrap();
+    let (first_position, first_payload) = records.next().unwrap();
+    assert_eq!(first_position, 150);
+    assert_eq!(&first_payload[..], &[150u8; 1_000][..]);
+    assert_eq!(records.count(), 149);
+}
+
+#[tokio::test]
+async fn test_create_queues() {
+    let file_system = InMemoryFileSystem::default();
+    let queues: Vec<String> = (0..500).map(|i| format!("queue-{i}")).collect();
+    let queue_refs: Vec<&str> = queues.iter().map(String::as_str).collect();
+    {
+        let mut multi_record_log =
+            MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+                .await
+                .unwrap();
+        multi_record_log.create_queue("existing").await.unwrap();
+        let num_flushes_before = file_system.num_flushes.load(Ordering::Relaxed);
+        let queue_ids = multi_record_log.create_queues(&queue_refs).await.unwrap();
+        // All the records fit in the write buffer: they are written by a single flush.
+        assert_eq!(
+            file_system.num_flushes.load(Ordering::Relaxed),
+            num_flushes_before + 1
+        );
+        assert_eq!(queue_ids.len(), queues.len());
+        assert_eq!(
+            multi_record_log.resolve_queue("queue-10").unwrap(),
+            queue_ids[10]
+        );
+
+        // Nothing is created if one of the queues already exists.
+        assert!(matches!(
+            multi_record_log.create_queues(&["new", "existing"]).await,
+            Err(CreateQueueError::AlreadyExists)
+        ));
+        assert!(matches!(
+            multi_record_log.create_queues(&["new", "new"]).await,
+            Err(CreateQueueError::AlreadyExists)
+        ));
+        assert!(!multi_record_log.queue_exists("new"));
+    }
+    let multi_record_log = MultiRecordLog::open_with_file_system(file_system, Options::default())
+        .await
+        .unwrap();
+    for queue in &queues {
+        assert!(multi_record_log.queue_exists(queue));
+    }
+    assert!(!multi_record_log.queue_exists("new"));
+    assert_eq!(multi_record_log.list_queues().count(), queues.len() + 1);
+}
+
+#[tokio::test]
+async fn test_create_queue_with_record() {
+    let file_system = InMemoryFileSystem::default();
+    let mut multi_record_log =
+        MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+            .await
+            .unwrap();
+    multi_record_log.create_queue("other-queue").await.unwrap();
+    let payload: Vec<u8> = (0..40_000u32).map(|i| i as u8).collect();
+    assert_eq!(
+        multi_record_log
+            .create_queue_with_record("queue", &payload[..])
+            .await
+            .unwrap(),
+        0
+    );
+    assert!(matches!(
+        multi_record_log
+            .create_queue_with_record("queue", &payload[..])
+            .await,
+        Err(CreateQueueError::AlreadyExists)
+    ));
+    assert_eq!(
+        &read_all_records(&multi_record_log, "queue"),
+        &[&payload[..]]
+    );
+    drop(multi_record_log);
+
+    // Simulate crashes happening while the record was being written, by erasing the end of
+    // what was written.
+    let files = file_system.files.lock().unwrap().clone();
+    assert_eq!(files.len(), 1);
+    let (file_name, file_data) = files.into_iter().next().unwrap();
+    let file_data = file_data.lock().unwrap().clone();
+    let written_len = file_data.iter().rposition(|&byte| byte != 0).unwrap() + 1;
+    let mut num_crashes_without_queue = 0;
+    for crash_offset in (FileHeader::current().num_bytes()..=written_len).step_by(97) {
+        let mut crashed_file_data = file_data.clone();
+        crashed_file_data[crash_offset..].fill(0u8);
+        let crashed_file_system = InMemoryFileSystem::default();
+        crashed_file_system
+            .files
+            .lock()
+            .unwrap()
+            .insert(file_name.clone(), Arc::new(Mutex::new(crashed_file_data)));
+        let multi_record_log =
+            MultiRecordLog::open_with_file_system(crashed_file_system, Options::default())
+                .await
+                .unwrap();
+        if multi_record_log.queue_exists("queue") {
+            // The queue is never empty.
+            assert_eq!(
+                &read_all_records(&multi_record_log, "queue"),
+                &[&payload[..]]
+            );
+        } else {
+            num_crashes_without_queue += 1;
+        }
+    }
+    assert!(num_crashes_without_queue > 0);
+    let multi_record_log = MultiRecordLog::open_with_file_system(file_system, Options::default())
+        .await
+        .unwrap();
+    assert_eq!(
+        &read_all_records(&multi_record_log, "queue"),
+        &[&payload[..]]
+    );
+}
+
+#[tokio::test]
+async fn test_logs_equivalent() {
+    async fn write_log(
+        dir_path: &std::path::Path,
+        first_position: u64,
+        payloads: &[&[u8]],
+        queues: &[&str],
+    ) {
+        let mut multi_record_log = MultiRecordLog::open(dir_path).await.unwrap();
+        for queue in queues {
+            multi_record_log.create_queue(queue).await.unwrap();
+        }
+        for (position, payload) in (first_position..).zip(payloads) {
+            multi_record_log
+                .append_record("queue", Some(position), *payload)
+                .await
+                .unwrap();
+        }
+    }
+
+    let payload = vec![0u8; 1_000];
+    let payloads: Vec<&[u8]> = std::iter::repeat(&payload[..]).take(300).collect();
+    let reference_dir = tempfile::tempdir().unwrap();
+    write_log(reference_dir.path(), 0, &payloads, &["queue"]).await;
+    {
+        let mut multi_record_log = MultiRecordLog::open(reference_dir.path()).await.unwrap();
+        multi_record_log.truncate("queue", 149).await.unwrap();
+    }
+
+    // Same live records, but laid out in different files.
+    let equal_dir = tempfile::tempdir().unwrap();
+    write_log(equal_dir.path(), 150, &payloads[150..], &["queue"]).await;
+    assert_eq!(
+        logs_equivalent(reference_dir.path(), equal_dir.path())
+            .await
+            .unwrap(),
+        Equivalence::Equal
+    );
+
+    let mut different_payloads = payloads.clone();
+    different_payloads[200] = b"different";
+    let different_payload_dir = tempfile::tempdir().unwrap();
+    write_log(
+        different_payload_dir.path(),
+        150,
+        &different_payloads[150..],
+        &["queue"],
+    )
+    .await;
+    assert_eq!(
+        logs_equivalent(reference_dir.path(), different_payload_dir.path())
+            .await
+            .unwrap(),
+        Equivalence::Diff {
+            queue: "queue".to_string(),
+            diff: QueueDiff::Record { position: 200 },
+        }
+    );
+
+    let extra_queue_dir = tempfile::tempdir().unwrap();
+    write_log(
+        extra_queue_dir.path(),
+        150,
+        &payloads[150..],
+        &["queue", "extra-queue"],
+    )
+    .await;
+    assert_eq!(
+        logs_equivalent(reference_dir.path(), extra_queue_dir.path())
+            .await
+            .unwrap(),
+        Equivalence::Diff {
+            queue: "extra-queue".to_string(),
+            diff: QueueDiff::OnlyInSecond,
+        }
+    );
+    assert_eq!(
+        logs_equivalent(extra_queue_dir.path(), reference_dir.path())
+            .await
+            .unwrap(),
+        Equivalence::Diff {
+            queue: "extra-queue".to_string(),
+            diff: QueueDiff::OnlyInFirst,
+        }
+    );
+
+    // Comparing does not create files in the directories.
+    let empty_dir = tempfile::tempdir().unwrap();
+    assert_eq!(
+        logs_equivalent(empty_dir.path(), empty_dir.path())
+            .await
+            .unwrap(),
+        Equivalence::Equal
+    );
+    assert!(std::fs::read_dir(empty_dir.path())
+        .unwrap()
+        .next()
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_cache_advice() {
+    for cache_advice in [CacheAdvice::Normal, CacheAdvice::DontNeed] {
+        let file_system = InMemoryFileSystem::default();
+        let options = Options {
+            cache_advice,
+            ..Default::default()
+        };
+        let mut multi_record_log =
+            MultiRecordLog::open_with_file_system(file_system.clone(), options)
+                .await
+                .unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        let payload = vec![0u8; 1_000];
+        for _ in 0..300 {
+            multi_record_log
+                .append_record("queue", None, &payload[..])
+                .await
+                .unwrap();
+        }
+        let file_numbers = multi_record_log.list_file_numbers();
+        assert!(file_numbers.len() >= 3);
+        let dont_need_advices = file_system.dont_need_advices.lock().unwrap().clone();
+        if cache_advice == CacheAdvice::DontNeed {
+            // All files but the one being written are advised.
+            let expected_advices: Vec<String> = file_numbers[..file_numbers.len() - 1]
+                .iter()
+                .map(|&file_number| FileNumber::for_test(file_number).filename())
+                .collect();
+            assert_eq!(dont_need_advices, expected_advices);
+        } else {
+            assert!(dont_need_advices.is_empty());
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_tokio_file_advise_dont_need() {
+    use crate::TokioFileSystem;
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let file_system = TokioFileSystem::new(tempdir.path());
+    let mut file = file_system.create_file("file").await.unwrap();
+    file.write(0, b"hello").await.unwrap();
+    file.advise_dont_need().await.unwrap();
+    let mut buf = [0u8; 5];
+    assert_eq!(file.read(0, &mut buf).await.unwrap(), 5);
+    assert_eq!(&buf, b"hello");
+}
+
+#[tokio::test]
+async fn test_append_record_with_headers() {
+    let tempdir = tempfile::tempdir().unwrap();
+    {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        multi_record_log
+            .append_record("queue", None, &b"no headers"[..])
+            .await
+            .unwrap();
+        multi_record_log
+            .append_record_with_headers(
+                "queue",
+                None,
+                &b"{}"[..],
+                &[("content-type", b"json"), ("source", b"node-1")],
+            )
+            .await
+            .unwrap();
+        multi_record_log
+            .append_record_with_headers("queue", None, &b"empty headers"[..], &[])
+            .await
+            .unwrap();
+    }
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    assert_eq!(
+        &read_all_records(&multi_record_log, "queue"),
+        &[
+            b"no headers".as_slice(),
+            b"{}".as_slice(),
+            b"empty headers".as_slice()
+        ]
+    );
+    let records: Vec<(u64, RecordHeaders, Vec<u8>)> = multi_record_log
+        .range_with_headers("queue", ..)
+        .unwrap()
+        .map(|(position, headers, payload)| (position, headers.to_vec(), payload.to_vec()))
+        .collect();
+    assert_eq!(
+        records,
+        vec![
+            (0, Vec::new(), b"no headers".to_vec()),
+            (
+                1,
+                vec![
+                    ("content-type".to_string(), b"json".to_vec()),
+                    ("source".to_string(), b"node-1".to_vec()),
+                ],
+                b"{}".to_vec()
+            ),
+            (2, Vec::new(), b"empty headers".to_vec()),
+        ]
+    );
+    multi_record_log.truncate("queue", 1).await.unwrap();
+    assert_eq!(
+        multi_record_log
+            .range_with_headers("queue", ..)
+            .unwrap()
+            .map(|(position, headers, _)| (position, headers.len()))
+            .collect::<Vec<_>>(),
+        vec![(2, 0)]
+    );
+}
+
+/// Payload of `remaining` zero bytes, which is never read.
+struct UnreadPayload {
+    remaining: usize,
+}
+
+impl bytes::Buf for UnreadPayload {
+    fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    fn chunk(&self) -> &[u8] {
+        unreachable!("the payload should be rejected before being read")
+    }
+
+    fn advance(&mut self, _cnt: usize) {
+        unreachable!("the payload should be rejected before being read")
+    }
+}
+
+#[tokio::test]
+async fn test_append_record_with_headers_too_large() {
+    let mut multi_record_log =
+        MultiRecordLog::open_with_file_system(InMemoryFileSystem::default(), Options::default())
+            .await
+            .unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    let long_key = "k".repeat(u16::MAX as usize + 1);
+    assert!(matches!(
+        multi_record_log
+            .append_record_with_headers("queue", None, &b"payload"[..], &[(&long_key, b"value")])
+            .await,
+        Err(AppendError::HeaderKeyTooLong { len }) if len == u16::MAX as usize + 1
+    ));
+    let max_key = "k".repeat(u16::MAX as usize);
+    multi_record_log
+        .append_record_with_headers("queue", None, &b"payload"[..], &[(&max_key, b"value")])
+        .await
+        .unwrap();
+
+    // The payload fits, but not along with its headers.
+    let payload = UnreadPayload {
+        remaining: MAX_PAYLOAD_NUM_BYTES - 16,
+    };
+    assert!(matches!(
+        multi_record_log
+            .append_record_with_headers("queue", None, payload, &[("key", b"value")])
+            .await,
+        Err(AppendError::TooLarge)
+    ));
+    let payload = UnreadPayload {
+        remaining: MAX_PAYLOAD_NUM_BYTES + 1,
+    };
+    assert!(matches!(
+        multi_record_log
+            .append_record_with_headers("queue", None, payload, &[])
+            .await,
+        Err(AppendError::TooLarge)
+    ));
+    assert_eq!(multi_record_log.range("queue", ..).unwrap().count(), 1);
+}
+
+#[tokio::test]
+async fn test_range_with_headers_on_log_without_headers() {
+    let tempdir = tempfile::tempdir().unwrap();
+    {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        multi_record_log
+            .append_records("queue", None, [&b"hello"[..], &b"happy"[..]].into_iter())
+            .await
+            .unwrap();
+    }
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    let records: Vec<(u64, usize, Vec<u8>)> = multi_record_log
+        .range_with_headers("queue", ..)
+        .unwrap()
+        .map(|(position, headers, payload)| (position, headers.len(), payload.to_vec()))
+        .collect();
+    assert_eq!(
+        records,
+        vec![(0, 0, b"hello".to_vec()), (1, 0, b"happy".to_vec())]
+    );
+}
+
+#[tokio::test]
+async fn test_log_id_is_stable() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let log_id = {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        let payload = vec![0u8; 1_000];
+        for _ in 0..300 {
+            multi_record_log
+                .append_record("queue", None, &payload[..])
+                .await
+                .unwrap();
+        }
+        multi_record_log.log_id()
+    };
+    assert_eq!(log_id.len(), 36);
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    assert_eq!(multi_record_log.log_id(), log_id);
+    assert!(multi_record_log.list_file_numbers().len() > 1);
+    let other_tempdir = tempfile::tempdir().unwrap();
+    let other_multi_record_log = MultiRecordLog::open(other_tempdir.path()).await.unwrap();
+    assert_ne!(other_multi_record_log.log_id(), log_id);
+}
+
+#[tokio::test]
+async fn test_open_rejects_files_from_different_logs() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let other_tempdir = tempfile::tempdir().unwrap();
+    let mut log_ids = Vec::new();
+    for dir in [tempdir.path(), other_tempdir.path()] {
+        let mut multi_record_log = MultiRecordLog::open(dir).await.unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        log_ids.push(multi_record_log.log_id());
+    }
+    // Misconfiguration: a file of the other log ends up in the directory.
+    std::fs::copy(
+        other_tempdir
+            .path()
+            .join(FileNumber::for_number(0).filename()),
+        tempdir.path().join(FileNumber::for_number(1).filename()),
+    )
+    .unwrap();
+    let open_res = MultiRecordLog::open(tempdir.path()).await;
+    let Err(ReadRecordError::MixedLogs { mut ids }) = open_res else {
+        panic!("expected a MixedLogs error");
+    };
+    ids.sort();
+    log_ids.sort();
+    assert_eq!(ids, log_ids);
+}
+
+#[tokio::test]
+async fn test_range_starting_before_truncation() {
+    let tempdir = tempfile::tempdir().unwrap();
+    {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        for i in 0..100u64 {
+            multi_record_log
+                .append_record("queue", None, &i.to_le_bytes()[..])
+                .await
+                .unwrap();
+        }
+        // The first live position is 50.
+        multi_record_log.truncate("queue", 49).await.unwrap();
+        let positions: Vec<u64> = multi_record_log
+            .range("queue", 10..60)
+            .unwrap()
+            .map(|(position, _)| position)
+            .collect();
+        assert_eq!(positions, (50..60).collect::<Vec<u64>>());
+    }
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    let records: Vec<(u64, Vec<u8>)> = multi_record_log
+        .range("queue", 10..60)
+        .unwrap()
+        .map(|(position, payload)| (position, payload.into_owned()))
+        .collect();
+    let expected_records: Vec<(u64, Vec<u8>)> = (50..60u64)
+        .map(|position| (position, position.to_le_bytes().to_vec()))
+        .collect();
+    assert_eq!(records, expected_records);
+    assert_eq!(multi_record_log.range("queue", 0..50).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn test_range_batched() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    for i in 0..10u64 {
+        multi_record_log
+            .append_record("queue", None, &i.to_le_bytes()[..])
+            .await
+            .unwrap();
+    }
+    let batches: Vec<Vec<(u64, Vec<u8>)>> = multi_record_log
+        .range_batched("queue", 2.., 3)
+        .unwrap()
+        .collect();
+    assert_eq!(
+        batches.iter().map(Vec::len).collect::<Vec<usize>>(),
+        vec![3, 3, 2]
+    );
+    let concatenated: Vec<(u64, Vec<u8>)> = batches.into_iter().flatten().collect();
+    let full_range: Vec<(u64, Vec<u8>)> = multi_record_log
+        .range("queue", 2..)
+        .unwrap()
+        .map(|(position, payload)| (position, payload.into_owned()))
+        .collect();
+    assert_eq!(concatenated, full_range);
+    assert_eq!(
+        multi_record_log
+            .range_batched("queue", 20.., 3)
+            .unwrap()
+            .count(),
+        0
+    );
+    assert!(multi_record_log
+        .range_batched("missing-queue", .., 3)
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_queue_id() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    let queue_id = multi_record_log.create_queue("queue").await.unwrap();
+    let other_queue_id = multi_record_log.create_queue("other-queue").await.unwrap();
+    assert_ne!(queue_id, other_queue_id);
+    assert_eq!(multi_record_log.resolve_queue("queue").unwrap(), queue_id);
+    assert!(multi_record_log.resolve_queue("missing-queue").is_err());
+
+    multi_record_log
+        .append_record_by_id(queue_id, None, &b"1"[..])
+        .await
+        .unwrap();
+    multi_record_log
+        .append_record("queue", None, &b"2"[..])
+        .await
+        .unwrap();
+    assert_eq!(
+        multi_record_log
+            .range_by_id(queue_id, ..)
+            .unwrap()
+            .collect::<Vec<_>>(),
+        &[(0, Cow::Borrowed(&b"1"[..])), (1, Cow::Borrowed(&b"2"[..]))]
+    );
+    assert_eq!(
+        multi_record_log.truncate_by_id(queue_id, 0).await.unwrap(),
+        1
+    );
+    assert_eq!(
+        multi_record_log.last_position_by_id(queue_id).unwrap(),
+        Some(1)
+    );
+    assert_eq!(
+        multi_record_log
+            .last_position_by_id(other_queue_id)
+            .unwrap(),
+        None
+    );
+
+    multi_record_log.delete_queue("queue").await.unwrap();
+    assert!(matches!(
+        multi_record_log
+            .append_record_by_id(queue_id, None, &b"3"[..])
+            .await,
+        Err(AppendError::QueueDeleted(_))
+    ));
+    assert!(multi_record_log.range_by_id(queue_id, ..).is_err());
+    assert!(multi_record_log.last_position_by_id(queue_id).is_err());
+    assert!(multi_record_log.truncate_by_id(queue_id, 1).await.is_err());
+
+    // A queue recreated with the same name gets a different id.
+    let recreated_queue_id = multi_record_log.create_queue("queue").await.unwrap();
+    assert_ne!(recreated_queue_id, queue_id);
+    assert!(multi_record_log.last_position_by_id(queue_id).is_err());
+    assert_eq!(
+        multi_record_log
+            .last_position_by_id(recreated_queue_id)
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        multi_record_log
+            .last_position_by_id(other_queue_id)
+            .unwrap(),
+        None
+    );
+}
+
+#[tokio::test]
+async fn test_wait_for_position() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    multi_record_log
+        .append_record("queue", None, &b"hello"[..])
+        .await
+        .unwrap();
+    // Already available.
+    multi_record_log
+        .wait_for_position("queue", 0)
+        .await
+        .unwrap();
+    assert!(multi_record_log
+        .wait_for_position("missing-queue", 0)
+        .await
+        .is_err());
+
+    let wait_handle = tokio::spawn(multi_record_log.wait_for_position("queue", 2));
+    let multi_record_log = Arc::new(tokio::sync::Mutex::new(multi_record_log));
+    let append_handle = {
+        let multi_record_log = multi_record_log.clone();
+        tokio::spawn(async move {
+            for payload in [&b"happy"[..], &b"tax"[..]] {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                multi_record_log
+                    .lock()
+                    .await
+                    .append_record("queue", None, payload)
+                    .await
+                    .unwrap();
+            }
+        })
+    };
+    tokio::time::timeout(Duration::from_secs(5), wait_handle)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    append_handle.await.unwrap();
+    let mut multi_record_log = multi_record_log.lock().await;
+    assert_eq!(multi_record_log.last_position("queue").unwrap(), Some(2));
+
+    // Deleting the queue wakes up waiters with an error.
+    let wait_handle = tokio::spawn(multi_record_log.wait_for_position("queue", 10));
+    multi_record_log.delete_queue("queue").await.unwrap();
+    assert!(tokio::time::timeout(Duration::from_secs(5), wait_handle)
+        .await
+        .unwrap()
+        .unwrap()
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_pending_unflushed() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open_with_prefs(
+        tempdir.path(),
+        SyncPolicy::OnDelay(Duration::from_secs(3600)),
+    )
+    .await
+    .unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    assert_eq!(
+        multi_record_log.pending_unflushed(),
+        PendingFlush::default()
+    );
+    multi_record_log
+        .append_record("queue", None, &b"hello"[..])
+        .await
+        .unwrap();
+    let pending_flush = multi_record_log.pending_unflushed();
+    assert_eq!(pending_flush.records, 1);
+    assert!(pending_flush.bytes > 5);
+    multi_record_log
+        .append_records("queue", None, [&b"happy"[..], &b"tax"[..]].into_iter())
+        .await
+        .unwrap();
+    let pending_flush_after = multi_record_log.pending_unflushed();
+    assert_eq!(pending_flush_after.records, 3);
+    assert!(pending_flush_after.bytes > pending_flush.bytes);
+    multi_record_log.sync().await.unwrap();
+    assert_eq!(
+        multi_record_log.pending_unflushed(),
+        PendingFlush::default()
+    );
+}
+
+fn count_sidecars(dir_path: &std::path::Path) -> usize {
+    std::fs::read_dir(dir_path)
+        .unwrap()
+        .filter(|dir_entry| {
+            dir_entry
+                .as_ref()
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .starts_with("sidecar-")
+        })
+        .count()
+}
+
+#[tokio::test]
+async fn test_large_record_threshold() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let options = || Options {
+        large_record_threshold: Some(1_000),
+        ..Default::default()
+    };
+    let payload = |position: u64| {
+        let len = if position % 2 == 0 { 100 } else { 5_000 };
+        vec![position as u8; len]
+    };
+    {
+        let mut multi_record_log = MultiRecordLog::open_with_options(tempdir.path(), options())
+            .await
+            .unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        for position in 0..10 {
+            multi_record_log
+                .append_record("queue", None, &payload(position)[..])
+                .await
+                .unwrap();
+        }
+        // The log files only hold references to the large payloads.
+        assert_eq!(count_sidecars(tempdir.path()), 5);
+        assert_eq!(multi_record_log.list_file_numbers(), &[0]);
+        assert!(multi_record_log.disk_usage() > 5 * 5_000);
+    }
+    let mut multi_record_log = MultiRecordLog::open_with_options(tempdir.path(), options())
+        .await
+        .unwrap();
+    let records: Vec<(u64, Vec<u8>)> = multi_record_log
+        .range("queue", ..)
+        .unwrap()
+        .map(|(position, payload)| (position, payload.into_owned()))
+        .collect();
+    let expected_records: Vec<(u64, Vec<u8>)> = (0..10)
+        .map(|position| (position, payload(position)))
+        .collect();
+    assert_eq!(records, expected_records);
+
+    // Fill the first file with small records, so that the log moves on to the next one.
+    for _ in 0..300 {
+        multi_record_log
+            .append_record("queue", None, &[0u8; 500][..])
+            .await
+            .unwrap();
+    }
+    assert!(multi_record_log.list_file_numbers().len() > 1);
+    multi_record_log.truncate("queue", 9).await.unwrap();
+    assert_eq!(count_sidecars(tempdir.path()), 5);
+    multi_record_log.truncate("queue", 309).await.unwrap();
+    assert!(!multi_record_log.list_file_numbers().contains(&0));
+    assert_eq!(count_sidecars(tempdir.path()), 0);
+
+    multi_record_log
+        .append_record("queue", None, &[1u8; 5_000][..])
+        .await
+        .unwrap();
+    drop(multi_record_log);
+    let multi_record_log = MultiRecordLog::open_with_options(tempdir.path(), options())
+        .await
+        .unwrap();
+    let records: Vec<(u64, Vec<u8>)> = multi_record_log
+        .range("queue", ..)
+        .unwrap()
+        .map(|(position, payload)| (position, payload.into_owned()))
+        .collect();
+    assert_eq!(records, vec![(310, vec![1u8; 5_000])]);
+    assert_eq!(count_sidecars(tempdir.path()), 1);
+}
+
+#[tokio::test]
+async fn test_sidecar_synced_before_record() {
+    let file_system = InMemoryFileSystem::default();
+    let options = || Options {
+        large_record_threshold: Some(1_000),
+        ..Default::default()
+    };
+    let mut multi_record_log =
+        MultiRecordLog::open_with_file_system(file_system.clone(), options())
+            .await
+            .unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    multi_record_log
+        .append_record("queue", None, &[1u8; 5_000][..])
+        .await
+        .unwrap();
+    multi_record_log.sync_barrier().await.unwrap();
+    drop(multi_record_log);
+
+    // The sidecar survives a power loss along with the record referencing it.
+    let file_system = file_system.power_loss();
+    let multi_record_log = MultiRecordLog::open_with_file_system(file_system.clone(), options())
+        .await
+        .unwrap();
+    let records: Vec<(u64, Vec<u8>)> = multi_record_log
+        .range("queue", ..)
+        .unwrap()
+        .map(|(position, payload)| (position, payload.into_owned()))
+        .collect();
+    assert_eq!(records, vec![(0, vec![1u8; 5_000])]);
+    drop(multi_record_log);
+
+    // A record whose sidecar is missing is not silently dropped.
+    file_system
+        .files
+        .lock()
+        .unwrap()
+        .retain(|name, _| !name.starts_with("sidecar-"));
+    assert!(matches!(
+        MultiRecordLog::open_with_file_system(file_system, options()).await,
+        Err(ReadRecordError::Corruption)
+    ));
+}
+
+#[tokio::test]
+async fn test_directory_synced_after_file_creation() {
+    let file_system = InMemoryFileSystem::default();
+    {
+        let mut multi_record_log =
+            MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+                .await
+                .unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        for _ in 0..300 {
+            multi_record_log
+                .append_record("queue", None, &[1u8; 1000][..])
+                .await
+                .unwrap();
+        }
+        assert_eq!(multi_record_log.list_file_numbers(), &[0, 1, 2]);
+    }
+    // Without syncing the directory, the crash would lose the files, and their records.
+    let multi_record_log =
+        MultiRecordLog::open_with_file_system(file_system.crash(), Options::default())
+            .await
+            .unwrap();
+    assert_eq!(multi_record_log.range("queue", ..).unwrap().count(), 300);
+}
+
+#[tokio::test]
+async fn test_directory_sync_follows_sync_policy() {
+    let file_system = InMemoryFileSystem::default();
+    let options = Options {
+        sync_policy: SyncPolicy::OnDelay(Duration::from_secs(3600)),
+        ..Default::default()
+    };
+    let mut multi_record_log = MultiRecordLog::open_with_file_system(file_system.clone(), options)
+        .await
+        .unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    multi_record_log.sync().await.unwrap();
+    let num_directory_syncs = file_system.num_directory_syncs.load(Ordering::Relaxed);
+    for _ in 0..300 {
+        multi_record_log
+            .append_record("queue", None, &[1u8; 1000][..])
+            .await
+            .unwrap();
+    }
+    assert_eq!(multi_record_log.list_file_numbers(), &[0, 1, 2]);
+    assert_eq!(
+        file_system.num_directory_syncs.load(Ordering::Relaxed),
+        num_directory_syncs
+    );
+    multi_record_log.sync().await.unwrap();
+    assert_eq!(
+        file_system.num_directory_syncs.load(Ordering::Relaxed),
+        num_directory_syncs + 1
+    );
+    // Nothing was created or deleted since.
+    multi_record_log.sync().await.unwrap();
+    assert_eq!(
+        file_system.num_directory_syncs.load(Ordering::Relaxed),
+        num_directory_syncs + 1
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_tokio_file_system_sync_directory() {
+    use crate::TokioFileSystem;
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let file_system = TokioFileSystem::new(tempdir.path());
+    file_system.create_file("file").await.unwrap();
+    file_system.sync_directory().await.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_directory_locked_by_writer() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    assert!(matches!(
+        MultiRecordLog::open(tempdir.path()).await,
+        Err(ReadRecordError::AlreadyLocked { pid: Some(pid) }) if pid == std::process::id()
+    ));
+    // Comparing logs only reads them.
+    assert_eq!(
+        logs_equivalent(tempdir.path(), tempdir.path())
+            .await
+            .unwrap(),
+        Equivalence::Equal
+    );
+    drop(multi_record_log);
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    assert!(multi_record_log.queue_exists("queue"));
+}
+
+#[tokio::test]
+async fn test_open_with_first_files_garbage_collected() {
+    let tempdir = tempfile::tempdir().unwrap();
+    {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        for position in 0..300u64 {
+            multi_record_log
+                .append_record("queue", None, &[0u8; 1000][..])
+                .await
+                .unwrap();
+            if position == 250 {
+                multi_record_log.truncate("queue", position).await.unwrap();
+            }
+        }
+        assert!(!multi_record_log.list_file_numbers().contains(&0));
+    }
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    assert!(!multi_record_log.list_file_numbers().contains(&0));
+    let positions: Vec<u64> = multi_record_log
+        .range("queue", ..)
+        .unwrap()
+        .map(|(position, _)| position)
+        .collect();
+    assert_eq!(positions, (251..300).collect::<Vec<u64>>());
+}
+
+#[tokio::test]
+async fn test_open_with_missing_file() {
+    let tempdir = tempfile::tempdir().unwrap();
+    {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        multi_record_log
+            .append_records("queue", None, std::iter::repeat(&[0u8; 1000][..]).take(300))
+            .await
+            .unwrap();
+        assert_eq!(multi_record_log.list_file_numbers(), &[0, 1, 2]);
+    }
+    std::fs::remove_file(tempdir.path().join(FileNumber::for_test(1).filename())).unwrap();
+    assert!(matches!(
+        MultiRecordLog::open(tempdir.path()).await,
+        Err(ReadRecordError::MissingFile { file_number: 1 })
+    ));
+}
+
+#[tokio::test]
+async fn test_initial_file_number() {
+    let tempdir = tempfile::tempdir().unwrap();
+    {
+        let options = Options {
+            initial_file_number: 42,
+            ..Default::default()
+        };
+        let mut multi_record_log = MultiRecordLog::open_with_options(tempdir.path(), options)
+            .await
+            .unwrap();
+        assert_eq!(multi_record_log.list_file_numbers(), &[42]);
+        multi_record_log.create_queue("queue").await.unwrap();
+        multi_record_log
+            .append_records("queue", None, std::iter::repeat(&[0u8; 1000][..]).take(200))
+            .await
+            .unwrap();
+        assert_eq!(multi_record_log.list_file_numbers(), &[42, 43]);
+    }
+    assert!(tempdir
+        .path()
+        .join(FileNumber::for_test(42).filename())
+        .exists());
+    // The option is ignored for existing logs.
+    let options = Options {
+        initial_file_number: 7,
+        ..Default::default()
+    };
+    let multi_record_log = MultiRecordLog::open_with_options(tempdir.path(), options)
+        .await
+        .unwrap();
+    assert_eq!(multi_record_log.list_file_numbers(), &[42, 43]);
+    assert_eq!(multi_record_log.range("queue", ..).unwrap().count(), 200);
+}
+
+#[tokio::test]
+async fn test_checksum_algos() {
+    for checksum_algo in [
+        ChecksumAlgo::Crc32,
+        ChecksumAlgo::Crc32c,
+        ChecksumAlgo::XxHash32,
+    ] {
+        let tempdir = tempfile::tempdir().unwrap();
+        let payloads: Vec<Vec<u8>> = (0..100usize)
+            .map(|i| vec![i as u8; (i * 997) % 7000])
+            .collect();
+        {
+            let options = Options {
+                checksum: checksum_algo,
+                ..Default::default()
+            };
+            let mut multi_record_log = MultiRecordLog::open_with_options(tempdir.path(), options)
+                .await
+                .unwrap();
+            multi_record_log.create_queue("queue").await.unwrap();
+            for payload in &payloads {
+                multi_record_log
+                    .append_record("queue", None, &payload[..])
+                    .await
+                    .unwrap();
+            }
+        }
+        let header_bytes =
+            std::fs::read(tempdir.path().join(FileNumber::for_test(0).filename())).unwrap();
+        let header = FileHeader::deserialize(&header_bytes).unwrap();
+        assert_eq!(header.checksum_algo, checksum_algo);
+        let options = Options {
+            checksum: checksum_algo,
+            ..Default::default()
+        };
+        let multi_record_log = MultiRecordLog::open_with_options(tempdir.path(), options)
+            .await
+            .unwrap();
+        let records: Vec<Vec<u8>> = multi_record_log
+            .range("queue", ..)
+            .unwrap()
+            .map(|(_, payload)| payload.into_owned())
+            .collect();
+        assert_eq!(records, payloads);
+    }
+}
+
+#[tokio::test]
+async fn test_checksum_algo_read_from_file_headers() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let options = |checksum_algo| Options {
+        checksum: checksum_algo,
+        ..Default::default()
+    };
+    {
+        let mut multi_record_log =
+            MultiRecordLog::open_with_options(tempdir.path(), options(ChecksumAlgo::XxHash32))
+                .await
+                .unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        multi_record_log
+            .append_records("queue", None, std::iter::repeat(&[1u8; 1000][..]).take(100))
+            .await
+            .unwrap();
+    }
+    {
+        // Appends go on in the last file with its algorithm, the next files use the new one.
+        let mut multi_record_log =
+            MultiRecordLog::open_with_options(tempdir.path(), options(ChecksumAlgo::Crc32c))
+                .await
+                .unwrap();
+        assert_eq!(multi_record_log.range("queue", ..).unwrap().count(), 100);
+        multi_record_log
+            .append_records("queue", None, std::iter::repeat(&[2u8; 1000][..]).take(200))
+            .await
+            .unwrap();
+    }
+    let checksum_algos: Vec<ChecksumAlgo> = std::fs::read_dir(tempdir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .starts_with("wal-")
+        })
+        .map(|path| {
+            FileHeader::deserialize(&std::fs::read(path).unwrap())
+                .unwrap()
+                .checksum_algo
+        })
+        .collect();
+    assert!(checksum_algos.contains(&ChecksumAlgo::XxHash32));
+    assert!(checksum_algos.contains(&ChecksumAlgo::Crc32c));
+    // Whatever the option, each file is verified with the algorithm of its header.
+    let multi_record_log =
+        MultiRecordLog::open_with_options(tempdir.path(), options(ChecksumAlgo::Crc32))
+            .await
+            .unwrap();
+    let payloads: Vec<u8> = multi_record_log
+        .range("queue", ..)
+        .unwrap()
+        .map(|(_, payload)| payload[0])
+        .collect();
+    assert_eq!(payloads.len(), 300);
+    assert!(payloads[..100].iter().all(|&b| b == 1));
+    assert!(payloads[100..].iter().all(|&b| b == 2));
+}
+
+#[tokio::test]
+async fn test_replay_reads_blocks_ahead() {
+    let file_system = InMemoryFileSystem::default();
+    // Payloads of various lengths, so that records span blocks, read ahead parts and files.
+    let payloads: Vec<Vec<u8>> = (0..200usize)
+        .map(|i| vec![i as u8; (i * 997) % 7000])
+        .collect();
+    {
+        let mut multi_record_log =
+            MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+                .await
+                .unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        for payload in &payloads {
+            multi_record_log
+                .append_record("queue", None, &payload[..])
+                .await
+                .unwrap();
+        }
+    }
+    file_system.num_reads.store(0, Ordering::Relaxed);
+    let multi_record_log =
+        MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+            .await
+            .unwrap();
+    let num_files = multi_record_log.list_file_numbers().len();
+    assert!(num_files > 1);
+    // At most a read for the header of each file, two for its blocks, and one finding its end.
+    assert!(file_system.num_reads.load(Ordering::Relaxed) <= num_files * 4);
+    let records: Vec<(u64, Cow<[u8]>)> = multi_record_log.range("queue", ..).unwrap().collect();
+    assert_eq!(records.len(), payloads.len());
+    for ((position, payload), (expected_position, expected_payload)) in
+        records.into_iter().zip(payloads.iter().enumerate())
+    {
+        assert_eq!(position, expected_position as u64);
+        assert_eq!(payload.as_ref(), &expected_payload[..]);
+    }
+}
+
+#[tokio::test]
+async fn test_records_since() {
+    let mut multi_record_log =
+        MultiRecordLog::open_with_file_system(InMemoryFileSystem::default(), Options::default())
+            .await
+            .unwrap();
+    assert!(multi_record_log.records_since("queue", 0).is_none());
+    multi_record_log.create_queue("queue").await.unwrap();
+    assert_eq!(multi_record_log.records_since("queue", 0), Some(Vec::new()));
+    for payload in [&b"a"[..], b"", b"ccc", b"dd"] {
+        multi_record_log
+            .append_record("queue", None, payload)
+            .await
+            .unwrap();
+    }
+    let positions_since = |multi_record_log: &MultiRecordLog<InMemoryFileSystem>, after| {
+        multi_record_log
+            .records_since("queue", after)
+            .unwrap()
+            .into_iter()
+            .map(|(position, _)| position)
+            .collect::<Vec<u64>>()
+    };
+    assert_eq!(
+        multi_record_log.records_since("queue", 0).unwrap(),
+        vec![
+            (1, Bytes::from_static(b"")),
+            (2, Bytes::from_static(b"ccc")),
+            (3, Bytes::from_static(b"dd")),
+        ]
+    );
+    assert_eq!(positions_since(&multi_record_log, 2), &[3]);
+    assert_eq!(positions_since(&multi_record_log, 3), &[] as &[u64]);
+    assert_eq!(positions_since(&multi_record_log, u64::MAX), &[] as &[u64]);
+
+    multi_record_log.truncate("queue", 1).await.unwrap();
+    assert_eq!(positions_since(&multi_record_log, 0), &[2, 3]);
+    assert_eq!(positions_since(&multi_record_log, 2), &[3]);
+}
+
+#[tokio::test]
+async fn test_range_snapshot_survives_truncate() {
+    let mut multi_record_log =
+        MultiRecordLog::open_with_file_system(InMemoryFileSystem::default(), Options::default())
+            .await
+            .unwrap();
+    assert!(multi_record_log.range_snapshot("queue", ..).is_err());
+    multi_record_log.create_queue("queue").await.unwrap();
+    for payload in [&b"a"[..], b"bb", b"ccc", b"dddd"] {
+        multi_record_log
+            .append_record("queue", None, payload)
+            .await
+            .unwrap();
+    }
+    let snapshot = multi_record_log.range_snapshot("queue", 1..).unwrap();
+    let snapshot_clone = snapshot.clone();
+    multi_record_log.truncate("queue", 2).await.unwrap();
+    multi_record_log
+        .append_record("queue", None, &b"eeeee"[..])
+        .await
+        .unwrap();
+    let expected: &[(u64, &[u8])] = &[(1, b"bb"), (2, b"ccc"), (3, b"dddd")];
+    for snapshot in [&snapshot, &snapshot_clone] {
+        assert_eq!(snapshot.len(), 3);
+        let records: Vec<(u64, &[u8])> = snapshot
+            .iter()
+            .map(|(position, payload)| (position, payload.as_ref()))
+            .collect();
+        assert_eq!(records, expected);
+    }
+    let records: Vec<(u64, Cow<[u8]>)> = multi_record_log.range("queue", ..).unwrap().collect();
+    assert_eq!(
+        records,
+        &[
+            (3, Cow::Borrowed(&b"dddd"[..])),
+            (4, Cow::Borrowed(&b"eeeee"[..]))
+        ]
+    );
+    let fresh_snapshot = multi_record_log.range_snapshot("queue", 1..).unwrap();
+    let positions: Vec<u64> = fresh_snapshot
+        .iter()
+        .map(|(position, _)| position)
+        .collect();
+    assert_eq!(positions, &[3, 4]);
+    assert!(multi_record_log
+        .range_snapshot("queue", 10..)
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn test_per_queue_rate_limit() {
+    let options = Options {
+        per_queue_rate_limit: Some(RateLimit {
+            records_per_sec: Some(5),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let mut multi_record_log =
+        MultiRecordLog::open_with_file_system(InMemoryFileSystem::default(), options)
+            .await
+            .unwrap();
+    multi_record_log.create_queue("noisy").await.unwrap();
+    multi_record_log.create_queue("quiet").await.unwrap();
+    // A burst of one second worth of records is accepted.
+    multi_record_log
+        .append_records("noisy", None, std::iter::repeat(&b"a"[..]).take(3))
+        .await
+        .unwrap();
+    for _ in 0..2 {
+        multi_record_log
+            .append_record("noisy", None, &b"b"[..])
+            .await
+            .unwrap();
+    }
+    let retry_after = match multi_record_log
+        .append_record("noisy", None, &b"c"[..])
+        .await
+    {
+        Err(AppendError::RateLimited { retry_after }) => retry_after,
+        res => panic!("unexpected result: {res:?}"),
+    };
+    assert!(!retry_after.is_zero());
+    assert!(retry_after <= Duration::from_millis(200));
+    assert_eq!(multi_record_log.range("noisy", ..).unwrap().count(), 5);
+    // Retries of appended records are not limited, and other queues have their own limit.
+    multi_record_log
+        .append_record("noisy", Some(4), &b"b"[..])
+        .await
+        .unwrap();
+    multi_record_log
+        .append_record("quiet", None, &b"d"[..])
+        .await
+        .unwrap();
+    tokio::time::sleep(retry_after).await;
+    multi_record_log
+        .append_record("noisy", None, &b"c"[..])
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_per_queue_rate_limit_batches() {
+    let options = Options {
+        per_queue_rate_limit: Some(RateLimit {
+            records_per_sec: Some(5),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let mut multi_record_log =
+        MultiRecordLog::open_with_file_system(InMemoryFileSystem::default(), options)
+            .await
+            .unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    multi_record_log
+        .append_record("queue", None, &b"a"[..])
+        .await
+        .unwrap();
+    // Appends of a batch to the same queue are limited together.
+    let mut batch = Batch::default();
+    batch
+        .append_records("queue", std::iter::repeat(&b"b"[..]).take(2))
+        .append_records("queue", std::iter::repeat(&b"c"[..]).take(3));
+    assert!(matches!(
+        multi_record_log.commit_batch(&batch).await,
+        Err(BatchError::Append {
+            index: 1,
+            error: AppendError::RateLimited { .. },
+        })
+    ));
+    assert_eq!(multi_record_log.range("queue", ..).unwrap().count(), 1);
+
+    // Queues created by the batch start with a fresh limit.
+    let mut batch = Batch::default();
+    batch
+        .append_records("queue", std::iter::repeat(&b"b"[..]).take(2))
+        .append_records("queue", std::iter::repeat(&b"c"[..]).take(2))
+        .create_queue("new")
+        .append_records("new", std::iter::repeat(&b"d"[..]).take(10));
+    multi_record_log.commit_batch(&batch).await.unwrap();
+    assert_eq!(multi_record_log.range("queue", ..).unwrap().count(), 5);
+
+    // The batch used up the limit of the queues.
+    for queue in ["queue", "new"] {
+        assert!(matches!(
+            multi_record_log.validate_append(queue, None, &b"e"[..]),
+            Err(AppendError::RateLimited { .. })
+        ));
+        assert!(matches!(
+            multi_record_log.append_record(queue, None, &b"e"[..]).await,
+            Err(AppendError::RateLimited { .. })
+        ));
+    }
+}
+
+#[tokio::test]
+async fn test_hard_disk_cap() {
+    // A new log is made of a single file.
+    let file_num_bytes =
+        MultiRecordLog::open_with_file_system(InMemoryFileSystem::default(), Options::default())
+            .await
+            .unwrap()
+            .disk_usage() as u64;
+    let options = Options {
+        hard_disk_cap: Some(2 * file_num_bytes),
+        ..Default::default()
+    };
+    let mut multi_record_log =
+        MultiRecordLog::open_with_file_system(InMemoryFileSystem::default(), options)
+            .await
+            .unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    let mut num_records = 0;
+    loop {
+        // Validation agrees with the append, up to the last record fitting.
+        let validate_res = multi_record_log.validate_append("queue", None, &[0u8; 1000][..]);
+        match multi_record_log
+            .append_record("queue", None, &[0u8; 1000][..])
+            .await
+        {
+            Ok(position) => {
+                assert_eq!(validate_res.unwrap(), position);
+                num_records += 1;
+            }
+            Err(AppendError::DiskFull) => {
+                assert!(matches!(validate_res, Err(AppendError::DiskFull)));
+                break;
+            }
+            Err(error) => panic!("unexpected error: {error:?}"),
+        }
+    }
+    // The records fill two files.
+    assert!(num_records > 250);
+    assert_eq!(multi_record_log.list_file_numbers(), &[0, 1]);
+    assert!(multi_record_log.disk_usage() as u64 <= 2 * file_num_bytes);
+    assert_eq!(
+        multi_record_log.last_position("queue").unwrap(),
+        Some(num_records - 1)
+    );
+    assert!(matches!(
+        multi_record_log
+            .append_records("queue", None, std::iter::repeat(&[0u8; 1000][..]).take(10))
+            .await,
+        Err(AppendError::DiskFull)
+    ));
+    assert!(matches!(
+        multi_record_log
+            .create_queue_with_record("other", &[0u8; 1000][..])
+            .await,
+        Err(CreateQueueError::DiskFull)
+    ));
+    assert!(multi_record_log.last_position("other").is_err());
+    // Small records still fit at the end of the last file.
+    multi_record_log
+        .append_record("queue", None, &b"small"[..])
+        .await
+        .unwrap();
+
+    // Reclaiming space makes room for new records.
+    multi_record_log
+        .truncate("queue", num_records)
+        .await
+        .unwrap();
+    assert_eq!(multi_record_log.list_file_numbers(), &[1]);
+    multi_record_log
+        .append_record("queue", None, &[0u8; 1000][..])
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_append_to_deleted_queue() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    let queue_id = multi_record_log.resolve_queue("queue").unwrap();
+    multi_record_log.delete_queue("queue").await.unwrap();
+    assert!(matches!(
+        multi_record_log
+            .append_record("queue", None, &b"record"[..])
+            .await,
+        Err(AppendError::QueueDeleted(_))
+    ));
+    assert!(matches!(
+        multi_record_log
+            .append_record_by_id(queue_id, None, &b"record"[..])
+            .await,
+        Err(AppendError::QueueDeleted(_))
+    ));
+    assert!(matches!(
+        multi_record_log
+            .append_record("never-created", None, &b"record"[..])
+            .await,
+        Err(AppendError::MissingQueue(_))
+    ));
+
+    // Tombstones are dropped along with the files garbage collected.
+    multi_record_log.create_queue("other").await.unwrap();
+    for _ in 0..300 {
+        multi_record_log
+            .append_record("other", None, &[0u8; 1000][..])
+            .await
+            .unwrap();
+    }
+    multi_record_log.truncate("other", 299).await.unwrap();
+    assert!(matches!(
+        multi_record_log
+            .append_record("queue", None, &b"record"[..])
+            .await,
+        Err(AppendError::MissingQueue(_))
+    ));
+
+    // A queue created again with the same name is appended to as usual.
+    multi_record_log.delete_queue("other").await.unwrap();
+    multi_record_log.create_queue("other").await.unwrap();
+    assert_eq!(
+        multi_record_log
+            .append_record("other", None, &b"record"[..])
+            .await
+            .unwrap(),
+        Some(0)
+    );
+}
+
+#[tokio::test]
+async fn test_keep_deleted_queue_positions() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let options = || Options {
+        keep_deleted_queue_positions: true,
+        ..Default::default()
+    };
+    {
+        let mut multi_record_log = MultiRecordLog::open_with_options(tempdir.path(), options())
+            .await
+            .unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        multi_record_log
+            .append_records("queue", None, std::iter::repeat(&b"old"[..]).take(100))
+            .await
+            .unwrap();
+        multi_record_log.delete_queue("queue").await.unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        assert_eq!(
+            multi_record_log
+                .append_record("queue", None, &b"new"[..])
+                .await
+                .unwrap(),
+            Some(100)
+        );
+        multi_record_log.delete_queue("queue").await.unwrap();
+
+        // Fill files with another queue and truncate it, so that the files recording the
+        // deletion get garbage collected.
+        multi_record_log.create_queue("other").await.unwrap();
+        for _ in 0..300 {
+            multi_record_log
+                .append_record("other", None, &[0u8; 1000][..])
+                .await
+                .unwrap();
+        }
+        multi_record_log.truncate("other", 299).await.unwrap();
+        assert!(!multi_record_log.list_file_numbers().contains(&0));
+    }
+    {
+        let mut multi_record_log = MultiRecordLog::open_with_options(tempdir.path(), options())
+            .await
+            .unwrap();
+        assert!(!multi_record_log.queue_exists("queue"));
+        multi_record_log.create_queue("queue").await.unwrap();
+        assert_eq!(
+            multi_record_log
+                .append_record("queue", None, &b"new"[..])
+                .await
+                .unwrap(),
+            Some(101)
+        );
+        multi_record_log.delete_queue("queue").await.unwrap();
+    }
+    // Without the option, recreated queues start at 0.
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    assert_eq!(
+        multi_record_log
+            .append_record("queue", None, &b"new"[..])
+            .await
+            .unwrap(),
+        Some(0)
+    );
+}
+
+#[tokio::test]
+async fn test_flush_timeout() {
+    let file_system = InMemoryFileSystem::default();
+    let options = || Options {
+        flush_timeout: Some(Duration::from_millis(20)),
+        ..Default::default()
+    };
+    {
+        let mut multi_record_log =
+            MultiRecordLog::open_with_file_system(file_system.clone(), options())
+                .await
+                .unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+
+        *file_system.flush_delay.lock().unwrap() = Duration::from_secs(1);
+        assert!(matches!(
+            multi_record_log
+                .append_record("queue", None, &b"hello"[..])
+                .await,
+            Err(AppendError::FlushTimeout)
+        ));
+        // The record was appended, it is only not flushed yet.
+        assert_eq!(multi_record_log.last_position("queue").unwrap(), Some(0));
+        assert_eq!(multi_record_log.pending_unflushed().records, 1);
+
+        *file_system.flush_delay.lock().unwrap() = Duration::ZERO;
+        assert_eq!(
+            multi_record_log
+                .append_record("queue", None, &b"world"[..])
+                .await
+                .unwrap(),
+            Some(1)
+        );
+        assert_eq!(multi_record_log.pending_unflushed().records, 0);
+    }
+    let multi_record_log = MultiRecordLog::open_with_file_system(file_system, options())
+        .await
+        .unwrap();
+    let records: Vec<(u64, Cow<[u8]>)> = multi_record_log.range("queue", ..).unwrap().collect();
+    assert_eq!(
+        records,
+        &[
+            (0, Cow::Borrowed(&b"hello"[..])),
+            (1, Cow::Borrowed(&b"world"[..]))
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_queue_digest() {
+    let first_tempdir = tempfile::tempdir().unwrap();
+    let second_tempdir = tempfile::tempdir().unwrap();
+    let mut first_log = MultiRecordLog::open(first_tempdir.path()).await.unwrap();
+    let mut second_log = MultiRecordLog::open(second_tempdir.path()).await.unwrap();
+    assert_eq!(first_log.queue_digest("queue"), None);
+    first_log.create_queue("queue").await.unwrap();
+    second_log.create_queue("queue").await.unwrap();
+    assert_eq!(first_log.queue_digest("queue"), Some(0));
+
+    // The same records, appended in different batches.
+    let payloads: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i; i as usize]).collect();
+    for payload in &payloads {
+        first_log
+            .append_record("queue", None, &payload[..])
+            .await
+            .unwrap();
+    }
+    for chunk in payloads.chunks(7) {
+        second_log
+            .append_records("queue", None, chunk.iter().map(|payload| &payload[..]))
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        first_log.queue_digest("queue"),
+        second_log.queue_digest("queue")
+    );
+    assert_ne!(first_log.queue_digest("queue"), Some(0));
+
+    first_log.truncate("queue", 49).await.unwrap();
+    assert_ne!(
+        first_log.queue_digest("queue"),
+        second_log.queue_digest("queue")
+    );
+    second_log.truncate("queue", 20).await.unwrap();
+    second_log.truncate("queue", 49).await.unwrap();
+    assert_eq!(
+        first_log.queue_digest("queue"),
+        second_log.queue_digest("queue")
+    );
+
+    // Same payload, at a different position.
+    first_log.truncate("queue", 99).await.unwrap();
+    second_log.truncate("queue", 99).await.unwrap();
+    first_log
+        .append_record("queue", None, &b"payload"[..])
+        .await
+        .unwrap();
+    second_log
+        .append_record("queue", Some(101), &b"payload"[..])
+        .await
+        .unwrap();
+    assert_ne!(
+        first_log.queue_digest("queue"),
+        second_log.queue_digest("queue")
+    );
+
+    // The digest is rebuilt on reopen.
+    let digest = first_log.queue_digest("queue");
+    drop(first_log);
+    let first_log = MultiRecordLog::open(first_tempdir.path()).await.unwrap();
+    assert_eq!(first_log.queue_digest("queue"), digest);
+}
+
+#[tokio::test]
+async fn test_empty_queues_positions_do_not_pin_files() {
+    let tempdir = tempfile::tempdir().unwrap();
+    {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        for queue_id in 0..10 {
+            multi_record_log
+                .create_queue(&format!("queue-{queue_id}"))
+                .await
+                .unwrap();
+        }
+        // Queues get filled and emptied again and again, leaving their positions scattered
+        // across files.
+        for cycle in 0..30u64 {
+            for queue_id in 0..10 {
+                let queue = format!("queue-{queue_id}");
+                multi_record_log
+                    .append_record(&queue, None, &[0u8; 1000][..])
+                    .await
+                    .unwrap();
+                multi_record_log.truncate(&queue, cycle).await.unwrap();
+            }
+        }
+        // "pinned" keeps its file, and the files after it, from being deleted before the log
+        // is closed.
+        multi_record_log.create_queue("pinned").await.unwrap();
+        let first_file_number = *multi_record_log.list_file_numbers().last().unwrap();
+        multi_record_log
+            .append_record("pinned", None, &b"pinned"[..])
+            .await
+            .unwrap();
+        for cycle in 30..300u64 {
+            multi_record_log
+                .append_record("queue-0", None, &[0u8; 1000][..])
+                .await
+                .unwrap();
+            multi_record_log.truncate("queue-0", cycle).await.unwrap();
+        }
+        assert_eq!(multi_record_log.list_file_numbers()[0], first_file_number);
+        assert!(multi_record_log.list_file_numbers().len() > 1);
+        multi_record_log.truncate("pinned", 0).await.unwrap();
+        assert_eq!(multi_record_log.list_file_numbers().len(), 1);
+    }
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    assert_eq!(multi_record_log.list_file_numbers().len(), 1);
+    assert_eq!(
+        multi_record_log.last_position("queue-0").unwrap(),
+        Some(299)
+    );
+    for queue_id in 1..10 {
+        let queue = format!("queue-{queue_id}");
+        assert_eq!(multi_record_log.last_position(&queue).unwrap(), Some(29));
+    }
+    assert_eq!(multi_record_log.last_position("pinned").unwrap(), Some(0));
+}
+
+#[tokio::test]
+async fn test_compact_queue() {
+    let tempdir = tempfile::tempdir().unwrap();
+    {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        multi_record_log.create_queue("hot").await.unwrap();
+        multi_record_log.create_queue("filler").await.unwrap();
+        multi_record_log.create_queue("other").await.unwrap();
+        // Each record of "hot" ends up in a different file, pinning all of them.
+        for cycle in 0..5u64 {
+            multi_record_log
+                .append_record_with_headers(
+                    "hot",
+                    None,
+                    &format!("hot-{cycle}").into_bytes()[..],
+                    &[("cycle", &cycle.to_le_bytes()[..])],
+                )
+                .await
+                .unwrap();
+            for _ in 0..150 {
+                let position = multi_record_log
+                    .append_record("filler", None, &[0u8; 1000][..])
+                    .await
+                    .unwrap()
+                    .unwrap();
+                multi_record_log.truncate("filler", position).await.unwrap();
+            }
+        }
+        multi_record_log
+            .append_record("other", None, &b"other"[..])
+            .await
+            .unwrap();
+        multi_record_log.truncate("hot", 1).await.unwrap();
+        let num_files = multi_record_log.list_file_numbers().len();
+        assert!(num_files >= 5);
+
+        let report = multi_record_log.compact_queue("hot").await.unwrap();
+        assert_eq!(report.num_records, 3);
+        assert_eq!(report.num_files_deleted, num_files - 1);
+        assert_eq!(multi_record_log.list_file_numbers().len(), 1);
+
+        assert_eq!(
+            multi_record_log.compact_queue("filler").await.unwrap(),
+            CompactReport::default()
+        );
+        assert!(matches!(
+            multi_record_log.compact_queue("missing").await,
+            Err(AppendError::MissingQueue(_))
+        ));
+    }
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    #[allow(clippy::type_complexity)]
+    let hot_records: Vec<(u64, Vec<(String, Vec<u8>)>, Vec<u8>)> = multi_record_log
+        .range_with_headers("hot", ..)
+        .unwrap()
+        .map(|(position, headers, payload)| (position, headers.to_vec(), payload.into_owned()))
+        .collect();
+    assert_eq!(hot_records.len(), 3);
+    for (cycle, (position, headers, payload)) in (2..5u64).zip(hot_records) {
+        assert_eq!(position, cycle);
+        assert_eq!(
+            headers,
+            vec![("cycle".to_string(), cycle.to_le_bytes().to_vec())]
+        );
+        assert_eq!(payload, format!("hot-{cycle}").into_bytes());
+    }
+    assert_eq!(multi_record_log.last_position("other").unwrap(), Some(0));
+    assert_eq!(multi_record_log.last_position("hot").unwrap(), Some(4));
+}
+
+#[tokio::test]
+async fn test_records_reference_their_first_file_across_rotation() {
+    // Records end up spanning files 0 and 1 somewhere around position 128. Truncating right
+    // before such a record must keep file 0, which holds its beginning.
+    for truncate_position in 120..140 {
+        let file_system = InMemoryFileSystem::default();
+        {
+            let mut multi_record_log =
+                MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+                    .await
+                    .unwrap();
+            multi_record_log.create_queue("queue").await.unwrap();
+            for position in 0..300u64 {
+                multi_record_log
+                    .append_record("queue", None, &[position as u8; 1000][..])
+                    .await
+                    .unwrap();
+            }
+            multi_record_log
+                .truncate("queue", truncate_position)
+                .await
+                .unwrap();
+        }
+        let multi_record_log =
+            MultiRecordLog::open_with_file_system(file_system, Options::default())
+                .await
+                .unwrap();
+        let records: Vec<(u64, Vec<u8>)> = multi_record_log
+            .range("queue", ..)
+            .unwrap()
+            .map(|(position, payload)| (position, payload.into_owned()))
+            .collect();
+        let expected_records: Vec<(u64, Vec<u8>)> = (truncate_position + 1..300)
+            .map(|position| (position, vec![position as u8; 1000]))
+            .collect();
+        assert_eq!(records, expected_records);
+    }
+}
+
+#[tokio::test]
+async fn test_append_bytes_payload() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    let payload = bytes::Bytes::from(vec![7u8; 10_000]);
+    multi_record_log
+        .append_record("queue", None, payload.clone())
+        .await
+        .unwrap();
+    // A payload made of several chunks is appended as a single record.
+    let chained_payload = bytes::Bytes::from_static(b"hello ").chain(&b"world"[..]);
+    multi_record_log
+        .append_record("queue", None, chained_payload)
+        .await
+        .unwrap();
+    drop(multi_record_log);
+
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    let records: Vec<(u64, Cow<[u8]>)> = multi_record_log.range("queue", ..).unwrap().collect();
+    assert_eq!(
+        records,
+        &[
+            (0, Cow::Borrowed(&payload[..])),
+            (1, Cow::Borrowed(&b"hello world"[..]))
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_queue_name_policy() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    multi_record_log
+        .create_queue("index/source-1")
+        .await
+        .unwrap();
+    multi_record_log.create_queue("émoji-🦀").await.unwrap();
+    for invalid_name in ["nul\0byte", "new\nline", "\u{7f}"] {
+        assert!(matches!(
+            multi_record_log.create_queue(invalid_name).await,
+            Err(CreateQueueError::InvalidName { .. })
+        ));
+        assert!(matches!(
+            multi_record_log
+                .create_queue_with_record(invalid_name, &b"payload"[..])
+                .await,
+            Err(CreateQueueError::InvalidName { .. })
+        ));
+        assert!(matches!(
+            multi_record_log
+                .create_queues(&["valid", invalid_name])
+                .await,
+            Err(CreateQueueError::InvalidName { .. })
+        ));
+        assert!(matches!(
+            multi_record_log
+                .append_record(invalid_name, None, &b"payload"[..])
+                .await,
+            Err(AppendError::MissingQueue(_))
+        ));
+    }
+    assert!(!multi_record_log.queue_exists("valid"));
+    let too_long_name = "a".repeat(u16::MAX as usize + 1);
+    assert!(matches!(
+        multi_record_log.create_queue(&too_long_name).await,
+        Err(CreateQueueError::InvalidName { .. })
+    ));
+    drop(multi_record_log);
+
+    let options = Options {
+        queue_name_policy: QueueNamePolicy::AllowAll,
+        ..Default::default()
+    };
+    let mut multi_record_log = MultiRecordLog::open_with_options(tempdir.path(), options)
+        .await
+        .unwrap();
+    multi_record_log.create_queue("new\nline").await.unwrap();
+    drop(multi_record_log);
+    // Existing queues are loaded whatever the policy.
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    assert!(multi_record_log.queue_exists("new\nline"));
+}
+
+#[tokio::test]
+async fn test_unknown_record_policy() {
+    let tempdir = tempfile::tempdir().unwrap();
+    {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        multi_record_log
+            .append_record("queue", None, &b"hello"[..])
+            .await
+            .unwrap();
+        // A record as a newer version could write it, with a type this version does not know.
+        let record_log_writer = multi_record_log.record_log_writer_for_test();
+        record_log_writer
+            .write_record("\u{7f}some record from the future")
+            .await
+            .unwrap();
+        record_log_writer.flush().await.unwrap();
+        multi_record_log
+            .append_record("queue", None, &b"happy"[..])
+            .await
+            .unwrap();
+    }
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    let payloads: Vec<Cow<[u8]>> = multi_record_log
+        .range("queue", ..)
+        .unwrap()
+        .map(|(_, payload)| payload)
+        .collect();
+    assert_eq!(payloads, [&b"hello"[..], &b"happy"[..]]);
+    drop(multi_record_log);
+
+    let options = Options {
+        unknown_record_policy: UnknownRecordPolicy::Fail,
+        ..Default::default()
+    };
+    assert!(matches!(
+        MultiRecordLog::open_with_options(tempdir.path(), options).await,
+        Err(ReadRecordError::UnknownRecordType { record_type: 0x7f })
+    ));
+}
+
+#[tokio::test]
+async fn test_list_queues_page() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    let mut expected_queues = HashSet::new();
+    for i in 0..250 {
+        let queue = format!("queue-{i}");
+        multi_record_log.create_queue(&queue).await.unwrap();
+        expected_queues.insert(queue);
+    }
+    let mut queues: Vec<&str> = Vec::new();
+    let mut after: Option<&str> = None;
+    loop {
+        let page = multi_record_log.list_queues_page(after, 40);
+        assert!(page.len() <= 40);
+        let Some(&last) = page.last() else {
+            break;
+        };
+        after = Some(last);
+        queues.extend(page);
+    }
+    assert!(queues.windows(2).all(|window| window[0] < window[1]));
+    assert_eq!(queues.len(), expected_queues.len());
+    assert_eq!(
+        queues
+            .into_iter()
+            .map(str::to_string)
+            .collect::<HashSet<_>>(),
+        expected_queues
+    );
+
+    // The cursor does not need to be the name of an existing queue.
+    multi_record_log.delete_queue("queue-130").await.unwrap();
+    assert_eq!(
+        multi_record_log.list_queues_page(Some("queue-130"), 2),
+        ["queue-131", "queue-132"]
+    );
+}
+
+#[tokio::test]
+async fn test_delete_queue_durable_before_gc() {
+    let file_system = InMemoryFileSystem::default();
+    let mut multi_record_log =
+        MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+            .await
+            .unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    multi_record_log
+        .append_records("queue", None, std::iter::repeat(&[0u8; 1000][..]).take(300))
+        .await
+        .unwrap();
+    multi_record_log.create_queue("other").await.unwrap();
+    multi_record_log
+        .append_record("other", None, &b"hello"[..])
+        .await
+        .unwrap();
+    assert_eq!(multi_record_log.list_file_numbers(), &[0, 1, 2]);
+    multi_record_log.delete_queue("queue").await.unwrap();
+    assert_eq!(multi_record_log.list_file_numbers(), &[2]);
+
+    // Crash right before the files of the deleted queue get removed: the queue deletion must
+    // already be durable, or the queue would come back on reopen.
+    let crashed_file_system = file_system
+        .crashed_before_removal
+        .lock()
+        .unwrap()
+        .take()
+        .unwrap();
+    let multi_record_log =
+        MultiRecordLog::open_with_file_system(crashed_file_system, Options::default())
+            .await
+            .unwrap();
+    assert!(!multi_record_log.queue_exists("queue"));
+    assert_eq!(multi_record_log.range("other", ..).unwrap().count(), 1);
+}
+
+#[tokio::test]
+async fn test_empty_payload() {
+    let tempdir = tempfile::tempdir().unwrap();
+    {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        multi_record_log
+            .append_record("queue", None, &b""[..])
+            .await
+            .unwrap();
+        multi_record_log
+            .append_records("queue", None, [&b""[..], b"hello", b""].into_iter())
+            .await
+            .unwrap();
+    }
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    let records: Vec<(u64, Cow<[u8]>)> = multi_record_log.range("queue", ..).unwrap().collect();
+    assert_eq!(
+        records,
+        [
+            (0, Cow::Borrowed(&b""[..])),
+            (1, Cow::Borrowed(&b""[..])),
+            (2, Cow::Borrowed(&b"hello"[..])),
+            (3, Cow::Borrowed(&b""[..])),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_reject_empty_payloads() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let options = Options {
+        reject_empty_payloads: true,
+        ..Default::default()
+    };
+    let mut multi_record_log = MultiRecordLog::open_with_options(tempdir.path(), options)
+        .await
+        .unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    assert!(matches!(
+        multi_record_log.validate_append("queue", None, &b""[..]),
+        Err(AppendError::EmptyPayload)
+    ));
+    assert!(matches!(
+        multi_record_log
+            .append_record("queue", None, &b""[..])
+            .await,
+        Err(AppendError::EmptyPayload)
+    ));
+    assert!(matches!(
+        multi_record_log
+            .append_records("queue", None, [&b"hello"[..], b""].into_iter())
+            .await,
+        Err(AppendError::EmptyPayload)
+    ));
+    assert!(matches!(
+        multi_record_log
+            .append_records_at("queue", [(0, &b""[..])].into_iter())
+            .await,
+        Err(AppendError::EmptyPayload)
+    ));
+    assert_eq!(
+        multi_record_log
+            .append_record("queue", None, &b"hello"[..])
+            .await
+            .unwrap(),
+        Some(0)
+    );
+    assert_eq!(multi_record_log.range("queue", ..).unwrap().count(), 1);
+
+    assert!(matches!(
+        multi_record_log
+            .create_queue_with_record("other", &b""[..])
+            .await,
+        Err(CreateQueueError::EmptyPayload)
+    ));
+    assert!(multi_record_log.last_position("other").is_err());
+    multi_record_log
+        .create_queue_with_record("other", &b"hello"[..])
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_queues_referencing_file() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    multi_record_log.create_queue("queue1").await.unwrap();
+    multi_record_log.create_queue("queue2").await.unwrap();
+    multi_record_log
+        .append_record("queue2", None, &b"hello"[..])
+        .await
+        .unwrap();
+    for _ in 0..300 {
+        multi_record_log
+            .append_record("queue1", None, &[0u8; 1000][..])
+            .await
+            .unwrap();
+    }
+    multi_record_log
+        .append_record("queue2", None, &b"happy"[..])
+        .await
+        .unwrap();
+    multi_record_log.truncate("queue1", 49).await.unwrap();
+    assert_eq!(multi_record_log.list_file_numbers(), &[0, 1, 2]);
+
+    fn sorted_references(
+        multi_record_log: &MultiRecordLog,
+        file_number: u64,
+    ) -> Vec<(&str, std::ops::Range<u64>)> {
+        let mut references = multi_record_log.queues_referencing_file(file_number);
+        references.sort_by_key(|(queue, _)| *queue);
+        references
+    }
+    let references_0 = sorted_references(&multi_record_log, 0);
+    let references_1 = sorted_references(&multi_record_log, 1);
+    let references_2 = sorted_references(&multi_record_log, 2);
+    assert_eq!(references_0.len(), 2);
+    let (_, queue1_range_0) = references_0[0].clone();
+    assert_eq!(references_0[1], ("queue2", 0..1));
+    assert_eq!(queue1_range_0.start, 50);
+    assert_eq!(references_1.len(), 1);
+    let (_, queue1_range_1) = references_1[0].clone();
+    assert_eq!(queue1_range_1.start, queue1_range_0.end);
+    assert_eq!(references_2.len(), 2);
+    assert_eq!(references_2[0], ("queue1", queue1_range_1.end..300));
+    assert_eq!(references_2[1], ("queue2", 1..2));
+
+    // Once the queues are truncated past the first file, it gets deleted.
+    multi_record_log
+        .truncate("queue1", queue1_range_0.end - 1)
+        .await
+        .unwrap();
+    assert_eq!(sorted_references(&multi_record_log, 0), [("queue2", 0..1)]);
+    multi_record_log.truncate("queue2", 0).await.unwrap();
+    assert_eq!(multi_record_log.list_file_numbers(), &[1, 2]);
+    assert!(multi_record_log.queues_referencing_file(0).is_empty());
+}
+
+#[tokio::test]
+async fn test_sync_deadline() {
+    let file_system = InMemoryFileSystem::default();
+    let options = Options {
+        sync_policy: SyncPolicy::OnDelay(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let mut multi_record_log = MultiRecordLog::open_with_file_system(file_system.clone(), options)
+        .await
+        .unwrap();
+    assert!(multi_record_log.sync_deadline().is_none());
+    multi_record_log.create_queue("queue").await.unwrap();
+    multi_record_log
+        .append_records("queue", None, [&b"hello"[..], b"happy", b"tax"].into_iter())
+        .await
+        .unwrap();
+    let sync_deadline = multi_record_log.sync_deadline().unwrap();
+    assert!(sync_deadline <= Instant::now() + Duration::from_millis(100));
+    let num_records_after_crash = |file_system: &InMemoryFileSystem| {
+        let file_system = file_system.crash();
+        async move {
+            let multi_record_log =
+                MultiRecordLog::open_with_file_system(file_system, Options::default())
+                    .await
+                    .unwrap();
+            multi_record_log
+                .range("queue", ..)
+                .map_or(0, |records| records.count())
+        }
+    };
+    assert_eq!(num_records_after_crash(&file_system).await, 0);
+
+    tokio::time::sleep_until(sync_deadline.into()).await;
+    multi_record_log.sync().await.unwrap();
+    assert!(multi_record_log.sync_deadline().is_none());
+    assert_eq!(num_records_after_crash(&file_system).await, 3);
+
+    // The delay starts again from the sync: the next append is not flushed right away.
+    multi_record_log
+        .append_record("queue", None, &b"hello"[..])
+        .await
+        .unwrap();
+    assert_eq!(multi_record_log.pending_unflushed().records, 1);
+    assert!(multi_record_log.sync_deadline().unwrap() > Instant::now());
+}
+
+#[tokio::test]
+async fn test_crash_while_writing_truncate() {
+    let file_system = InMemoryFileSystem::default();
+    let mut multi_record_log =
+        MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+            .await
+            .unwrap();
+    // An empty queue whose position gets recorded right after the truncate, before the files
+    // are garbage collected.
+    multi_record_log.create_queue("empty").await.unwrap();
+    multi_record_log
+        .append_record("empty", None, &b"hello"[..])
+        .await
+        .unwrap();
+    multi_record_log.truncate("empty", 0).await.unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    multi_record_log
+        .append_records("queue", None, std::iter::repeat(&[0u8; 1000][..]).take(300))
+        .await
+        .unwrap();
+    assert_eq!(multi_record_log.list_file_numbers(), &[0, 1, 2]);
+
+    let file_system_before = file_system.crash();
+    let last_filename = FileNumber::for_test(2).filename();
+    let last_file_data = |file_system: &InMemoryFileSystem| {
+        let files = file_system.files.lock().unwrap();
+        let data = files[&last_filename].lock().unwrap().clone();
+        data
+    };
+    let mut data_before = last_file_data(&file_system_before);
+    multi_record_log.truncate("queue", 299).await.unwrap();
+    assert_eq!(multi_record_log.list_file_numbers(), &[2]);
+    let data_after = last_file_data(&file_system);
+    data_before.resize(data_after.len(), 0u8);
+    let first_written = data_before
+        .iter()
+        .zip(&data_after)
+        .position(|(before, after)| before != after)
+        .unwrap();
+    let end_written = data_before
+        .iter()
+        .zip(&data_after)
+        .rposition(|(before, after)| before != after)
+        .unwrap()
+        + 1;
+
+    // Crash after writing each prefix of the records written by the truncate, before the first
+    // files get deleted.
+    for crash_offset in first_written..=end_written {
+        let crashed_file_system = file_system_before.crash();
+        let mut crashed_data = data_after[..crash_offset].to_vec();
+        crashed_data.extend_from_slice(&data_before[crash_offset..]);
+        *crashed_file_system.files.lock().unwrap()[&last_filename]
+            .lock()
+            .unwrap() = crashed_data;
+        let multi_record_log =
+            MultiRecordLog::open_with_file_system(crashed_file_system, Options::default())
+                .await
+                .unwrap();
+        let num_records = multi_record_log.range("queue", ..).unwrap().count();
+        // Either before or after the truncate, never in between.
+        assert!(
+            num_records == 300 || num_records == 0,
+            "{num_records} records after crash at offset {crash_offset}"
+        );
+        if crash_offset == end_written {
+            assert_eq!(num_records, 0);
+        }
+        assert_eq!(multi_record_log.last_position("queue").unwrap(), Some(299));
+        assert_eq!(multi_record_log.range("empty", ..).unwrap().count(), 0);
+        assert_eq!(multi_record_log.last_position("empty").unwrap(), Some(0));
+    }
+}
+
+#[tokio::test]
+async fn test_typed_multi_record_log() {
+    #[derive(Debug, PartialEq)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("expected 8 bytes, got {0}")]
+    struct InvalidPoint(usize);
+
+    impl Record for Point {
+        type Error = InvalidPoint;
+
+        fn to_bytes(&self, buffer: &mut Vec<u8>) {
+            buffer.extend_from_slice(&self.x.to_le_bytes());
+            buffer.extend_from_slice(&self.y.to_le_bytes());
+        }
+
+        fn from_bytes(bytes: &[u8]) -> Result<Self, InvalidPoint> {
+            let bytes: [u8; 8] = bytes.try_into().map_err(|_| InvalidPoint(bytes.len()))?;
+            Ok(Point {
+                x: i32::from_le_bytes(bytes[..4].try_into().unwrap()),
+                y: i32::from_le_bytes(bytes[4..].try_into().unwrap()),
+            })
+        }
+    }
+
+    let tempdir = tempfile::tempdir().unwrap();
+    {
+        let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        let mut typed_log: TypedMultiRecordLog<Point> = TypedMultiRecordLog::new(multi_record_log);
+        typed_log.inner_mut().create_queue("queue").await.unwrap();
+        assert_eq!(
+            typed_log
+                .append("queue", None, &Point { x: 1, y: -2 })
+                .await
+                .unwrap(),
+            Some(0)
+        );
+        typed_log
+            .append("queue", None, &Point { x: 3, y: 4 })
+            .await
+            .unwrap();
+        // A payload which is not a point, appended through the underlying log.
+        typed_log
+            .inner_mut()
+            .append_record("queue", None, &b"hello"[..])
+            .await
+            .unwrap();
+    }
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    let typed_log: TypedMultiRecordLog<Point> = TypedMultiRecordLog::new(multi_record_log);
+    let mut records = typed_log.range("queue", ..).unwrap();
+    let (position, point) = records.next().unwrap();
+    assert_eq!((position, point.unwrap()), (0, Point { x: 1, y: -2 }));
+    let (position, point) = records.next().unwrap();
+    assert_eq!((position, point.unwrap()), (1, Point { x: 3, y: 4 }));
+    let (position, point) = records.next().unwrap();
+    assert_eq!(position, 2);
+    assert_eq!(point.unwrap_err().to_string(), "expected 8 bytes, got 5");
+    assert!(records.next().is_none());
+    assert!(typed_log.range("missing", ..).is_err());
+}
+
+#[tokio::test]
+async fn test_sync_barrier() {
+    let file_system = InMemoryFileSystem::default();
+    let options = Options {
+        sync_policy: SyncPolicy::OnDelay(Duration::from_secs(3600)),
+        ..Default::default()
+    };
+    let mut multi_record_log = MultiRecordLog::open_with_file_system(file_system.clone(), options)
+        .await
+        .unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    for _ in 0..300 {
+        multi_record_log
+            .append_record("queue", None, &[0u8; 1000][..])
+            .await
+            .unwrap();
+    }
+    multi_record_log
+        .append_record("queue", None, &b"hello"[..])
+        .await
+        .unwrap();
+    multi_record_log.sync().await.unwrap();
+    assert_eq!(multi_record_log.list_file_numbers(), &[0, 1, 2]);
+    let num_records_after_power_loss = |file_system: &InMemoryFileSystem| {
+        let file_system = file_system.power_loss();
+        async move {
+            let multi_record_log =
+                MultiRecordLog::open_with_file_system(file_system, Options::default())
+                    .await
+                    .unwrap();
+            multi_record_log
+                .range("queue", ..)
+                .map_or(0, |records| records.count())
+        }
+    };
+    // The files the log moved on from are persisted, but not the last one, which was only
+    // flushed.
+    let num_records = num_records_after_power_loss(&file_system).await;
+    assert!(num_records > 0 && num_records < 301, "{num_records}");
+
+    multi_record_log.sync_barrier().await.unwrap();
+    assert_eq!(num_records_after_power_loss(&file_system).await, 301);
+}
+
+#[tokio::test]
+async fn test_range_chunked_owned() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    for position in 0..10u64 {
+        multi_record_log
+            .append_record("queue", None, &position.to_le_bytes()[..])
+            .await
+            .unwrap();
+    }
+    assert!(multi_record_log
+        .range_chunked_owned("missing", .., 4)
+        .is_err());
+    let mut cursor = multi_record_log
+        .range_chunked_owned("queue", 2.., 4)
+        .unwrap();
+    let chunk_positions = |chunk: Vec<(u64, Vec<u8>)>| -> Vec<u64> {
+        chunk
+            .into_iter()
+            .map(|(position, payload)| {
+                assert_eq!(payload, position.to_le_bytes());
+                position
+            })
+            .collect()
+    };
+    assert_eq!(
+        chunk_positions(cursor.next_chunk(&multi_record_log).unwrap()),
+        [2, 3, 4, 5]
+    );
+    // The log can be written to between chunks.
+    for position in 10..12u64 {
+        multi_record_log
+            .append_record("queue", None, &position.to_le_bytes()[..])
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        chunk_positions(cursor.next_chunk(&multi_record_log).unwrap()),
+        [6, 7, 8, 9]
+    );
+    multi_record_log.truncate("queue", 10).await.unwrap();
+    assert_eq!(
+        chunk_positions(cursor.next_chunk(&multi_record_log).unwrap()),
+        [11]
+    );
+    assert!(cursor.next_chunk(&multi_record_log).unwrap().is_empty());
+    multi_record_log
+        .append_record("queue", None, &12u64.to_le_bytes()[..])
+        .await
+        .unwrap();
+    assert_eq!(
+        chunk_positions(cursor.next_chunk(&multi_record_log).unwrap()),
+        [12]
+    );
+}
+
+#[tokio::test]
+async fn test_strict_file_attribution() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let strict_options = || Options {
+        strict_file_attribution: true,
+        ..Default::default()
+    };
+    {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        for _ in 0..300 {
+            multi_record_log
+                .append_record("queue", None, &[0u8; 1000][..])
+                .await
+                .unwrap();
+        }
+        assert_eq!(multi_record_log.list_file_numbers(), &[0, 1, 2]);
+    }
+    MultiRecordLog::open_with_options(tempdir.path(), strict_options())
+        .await
+        .unwrap();
+    // Mis-assemble the files: file 2 gets the content of file 1.
+    std::fs::copy(
+        tempdir.path().join(FileNumber::for_number(1).filename()),
+        tempdir.path().join(FileNumber::for_number(2).filename()),
+    )
+    .unwrap();
+    assert!(matches!(
+        MultiRecordLog::open_with_options(tempdir.path(), strict_options()).await,
+        Err(ReadRecordError::FileNumberMismatch {
+            file_number: 2,
+            header_file_number: 1
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_stats() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    multi_record_log.create_queue("queue1").await.unwrap();
+    multi_record_log.create_queue("queue2").await.unwrap();
+    multi_record_log.create_queue("queue3").await.unwrap();
+    multi_record_log
+        .append_record("queue2", None, &b"hello"[..])
+        .await
+        .unwrap();
+    for _ in 0..300 {
+        multi_record_log
+            .append_record("queue1", None, &[0u8; 1000][..])
+            .await
+            .unwrap();
+    }
+    multi_record_log.truncate("queue1", 99).await.unwrap();
+
+    let stats = multi_record_log.stats();
+    assert_eq!(stats.num_queues, 3);
+    assert_eq!(stats.num_records, 201);
+    assert_eq!(stats.payload_bytes, 200 * 1000 + 5);
+    assert_eq!(stats.disk_bytes, multi_record_log.disk_usage());
+    assert_eq!(stats.num_files, 3);
+    assert_eq!(stats.file_number_range, Some(0..=2));
+    let queue_names: Vec<&str> = stats.queues.iter().map(|queue| &queue.queue[..]).collect();
+    assert_eq!(queue_names, &["queue1", "queue2", "queue3"]);
+    assert_eq!(stats.queues[0].live_range, 100..300);
+    assert_eq!(stats.queues[1].live_range, 0..1);
+    assert_eq!(stats.queues[2].num_records, 0);
+    assert_eq!(
+        stats
+            .queues
+            .iter()
+            .map(|queue| queue.num_records)
+            .sum::<usize>(),
+        stats.num_records
+    );
+    assert_eq!(
+        stats
+            .queues
+            .iter()
+            .map(|queue| queue.payload_bytes)
+            .sum::<usize>(),
+        stats.payload_bytes
+    );
+    let memory_usage = multi_record_log.memory_usage();
+    assert_eq!(memory_usage.num_records, stats.num_records);
+    assert_eq!(memory_usage.payload_bytes, stats.payload_bytes);
+}
+
+#[tokio::test]
+async fn test_compact_multi_records() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let compact_options = || Options {
+        compact_multi_records: true,
+        ..Default::default()
+    };
+    {
+        // Records written in the full format, before the option is set.
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        multi_record_log
+            .append_records("queue", None, [&b"full1"[..], &b"full2"[..]].into_iter())
+            .await
+            .unwrap();
+    }
+    {
+        let mut multi_record_log =
+            MultiRecordLog::open_with_options(tempdir.path(), compact_options())
+                .await
+                .unwrap();
+        let payloads: Vec<Vec<u8>> = (0..1_000u32).map(|i| i.to_le_bytes().to_vec()).collect();
+        multi_record_log
+            .append_records("queue", None, payloads.iter().map(|payload| &payload[..]))
+            .await
+            .unwrap();
+        multi_record_log
+            .append_record("queue", Some(1_002), &b"compact"[..])
+            .await
+            .unwrap();
+    }
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    let records: Vec<(u64, Vec<u8>)> = multi_record_log
+        .range("queue", ..)
+        .unwrap()
+        .map(|(position, payload)| (position, payload.into_owned()))
+        .collect();
+    assert_eq!(records.len(), 1_003);
+    assert_eq!(records[0], (0, b"full1".to_vec()));
+    assert_eq!(records[1], (1, b"full2".to_vec()));
+    for (i, (position, payload)) in records[2..1_002].iter().enumerate() {
+        assert_eq!(*position, i as u64 + 2);
+        assert_eq!(payload, &(i as u32).to_le_bytes());
+    }
+    assert_eq!(records[1_002], (1_002, b"compact".to_vec()));
+}
+
+#[tokio::test]
+async fn test_extract_queue() {
+    use crate::TokioFileSystem;
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let dest_tempdir = tempfile::tempdir().unwrap();
+    let dest_file_system = || TokioFileSystem::new(dest_tempdir.path());
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    multi_record_log.create_queue("other").await.unwrap();
+    multi_record_log.create_queue("empty").await.unwrap();
+    for i in 0..10u8 {
+        multi_record_log
+            .append_record("queue", None, &[i][..])
+            .await
+            .unwrap();
+    }
+    multi_record_log
+        .append_record_with_headers("queue", None, &b"last"[..], &[("key", &b"value"[..])])
+        .await
+        .unwrap();
+    multi_record_log
+        .append_record("other", None, &b"other"[..])
+        .await
+        .unwrap();
+    multi_record_log
+        .append_record("empty", None, &b"truncated"[..])
+        .await
+        .unwrap();
+    multi_record_log.truncate("queue", 3).await.unwrap();
+    multi_record_log.truncate("empty", 0).await.unwrap();
+
+    fn records_with_headers(
+        multi_record_log: &MultiRecordLog,
+        queue: &str,
+    ) -> Vec<(u64, RecordHeaders, Vec<u8>)> {
+        multi_record_log
+            .range_with_headers(queue, ..)
+            .unwrap()
+            .map(|(position, headers, payload)| (position, headers.to_vec(), payload.into_owned()))
+            .collect()
+    }
+    let expected_records = records_with_headers(&multi_record_log, "queue");
+    assert_eq!(expected_records.len(), 7);
+
+    multi_record_log
+        .extract_queue("queue", dest_file_system(), Options::default())
+        .await
+        .unwrap();
+    multi_record_log
+        .extract_queue("empty", dest_file_system(), Options::default())
+        .await
+        .unwrap();
+    assert!(matches!(
+        multi_record_log
+            .extract_queue("queue", dest_file_system(), Options::default())
+            .await,
+        Err(ExtractError::MissingQueue(_))
+    ));
+    assert_eq!(
+        multi_record_log.list_queues().collect::<Vec<_>>(),
+        &["other"]
+    );
+    drop(multi_record_log);
+
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    assert_eq!(
+        multi_record_log.list_queues().collect::<Vec<_>>(),
+        &["other"]
+    );
+    let mut dest_log = MultiRecordLog::open(dest_tempdir.path()).await.unwrap();
+    let mut dest_queues: Vec<&str> = dest_log.list_queues().collect();
+    dest_queues.sort();
+    assert_eq!(dest_queues, &["empty", "queue"]);
+    assert_eq!(records_with_headers(&dest_log, "queue"), expected_records);
+    assert_eq!(dest_log.range("empty", ..).unwrap().count(), 0);
+    // Positions go on from the source log.
+    assert_eq!(
+        dest_log
+            .append_record("queue", None, &b"next"[..])
+            .await
+            .unwrap(),
+        Some(11)
+    );
+    assert_eq!(
+        dest_log
+            .append_record("empty", None, &b"next"[..])
+            .await
+            .unwrap(),
+        Some(1)
+    );
+    drop(dest_log);
+
+    // The destination must not have the queue already.
+    let mut multi_record_log = multi_record_log;
+    multi_record_log.create_queue("queue").await.unwrap();
+    assert!(matches!(
+        multi_record_log
+            .extract_queue("queue", dest_file_system(), Options::default())
+            .await,
+        Err(ExtractError::AlreadyExists)
+    ));
+    assert!(multi_record_log.queue_exists("queue"));
+
+    // The destination log may use another file system and other options.
+    let in_mem_file_system = InMemoryFileSystem::default();
+    let dest_options = || Options {
+        initial_file_number: 5,
+        ..Default::default()
+    };
+    multi_record_log
+        .extract_queue("other", in_mem_file_system.clone(), dest_options())
+        .await
+        .unwrap();
+    assert!(!multi_record_log.queue_exists("other"));
+    let dest_log =
+        MultiRecordLog::open_with_file_system(in_mem_file_system.clone(), dest_options())
+            .await
+            .unwrap();
+    assert_eq!(read_all_records(&dest_log, "other"), &[b"other".to_vec()]);
+    assert_eq!(dest_log.list_file_numbers(), &[5]);
+}
+
+/// Reads the records following the cursor, returning the positions appended to `queue` and the
+/// cursor following them.
+async fn read_appended_positions(
+    mut record_reader: RecordReader<RollingReader>,
+    queue: &str,
+) -> (Vec<u64>, (u64, u64)) {
+    let mut positions = Vec::new();
+    while let Some(record) = record_reader
+        .read_record::<MultiPlexedRecord>()
+        .await
+        .unwrap()
+    {
+        if let MultiPlexedRecord::AppendRecords {
+            queue: appended_queue,
+            records,
+            ..
+        } = record
+        {
+            if appended_queue == queue {
+                positions.extend(records.map(|record| record.unwrap().0));
+            }
+        }
+    }
+    (positions, record_reader.cursor())
+}
+
+#[tokio::test]
+async fn test_record_reader_resume_from() {
+    let tempdir = tempfile::tempdir().unwrap();
+    {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        for _ in 0..300 {
+            multi_record_log
+                .append_record("queue", None, &[1u8; 1000][..])
+                .await
+                .unwrap();
+        }
+    }
+    let rolling_reader = RollingReader::open(tempdir.path()).await.unwrap();
+    let (positions, cursor) =
+        read_appended_positions(RecordReader::open(rolling_reader), "queue").await;
+    assert_eq!(positions, (0..300).collect::<Vec<u64>>());
+    assert_eq!(cursor.0, 2);
+    {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        for _ in 0..5 {
+            multi_record_log
+                .append_record("queue", None, &[1u8; 1000][..])
+                .await
+                .unwrap();
+        }
+    }
+    let rolling_reader = RollingReader::open(tempdir.path()).await.unwrap();
+    let record_reader = RecordReader::resume_from(rolling_reader, cursor.0, cursor.1)
+        .await
+        .unwrap();
+    let (positions, next_cursor) = read_appended_positions(record_reader, "queue").await;
+    assert_eq!(positions, (300..305).collect::<Vec<u64>>());
+
+    // Nothing new since the last cursor.
+    let rolling_reader = RollingReader::open(tempdir.path()).await.unwrap();
+    let record_reader = RecordReader::resume_from(rolling_reader, next_cursor.0, next_cursor.1)
+        .await
+        .unwrap();
+    let (positions, _) = read_appended_positions(record_reader, "queue").await;
+    assert!(positions.is_empty());
+
+    // The cursor must be at a record boundary, in an existing file.
+    let rolling_reader = RollingReader::open(tempdir.path()).await.unwrap();
+    assert!(matches!(
+        RecordReader::resume_from(rolling_reader, cursor.0, cursor.1 + 1).await,
+        Err(ReadRecordError::InvalidCursor { .. })
+    ));
+    let rolling_reader = RollingReader::open(tempdir.path()).await.unwrap();
+    assert!(matches!(
+        RecordReader::resume_from(rolling_reader, cursor.0, u64::MAX).await,
+        Err(ReadRecordError::InvalidCursor { .. })
+    ));
+    let rolling_reader = RollingReader::open(tempdir.path()).await.unwrap();
+    assert!(matches!(
+        RecordReader::resume_from(rolling_reader, 10, 0).await,
+        Err(ReadRecordError::InvalidCursor { .. })
+    ));
+
+    // The file of a cursor at the start of the log gets garbage collected.
+    {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        multi_record_log.truncate("queue", 304).await.unwrap();
+        assert_eq!(multi_record_log.list_file_numbers(), &[2]);
+    }
+    let rolling_reader = RollingReader::open(tempdir.path()).await.unwrap();
+    assert!(matches!(
+        RecordReader::resume_from(rolling_reader, 0, 0).await,
+        Err(ReadRecordError::CursorBehindGc {
+            file_number: 0,
+            first_file_number: 2,
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_append_position_overflow() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    // Positions in the future are accepted, which seeds the queue near the limit.
+    multi_record_log
+        .append_record("queue", Some(u64::MAX - 3), &b"hello"[..])
+        .await
+        .unwrap();
+    assert!(matches!(
+        multi_record_log
+            .append_records("queue", None, [&b"a"[..], &b"b"[..], &b"c"[..]].into_iter())
+            .await,
+        Err(AppendError::PositionOverflow)
+    ));
+    assert!(matches!(
+        multi_record_log
+            .append_record("queue", Some(u64::MAX), &b"max"[..])
+            .await,
+        Err(AppendError::PositionOverflow)
+    ));
+    assert_eq!(
+        multi_record_log
+            .append_records("queue", None, [&b"a"[..], &b"b"[..]].into_iter())
+            .await
+            .unwrap(),
+        Some(u64::MAX - 1)
+    );
+    assert!(matches!(
+        multi_record_log
+            .append_record("queue", None, &b"overflow"[..])
+            .await,
+        Err(AppendError::PositionOverflow)
+    ));
+    // Appending the last record again is still detected as a retry.
+    assert_eq!(
+        multi_record_log
+            .append_record("queue", Some(u64::MAX - 1), &b"b"[..])
+            .await
+            .unwrap(),
+        None
+    );
+    drop(multi_record_log);
+
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    assert_eq!(
+        multi_record_log
+            .range("queue", ..)
+            .unwrap()
+            .map(|(position, _)| position)
+            .collect::<Vec<u64>>(),
+        &[u64::MAX - 3, u64::MAX - 2, u64::MAX - 1]
+    );
+}
+
+#[tokio::test]
+async fn test_queue_history() {
+    let tempdir = tempfile::tempdir().unwrap();
+    {
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        multi_record_log.create_queue("other").await.unwrap();
+        multi_record_log
+            .append_record("other", None, &b"other"[..])
+            .await
+            .unwrap();
+        multi_record_log
+            .append_records("queue", None, [&b"a"[..], &b"b"[..]].into_iter())
+            .await
+            .unwrap();
+        multi_record_log
+            .append_record("other", None, &b"other"[..])
+            .await
+            .unwrap();
+        multi_record_log
+            .append_record_with_headers("queue", None, &b"c"[..], &[("key", &b"value"[..])])
+            .await
+            .unwrap();
+        multi_record_log.truncate("queue", 0).await.unwrap();
+        multi_record_log.truncate("other", 0).await.unwrap();
+        multi_record_log.compact_queue("queue").await.unwrap();
+        multi_record_log.delete_queue("queue").await.unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        multi_record_log.delete_queue("other").await.unwrap();
+    }
+    assert_eq!(
+        queue_history(tempdir.path(), "queue").await.unwrap(),
+        &[
+            QueueEvent::PositionRecorded { next_position: 0 },
+            QueueEvent::Append { positions: 0..2 },
+            QueueEvent::Append { positions: 2..3 },
+            QueueEvent::Truncate { position: 0 },
+            QueueEvent::Replace { positions: 1..3 },
+            QueueEvent::Delete { next_position: 3 },
+            QueueEvent::PositionRecorded { next_position: 0 },
+        ]
+    );
+    assert_eq!(
+        queue_history(tempdir.path(), "other").await.unwrap(),
+        &[
+            QueueEvent::PositionRecorded { next_position: 0 },
+            QueueEvent::Append { positions: 0..1 },
+            QueueEvent::Append { positions: 1..2 },
+            QueueEvent::Truncate { position: 0 },
+            QueueEvent::Delete { next_position: 2 },
+        ]
+    );
+    assert!(queue_history(tempdir.path(), "missing")
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Inspecting an empty directory does not create files in it.
+    let empty_dir = tempfile::tempdir().unwrap();
+    assert!(queue_history(empty_dir.path(), "queue")
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(std::fs::read_dir(empty_dir.path())
+        .unwrap()
+        .next()
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_queue_indexes_skip_files() {
+    let file_system = InMemoryFileSystem::default();
+    let options = |queues: &[&str]| Options {
+        queues_filter: Some(queues.iter().map(|queue| queue.to_string()).collect()),
+        write_queue_indexes: true,
+        ..Default::default()
+    };
+    let payload = |i: usize| vec![i as u8; 7_000 + i];
+    {
+        let mut multi_record_log = MultiRecordLog::open_with_file_system(
+            file_system.clone(),
+            Options {
+                write_queue_indexes: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        multi_record_log
+            .create_queues(&["target", "other"])
+            .await
+            .unwrap();
+        // The target queue only has records in the first file, in a file in the middle and in
+        // the last one. Records of the other queue span files.
+        for i in 0..150 {
+            if i % 100 == 0 {
+                multi_record_log
+                    .append_record("target", None, &b"target"[..])
+                    .await
+                    .unwrap();
+            }
+            multi_record_log
+                .append_record("other", None, &payload(i)[..])
+                .await
+                .unwrap();
+        }
+        multi_record_log
+            .append_record("target", None, &b"target"[..])
+            .await
+            .unwrap();
+        assert!(multi_record_log.list_file_numbers().len() >= 8);
+    }
+    let wal_file_names = || -> Vec<String> {
+        let mut file_names: Vec<String> = file_system
+            .files
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|file_name| file_name.starts_with("wal-"))
+            .cloned()
+            .collect();
+        file_names.sort();
+        file_names
+    };
+    let num_files = wal_file_names().len();
+    file_system.num_reads_by_file.lock().unwrap().clear();
+    {
+        let mut multi_record_log =
+            MultiRecordLog::open_with_file_system(file_system.clone(), options(&["target"]))
+                .await
+                .unwrap();
+        let num_reads_by_file = file_system.num_reads_by_file.lock().unwrap().clone();
+        // Skipped files only get their header read.
+        let num_skipped_files = wal_file_names()
+            .iter()
+            .filter(|file_name| num_reads_by_file[*file_name] == 1)
+            .count();
+        assert_eq!(num_skipped_files, num_files - 3);
+        assert_eq!(
+            multi_record_log
+                .range("target", ..)
+                .unwrap()
+                .map(|(position, _)| position)
+                .collect::<Vec<_>>(),
+            &[0, 1, 2]
+        );
+        // The queue may have been created in a skipped file.
+        assert!(matches!(
+            multi_record_log.create_queue("new").await,
+            Err(CreateQueueError::FilteredOut)
+        ));
+        // The other queue is not entirely known: files must not be garbage collected.
+        multi_record_log.truncate("target", 2).await.unwrap();
+        for _ in 0..20 {
+            multi_record_log
+                .append_record("target", None, &payload(0)[..])
+                .await
+                .unwrap();
+        }
+        multi_record_log.truncate("target", 22).await.unwrap();
+        assert_eq!(multi_record_log.list_file_numbers()[0], 0);
+    }
+    {
+        let multi_record_log =
+            MultiRecordLog::open_with_file_system(file_system.clone(), options(&["other"]))
+                .await
+                .unwrap();
+        let records: Vec<(u64, Cow<[u8]>)> = multi_record_log.range("other", ..).unwrap().collect();
+        assert_eq!(records.len(), 150);
+        for (i, (position, record)) in records.into_iter().enumerate() {
+            assert_eq!(position, i as u64);
+            assert_eq!(&record[..], &payload(i)[..]);
+        }
+    }
+    let mut multi_record_log =
+        MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+            .await
+            .unwrap();
+    assert_eq!(multi_record_log.range("other", ..).unwrap().count(), 150);
+    multi_record_log.delete_queue("other").await.unwrap();
+    multi_record_log.truncate("target", 22).await.unwrap();
+    // The queue indexes are deleted along with their files.
+    let file_numbers = multi_record_log.list_file_numbers();
+    for file_name in file_system.files.lock().unwrap().keys() {
+        if let Some(file_number) = file_name.strip_prefix("queues-") {
+            assert!(file_numbers.contains(&file_number.parse().unwrap()));
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_queue_indexes_record_spanning_files_before_reopen() {
+    let file_system = InMemoryFileSystem::default();
+    let options = || Options {
+        write_queue_indexes: true,
+        ..Default::default()
+    };
+    {
+        let mut multi_record_log =
+            MultiRecordLog::open_with_file_system(file_system.clone(), options())
+                .await
+                .unwrap();
+        multi_record_log.create_queues(&["a", "b"]).await.unwrap();
+        for _ in 0..12 {
+            multi_record_log
+                .append_record("b", None, &[0u8; 10_000][..])
+                .await
+                .unwrap();
+        }
+        // The record starts in the first file and ends in the second one.
+        multi_record_log
+            .append_record("a", None, &[1u8; 30_000][..])
+            .await
+            .unwrap();
+        assert_eq!(multi_record_log.list_file_numbers(), &[0, 1]);
+    }
+    {
+        // The index of the second file is written by the writer resuming on open.
+        let mut multi_record_log =
+            MultiRecordLog::open_with_file_system(file_system.clone(), options())
+                .await
+                .unwrap();
+        for _ in 0..20 {
+            multi_record_log
+                .append_record("b", None, &[0u8; 10_000][..])
+                .await
+                .unwrap();
+        }
+        assert!(multi_record_log.list_file_numbers().len() > 2);
+    }
+    let multi_record_log = MultiRecordLog::open_with_file_system(
+        file_system,
+        Options {
+            queues_filter: Some(HashSet::from(["a".to_string()])),
+            ..options()
+        },
+    )
+    .await
+    .unwrap();
+    let records: Vec<(u64, Cow<[u8]>)> = multi_record_log.range("a", ..).unwrap().collect();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].0, 0);
+    assert_eq!(&records[0].1[..], &[1u8; 30_000][..]);
+}
+
+#[tokio::test]
+async fn test_truncate_keep_last() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    for i in 0..100u64 {
+        multi_record_log
+            .append_record("queue", None, &i.to_le_bytes()[..])
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        multi_record_log
+            .truncate_keep_last("queue", 10)
+            .await
+            .unwrap(),
+        90
+    );
+    assert_eq!(
+        multi_record_log
+            .range("queue", ..)
+            .unwrap()
+            .map(|(position, _)| position)
+            .collect::<Vec<u64>>(),
+        (90..100).collect::<Vec<u64>>()
+    );
+    // Keeping more records than the queue holds removes nothing.
+    assert_eq!(
+        multi_record_log
+            .truncate_keep_last("queue", 20)
+            .await
+            .unwrap(),
+        0
+    );
+    assert_eq!(
+        multi_record_log
+            .truncate_keep_last("queue", 0)
+            .await
+            .unwrap(),
+        10
+    );
+    assert_eq!(multi_record_log.range("queue", ..).unwrap().count(), 0);
+    assert!(matches!(
+        multi_record_log.truncate_keep_last("missing", 1).await,
+        Err(TruncateError::MissingQueue(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_commit_batch() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let options = Options {
+        io_retry: RetryPolicy::no_retry(),
+        ..Default::default()
+    };
+    {
+        let mut multi_record_log = MultiRecordLog::open_with_options(tempdir.path(), options)
+            .await
+            .unwrap();
+        multi_record_log
+            .create_queue_with_record("truncated", &b"old"[..])
+            .await
+            .unwrap();
+        multi_record_log.create_queue("deleted").await.unwrap();
+        let mut batch = Batch::new();
+        batch
+            .create_queue("appended")
+            .append_records("appended", [&b"hello"[..], &b"happy"[..]])
+            .append_record("truncated", &b"new"[..])
+            .truncate("truncated", 0)
+            .delete_queue("deleted");
+        assert_eq!(batch.len(), 5);
+        multi_record_log.commit_batch(&batch).await.unwrap();
+
+        // A batch is validated as a whole before anything gets written.
+        let mut batch = Batch::new();
+        batch
+            .append_record("appended", &b"ignored"[..])
+            .append_record("deleted", &b"ignored"[..]);
+        assert!(matches!(
+            multi_record_log.commit_batch(&batch).await,
+            Err(BatchError::Append {
+                index: 1,
+                error: AppendError::QueueDeleted(_)
+            })
+        ));
+        // Nothing is applied if writing the batch fails.
+        multi_record_log
+            .directory_for_test()
+            .inject_write_failures(1);
+        let mut batch = Batch::new();
+        batch
+            .create_queue("failed")
+            .append_record("appended", &b"ignored"[..]);
+        assert!(matches!(
+            multi_record_log.commit_batch(&batch).await,
+            Err(BatchError::IoError(_))
+        ));
+        assert!(!multi_record_log.queue_exists("failed"));
+        assert_eq!(multi_record_log.range("appended", ..).unwrap().count(), 2);
+    }
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    assert_eq!(
+        &read_all_records(&multi_record_log, "appended"),
+        &[b"hello".as_slice(), b"happy".as_slice()]
+    );
+    assert_eq!(
+        multi_record_log
+            .range("truncated", ..)
+            .unwrap()
+            .collect::<Vec<_>>(),
+        vec![(1, Cow::Borrowed(&b"new"[..]))]
+    );
+    assert!(!multi_record_log.queue_exists("deleted"));
+    assert!(!multi_record_log.queue_exists("failed"));
+}
+
+#[tokio::test]
+async fn test_key_index() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let options = || Options {
+        key_index: true,
+        ..Default::default()
+    };
+    {
+        let mut multi_record_log = MultiRecordLog::open_with_options(tempdir.path(), options())
+            .await
+            .unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        for (key, payload) in [
+            (&b"a"[..], &b"first"[..]),
+            (&b"b"[..], &b"second"[..]),
+            (&b"c"[..], &b"third"[..]),
+            (&b"a"[..], &b"fourth"[..]),
+        ] {
+            multi_record_log
+                .append_record_keyed("queue", key, payload)
+                .await
+                .unwrap();
+        }
+        multi_record_log
+            .append_record("queue", None, &b"unkeyed"[..])
+            .await
+            .unwrap();
+        assert_eq!(
+            multi_record_log.get_by_key("queue", b"b").unwrap(),
+            Some((1, Cow::Borrowed(&b"second"[..])))
+        );
+        // The last record appended with a key is returned.
+        assert_eq!(
+            multi_record_log.get_by_key("queue", b"a").unwrap(),
+            Some((3, Cow::Borrowed(&b"fourth"[..])))
+        );
+        assert_eq!(multi_record_log.get_by_key("queue", b"d").unwrap(), None);
+        assert!(multi_record_log.get_by_key("missing", b"a").is_err());
+        multi_record_log.truncate("queue", 1).await.unwrap();
+        assert_eq!(multi_record_log.get_by_key("queue", b"b").unwrap(), None);
+    }
+    let multi_record_log = MultiRecordLog::open_with_options(tempdir.path(), options())
+        .await
+        .unwrap();
+    assert_eq!(multi_record_log.get_by_key("queue", b"b").unwrap(), None);
+    assert_eq!(
+        multi_record_log.get_by_key("queue", b"a").unwrap(),
+        Some((3, Cow::Borrowed(&b"fourth"[..])))
+    );
+    assert_eq!(
+        multi_record_log.get_by_key("queue", b"c").unwrap(),
+        Some((2, Cow::Borrowed(&b"third"[..])))
+    );
+    drop(multi_record_log);
+    // Keys are only indexed if the option is set.
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    assert_eq!(multi_record_log.get_by_key("queue", b"a").unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_append_to_queues() {
+    let file_system = InMemoryFileSystem::default();
+    let queues: Vec<String> = (0..10).map(|i| format!("queue-{i}")).collect();
+    {
+        let mut multi_record_log =
+            MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+                .await
+                .unwrap();
+        for queue in &queues {
+            multi_record_log.create_queue(queue).await.unwrap();
+        }
+        let num_writes_before = file_system.num_writes.load(Ordering::Relaxed);
+        for queue in &queues {
+            multi_record_log
+                .append_record(queue, None, queue.as_bytes())
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            file_system.num_writes.load(Ordering::Relaxed),
+            num_writes_before + queues.len()
+        );
+
+        let num_writes_before = file_system.num_writes.load(Ordering::Relaxed);
+        let records = queues
+            .iter()
+            .map(|queue| (queue.as_str(), queue.as_bytes()))
+            .chain([("queue-0", &b"again"[..])]);
+        let positions = multi_record_log.append_to_queues(records).await.unwrap();
+        assert_eq!(positions, [vec![1; queues.len()], vec![2]].concat());
+        assert_eq!(
+            file_system.num_writes.load(Ordering::Relaxed),
+            num_writes_before + 1
+        );
+
+        // The records before the failing one are appended.
+        let records = [("queue-1", &b"appended"[..]), ("missing", &b"failed"[..])];
+        assert!(matches!(
+            multi_record_log.append_to_queues(records).await,
+            Err(AppendError::MissingQueue(_))
+        ));
+        assert_eq!(multi_record_log.last_position("queue-1").unwrap(), Some(2));
+    }
+    let multi_record_log =
+        MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+            .await
+            .unwrap();
+    assert_eq!(
+        &read_all_records(&multi_record_log, "queue-0"),
+        &[
+            b"queue-0".as_slice(),
+            b"queue-0".as_slice(),
+            b"again".as_slice()
+        ]
+    );
+    for queue in &queues[2..] {
+        assert_eq!(
+            &read_all_records(&multi_record_log, queue),
+            &[queue.as_bytes(), queue.as_bytes()]
+        );
+    }
+    assert_eq!(
+        &read_all_records(&multi_record_log, "queue-1"),
+        &[
+            b"queue-1".as_slice(),
+            b"queue-1".as_slice(),
+            b"appended".as_slice()
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_read_only_refresh() {
+    let file_system = InMemoryFileSystem::default();
+    let mut multi_record_log =
+        MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+            .await
+            .unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    multi_record_log
+        .append_record("queue", None, &b"first"[..])
+        .await
+        .unwrap();
+    let mut read_only_log =
+        ReadOnlyMultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+            .await
+            .unwrap();
+    assert_eq!(
+        &read_all_records_read_only(&read_only_log, "queue"),
+        &[(0, b"first".to_vec())]
+    );
+
+    multi_record_log
+        .append_record("queue", None, &b"second"[..])
+        .await
+        .unwrap();
+    multi_record_log.create_queue("other").await.unwrap();
+    // Records are only read on refresh.
+    assert_eq!(read_only_log.range("queue", ..).unwrap().count(), 1);
+    read_only_log.refresh().await.unwrap();
+    assert_eq!(
+        &read_all_records_read_only(&read_only_log, "queue"),
+        &[(0, b"first".to_vec()), (1, b"second".to_vec())]
+    );
+    assert!(read_only_log.queue_exists("other"));
+    // Refreshing without new records changes nothing.
+    read_only_log.refresh().await.unwrap();
+    assert_eq!(read_only_log.last_position("queue").unwrap(), Some(1));
+
+    // Records spanning several files, and truncations, are read too.
+    let payload = vec![7u8; 10_000];
+    for _ in 0..50 {
+        multi_record_log
+            .append_record("queue", None, &payload[..])
+            .await
+            .unwrap();
+    }
+    multi_record_log.truncate("queue", 0).await.unwrap();
+    read_only_log.refresh().await.unwrap();
+    assert_eq!(read_only_log.range("queue", ..).unwrap().count(), 51);
+    assert_eq!(read_only_log.last_position("queue").unwrap(), Some(51));
+
+    // Files garbage collected before being read make the log be loaded again.
+    for _ in 0..50 {
+        multi_record_log
+            .append_record("queue", None, &payload[..])
+            .await
+            .unwrap();
+    }
+    multi_record_log.truncate("queue", 100).await.unwrap();
+    multi_record_log.delete_queue("other").await.unwrap();
+    read_only_log.refresh().await.unwrap();
+    assert_eq!(
+        read_only_log
+            .range("queue", ..)
+            .unwrap()
+            .map(|(position, _)| position)
+            .collect::<Vec<u64>>(),
+        [101]
+    );
+    assert!(!read_only_log.queue_exists("other"));
+}
+
+#[tokio::test]
+async fn test_read_only_does_not_modify_files() {
+    let file_system = InMemoryFileSystem::default();
+    let mut read_only_log =
+        ReadOnlyMultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+            .await
+            .unwrap();
+    read_only_log.refresh().await.unwrap();
+    assert!(file_system.list_files().await.unwrap().is_empty());
+
+    let mut multi_record_log =
+        MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+            .await
+            .unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    multi_record_log
+        .append_record("queue", None, &b"first"[..])
+        .await
+        .unwrap();
+    read_only_log.refresh().await.unwrap();
+    assert_eq!(
+        &read_all_records_read_only(&read_only_log, "queue"),
+        &[(0, b"first".to_vec())]
+    );
+
+    // A sidecar of a deleted file, and the index of the file being written, are left as is.
+    let stale_file_names = [
+        SidecarId {
+            file_number: 7,
+            id: 0,
+        }
+        .filename(),
+        QueueIndex::filename(0),
+    ];
+    for stale_file_name in &stale_file_names {
+        file_system.create_file(stale_file_name).await.unwrap();
+    }
+    let mut file_names_before = file_system.list_files().await.unwrap();
+    file_names_before.sort();
+    let read_only_log =
+        ReadOnlyMultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+            .await
+            .unwrap();
+    assert_eq!(
+        &read_all_records_read_only(&read_only_log, "queue"),
+        &[(0, b"first".to_vec())]
+    );
+    let mut file_names_after = file_system.list_files().await.unwrap();
+    file_names_after.sort();
+    assert_eq!(file_names_before, file_names_after);
+}
+
+fn read_all_records_read_only<FS: FileSystem + Clone>(
+    read_only_log: &ReadOnlyMultiRecordLog<FS>,
+    queue: &str,
+) -> Vec<(u64, Vec<u8>)> {
+    read_only_log
+        .range(queue, ..)
+        .unwrap()
+        .map(|(position, payload)| (position, payload.into_owned()))
+        .collect()
+}
+
+#[tokio::test]
+async fn test_strict_idempotence() {
+    let tempdir = tempfile::tempdir().unwrap();
+    {
+        // Without strict idempotence, an off-by-one new record is dropped.
+        let mut multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        multi_record_log
+            .append_record("queue", Some(0), &b"first"[..])
+            .await
+            .unwrap();
+        assert_eq!(
+            multi_record_log
+                .append_record("queue", Some(0), &b"second"[..])
+                .await
+                .unwrap(),
+            None
+        );
+    }
+    let options = Options {
+        strict_idempotence: true,
+        ..Default::default()
+    };
+    let mut multi_record_log = MultiRecordLog::open_with_options(tempdir.path(), options)
+        .await
+        .unwrap();
+    // A genuine retry is still ignored.
+    assert_eq!(
+        multi_record_log
+            .append_record("queue", Some(0), &b"first"[..])
+            .await
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        multi_record_log
+            .validate_append("queue", Some(0), &b"first"[..])
+            .unwrap(),
+        None
+    );
+    assert!(matches!(
+        multi_record_log
+            .append_record("queue", Some(0), &b"second"[..])
+            .await,
+        Err(AppendError::PositionConflict)
+    ));
+    assert!(matches!(
+        multi_record_log.validate_append("queue", Some(0), &b"second"[..]),
+        Err(AppendError::PositionConflict)
+    ));
+    assert!(matches!(
+        multi_record_log
+            .append_records(
+                "queue",
+                Some(0),
+                [&b"first"[..], &b"second"[..]].into_iter()
+            )
+            .await,
+        Err(AppendError::PositionConflict)
+    ));
+    assert!(matches!(
+        multi_record_log
+            .append_record_with_headers("queue", Some(0), &b"second"[..], &[])
+            .await,
+        Err(AppendError::PositionConflict)
+    ));
+    assert_eq!(
+        multi_record_log
+            .append_record("queue", Some(1), &b"second"[..])
+            .await
+            .unwrap(),
+        Some(1)
+    );
+    // A retried record which was truncated cannot be checked.
+    multi_record_log.truncate("queue", 1).await.unwrap();
+    assert!(matches!(
+        multi_record_log
+            .append_record("queue", Some(1), &b"second"[..])
+            .await,
+        Err(AppendError::PositionConflict)
+    ));
+}
+
+#[tokio::test]
+async fn test_max_position_gap() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let options = Options {
+        max_position_gap: Some(10),
+        ..Default::default()
+    };
+    let mut multi_record_log = MultiRecordLog::open_with_options(tempdir.path(), options)
+        .await
+        .unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    multi_record_log
+        .append_records_at("queue", [(0, &b"first"[..])].into_iter())
+        .await
+        .unwrap();
+    // A small gap is a regular early append.
+    assert!(matches!(
+        multi_record_log
+            .append_records_at("queue", [(11, &b"early"[..])].into_iter())
+            .await,
+        Err(AppendError::Future)
+    ));
+    assert!(matches!(
+        multi_record_log
+            .append_records_at("queue", [(1_000, &b"desync"[..])].into_iter())
+            .await,
+        Err(AppendError::PositionGapTooLarge {
+            expected: 1,
+            got: 1_000
+        })
+    ));
+    assert!(matches!(
+        multi_record_log
+            .append_record("queue", Some(12), &b"desync"[..])
+            .await,
+        Err(AppendError::PositionGapTooLarge {
+            expected: 1,
+            got: 12
+        })
+    ));
+    assert_eq!(
+        multi_record_log
+            .append_record("queue", Some(11), &b"forward"[..])
+            .await
+            .unwrap(),
+        Some(11)
+    );
+    assert_eq!(multi_record_log.last_position("queue").unwrap(), Some(11));
+}
+
+#[tokio::test]
+async fn test_verify_and_repair_in_place() {
+    let file_system = InMemoryFileSystem::default();
+    {
+        let mut multi_record_log =
+            MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+                .await
+                .unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        for _ in 0..300 {
+            multi_record_log
+                .append_record("queue", None, &[1u8; 1000][..])
+                .await
+                .unwrap();
+        }
+        assert_eq!(multi_record_log.list_file_numbers(), &[0, 1, 2]);
+    }
+    // The end of the first file was not persisted before the log rolled to the next one.
+    let first_filename = FileNumber::for_test(0).filename();
+    {
+        let files = file_system.files.lock().unwrap();
+        let mut data = files[&first_filename].lock().unwrap();
+        let data_len = data.len();
+        data[data_len - 40_000..].fill(0u8);
+    }
+    // Reading stops in the first file.
+    let read_only_log =
+        ReadOnlyMultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+            .await
+            .unwrap();
+    assert_eq!(read_only_log.last_position("queue").unwrap(), Some(86));
+
+    let repair_report = verify_and_repair_in_place_with_file_system(file_system.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        repair_report,
+        RepairReport {
+            num_files_checked: 3,
+            repaired_files: vec![0],
+            corrupted_files: Vec::new(),
+        }
+    );
+    let rolling_reader =
+        RollingReader::open_with_file_system(file_system.clone(), 0, ChecksumAlgo::default())
+            .await
+            .unwrap();
+    let mut record_reader = RecordReader::open(rolling_reader);
+    while record_reader.go_next().await.unwrap() {}
+
+    // The records of the following files are read again.
+    let mut multi_record_log =
+        MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+            .await
+            .unwrap();
+    let positions: Vec<u64> = multi_record_log
+        .range("queue", ..)
+        .unwrap()
+        .map(|(position, _)| position)
+        .collect();
+    assert_eq!(positions[..87], (0..87).collect::<Vec<u64>>());
+    assert_eq!(positions.last(), Some(&299));
+    assert_eq!(positions.len(), 300 - 40);
+    multi_record_log
+        .append_record("queue", None, &b"after repair"[..])
+        .await
+        .unwrap();
+    drop(multi_record_log);
+
+    // The repaired log is left untouched.
+    let repair_report = verify_and_repair_in_place_with_file_system(file_system.clone())
+        .await
+        .unwrap();
+    assert!(repair_report.repaired_files.is_empty());
+    let multi_record_log =
+        MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+            .await
+            .unwrap();
+    assert_eq!(multi_record_log.last_position("queue").unwrap(), Some(300));
+}
+
+#[tokio::test]
+async fn test_verify_and_repair_in_place_corrupted_middle_block() {
+    let file_system = InMemoryFileSystem::default();
+    {
+        let mut multi_record_log =
+            MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+                .await
+                .unwrap();
+        multi_record_log.create_queue("queue").await.unwrap();
+        for _ in 0..100 {
+            multi_record_log
+                .append_record("queue", None, &[1u8; 1000][..])
+                .await
+                .unwrap();
+        }
+        assert_eq!(multi_record_log.list_file_numbers(), &[0]);
+    }
+    // Bits flipped in the second block, while the following blocks hold valid records.
+    let filename = FileNumber::for_test(0).filename();
+    let corrupted_data = {
+        let files = file_system.files.lock().unwrap();
+        let mut data = files[&filename].lock().unwrap();
+        let block_start = FileHeader::current().num_bytes() + BLOCK_NUM_BYTES;
+        for byte in &mut data[block_start + 100..block_start + 110] {
+            *byte ^= 0xFF;
+        }
+        data.clone()
+    };
+
+    let repair_report = verify_and_repair_in_place_with_file_system(file_system.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        repair_report,
+        RepairReport {
+            num_files_checked: 1,
+            repaired_files: Vec::new(),
+            corrupted_files: vec![0],
+        }
+    );
+    assert_eq!(
+        *file_system.files.lock().unwrap()[&filename].lock().unwrap(),
+        corrupted_data
+    );
+    // The records following the corrupted block are still read.
+    let multi_record_log =
+        MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+            .await
+            .unwrap();
+    assert_eq!(multi_record_log.last_position("queue").unwrap(), Some(99));
+}
+
+#[tokio::test]
+async fn test_try_range() {
+    use std::ops::Bound;
+
+    let mut multi_record_log =
+        MultiRecordLog::open_with_file_system(InMemoryFileSystem::default(), Options::default())
+            .await
+            .unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    for payload in [&b"a"[..], b"b", b"c", b"d"] {
+        multi_record_log
+            .append_record("queue", None, payload)
+            .await
+            .unwrap();
+    }
+    let try_range_positions = |range: (Bound<u64>, Bound<u64>)| {
+        multi_record_log
+            .try_range("queue", range)
+            .map(|records| records.map(|(position, _)| position).collect::<Vec<u64>>())
+    };
+    assert_eq!(
+        try_range_positions((Bound::Included(1), Bound::Excluded(3))).unwrap(),
+        &[1, 2]
+    );
+    assert_eq!(
+        try_range_positions((Bound::Excluded(0), Bound::Unbounded)).unwrap(),
+        &[1, 2, 3]
+    );
+    // Ranges starting where they end are empty, but valid.
+    assert!(
+        try_range_positions((Bound::Included(2), Bound::Excluded(2)))
+            .unwrap()
+            .is_empty()
+    );
+    assert!(
+        try_range_positions((Bound::Excluded(1), Bound::Excluded(2)))
+            .unwrap()
+            .is_empty()
+    );
+    assert!(
+        try_range_positions((Bound::Included(u64::MAX), Bound::Included(u64::MAX)))
+            .unwrap()
+            .is_empty()
+    );
+
+    assert!(matches!(
+        try_range_positions((Bound::Included(3), Bound::Excluded(1))),
+        Err(RangeError::Inverted {
+            start: Bound::Included(3),
+            end: Bound::Excluded(1),
+        })
+    ));
+    assert!(matches!(
+        try_range_positions((Bound::Excluded(2), Bound::Excluded(2))),
+        Err(RangeError::Inverted { .. })
+    ));
+    assert!(matches!(
+        try_range_positions((Bound::Included(3), Bound::Included(1))),
+        Err(RangeError::Inverted { .. })
+    ));
+    // The lenient variant yields nothing.
+    assert_eq!(
+        multi_record_log
+            .range("queue", (Bound::Included(3), Bound::Excluded(1)))
+            .unwrap()
+            .count(),
+        0
+    );
+    assert!(matches!(
+        multi_record_log.try_range("missing", ..),
+        Err(RangeError::MissingQueue(queue)) if queue == "missing"
+    ));
+}
+
+#[tokio::test]
+async fn test_queue_format() {
+    let file_system = InMemoryFileSystem::default();
+    {
+        let mut multi_record_log =
+            MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+                .await
+                .unwrap();
+        multi_record_log
+            .create_queue_with_format("json", 1)
+            .await
+            .unwrap();
+        multi_record_log
+            .create_queue_with_format("avro", 2)
+            .await
+            .unwrap();
+        multi_record_log.create_queue("plain").await.unwrap();
+        assert!(matches!(
+            multi_record_log.create_queue_with_format("json", 3).await,
+            Err(CreateQueueError::AlreadyExists)
+        ));
+        assert_eq!(multi_record_log.queue_format("json").unwrap(), Some(1));
+        assert_eq!(multi_record_log.queue_format("plain").unwrap(), None);
+        assert!(multi_record_log.queue_format("missing").is_err());
+        multi_record_log
+            .append_record("json", None, &b"{}"[..])
+            .await
+            .unwrap();
+    }
+    {
+        let mut multi_record_log =
+            MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+                .await
+                .unwrap();
+        assert_eq!(multi_record_log.queue_format("json").unwrap(), Some(1));
+        assert_eq!(multi_record_log.queue_format("avro").unwrap(), Some(2));
+        assert_eq!(multi_record_log.queue_format("plain").unwrap(), None);
+        let read_only_log =
+            ReadOnlyMultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+                .await
+                .unwrap();
+        assert_eq!(read_only_log.queue_format("avro").unwrap(), Some(2));
+
+        // The format tags survive the deletion of the file they were first recorded in, whether
+        // the queue holds records or not.
+        for _ in 0..200 {
+            multi_record_log
+                .append_record("json", None, &[0u8; 1000][..])
+                .await
+                .unwrap();
+        }
+        multi_record_log.truncate("json", 150).await.unwrap();
+        assert!(!multi_record_log.list_file_numbers().contains(&0));
+    }
+    let multi_record_log =
+        MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+            .await
+            .unwrap();
+    assert_eq!(multi_record_log.queue_format("json").unwrap(), Some(1));
+    assert_eq!(multi_record_log.queue_format("avro").unwrap(), Some(2));
+    assert_eq!(multi_record_log.queue_format("plain").unwrap(), None);
+    assert_eq!(multi_record_log.range("json", ..).unwrap().count(), 50);
+    assert_eq!(multi_record_log.last_position("json").unwrap(), Some(200));
+}
+
+#[tokio::test]
+async fn test_append_if_last_is() {
+    let mut multi_record_log =
+        MultiRecordLog::open_with_file_system(InMemoryFileSystem::default(), Options::default())
+            .await
+            .unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    assert_eq!(
+        multi_record_log
+            .append_if_last_is("queue", None, &b"first"[..])
+            .await
+            .unwrap(),
+        0
+    );
+    assert_eq!(
+        multi_record_log
+            .append_if_last_is("queue", Some(0), &b"second"[..])
+            .await
+            .unwrap(),
+        1
+    );
+    // A writer with an outdated view of the queue is told its actual last position.
+    assert!(matches!(
+        multi_record_log
+            .append_if_last_is("queue", Some(0), &b"stale"[..])
+            .await,
+        Err(AppendError::CasMismatch { actual: Some(1) })
+    ));
+    assert!(matches!(
+        multi_record_log
+            .append_if_last_is("queue", None, &b"stale"[..])
+            .await,
+        Err(AppendError::CasMismatch { actual: Some(1) })
+    ));
+    assert_eq!(
+        &read_all_records(&multi_record_log, "queue"),
+        &[&b"first"[..], &b"second"[..]]
+    );
+
+    // Truncating does not change the last position.
+    multi_record_log.truncate("queue", 1).await.unwrap();
+    assert_eq!(
+        multi_record_log
+            .append_if_last_is("queue", Some(1), &b"third"[..])
+            .await
+            .unwrap(),
+        2
+    );
+
+    multi_record_log.create_queue("empty").await.unwrap();
+    assert!(matches!(
+        multi_record_log
+            .append_if_last_is("empty", Some(0), &b"first"[..])
+            .await,
+        Err(AppendError::CasMismatch { actual: None })
+    ));
+    assert!(matches!(
+        multi_record_log
+            .append_if_last_is("missing", None, &b"first"[..])
+            .await,
+        Err(AppendError::MissingQueue(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_gc_keeps_files_of_unflushed_records() {
+    let file_system = InMemoryFileSystem::default();
+    let options = Options {
+        sync_policy: SyncPolicy::OnDelay(Duration::from_secs(3600)),
+        ..Default::default()
+    };
+    let mut multi_record_log = MultiRecordLog::open_with_file_system(file_system.clone(), options)
+        .await
+        .unwrap();
+    multi_record_log.create_queue("old").await.unwrap();
+    multi_record_log.create_queue("new").await.unwrap();
+    for _ in 0..300 {
+        multi_record_log
+            .append_record("old", None, &[1u8; 1000][..])
+            .await
+            .unwrap();
+    }
+    assert_eq!(multi_record_log.list_file_numbers(), &[0, 1, 2]);
+    multi_record_log.sync().await.unwrap();
+    for _ in 0..3 {
+        multi_record_log
+            .append_record("new", None, &[2u8; 10][..])
+            .await
+            .unwrap();
+    }
+    assert_eq!(multi_record_log.pending_unflushed().records, 3);
+
+    // The current file only holds truncated records and records which were not flushed.
+    multi_record_log.truncate("old", 299).await.unwrap();
+    assert_eq!(multi_record_log.list_file_numbers(), &[2]);
+    assert_eq!(
+        multi_record_log.pending_unflushed(),
+        PendingFlush::default()
+    );
+    assert_eq!(multi_record_log.health(), Health::Healthy);
+    assert_eq!(multi_record_log.range("new", ..).unwrap().count(), 3);
+
+    // The records were flushed before files got deleted.
+    let multi_record_log =
+        MultiRecordLog::open_with_file_system(file_system.crash(), Options::default())
+            .await
+            .unwrap();
+    assert_eq!(multi_record_log.range("new", ..).unwrap().count(), 3);
+    assert_eq!(multi_record_log.range("old", ..).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn test_append_records_acked() {
+    use futures::FutureExt;
+
+    let options = Options {
+        sync_policy: SyncPolicy::OnDelay(Duration::from_secs(3600)),
+        ..Default::default()
+    };
+    let mut multi_record_log =
+        MultiRecordLog::open_with_file_system(InMemoryFileSystem::default(), options)
+            .await
+            .unwrap();
+    multi_record_log.create_queue("queue").await.unwrap();
+    let mut first_acks = multi_record_log
+        .append_records_acked("queue", None, [&b"a"[..], b"b", b"c"].into_iter())
+        .await
+        .unwrap();
+    assert_eq!(first_acks.pending_positions(), 0..3);
+    assert_eq!(first_acks.next().now_or_never(), None);
+    multi_record_log.sync().await.unwrap();
+    let mut second_acks = multi_record_log
+        .append_records_acked("queue", None, [&b"d"[..], b"e"].into_iter())
+        .await
+        .unwrap();
+    assert_eq!(second_acks.pending_positions(), 3..5);
+
+    // Only the first batch was flushed.
+    for position in 0..3 {
+        assert_eq!(first_acks.next().await, Some(position));
+    }
+    assert_eq!(first_acks.next().await, None);
+    assert_eq!(second_acks.next().now_or_never(), None);
+
+    multi_record_log.sync().await.unwrap();
+    assert_eq!(second_acks.next().await, Some(3));
+    assert_eq!(second_acks.pending_positions(), 4..5);
+    assert_eq!(second_acks.next().await, Some(4));
+    assert_eq!(second_acks.next().await, None);
+
+    // A retried append writes nothing, and has nothing to acknowledge.
+    let mut retried_acks = multi_record_log
+        .append_records_acked("queue", Some(4), std::iter::once(&b"e"[..]))
+        .await
+        .unwrap();
+    assert!(retried_acks.pending_positions().is_empty());
+    assert_eq!(retried_acks.next().await, None);
+
+    // The acknowledgments end if the log is dropped before flushing.
+    let mut dropped_acks = multi_record_log
+        .append_records_acked("queue", None, std::iter::once(&b"f"[..]))
+        .await
+        .unwrap();
+    drop(multi_record_log);
+    assert_eq!(dropped_acks.next().await, None);
+}
+
+#[tokio::test]
+async fn test_auto_compact_on_open() {
+    let auto_compact_options = |min_total_bytes| Options {
+        auto_compact_on_open: Some(AutoCompact {
+            min_dead_ratio: 0.5,
+            min_total_bytes,
+        }),
+        ..Default::default()
+    };
+    let file_system = InMemoryFileSystem::default();
+    {
+        let mut multi_record_log =
+            MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+                .await
+                .unwrap();
+        multi_record_log.create_queue("healthy").await.unwrap();
+        for _ in 0..300 {
+            multi_record_log
+                .append_record("healthy", None, &[1u8; 1000][..])
+                .await
+                .unwrap();
+        }
+        assert_eq!(multi_record_log.list_file_numbers(), &[0, 1, 2]);
+    }
+    // The records of a healthy log are all live: it is left alone.
+    let multi_record_log =
+        MultiRecordLog::open_with_file_system(file_system.clone(), auto_compact_options(100_000))
+            .await
+            .unwrap();
+    assert_eq!(multi_record_log.list_file_numbers(), &[0, 1, 2]);
+    drop(multi_record_log);
+
+    let file_system = InMemoryFileSystem::default();
+    {
+        let mut multi_record_log =
+            MultiRecordLog::open_with_file_system(file_system.clone(), Options::default())
+                .await
+                .unwrap();
+        multi_record_log.create_queue("slow").await.unwrap();
+        multi_record_log.create_queue("fast").await.unwrap();
+        multi_record_log
+            .append_record("slow", None, &b"slow"[..])
+            .await
+            .unwrap();
+        for _ in 0..300 {
+            multi_record_log
+                .append_record("fast", None, &[1u8; 1000][..])
+                .await
+                .unwrap();
+        }
+        multi_record_log.truncate("fast", 298).await.unwrap();
+        // The record of the slow queue keeps all the files from being garbage collected.
+        assert_eq!(multi_record_log.list_file_numbers(), &[0, 1, 2]);
+    }
+    // The fragmented log is too small to be compacted.
+    let multi_record_log =
+        MultiRecordLog::open_with_file_system(file_system.clone(), auto_compact_options(1_000_000))
+            .await
+            .unwrap();
+    assert_eq!(multi_record_log.list_file_numbers(), &[0, 1, 2]);
+    drop(multi_record_log);
+
+    let multi_record_log =
+        MultiRecordLog::open_with_file_system(file_system.clone(), auto_compact_options(100_000))
+            .await
+            .unwrap();
+    assert_eq!(multi_record_log.list_file_numbers(), &[2]);
+    assert_eq!(
+        multi_record_log
+            .range("slow", ..)
+            .unwrap()
+            .collect::<Vec<_>>(),
+        [(0, Cow::Borrowed(&b"slow"[..]))]
+    );
+    assert_eq!(multi_record_log.range("fast", ..).unwrap().count(), 1);
+    drop(multi_record_log);
+
+    // The compaction was persisted.
+    let multi_record_log =
+        MultiRecordLog::open_with_file_system(file_system.crash(), Options::default())
+            .await
+            .unwrap();
+    assert_eq!(multi_record_log.list_file_numbers(), &[2]);
+    assert_eq!(multi_record_log.range("slow", ..).unwrap().count(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_shared_multi_record_log_concurrent_appends() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let shared_log = SharedMultiRecordLog::new(MultiRecordLog::open(tempdir.path()).await.unwrap());
+    for queue in ["queue1", "queue2"] {
+        shared_log.create_queue(queue).await.unwrap();
+    }
+    let num_appends_per_task = 50;
+    let mut append_handles = Vec::new();
+    for task_id in 0..8u8 {
+        let shared_log = shared_log.clone();
+        let queue = if task_id % 2 == 0 { "queue1" } else { "queue2" };
+        append_handles.push(tokio::spawn(async move {
+            let mut positions = Vec::new();
+            for i in 0..num_appends_per_task {
+                let position = if i % 5 == 0 {
+                    let payloads = [[task_id, i], [task_id, i + 1]];
+                    shared_log
+                        .append_records(queue, None, payloads.iter().map(|payload| &payload[..]))
+                        .await
+                } else {
+                    shared_log
+                        .append_record(queue, None, &[task_id, i][..])
+                        .await
+                };
+                positions.push(position.unwrap().unwrap());
+                tokio::task::yield_now().await;
+            }
+            positions
+        }));
+    }
+    for append_handle in append_handles {
+        let positions = append_handle.await.unwrap();
+        // The appends of a task get increasing positions.
+        assert!(positions.windows(2).all(|window| window[0] < window[1]));
+    }
+    shared_log.sync().await.unwrap();
+
+    let check_queues = |multi_record_log: &MultiRecordLog| {
+        for (queue, task_ids) in [("queue1", [0, 2, 4, 6]), ("queue2", [1, 3, 5, 7])] {
+            let records: Vec<(u64, Vec<u8>)> = multi_record_log
+                .range(queue, ..)
+                .unwrap()
+                .map(|(position, payload)| (position, payload.into_owned()))
+                .collect();
+            // 4 tasks, each appending 10 batches of 2 records and 40 single records.
+            assert_eq!(records.len(), 4 * 60);
+            // Positions are contiguous: no record got lost or overwritten.
+            for (expected_position, (position, _)) in records.iter().enumerate() {
+                assert_eq!(*position, expected_position as u64);
+            }
+            for task_id in task_ids {
+                let task_records: Vec<u8> = records
+                    .iter()
+                    .filter(|(_, payload)| payload[0] == task_id)
+                    .map(|(_, payload)| payload[1])
+                    .collect();
+                let expected_records: Vec<u8> = (0..num_appends_per_task)
+                    .flat_map(|i| if i % 5 == 0 { vec![i, i + 1] } else { vec![i] })
+                    .collect();
+                assert_eq!(task_records, expected_records);
+            }
+        }
+    };
+    check_queues(&*shared_log.lock().await);
+    drop(shared_log);
+
+    let multi_record_log = MultiRecordLog::open(tempdir.path()).await.unwrap();
+    check_queues(&multi_record_log);
+}
@@ -0,0 +1,83 @@
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
+
+use crate::error::{AppendError, MissingQueue};
+use crate::file_system::{DefaultFileSystem, FileSystem};
+use crate::MultiRecordLog;
+
+/// A type which can be stored in a [`TypedMultiRecordLog`].
+pub trait Record: Sized {
+    /// Error returned when bytes read back from the log are not a valid record.
+    type Error: std::error::Error;
+
+    /// Serializes the record into `buffer`, which is empty.
+    fn to_bytes(&self, buffer: &mut Vec<u8>);
+
+    /// Deserializes a record serialized by [`Self::to_bytes`].
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Self::Error>;
+}
+
+/// A [`MultiRecordLog`] storing records of type `T` instead of raw payloads.
+///
+/// Records are serialized with [`Record::to_bytes`] when appended, and deserialized each time
+/// they are read. Operations which don't deal with payloads, such as creating queues or
+/// truncating them, are done on the underlying log.
+pub struct TypedMultiRecordLog<T: Record, FS: FileSystem = DefaultFileSystem> {
+    multi_record_log: MultiRecordLog<FS>,
+    // Reused to serialize records, so that appends don't allocate.
+    buffer: Vec<u8>,
+    _record: PhantomData<fn() -> T>,
+}
+
+impl<T: Record, FS: FileSystem> TypedMultiRecordLog<T, FS> {
+    pub fn new(multi_record_log: MultiRecordLog<FS>) -> Self {
+        TypedMultiRecordLog {
+            multi_record_log,
+            buffer: Vec::new(),
+            _record: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &MultiRecordLog<FS> {
+        &self.multi_record_log
+    }
+
+    pub fn inner_mut(&mut self) -> &mut MultiRecordLog<FS> {
+        &mut self.multi_record_log
+    }
+
+    pub fn into_inner(self) -> MultiRecordLog<FS> {
+        self.multi_record_log
+    }
+
+    /// Appends a record to the log. See [`MultiRecordLog::append_record`].
+    pub async fn append(
+        &mut self,
+        queue: &str,
+        position_opt: Option<u64>,
+        record: &T,
+    ) -> Result<Option<u64>, AppendError> {
+        self.buffer.clear();
+        record.to_bytes(&mut self.buffer);
+        self.multi_record_log
+            .append_record(queue, position_opt, &self.buffer[..])
+            .await
+    }
+
+    /// Returns the records of `queue` whose position is contained in `range`. See
+    /// [`MultiRecordLog::range`].
+    ///
+    /// Each record is deserialized as it is yielded. A record which fails to deserialize is
+    /// yielded as an error, along with its position, and does not stop the iteration.
+    pub fn range<R>(
+        &self,
+        queue: &str,
+        range: R,
+    ) -> Result<impl Iterator<Item = (u64, Result<T, T::Error>)> + '_, MissingQueue>
+    where
+        R: RangeBounds<u64> + 'static,
+    {
+        let records = self.multi_record_log.range(queue, range)?;
+        Ok(records.map(|(position, payload)| (position, T::from_bytes(&payload))))
+    }
+}